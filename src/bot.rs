@@ -1,22 +1,36 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
 use futures::StreamExt;
-use hyper::{Body, Request, Response, StatusCode};
-use ring::hmac;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ring::{constant_time, hmac};
+use serde::Serialize;
 use snafu::{OptionExt, ResultExt};
 use tokio::{sync::Mutex, time::sleep};
 
 use crate::{
 	core::{
-		handle_command, process_commit_checks_and_statuses, AppState,
-		CommentCommand, MergeCommentCommand, PullRequestMergeCancelOutcome,
+		process_commit_checks_and_statuses, requeue_pull_request, run_command,
+		AppState, CommentCommand, MergeCommentCommand,
+		PullRequestMergeCancelOutcome,
 	},
+	db::merge_requests_cf,
 	error::{self, handle_error, Error, PullRequestDetails},
 	github::*,
+	logging::with_delivery_id,
 	merge_request::{
-		cleanup_merge_request, MergeRequest, MergeRequestCleanupReason,
+		cleanup_merge_request, consume_merge_allowance, delete_merge_request,
+		deserialize_merge_request, forget_head_push, list_frozen_repositories,
+		list_merge_requests, queue_merge_request, record_head_push, set_paused,
+		set_repository_frozen, was_head_pushed_after, MergeRequest,
+		MergeRequestCleanupReason, MergeRequestQueuedMessage,
 	},
+	messages::Message,
+	poll_heartbeat::PollHeartbeat,
 	types::Result,
+	webhook_archive::{archive_webhook_payload, replay_webhook_payload},
 	WEBHOOK_PARSING_ERROR_TEMPLATE,
 };
 
@@ -32,37 +46,93 @@ fn verify_github_webhook_signature(
 pub async fn handle_http_request_for_bot(
 	req: Request<Body>,
 	state: Arc<Mutex<AppState>>,
+	poll_heartbeat: Arc<PollHeartbeat>,
 ) -> Result<Response<Body>> {
 	if req.uri().path() == "/webhook" {
 		let state = &*state.lock().await;
 
-		if let Some((merge_cancel_outcome, err)) =
+		// Tags the logs of the delivery so that they can be correlated with
+		// Github's delivery logs
+		let delivery_id = req
+			.headers()
+			.get("x-github-delivery")
+			.and_then(|value| value.to_str().ok())
+			.map(|value| value.to_string());
+		// Errors which happen while processing a valid delivery are handled on
+		// our side, thus Github is only told about the deliveries it should
+		// not have sent, which makes them show up as failed in the App's
+		// dashboard
+		let status = with_delivery_id(delivery_id, async {
 			match process_webhook_request(req, state).await {
-				Ok((merge_cancel_outcome, result)) => match result {
-					Ok(_) => None,
-					Err(err) => Some((merge_cancel_outcome, err)),
-				},
+				Ok((_, Ok(_))) => StatusCode::OK,
+				Ok((merge_cancel_outcome, Err(err))) => {
+					handle_error(merge_cancel_outcome, err, state).await;
+					StatusCode::OK
+				}
 				Err(err) => {
-					Some((PullRequestMergeCancelOutcome::WasNotCancelled, err))
+					let status = match err {
+						Error::MalformedWebhookRequest { .. } => {
+							StatusCode::BAD_REQUEST
+						}
+						Error::InvalidWebhookSignature { .. } => {
+							StatusCode::UNAUTHORIZED
+						}
+						_ => StatusCode::OK,
+					};
+					handle_error(
+						PullRequestMergeCancelOutcome::WasNotCancelled,
+						err,
+						state,
+					)
+					.await;
+					status
 				}
-			} {
-			handle_error(merge_cancel_outcome, err, state).await
-		};
+			}
+		})
+		.await;
 
+		build_status_response(status)
+	} else if req.uri().path() == "/queue"
+		|| req.uri().path().starts_with("/queue/")
+	{
+		let state = &*state.lock().await;
+		handle_queue_request(&req, state)
+	} else if req.uri().path() == "/freeze"
+		|| req.uri().path().starts_with("/freeze/")
+	{
+		let state = &*state.lock().await;
+		handle_freeze_request(&req, state)
+	} else if req.uri().path().starts_with("/replay/") {
+		let state = &*state.lock().await;
+		handle_replay_request(&req, state).await
+	} else if req.uri().path().starts_with("/requeue/") {
+		let state = &*state.lock().await;
+		handle_requeue_request(&req, state).await
+	} else if req.uri().path() == "/pause" || req.uri().path() == "/resume" {
+		let state = &*state.lock().await;
+		handle_pause_request(&req, state)
+	} else if req.uri().path() == "/health" {
 		Response::builder()
 			.status(StatusCode::OK)
-			.body(Body::from(""))
+			.body(Body::from("OK"))
 			.ok()
 			.context(error::Message {
-				msg: "Error building response".to_owned(),
+				msg: "Healthcheck".to_owned(),
 			})
-	} else if req.uri().path() == "/health" {
+	} else if req.uri().path() == "/health/poll" {
+		// The state's lock is not taken since it's held for as long as a poll
+		// is ongoing
+		let (status, body) = if poll_heartbeat.is_healthy() {
+			(StatusCode::OK, "OK")
+		} else {
+			(StatusCode::SERVICE_UNAVAILABLE, "The poll has stalled")
+		};
 		Response::builder()
-			.status(StatusCode::OK)
-			.body(Body::from("OK"))
+			.status(status)
+			.body(Body::from(body))
 			.ok()
 			.context(error::Message {
-				msg: "Healthcheck".to_owned(),
+				msg: "Poll healthcheck".to_owned(),
 			})
 	} else {
 		Response::builder()
@@ -75,31 +145,331 @@ pub async fn handle_http_request_for_bot(
 	}
 }
 
+// Admin requests are authenticated through the ADMIN_TOKEN bearer token. Returns
+// the status to respond with if the request should not be handled.
+fn check_admin_request(
+	req: &Request<Body>,
+	state: &AppState,
+	allowed_methods: &[Method],
+) -> Option<StatusCode> {
+	let AppState { config, .. } = state;
+
+	match &config.admin_token {
+		None => Some(StatusCode::NOT_FOUND),
+		Some(admin_token) => {
+			let is_authorized = req
+				.headers()
+				.get("authorization")
+				.and_then(|value| value.to_str().ok())
+				.and_then(|value| value.strip_prefix("Bearer "))
+				.map(|token| {
+					constant_time::verify_slices_are_equal(
+						token.as_bytes(),
+						admin_token.as_bytes(),
+					)
+					.is_ok()
+				})
+				.unwrap_or(false);
+			if !is_authorized {
+				Some(StatusCode::UNAUTHORIZED)
+			} else if !allowed_methods.contains(req.method()) {
+				Some(StatusCode::METHOD_NOT_ALLOWED)
+			} else {
+				None
+			}
+		}
+	}
+}
+
+fn build_status_response(status: StatusCode) -> Result<Response<Body>> {
+	Response::builder()
+		.status(status)
+		.body(Body::from(""))
+		.ok()
+		.context(error::Message {
+			msg: "Error building response".to_owned(),
+		})
+}
+
+fn build_json_response(body: String) -> Result<Response<Body>> {
+	Response::builder()
+		.status(StatusCode::OK)
+		.header("Content-Type", "application/json")
+		.body(Body::from(body))
+		.ok()
+		.context(error::Message {
+			msg: "Error building response".to_owned(),
+		})
+}
+
+// Lists the merge requests in the database for dashboards (GET /queue) or
+// deletes the records of a pull request without notifying anyone (DELETE
+// /queue/owner/repo/number). The queue might reference private repositories,
+// hence why it's an admin request.
+fn handle_queue_request(
+	req: &Request<Body>,
+	state: &AppState,
+) -> Result<Response<Body>> {
+	if let Some(status) =
+		check_admin_request(req, state, &[Method::GET, Method::DELETE])
+	{
+		return build_status_response(status);
+	}
+
+	let pull_request = req
+		.uri()
+		.path()
+		.trim_start_matches("/queue")
+		.trim_matches('/');
+
+	if req.method() == Method::DELETE {
+		let parts = pull_request.split('/').collect::<Vec<_>>();
+		let (owner, repo, number) = match parts.as_slice() {
+			[owner, repo, number] if !owner.is_empty() && !repo.is_empty() => {
+				match number.parse::<i64>() {
+					Ok(number) => (*owner, *repo, number),
+					Err(_) => {
+						return build_status_response(StatusCode::BAD_REQUEST)
+					}
+				}
+			}
+			_ => return build_status_response(StatusCode::BAD_REQUEST),
+		};
+		return build_status_response(
+			if delete_merge_request(state, owner, repo, number)? {
+				StatusCode::NO_CONTENT
+			} else {
+				StatusCode::NOT_FOUND
+			},
+		);
+	} else if !pull_request.is_empty() {
+		return build_status_response(StatusCode::BAD_REQUEST);
+	}
+
+	#[derive(Serialize)]
+	struct QueueEntry {
+		#[serde(flatten)]
+		mr: MergeRequest,
+		seconds_in_queue: Option<u64>,
+	}
+	let queue = list_merge_requests(state)
+		.into_iter()
+		.map(|mr| QueueEntry {
+			seconds_in_queue: mr.time_in_queue().map(|time| time.as_secs()),
+			mr,
+		})
+		.collect::<Vec<_>>();
+
+	build_json_response(serde_json::to_string(&queue).context(error::Json)?)
+}
+
+// Lists the frozen repositories (GET /freeze), freezes the merges of a
+// repository (PUT /freeze/owner/repo) or unfreezes them (DELETE
+// /freeze/owner/repo). A single base branch is targeted with
+// /freeze/owner/repo:branch. Responds with the resulting frozen repositories.
+fn handle_freeze_request(
+	req: &Request<Body>,
+	state: &AppState,
+) -> Result<Response<Body>> {
+	if let Some(status) = check_admin_request(
+		req,
+		state,
+		&[Method::GET, Method::PUT, Method::DELETE],
+	) {
+		return build_status_response(status);
+	}
+
+	let repository = req
+		.uri()
+		.path()
+		.trim_start_matches("/freeze")
+		.trim_matches('/');
+	let is_valid_repository = {
+		let (repository, branch) = match repository.split_once(':') {
+			Some((repository, branch)) => (repository, Some(branch)),
+			None => (repository, None),
+		};
+		let parts = repository.split('/').collect::<Vec<_>>();
+		parts.len() == 2
+			&& parts.iter().all(|part| !part.is_empty())
+			&& branch.map(|branch| !branch.is_empty()).unwrap_or(true)
+	};
+
+	if req.method() == Method::GET {
+		if !repository.is_empty() {
+			return build_status_response(StatusCode::BAD_REQUEST);
+		}
+	} else if is_valid_repository {
+		let is_frozen = req.method() == Method::PUT;
+		log::info!(
+			"Setting the merge freeze of {} to {}",
+			repository,
+			is_frozen
+		);
+		set_repository_frozen(state, repository, is_frozen)?;
+	} else {
+		return build_status_response(StatusCode::BAD_REQUEST);
+	}
+
+	let mut frozen_repos = list_frozen_repositories(state)?
+		.into_iter()
+		.collect::<Vec<_>>();
+	frozen_repos.sort();
+	build_json_response(
+		serde_json::to_string(&frozen_repos).context(error::Json)?,
+	)
+}
+
+// Reconsiders a pull request without a new GitHub event, e.g. after an outage
+// (POST /requeue/owner/repo/number?requested_by=login). The merge is recorded
+// as requested by the operator's login, which is required, so that the merge
+// rules are applied to an actual user.
+async fn handle_requeue_request(
+	req: &Request<Body>,
+	state: &AppState,
+) -> Result<Response<Body>> {
+	if let Some(status) = check_admin_request(req, state, &[Method::POST]) {
+		return build_status_response(status);
+	}
+
+	let pull_request = req
+		.uri()
+		.path()
+		.trim_start_matches("/requeue")
+		.trim_matches('/');
+	let parts = pull_request.split('/').collect::<Vec<_>>();
+	let (owner, repo, number) = match parts.as_slice() {
+		[owner, repo, number] if !owner.is_empty() && !repo.is_empty() => {
+			match number.parse::<i64>() {
+				Ok(number) => (*owner, *repo, number),
+				Err(_) => {
+					return build_status_response(StatusCode::BAD_REQUEST)
+				}
+			}
+		}
+		_ => return build_status_response(StatusCode::BAD_REQUEST),
+	};
+
+	let requested_by = req.uri().query().and_then(|query| {
+		url::form_urlencoded::parse(query.as_bytes())
+			.find(|(key, value)| key == "requested_by" && !value.is_empty())
+			.map(|(_, value)| value.into_owned())
+	});
+	let requested_by = match requested_by {
+		Some(requested_by) => requested_by,
+		None => return build_status_response(StatusCode::BAD_REQUEST),
+	};
+
+	log::info!(
+		"Requeueing {}/{}#{} as requested by {}",
+		owner,
+		repo,
+		number,
+		requested_by
+	);
+	let status =
+		match requeue_pull_request(state, owner, repo, number, &requested_by)
+			.await
+		{
+			Ok(_) => StatusCode::NO_CONTENT,
+			Err(err) => {
+				handle_error(
+					PullRequestMergeCancelOutcome::WasNotCancelled,
+					err,
+					state,
+				)
+				.await;
+				StatusCode::INTERNAL_SERVER_ERROR
+			}
+		};
+	build_status_response(status)
+}
+
+// Processes a payload archived through WEBHOOK_ARCHIVE_DIR again, e.g. for
+// reproducing a bug (POST /replay/file). Only the files directly inside of the
+// archive directory can be replayed.
+async fn handle_replay_request(
+	req: &Request<Body>,
+	state: &AppState,
+) -> Result<Response<Body>> {
+	if let Some(status) = check_admin_request(req, state, &[Method::POST]) {
+		return build_status_response(status);
+	}
+
+	let archive_dir = match &state.config.webhook_archive_dir {
+		Some(archive_dir) => archive_dir,
+		None => return build_status_response(StatusCode::NOT_FOUND),
+	};
+	let file = req.uri().path().trim_start_matches("/replay/");
+	if file.is_empty() || file.contains('/') || file.contains("..") {
+		return build_status_response(StatusCode::BAD_REQUEST);
+	}
+	let path = archive_dir.join(file);
+	if !path.is_file() {
+		return build_status_response(StatusCode::NOT_FOUND);
+	}
+
+	let status = match replay_webhook_payload(state, &path).await {
+		Ok((_, Ok(_))) => StatusCode::NO_CONTENT,
+		Ok((merge_cancel_outcome, Err(err))) => {
+			handle_error(merge_cancel_outcome, err, state).await;
+			StatusCode::NO_CONTENT
+		}
+		Err(err) => {
+			log::error!("Failed to replay {}: {}", path.display(), err);
+			StatusCode::BAD_REQUEST
+		}
+	};
+	build_status_response(status)
+}
+
+// Pauses (POST /pause) or resumes (POST /resume) all merges, e.g. during an
+// incident. Webhooks are still received while paused.
+fn handle_pause_request(
+	req: &Request<Body>,
+	state: &AppState,
+) -> Result<Response<Body>> {
+	if let Some(status) = check_admin_request(req, state, &[Method::POST]) {
+		return build_status_response(status);
+	}
+
+	let is_paused = req.uri().path() == "/pause";
+	log::info!("Setting the pause of processbot to {}", is_paused);
+	set_paused(state, is_paused)?;
+
+	build_json_response(
+		serde_json::to_string(&serde_json::json!({ "paused": is_paused }))
+			.context(error::Json)?,
+	)
+}
+
 pub async fn process_webhook_request(
 	mut req: Request<Body>,
 	state: &AppState,
 ) -> Result<(PullRequestMergeCancelOutcome, Result<()>)> {
 	let mut msg_bytes = vec![];
 	while let Some(item) = req.body_mut().next().await {
-		msg_bytes.extend_from_slice(&item.ok().context(error::Message {
-			msg: "Error getting bytes from request body".to_owned(),
-		})?);
+		msg_bytes.extend_from_slice(&item.ok().context(
+			error::MalformedWebhookRequest {
+				msg: "Error getting bytes from request body".to_owned(),
+			},
+		)?);
 	}
 
 	let webhook_signature = req
 		.headers()
 		.get("x-hub-signature")
-		.context(error::Message {
+		.context(error::InvalidWebhookSignature {
 			msg: "Missing x-hub-signature".to_string(),
 		})?
 		.to_str()
 		.ok()
-		.context(error::Message {
+		.context(error::MalformedWebhookRequest {
 			msg: "Error parsing x-hub-signature".to_owned(),
 		})?
 		.replace("sha1=", "");
 	let sig_bytes = base16::decode(webhook_signature.as_bytes()).ok().context(
-		error::Message {
+		error::MalformedWebhookRequest {
 			msg: "Error decoding x-hub-signature".to_owned(),
 		},
 	)?;
@@ -112,10 +482,50 @@ pub async fn process_webhook_request(
 		&sig_bytes,
 	)
 	.ok()
-	.context(error::Message {
+	.context(error::InvalidWebhookSignature {
 		msg: "Validation signature does not match".to_owned(),
 	})?;
 
+	// Payloads of events which processbot does not handle fail to be parsed
+	// later and are ignored, but they should at least be JSON
+	if let Err(err) = serde_json::from_slice::<serde_json::Value>(&msg_bytes) {
+		return Err(Error::MalformedWebhookRequest {
+			msg: format!("The payload is not valid JSON: {}", err),
+		});
+	}
+
+	// Archiving is only meant for debugging, thus it should not get in the way
+	// of processing the payload
+	if let Some(archive_dir) = &config.webhook_archive_dir {
+		match archive_webhook_payload(archive_dir, &msg_bytes) {
+			Ok(path) => log::info!("Archived payload to {}", path.display()),
+			Err(err) => log::error!(
+				"Failed to archive payload to {}: {}",
+				archive_dir.display(),
+				err
+			),
+		}
+	}
+
+	// A misconfigured Github App might deliver events from installations other
+	// than the one processbot is configured for
+	if let Ok(GithubWebhookInstallationPayload {
+		installation: Some(installation),
+	}) = serde_json::from_slice(&msg_bytes)
+	{
+		let expected_installation_id =
+			state.gh_client.installation_id().await?;
+		if installation.id != expected_installation_id {
+			log::info!(
+				"Ignoring payload from installation {} since processbot is configured for installation {} ({})",
+				installation.id,
+				expected_installation_id,
+				config.installation_login
+			);
+			return Ok((PullRequestMergeCancelOutcome::ShaNotFound, Ok(())));
+		}
+	}
+
 	log::info!("Parsing payload {}", String::from_utf8_lossy(&msg_bytes));
 	match serde_json::from_slice::<GithubWebhookPayload>(&msg_bytes) {
 		Ok(payload) => Ok(handle_github_payload(payload, state).await),
@@ -128,7 +538,9 @@ pub async fn process_webhook_request(
 				DetectUserCommentPullRequest,
 			>(&msg_bytes)
 			.ok()
-			.and_then(|detected| detected.get_pull_request_details());
+			.and_then(|detected| {
+				detected.get_pull_request_details(&config.installation_login)
+			});
 
 			if let Some(pr_details) = pr_details {
 				Err(Error::Message {
@@ -163,10 +575,11 @@ pub async fn handle_github_payload(
 			repository,
 		} => {
 			if issue.pull_request.is_none()
-				|| comment.user.type_field == GithubUserType::Bot
+				|| !is_allowed_command_author(state, &comment.user).await
 			{
 				(Ok(()), None)
 			} else {
+				let details = issue.get_pull_request_details_in(&repository);
 				let (sha, result) = handle_pull_request_comment(
 					state,
 					&comment,
@@ -177,29 +590,28 @@ pub async fn handle_github_payload(
 				.await;
 
 				(
-					result.map_err(|err| match err {
-						Error::WithPullRequestDetails { .. } => err,
-						err => {
-							if let Some(details) =
-								issue.get_pull_request_details()
-							{
-								err.with_pull_request_details(details)
-							} else {
-								err
-							}
+					result.map_err(|err| {
+						match err {
+							Error::WithPullRequestDetails { .. } => err,
+							err => err.with_pull_request_details(details),
 						}
+						.into_command_reply()
 					}),
 					sha,
 				)
 			}
 		}
-		GithubWebhookPayload::CommitStatus { sha, state: status } => (
-			match status {
-				GithubCommitStatusState::Unknown => Ok(()),
-				_ => process_commit_checks_and_statuses(state, &sha).await,
-			},
-			Some(sha),
-		),
+		// The commits are processed in the background (see process_next_queued_commit)
+		GithubWebhookPayload::CommitStatus {
+			sha,
+			state: status,
+			repository,
+		} => {
+			if status != GithubCommitStatusState::Unknown {
+				queue_commit_processing(state, &repository, &sha);
+			}
+			(Ok(()), None)
+		}
 		GithubWebhookPayload::CheckRun {
 			check_run:
 				GithubCheckRun {
@@ -207,42 +619,162 @@ pub async fn handle_github_payload(
 					head_sha: sha,
 					..
 				},
-			..
-		} => (
-			match status {
-				GithubCheckRunStatus::Completed => {
-					process_commit_checks_and_statuses(state, &sha).await
-				}
-				_ => Ok(()),
-			},
-			Some(sha),
-		),
+			repository,
+		} => {
+			if status == GithubCheckRunStatus::Completed {
+				queue_commit_processing(state, &repository, &sha);
+			}
+			(Ok(()), None)
+		}
 		GithubWebhookPayload::WorkflowJob {
 			workflow_job:
 				GithubWorkflowJob {
 					head_sha: sha,
 					conclusion,
 				},
+			repository,
+		} => {
+			if conclusion.is_some() {
+				queue_commit_processing(state, &repository, &sha);
+			}
+			(Ok(()), None)
+		}
+		GithubWebhookPayload::CheckSuite {
+			check_suite:
+				GithubCheckSuite {
+					status,
+					head_sha: sha,
+					..
+				},
+			repository,
+		} => {
+			// Some integrations only signal their completion through the check suite
+			if status == GithubCheckRunStatus::Completed {
+				queue_commit_processing(state, &repository, &sha);
+			}
+			(Ok(()), None)
+		}
+		GithubWebhookPayload::PullRequest {
+			action,
+			pull_request: pr,
+			label: Some(label),
+			sender,
+			..
+		} if matches!(
+			action,
+			GithubPullRequestAction::Labeled
+				| GithubPullRequestAction::Unlabeled
+		) =>
+		{
+			let (sha, result) =
+				handle_automerge_label(state, &action, &pr, &label, &sender)
+					.await;
+			(
+				result.map_err(|err| {
+					err.with_pull_request_details(PullRequestDetails {
+						owner: pr.base.repo.owner.login.to_owned(),
+						repo: pr.base.repo.name.to_owned(),
+						number: pr.number,
+					})
+					.into_command_reply()
+				}),
+				sha,
+			)
+		}
+		GithubWebhookPayload::PullRequest {
+			action,
+			pull_request: pr,
+			before,
+			sender,
 			..
 		} => (
-			if conclusion.is_some() {
-				process_commit_checks_and_statuses(state, &sha).await
-			} else {
-				Ok(())
-			},
-			Some(sha),
+			handle_pull_request_event(
+				state,
+				&action,
+				&pr,
+				before.as_deref(),
+				&sender,
+			)
+			.await
+			.map_err(|err| {
+				err.with_pull_request_details(PullRequestDetails {
+					owner: pr.base.repo.owner.login.to_owned(),
+					repo: pr.base.repo.name.to_owned(),
+					number: pr.number,
+				})
+			}),
+			None,
 		),
 	};
 
-	// From this point onwards we'll clean the SHA from the database if this is a error which stops
-	// the merge process
-
 	// Without the SHA we'll not be able to fetch the database for more context, so exit early
-	let sha = match sha {
-		Some(sha) => sha,
-		None => return (PullRequestMergeCancelOutcome::ShaNotFound, result),
-	};
+	match sha {
+		Some(sha) => cancel_merge_on_error(state, &sha, result).await,
+		None => (PullRequestMergeCancelOutcome::ShaNotFound, result),
+	}
+}
 
+// processbot's own login is only fetched for comments from bots. If it can't be
+// fetched, their comments are ignored rather than risking a loop of replies.
+async fn is_allowed_command_author(
+	state: &AppState,
+	user: &GithubUser,
+) -> bool {
+	if user.type_field != GithubUserType::Bot {
+		return true;
+	}
+	match state.gh_client.app_login().await {
+		Ok(app_login) => {
+			state.config.is_allowed_command_author(user, &app_login)
+		}
+		Err(err) => {
+			log::error!(
+				"Ignoring the comment of {} since processbot's own login could not be fetched due to {}",
+				user.login,
+				err
+			);
+			false
+		}
+	}
+}
+
+fn queue_commit_processing(
+	state: &AppState,
+	repository: &GithubIssueRepository,
+	sha: &str,
+) {
+	let repository = format!("{}/{}", repository.owner.login, repository.name);
+	if !state.work_queue.push(&repository, sha) {
+		log::error!(
+			"Dropping the processing of {} ({}) since the work queue is full; it will be resumed on the next poll",
+			sha,
+			repository
+		);
+	}
+}
+
+/// Process the checks and statuses of the next commit from the work queue, if any. Returns the
+/// processed commit's SHA.
+pub async fn process_next_queued_commit(state: &AppState) -> Option<String> {
+	let (repository, sha) = state.work_queue.pop()?;
+	log::info!("Processing queued commit {} of {}", sha, repository);
+
+	let result = process_commit_checks_and_statuses(state, &sha).await;
+	let (merge_cancel_outcome, result) =
+		cancel_merge_on_error(state, &sha, result).await;
+	if let Err(err) = result {
+		handle_error(merge_cancel_outcome, err, state).await;
+	}
+
+	Some(sha)
+}
+
+// Cleans the SHA from the database if the error stops the merge process
+async fn cancel_merge_on_error(
+	state: &AppState,
+	sha: &str,
+	result: Result<()>,
+) -> (PullRequestMergeCancelOutcome, Result<()>) {
 	// If it's not an error then don't bother with going further
 	let err = match result {
 		Ok(_) => {
@@ -267,63 +799,60 @@ pub async fn handle_github_payload(
 		err
 	);
 
-	match state.db.get(sha.as_bytes()) {
-		Ok(Some(bytes)) => {
-			match bincode::deserialize::<MergeRequest>(&bytes)
-				.context(error::Bincode)
-			{
-				Ok(mr) => {
-					let merge_cancel_outcome = match cleanup_merge_request(
-						state,
-						&sha,
-						&mr.owner,
-						&mr.repo,
-						mr.number,
-						&MergeRequestCleanupReason::Cancelled,
-					)
-					.await
-					{
-						Ok(_) => {
-							log::info!(
-								"Merge of {} (sha {}) was cancelled due to {:?}",
-								&mr.html_url,
-								sha,
-								err
-							);
-							PullRequestMergeCancelOutcome::WasCancelled
-						}
-						Err(err) => {
-							log::error!(
+	match state
+		.db
+		.get_cf(merge_requests_cf(&state.db), sha.as_bytes())
+	{
+		Ok(Some(bytes)) => match deserialize_merge_request(&bytes) {
+			Ok(mr) => {
+				let merge_cancel_outcome = match cleanup_merge_request(
+					state,
+					sha,
+					&mr.owner,
+					&mr.repo,
+					mr.number,
+					&MergeRequestCleanupReason::Cancelled,
+				)
+				.await
+				{
+					Ok(_) => {
+						log::info!(
+							"Merge of {} (sha {}) was cancelled due to {:?}",
+							&mr.html_url,
+							sha,
+							err
+						);
+						PullRequestMergeCancelOutcome::WasCancelled
+					}
+					Err(err) => {
+						log::error!(
 									"Failed to cancel merge of {} (sha {}) in handle_payload due to {:?}",
 									&mr.html_url,
 									sha,
 									err
 								);
-							PullRequestMergeCancelOutcome::WasNotCancelled
-						}
-					};
+						PullRequestMergeCancelOutcome::WasNotCancelled
+					}
+				};
 
-					(
-						merge_cancel_outcome,
-						Err(err.with_pull_request_details(
-							PullRequestDetails {
-								owner: mr.owner,
-								repo: mr.repo,
-								number: mr.number,
-							},
-						)),
-					)
-				}
-				Err(db_err) => {
-					log::error!(
-						"Failed to parse {} from the database due to {:?}",
-						&sha,
-						db_err
-					);
-					(PullRequestMergeCancelOutcome::WasNotCancelled, Err(err))
-				}
+				(
+					merge_cancel_outcome,
+					Err(err.with_pull_request_details(PullRequestDetails {
+						owner: mr.owner,
+						repo: mr.repo,
+						number: mr.number,
+					})),
+				)
 			}
-		}
+			Err(db_err) => {
+				log::error!(
+					"Failed to parse {} from the database due to {:?}",
+					&sha,
+					db_err
+				);
+				(PullRequestMergeCancelOutcome::WasNotCancelled, Err(err))
+			}
+		},
 		Ok(None) => (PullRequestMergeCancelOutcome::ShaNotFound, Err(err)),
 		Err(db_err) => {
 			log::info!(
@@ -336,6 +865,185 @@ pub async fn handle_github_payload(
 	}
 }
 
+/// Keeps the queued merge request of a pull request in sync with changes which happened outside
+/// of processbot: new commits are tracked if the requester pushed them, otherwise the merge is
+/// cancelled since the requester has not vouched for them; closing the pull request without
+/// merging it also cancels the merge.
+async fn handle_pull_request_event(
+	state: &AppState,
+	action: &GithubPullRequestAction,
+	pr: &GithubPullRequest,
+	before: Option<&str>,
+	sender: &GithubUser,
+) -> Result<()> {
+	let AppState { db, gh_client, .. } = state;
+
+	let owner = &pr.base.repo.owner.login;
+	let repo = &pr.base.repo.name;
+
+	match action {
+		GithubPullRequestAction::Synchronize => {
+			if let Err(err) =
+				record_head_push(state, owner, repo, pr.number, &pr.head.sha)
+			{
+				log::error!(
+					"Failed to record the push of {} to {} due to {:?}",
+					pr.head.sha,
+					pr.html_url,
+					err
+				);
+			}
+
+			let before = match before {
+				Some(before) => before,
+				None => return Ok(()),
+			};
+			let mr: MergeRequest = match db
+				.get_cf(merge_requests_cf(db), before.as_bytes())
+				.context(error::Db)?
+			{
+				Some(bytes) => deserialize_merge_request(&bytes)?,
+				None => return Ok(()),
+			};
+			if &mr.owner != owner || &mr.repo != repo || mr.number != pr.number
+			{
+				return Ok(());
+			}
+
+			if sender.login == mr.requested_by {
+				log::info!(
+					"{} was updated from {} to {} by its merge requester",
+					pr.html_url,
+					before,
+					pr.head.sha
+				);
+				cleanup_merge_request(
+					state,
+					before,
+					owner,
+					repo,
+					pr.number,
+					&MergeRequestCleanupReason::AfterSHAUpdate(&pr.head.sha),
+				)
+				.await?;
+				queue_merge_request(
+					state,
+					&MergeRequest {
+						sha: pr.head.sha.to_owned(),
+						..mr
+					},
+					&MergeRequestQueuedMessage::None,
+				)
+				.await
+			} else {
+				log::info!(
+					"Cancelling the merge of {} since {} pushed {}",
+					pr.html_url,
+					sender.login,
+					pr.head.sha
+				);
+				cleanup_merge_request(
+					state,
+					before,
+					owner,
+					repo,
+					pr.number,
+					&MergeRequestCleanupReason::Cancelled,
+				)
+				.await?;
+				if let Err(err) = gh_client
+					.create_issue_comment(
+						owner,
+						repo,
+						pr.number,
+						&state.config.message_templates.render(
+							Message::MergeCancelledDueToNewCommits,
+							&[
+								("pusher", &sender.login),
+								("requested_by", &mr.requested_by),
+							],
+						),
+					)
+					.await
+				{
+					log::error!(
+						"Failed to post comment on {} due to {}",
+						pr.html_url,
+						err
+					);
+				}
+				Ok(())
+			}
+		}
+		GithubPullRequestAction::Closed if !pr.merged => {
+			forget_head_push(state, owner, repo, pr.number)?;
+			if !list_merge_requests(state).iter().any(|mr| {
+				&mr.owner == owner && &mr.repo == repo && mr.number == pr.number
+			}) {
+				return Ok(());
+			}
+			log::info!(
+				"Cancelling the merge of {} since it was closed",
+				pr.html_url
+			);
+			cleanup_merge_request(
+				state,
+				&pr.head.sha,
+				owner,
+				repo,
+				pr.number,
+				&MergeRequestCleanupReason::Cancelled,
+			)
+			.await
+		}
+		GithubPullRequestAction::Closed => {
+			forget_head_push(state, owner, repo, pr.number)
+		}
+		_ => Ok(()),
+	}
+}
+
+/// Applying the repository's automerge label to a pull request is equivalent to commenting
+/// `bot merge` on it and removing the label is equivalent to `bot merge cancel`, with the user who
+/// changed the label being the requester.
+async fn handle_automerge_label(
+	state: &AppState,
+	action: &GithubPullRequestAction,
+	pr: &GithubPullRequest,
+	label: &GithubLabel,
+	sender: &GithubUser,
+) -> (Option<String>, Result<()>) {
+	let owner = &pr.base.repo.owner;
+	let repo = &pr.base.repo.name;
+
+	if state.config.automerge_label_for(&owner.login, repo)
+		!= Some(label.name.as_str())
+		|| sender.type_field == GithubUserType::Bot
+	{
+		return (None, Ok(()));
+	}
+
+	let cmd = match action {
+		GithubPullRequestAction::Labeled => {
+			CommentCommand::Merge(MergeCommentCommand::Normal)
+		}
+		_ => CommentCommand::CancelMerge,
+	};
+	handle_requested_command(
+		state,
+		cmd,
+		&sender.login,
+		pr.number,
+		&pr.html_url,
+		&GithubIssueRepository {
+			owner: owner.clone(),
+			name: repo.to_owned(),
+		},
+		None,
+	)
+	.await
+}
+
 /// Parse bot commands in pull request comments.
 /// The first member of the returned tuple is the relevant commit SHA to invalidate from the
 /// database in case of errors.
@@ -347,29 +1055,134 @@ async fn handle_pull_request_comment(
 	html_url: &str,
 	repo: GithubIssueRepository,
 ) -> (Option<String>, Result<()>) {
-	let body = &comment.body;
-	let requested_by = &comment.user.login;
-
-	let cmd = match parse_bot_comment_from_text(body) {
+	let cmd = match parse_bot_comment_from_text(
+		&comment.body,
+		&state.config.installation_login,
+	) {
 		Some(cmd) => cmd,
 		None => return (None, Ok(())),
 	};
 
+	handle_requested_command(
+		state,
+		cmd,
+		&comment.user.login,
+		number,
+		html_url,
+		&repo,
+		Some(comment),
+	)
+	.await
+}
+
+/// Check that `requested_by` is allowed to run `cmd` in the pull request, then run it. The
+/// comment which requested the command, if any, is acknowledged once that's done.
+async fn handle_requested_command(
+	state: &AppState,
+	cmd: CommentCommand,
+	requested_by: &str,
+	number: i64,
+	html_url: &str,
+	repo: &GithubIssueRepository,
+	comment: Option<&GithubIssueComment>,
+) -> (Option<String>, Result<()>) {
 	log::info!("{:?} requested by {} in {}", cmd, requested_by, html_url);
+	let comment_id = comment.map(|comment| comment.id);
 
 	let AppState {
 		gh_client, config, ..
 	} = state;
 
-	if !config.disable_org_checks {
+	// Only the privileged teams can hand out merges to other users
+	if let CommentCommand::AllowMerge(_) = cmd {
+		match gh_client
+			.member_of_any_team(&config.privileged_teams, requested_by)
+			.await
+		{
+			Ok(true) => {}
+			Ok(false) => {
+				return (
+					None,
+					Err(Error::Message {
+						msg: format!(
+							"{} is not allowed to use `bot merge allow` in {} since they're not a member of the privileged teams",
+							requested_by, html_url
+						),
+					}),
+				)
+			}
+			Err(err) => return (None, Err(err)),
+		}
+	}
+
+	// Listing the queue exposes the whole database and listing the configuration
+	// exposes the deployment's settings, hence why they're always restricted to
+	// organization members (or members of the privileged teams)
+	let mut org_check_failure = None;
+	if !config.disable_org_checks_for(&repo.owner.login, &repo.name)
+		|| matches!(cmd, CommentCommand::Queue | CommentCommand::Config)
+	{
 		if let Err(err) =
 			gh_client.org_member(&repo.owner.login, requested_by).await
 		{
-			return (None, Err(err));
+			let is_privileged = match gh_client
+				.member_of_any_team(&config.privileged_teams, requested_by)
+				.await
+			{
+				Ok(is_privileged) => is_privileged,
+				Err(teams_err) => {
+					log::error!(
+						"Failed to check the team membership of {} due to {}",
+						requested_by,
+						teams_err
+					);
+					false
+				}
+			};
+			let was_allowed_once = !is_privileged
+				&& matches!(cmd, CommentCommand::Merge(_))
+				&& match consume_merge_allowance(
+					state,
+					&repo.owner.login,
+					&repo.name,
+					number,
+					requested_by,
+				) {
+					Ok(was_allowed_once) => was_allowed_once,
+					Err(allowance_err) => {
+						log::error!(
+							"Failed to check the merge allowances of {} due to {}",
+							html_url,
+							allowance_err
+						);
+						false
+					}
+				};
+			if is_privileged {
+				log::info!(
+					"{} is allowed to use the commands in {} as a member of the privileged teams",
+					requested_by,
+					html_url
+				);
+			} else if was_allowed_once {
+				log::info!(
+					"{} is allowed to merge {} once through `bot merge allow`",
+					requested_by,
+					html_url
+				);
+			} else if config.codeowners_authorization
+				&& matches!(cmd, CommentCommand::Merge(_))
+			{
+				// Merge commands might still be authorized through CODEOWNERS
+				// once the pull request is fetched
+				org_check_failure = Some(err);
+			} else {
+				return (None, Err(err));
+			}
 		}
 	}
 
-	if let CommentCommand::Merge(_) = cmd {
+	let pr = if let CommentCommand::Merge(_) = cmd {
 		// We've noticed the bot failing for no human-discernable reason when, for
 		// instance, it complained that the pull request was not mergeable when, in
 		// fact, it seemed to be, if one were to guess what the state of the Github
@@ -377,67 +1190,314 @@ async fn handle_pull_request_comment(
 		// the lack of insight onto the Github Servers, it's assumed that those
 		// failures happened because the Github API did not update fast enough and
 		// therefore the state was invalid when the request happened, but it got
-		// cleared shortly after (possibly microseconds after, hence why it is not
-		// discernable at "second" resolution). As a workaround we'll wait for long
-		// enough so that Github hopefully has time to update the API and make our
-		// merges succeed. A proper workaround would also entail retrying every X
-		// seconds for recoverable errors such as "required statuses are missing or
-		// pending".
-		sleep(Duration::from_millis(config.merge_command_delay)).await;
-	};
-
-	let pr = match gh_client
-		.pull_request(&repo.owner.login, &repo.name, number)
+		// cleared shortly after. As a workaround we'll refetch the pull request until
+		// Github has computed its mergeability (or until the configured delay has
+		// elapsed).
+		wait_for_pull_request_mergeability(
+			gh_client,
+			&repo.owner.login,
+			&repo.name,
+			number,
+			config.merge_command_delay_for(&repo.owner.login, &repo.name),
+		)
 		.await
-	{
+	} else {
+		gh_client
+			.pull_request(&repo.owner.login, &repo.name, number)
+			.await
+	};
+	let pr = match pr {
 		Ok(pr) => pr,
 		Err(err) => return (None, Err(err)),
 	};
 
-	if let Err(err) = gh_client
-		.acknowledge_issue_comment(
+	if let Some(err) = org_check_failure {
+		match gh_client.is_code_owner_of_pull_request(&pr, requested_by).await
+		{
+			Ok(true) => log::info!(
+				"{} is allowed to merge {} as the owner of its changes according to CODEOWNERS",
+				requested_by,
+				pr.html_url
+			),
+			Ok(false) => return (None, Err(err)),
+			Err(codeowners_err) => {
+				log::error!(
+					"Failed to check the CODEOWNERS of {} due to {}",
+					pr.html_url,
+					codeowners_err
+				);
+				return (None, Err(err));
+			}
+		}
+	}
+
+	// The requester might not have seen the commits which were pushed after they
+	// commented, thus they're asked to confirm the merge again
+	if let (
+		CommentCommand::Merge(_),
+		Some(GithubIssueComment {
+			created_at: Some(commented_at),
+			..
+		}),
+	) = (&cmd, comment)
+	{
+		match was_head_pushed_after(
+			state,
 			&pr.base.repo.owner.login,
 			&pr.base.repo.name,
-			comment.id,
+			pr.number,
+			&pr.head.sha,
+			(*commented_at).into(),
+		) {
+			Ok(false) => {}
+			Ok(true) => {
+				log::info!(
+					"{} was pushed to {} after the merge command of {}",
+					pr.head.sha,
+					pr.html_url,
+					requested_by
+				);
+				if let Err(err) = gh_client
+					.create_issue_comment(
+						&pr.base.repo.owner.login,
+						&pr.base.repo.name,
+						pr.number,
+						&config.message_templates.render(
+							Message::MergeReconfirmationRequired,
+							&[
+								("sha", &pr.head.sha),
+								("requested_by", requested_by),
+							],
+						),
+					)
+					.await
+				{
+					log::error!(
+						"Failed to post comment on {} due to {}",
+						pr.html_url,
+						err
+					);
+				}
+				return (None, Ok(()));
+			}
+			Err(err) => return (None, Err(err)),
+		}
+	}
+
+	// With reactions as acknowledgements, the comment is marked as seen until the
+	// command is handled successfully; errors are still replied to
+	let is_acknowledged_by_reactions =
+		config.reaction_acknowledgements && comment_id.is_some();
+	if let Some(comment_id) = comment_id {
+		react_to_comment(
+			state,
+			&pr,
+			comment_id,
+			if is_acknowledged_by_reactions {
+				GithubReaction::Eyes
+			} else {
+				GithubReaction::PlusOne
+			},
+		)
+		.await;
+	}
+
+	let result = run_command(
+		state,
+		&cmd,
+		&pr,
+		requested_by,
+		is_acknowledged_by_reactions,
+	)
+	.await
+	.map_err(|err| {
+		err.with_pull_request_details(PullRequestDetails {
+			owner: (&pr.base.repo.owner.login).into(),
+			repo: (&pr.base.repo.name).into(),
+			number,
+		})
+	});
+
+	if let (true, Some(comment_id), Ok(())) =
+		(is_acknowledged_by_reactions, comment_id, &result)
+	{
+		react_to_comment(state, &pr, comment_id, GithubReaction::Rocket).await;
+	}
+
+	let sha = match cmd {
+		CommentCommand::Merge(_) => Some(pr.head.sha),
+		_ => None,
+	};
+
+	(sha, result)
+}
+
+async fn react_to_comment(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	comment_id: i64,
+	reaction: GithubReaction,
+) {
+	if let Err(err) = state
+		.gh_client
+		.create_reaction(
+			&pr.base.repo.owner.login,
+			&pr.base.repo.name,
+			comment_id,
+			reaction,
 		)
 		.await
 	{
 		log::error!(
-			"Failed to acknowledge comment on {} due to {}",
+			"Failed to react to comment on {} due to {}",
 			pr.html_url,
 			err
 		);
 	}
+}
 
-	let result = handle_command(state, &cmd, &pr, requested_by)
-		.await
-		.map_err(|err| {
-			err.with_pull_request_details(PullRequestDetails {
-				owner: (&pr.base.repo.owner.login).into(),
-				repo: (&pr.base.repo.name).into(),
-				number,
-			})
-		});
+/// Fetch a pull request until the Github API has computed whether it is mergeable, backing off
+/// exponentially between attempts. The last fetched pull request is returned once `max_delay`
+/// (milliseconds) has elapsed.
+pub async fn wait_for_pull_request_mergeability(
+	gh_client: &GithubClient,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	max_delay: u64,
+) -> Result<GithubPullRequest> {
+	const INITIAL_POLL_DELAY: u64 = 256;
 
-	let sha = match cmd {
-		CommentCommand::Merge(_) => Some(pr.head.sha),
-		_ => None,
-	};
+	let started_at = Instant::now();
+	let max_delay = Duration::from_millis(max_delay);
+	let mut poll_delay = Duration::from_millis(INITIAL_POLL_DELAY);
+	loop {
+		let pr = gh_client.pull_request(owner, repo, number).await?;
+		let elapsed = started_at.elapsed();
+		if pr.mergeable.is_some() || elapsed >= max_delay {
+			return Ok(pr);
+		}
 
-	(sha, result)
+		log::info!(
+			"Mergeability of {} is not yet known; polling again in {:?}",
+			pr.html_url,
+			poll_delay
+		);
+		sleep(std::cmp::min(poll_delay, max_delay - elapsed)).await;
+		poll_delay *= 2;
+	}
 }
 
-pub fn parse_bot_comment_from_text(text: &str) -> Option<CommentCommand> {
+/// Parse a command such as "bot merge" out of a comment. The "bot" keyword can also be written as
+/// a mention of `bot_username`, e.g. "@processbot merge". Comments which don't consist solely of
+/// the command, e.g. ones quoting it, are ignored.
+pub fn parse_bot_comment_from_text(
+	text: &str,
+	bot_username: &str,
+) -> Option<CommentCommand> {
+	// Branch names are case-sensitive, thus they're taken from the original text
+	let last_word = text.split_whitespace().last().map(|word| word.to_string());
 	let text = text.to_lowercase();
 	let text = text.trim();
 
+	let mention = format!("@{}", bot_username.to_lowercase());
+	let text = match text.strip_prefix(&mention) {
+		// The mention should not be merely the prefix of another username
+		Some(command) if command.starts_with(char::is_whitespace) => {
+			format!("bot {}", command.trim_start())
+		}
+		_ => text.to_string(),
+	};
+	let text = text.as_str();
+
 	let cmd = match text {
 		"bot merge" => CommentCommand::Merge(MergeCommentCommand::Normal),
+		"bot merge high" => CommentCommand::Merge(MergeCommentCommand::High),
 		"bot merge force" => CommentCommand::Merge(MergeCommentCommand::Force),
 		"bot merge cancel" => CommentCommand::CancelMerge,
+		"bot unqueue" => CommentCommand::Unqueue,
 		"bot rebase" => CommentCommand::Rebase,
-		_ => return None,
+		"bot update" => CommentCommand::Update,
+		"bot queue" => CommentCommand::Queue,
+		"bot config" => CommentCommand::Config,
+		"bot ping" => CommentCommand::Ping,
+		_ => {
+			if let Some(username) = text.strip_prefix("bot merge allow @") {
+				let username = username.trim();
+				if username.is_empty() || username.contains(char::is_whitespace)
+				{
+					return None;
+				}
+				return Some(CommentCommand::AllowMerge(username.to_string()));
+			}
+
+			if let Some(reference) = text.strip_prefix("bot merge after ") {
+				let (owner, repo, number) =
+					parse_pull_request_reference(reference.trim())?;
+				return Some(CommentCommand::Merge(
+					MergeCommentCommand::After {
+						owner,
+						repo,
+						number,
+					},
+				));
+			}
+
+			if let Some(branch) = text.strip_prefix("bot rebase onto ") {
+				if branch.trim().contains(char::is_whitespace) {
+					return None;
+				}
+				return Some(CommentCommand::RebaseOnto(last_word?));
+			}
+
+			let delay = text.strip_prefix("bot merge delay ")?;
+			CommentCommand::Merge(MergeCommentCommand::Delayed(
+				parse_merge_delay(delay.trim())?,
+			))
+		}
 	};
 
 	Some(cmd)
 }
+
+/// Parse a pull request reference such as "paritytech/substrate#123".
+fn parse_pull_request_reference(text: &str) -> Option<(String, String, i64)> {
+	let (repository, number) = text.split_once('#')?;
+	let (owner, repo) = repository.split_once('/')?;
+	if owner.is_empty() || repo.is_empty() || repo.contains('/') {
+		return None;
+	}
+	let number = number.parse::<i64>().ok().filter(|number| *number > 0)?;
+	Some((owner.to_string(), repo.to_string(), number))
+}
+
+/// Parse a duration such as "2h", "30m" or "1h30m" (hours, minutes and seconds are supported).
+pub fn parse_merge_delay(text: &str) -> Option<Duration> {
+	let mut seconds: u64 = 0;
+	let mut value = String::new();
+	for c in text.chars() {
+		if c.is_ascii_digit() {
+			value.push(c);
+			continue;
+		}
+		let unit_seconds = match c {
+			'h' => 60 * 60,
+			'm' => 60,
+			's' => 1,
+			_ => return None,
+		};
+		seconds = value
+			.parse::<u64>()
+			.ok()?
+			.checked_mul(unit_seconds)?
+			.checked_add(seconds)?;
+		value.clear();
+	}
+
+	// Every value should be followed by its unit
+	if !value.is_empty() || seconds == 0 {
+		return None;
+	}
+
+	Some(Duration::from_secs(seconds))
+}
@@ -0,0 +1,78 @@
+use regex::Regex;
+
+// A line of a CODEOWNERS file, e.g. "/runtime/ @paritytech/runtime @someone"
+#[derive(Debug)]
+struct CodeOwnersRule {
+	matcher: Regex,
+	owners: Vec<String>,
+}
+
+/// The rules of a CODEOWNERS file, which follow the syntax described in
+/// https://docs.github.com/en/repositories/managing-your-repositorys-settings-and-features/customizing-your-repository/about-code-owners
+#[derive(Debug)]
+pub struct CodeOwners {
+	rules: Vec<CodeOwnersRule>,
+}
+
+impl CodeOwners {
+	pub fn parse(text: &str) -> Self {
+		let rules = text
+			.lines()
+			.filter_map(|line| {
+				let line = line.trim();
+				if line.is_empty() || line.starts_with('#') {
+					return None;
+				}
+				let mut tokens = line.split_whitespace();
+				let matcher = build_path_matcher(tokens.next()?)?;
+				Some(CodeOwnersRule {
+					matcher,
+					owners: tokens
+						.take_while(|token| !token.starts_with('#'))
+						.map(|token| token.to_string())
+						.collect(),
+				})
+			})
+			.collect();
+		Self { rules }
+	}
+
+	/// The owners of `path` (e.g. "@someone" or "@org/team"). As in Github, the
+	/// last matching rule takes precedence.
+	pub fn owners_of(&self, path: &str) -> Option<&[String]> {
+		self.rules
+			.iter()
+			.rev()
+			.find(|rule| rule.matcher.is_match(path))
+			.map(|rule| rule.owners.as_slice())
+	}
+}
+
+// Translates a gitignore-style pattern into a regex matching the paths it
+// covers, including the contents of matched directories
+fn build_path_matcher(pattern: &str) -> Option<Regex> {
+	let is_anchored = pattern.trim_end_matches('/').contains('/');
+	let pattern = pattern.trim_start_matches('/');
+
+	let mut expression =
+		String::from(if is_anchored { "^" } else { "^(.*/)?" });
+	let mut chars = pattern.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'*' if chars.peek() == Some(&'*') => {
+				chars.next();
+				expression.push_str(".*");
+			}
+			'*' => expression.push_str("[^/]*"),
+			'?' => expression.push_str("[^/]"),
+			c => expression.push_str(&regex::escape(&c.to_string())),
+		}
+	}
+	expression.push_str(if expression.ends_with('/') {
+		".*$"
+	} else {
+		"(/.*)?$"
+	});
+
+	Regex::new(&expression).ok()
+}
@@ -1,30 +1,37 @@
 use std::{
-	collections::HashSet,
+	collections::{BTreeSet, HashSet},
 	iter::{FromIterator, Iterator},
 	path::Path,
-	time::Duration,
+	time::{Duration, Instant, SystemTime},
 };
 
 use async_recursion::async_recursion;
-use regex::RegexBuilder;
+use cargo_lock::package::GitReference;
+use regex::{Regex, RegexBuilder};
 use snafu::ResultExt;
 use tokio::time::sleep;
 
 use crate::{
+	config::MainConfig,
+	constants::MERGE_REQUEST_SCHEMA_VERSION,
 	core::{get_commit_statuses, process_dependents_after_merge, AppState},
 	error::*,
-	git_ops::{setup_contributor_branch, SetupContributorBranchData},
+	git_ops::{
+		acquire_branch_update_permit, commit_all_changes,
+		setup_contributor_branch, SetupContributorBranchData,
+	},
 	github::*,
 	merge_request::{
 		check_merge_is_allowed, cleanup_merge_request,
-		handle_merged_pull_request, is_ready_to_merge, merge_pull_request,
-		queue_merge_request, MergeRequest, MergeRequestCleanupReason,
-		MergeRequestQueuedMessage,
+		handle_merged_pull_request, is_ready_to_merge, list_merge_requests,
+		merge_pull_request, queue_merge_request, MergeRequest,
+		MergeRequestCleanupReason, MergeRequestQueuedMessage,
 	},
+	messages::Message,
 	shell::*,
 	types::Result,
-	COMPANION_LONG_REGEX, COMPANION_PREFIX_REGEX, COMPANION_SHORT_REGEX,
-	OWNER_AND_REPO_SEQUENCE, PR_HTML_URL_REGEX,
+	COMPANION_MARKER_SEPARATOR_REGEX, OWNER_AND_REPO_SEQUENCE,
+	PR_HTML_URL_REGEX, PR_SHORT_REFERENCE_REGEX,
 };
 
 #[derive(Clone)]
@@ -46,6 +53,8 @@ async fn update_pr_branch(
 ) -> Result<String> {
 	let AppState { config, .. } = state;
 
+	let _permit = acquire_branch_update_permit(config, owner, owner_repo).await;
+
 	let SetupContributorBranchData {
 		repo_dir,
 		secrets_to_hide,
@@ -83,33 +92,35 @@ async fn update_pr_branch(
 		number,
 		dependencies_to_update
 	);
-	for dependency_to_update in dependencies_to_update.iter() {
-		let source_to_update = format!(
-			"{}/{}/{}{}",
-			config.github_source_prefix,
-			owner,
-			dependency_to_update,
-			config.github_source_suffix
-		);
+	let sources_to_update = dependencies_to_update
+		.iter()
+		.map(|dependency_to_update| {
+			format!(
+				"{}/{}/{}{}",
+				config.github_source_prefix,
+				owner,
+				dependency_to_update,
+				config.github_source_suffix
+			)
+		})
+		.collect::<HashSet<_>>();
+	// Kept for verifying that only the intended packages were changed
+	let original_lockfile = if sources_to_update.is_empty() {
+		None
+	} else {
+		Some(load_lockfile(repo_dir, contributor_repo)?)
+	};
+	for source_to_update in sources_to_update.iter() {
 		log::info!(
 			"Updating references of {} in the Cargo.lock of {:?}",
 			source_to_update,
 			repo_dir
 		);
-		let cargo_lock_path = Path::new(&repo_dir).join("Cargo.lock");
-		let lockfile =
-			cargo_lock::Lockfile::load(cargo_lock_path).map_err(|err| {
-				Error::Message {
-					msg: format!(
-						"Failed to parse lockfile of {}: {:?}",
-						contributor_repo, err
-					),
-				}
-			})?;
+		let lockfile = load_lockfile(repo_dir, contributor_repo)?;
 		let pkgs_in_companion: HashSet<String> = {
 			HashSet::from_iter(lockfile.packages.iter().filter_map(|pkg| {
 				if let Some(src) = pkg.source.as_ref() {
-					if src.url().as_str() == source_to_update {
+					if src.url().as_str() == source_to_update.as_str() {
 						Some(format!("{}:{}", pkg.name.as_str(), pkg.version))
 					} else {
 						None
@@ -140,6 +151,23 @@ async fn update_pr_branch(
 		}
 	}
 
+	if let Some(original_lockfile) = original_lockfile {
+		let unexpected_changes = find_unexpected_lockfile_changes(
+			&original_lockfile,
+			&load_lockfile(repo_dir, contributor_repo)?,
+			&sources_to_update,
+		);
+		if !unexpected_changes.is_empty() {
+			return Err(Error::UnexpectedLockfileChanges {
+				pull_request: format!(
+					"{}/{}/pull/{}",
+					owner, owner_repo, number
+				),
+				changes: unexpected_changes,
+			});
+		}
+	}
+
 	// Check if `cargo update` resulted in any changes. If the master merge commit already had an
 	// up-to-date lockfile then no changes might have been made.
 	let output = run_cmd_with_output(
@@ -156,18 +184,11 @@ async fn update_pr_branch(
 		.trim()
 		.is_empty()
 	{
-		run_cmd(
-			"git",
-			&[
-				"commit",
-				"-am",
-				&format!("update lockfile for {:?}", dependencies_to_update),
-			],
+		commit_all_changes(
+			config,
 			&repo_dir,
-			CommandMessage::Configured(CommandMessageConfiguration {
-				secrets_to_hide,
-				are_errors_silenced: false,
-			}),
+			&format!("update lockfile for {:?}", dependencies_to_update),
+			secrets_to_hide,
 		)
 		.await?;
 	}
@@ -205,88 +226,391 @@ async fn update_pr_branch(
 	Ok(updated_sha)
 }
 
-fn parse_companion_from_url(
-	body: &str,
-) -> Option<PullRequestDetailsWithHtmlUrl> {
-	parse_companion_from_long_url(body)
-		.or_else(|| parse_companion_from_short_url(body))
+// Describes a package of a lockfile, e.g.
+// "serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)"
+fn describe_lockfile_package(pkg: &cargo_lock::Package) -> String {
+	match pkg.source.as_ref() {
+		Some(src) => format!("{} {} ({})", pkg.name, pkg.version, src),
+		None => format!("{} {}", pkg.name, pkg.version),
+	}
 }
 
-fn parse_companion_from_long_url(
-	body: &str,
-) -> Option<PullRequestDetailsWithHtmlUrl> {
-	let re = RegexBuilder::new(COMPANION_LONG_REGEX!())
-		.case_insensitive(true)
-		.build()
-		.unwrap();
-	let caps = re.captures(body)?;
-	let html_url = caps.name("html_url")?.as_str().to_owned();
-	let owner = caps.name("owner")?.as_str().to_owned();
-	let repo = caps.name("repo")?.as_str().to_owned();
-	let number = caps
-		.name("number")?
-		.as_str()
-		.to_owned()
-		.parse::<i64>()
-		.ok()?;
-	Some(PullRequestDetailsWithHtmlUrl {
-		html_url,
-		owner,
-		repo,
-		number,
-	})
+// Describes the packages which the packages from `sources` depend on, directly
+// or not, in `lockfile`
+fn find_lockfile_dependencies_of_sources(
+	lockfile: &cargo_lock::Lockfile,
+	sources: &HashSet<String>,
+) -> HashSet<String> {
+	let is_from_sources = |pkg: &cargo_lock::Package| {
+		pkg.source
+			.as_ref()
+			.map(|src| sources.contains(src.url().as_str()))
+			.unwrap_or(false)
+	};
+
+	let mut dependencies = HashSet::new();
+	let mut packages_to_visit = lockfile
+		.packages
+		.iter()
+		.filter(|pkg| is_from_sources(pkg))
+		.collect::<Vec<_>>();
+	while let Some(pkg) = packages_to_visit.pop() {
+		for dependency in &pkg.dependencies {
+			for dependency_pkg in lockfile.packages.iter().filter(|candidate| {
+				candidate.name == dependency.name
+					&& candidate.version == dependency.version
+			}) {
+				if dependencies
+					.insert(describe_lockfile_package(dependency_pkg))
+				{
+					packages_to_visit.push(dependency_pkg);
+				}
+			}
+		}
+	}
+	dependencies
 }
 
-fn parse_companion_from_short_url(
-	body: &str,
-) -> Option<PullRequestDetailsWithHtmlUrl> {
-	let re = RegexBuilder::new(COMPANION_SHORT_REGEX!())
-		.case_insensitive(true)
-		.build()
-		.unwrap();
-	let caps = re.captures(body)?;
-	let owner = caps.name("owner")?.as_str().to_owned();
-	let repo = caps.name("repo")?.as_str().to_owned();
-	let number = caps
-		.name("number")?
-		.as_str()
-		.to_owned()
-		.parse::<i64>()
-		.ok()?;
-	let html_url = format!(
-		"https://github.com/{owner}/{repo}/pull/{number}",
-		owner = owner,
-		repo = repo,
-		number = number
+// Lists the packages which were added, removed or modified between both versions
+// of a lockfile, leaving out the ones which come from `sources_to_update`, e.g.
+// "-serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)". The
+// packages which were brought in by the updated packages, or which they no
+// longer depend on, are expected to change as well.
+fn find_unexpected_lockfile_changes(
+	original_lockfile: &cargo_lock::Lockfile,
+	updated_lockfile: &cargo_lock::Lockfile,
+	sources_to_update: &HashSet<String>,
+) -> Vec<String> {
+	let describe_packages = |lockfile: &cargo_lock::Lockfile| {
+		lockfile
+			.packages
+			.iter()
+			.filter(|pkg| {
+				pkg.source
+					.as_ref()
+					.map(|src| !sources_to_update.contains(src.url().as_str()))
+					.unwrap_or(true)
+			})
+			.map(describe_lockfile_package)
+			.collect::<HashSet<_>>()
+	};
+	let original_packages = describe_packages(original_lockfile);
+	let updated_packages = describe_packages(updated_lockfile);
+
+	let original_dependencies = find_lockfile_dependencies_of_sources(
+		original_lockfile,
+		sources_to_update,
 	);
-	Some(PullRequestDetailsWithHtmlUrl {
-		html_url,
-		owner,
-		repo,
-		number,
+	let updated_dependencies = find_lockfile_dependencies_of_sources(
+		updated_lockfile,
+		sources_to_update,
+	);
+
+	let mut changes = original_packages
+		.difference(&updated_packages)
+		.filter(|pkg| !original_dependencies.contains(*pkg))
+		.map(|pkg| format!("-{}", pkg))
+		.chain(
+			updated_packages
+				.difference(&original_packages)
+				.filter(|pkg| !updated_dependencies.contains(*pkg))
+				.map(|pkg| format!("+{}", pkg)),
+		)
+		.collect::<Vec<_>>();
+	// Sorted by package rather than by kind of change so that it reads as a diff
+	changes.sort_by(|a, b| a[1..].cmp(&b[1..]));
+	changes
+}
+
+fn load_lockfile(
+	repo_dir: &str,
+	contributor_repo: &str,
+) -> Result<cargo_lock::Lockfile> {
+	let cargo_lock_path = Path::new(repo_dir).join("Cargo.lock");
+	if !cargo_lock_path.exists() {
+		return Err(Error::Message {
+			msg: format!(
+				"Could not find a Cargo.lock for {}. processbot expected a Cargo.lock in the root of the repository in order to update the dependency references before merging. If {} is not a Rust project, please disable the automatic lockfile update for it in processbot's configuration (DEPENDENCY_UPDATE_CONFIGURATION) or do not reference it as a companion.",
+				contributor_repo, contributor_repo
+			),
+		});
+	}
+	cargo_lock::Lockfile::load(cargo_lock_path).map_err(|err| Error::Message {
+		msg: format!(
+			"Failed to parse lockfile of {}: {:?}",
+			contributor_repo, err
+		),
 	})
 }
 
+/// Recognizes the references to companions in pull request descriptions, e.g.
+/// `companion: https://github.com/org/repo/pull/1` or `companion: org/repo#1`, for any of the
+/// configured markers. The regexes are built once since the markers don't change at runtime.
+#[derive(Debug, Clone)]
+pub struct CompanionMatcher {
+	long_url: Regex,
+	short_url: Regex,
+}
+
+impl CompanionMatcher {
+	pub fn new<T: AsRef<str>>(markers: &[T]) -> Self {
+		let marker_prefix = format!(
+			"(?:{}){}",
+			markers
+				.iter()
+				.map(|marker| regex::escape(marker.as_ref()))
+				.collect::<Vec<_>>()
+				.join("|"),
+			COMPANION_MARKER_SEPARATOR_REGEX!()
+		);
+		let build = |reference_regex: &str| {
+			RegexBuilder::new(&format!("{}{}", marker_prefix, reference_regex))
+				.case_insensitive(true)
+				.build()
+				.unwrap_or_else(|err| {
+					panic!(
+						"COMPANION_MARKERS produced an invalid regex: {}",
+						err
+					)
+				})
+		};
+		Self {
+			long_url: build(PR_HTML_URL_REGEX!()),
+			short_url: build(PR_SHORT_REFERENCE_REGEX!()),
+		}
+	}
+
+	fn parse_companion_from_url(
+		&self,
+		body: &str,
+	) -> Option<PullRequestDetailsWithHtmlUrl> {
+		self.parse_companion_from_long_url(body)
+			.or_else(|| self.parse_companion_from_short_url(body))
+			.filter(|comp| {
+				is_valid_name(&comp.owner) && is_valid_name(&comp.repo)
+			})
+	}
+
+	fn parse_companion_from_long_url(
+		&self,
+		body: &str,
+	) -> Option<PullRequestDetailsWithHtmlUrl> {
+		let caps = self.long_url.captures(body)?;
+		let html_url = caps.name("html_url")?.as_str().to_owned();
+		let owner = caps.name("owner")?.as_str().to_owned();
+		let repo = caps.name("repo")?.as_str().to_owned();
+		let number = caps
+			.name("number")?
+			.as_str()
+			.to_owned()
+			.parse::<i64>()
+			.ok()?;
+		Some(PullRequestDetailsWithHtmlUrl {
+			html_url,
+			owner,
+			repo,
+			number,
+		})
+	}
+
+	fn parse_companion_from_short_url(
+		&self,
+		body: &str,
+	) -> Option<PullRequestDetailsWithHtmlUrl> {
+		let caps = self.short_url.captures(body)?;
+		let owner = caps.name("owner")?.as_str().to_owned();
+		let repo = caps.name("repo")?.as_str().to_owned();
+		let number = caps
+			.name("number")?
+			.as_str()
+			.to_owned()
+			.parse::<i64>()
+			.ok()?;
+		let html_url = format!(
+			"https://github.com/{owner}/{repo}/pull/{number}",
+			owner = owner,
+			repo = repo,
+			number = number
+		);
+		Some(PullRequestDetailsWithHtmlUrl {
+			html_url,
+			owner,
+			repo,
+			number,
+		})
+	}
+}
+
+// "." and ".." are made of allowed characters but they would escape the
+// repositories' directory once joined to a path
+fn is_valid_name(name: &str) -> bool {
+	name != "." && name != ".."
+}
+
 pub fn parse_all_companions(
+	companion_matcher: &CompanionMatcher,
 	companion_reference_trail: &[CompanionReferenceTrailItem],
 	body: &str,
 ) -> Vec<PullRequestDetailsWithHtmlUrl> {
 	body.lines()
 		.filter_map(|line| {
-			parse_companion_from_url(line).and_then(|comp| {
-				// Break cyclical references between dependency and dependents because we're only
-				// interested in the dependency -> dependent relationship, not the other way around.
-				for item in companion_reference_trail {
-					if comp.owner == item.owner && comp.repo == item.repo {
-						return None;
+			companion_matcher
+				.parse_companion_from_url(line)
+				.and_then(|comp| {
+					// Break cyclical references between dependency and dependents because we're only
+					// interested in the dependency -> dependent relationship, not the other way around.
+					for item in companion_reference_trail {
+						// Github's names are case-insensitive
+						if comp.owner.eq_ignore_ascii_case(&item.owner)
+							&& comp.repo.eq_ignore_ascii_case(&item.repo)
+						{
+							return None;
+						}
 					}
-				}
-				Some(comp)
-			})
+					Some(comp)
+				})
+		})
+		.collect()
+}
+
+/// Parses the explicit order in which the companions should be merged, e.g.
+/// `companion-order: repoA, repoB`. Each item is either a repository name or an `owner/repo` pair.
+pub fn parse_companion_order(body: &str) -> Option<Vec<String>> {
+	lazy_static::lazy_static! {
+		static ref COMPANION_ORDER: Regex = RegexBuilder::new(
+			r"^\s*companion-order\s*:\s*(?P<order>.*)$"
+		)
+		.case_insensitive(true)
+		.multi_line(true)
+		.build()
+		.unwrap();
+	}
+
+	let caps = COMPANION_ORDER.captures(body)?;
+	let order = caps
+		.name("order")?
+		.as_str()
+		.split(',')
+		.map(|item| item.trim().to_owned())
+		.filter(|item| !item.is_empty())
+		.collect::<Vec<_>>();
+	if order.is_empty() {
+		None
+	} else {
+		Some(order)
+	}
+}
+
+/// Whether an item of `companion-order` refers to the companion `owner/repo`
+pub fn is_companion_order_item_for(
+	item: &str,
+	owner: &str,
+	repo: &str,
+) -> bool {
+	match item.split_once('/') {
+		Some((item_owner, item_repo)) => {
+			item_owner == owner && item_repo == repo
+		}
+		None => item == repo,
+	}
+}
+
+// Lists the repositories of `owner` (except `repo`) which a lockfile references
+// through git branches, e.g. "git+https://github.com/owner/other?branch=feature#sha",
+// as (repository, branch) pairs
+fn find_lockfile_companion_branches(
+	config: &MainConfig,
+	lockfile: &cargo_lock::Lockfile,
+	owner: &str,
+	repo: &str,
+) -> BTreeSet<(String, String)> {
+	let owner_source_prefix =
+		format!("{}/{}/", config.github_source_prefix, owner);
+	lockfile
+		.packages
+		.iter()
+		.filter_map(|pkg| {
+			let src = pkg.source.as_ref()?;
+			let branch = match src.git_reference()? {
+				GitReference::Branch(branch) => branch,
+				_ => return None,
+			};
+			let source_repo = src
+				.url()
+				.as_str()
+				.strip_prefix(&owner_source_prefix)?
+				.strip_suffix(&config.github_source_suffix)?;
+			if source_repo == repo || source_repo.contains('/') {
+				return None;
+			}
+			Some((source_repo.to_owned(), branch.to_owned()))
 		})
 		.collect()
 }
 
+/// The companions of `pr` referenced in its description. With COMPANION_DISCOVERY_ENABLED, the
+/// open pull requests whose branches are referenced by its Cargo.lock are included as well.
+pub async fn resolve_companions(
+	gh_client: &GithubClient,
+	config: &MainConfig,
+	pr: &GithubPullRequest,
+	companion_reference_trail: &[CompanionReferenceTrailItem],
+) -> Result<Option<Vec<PullRequestDetailsWithHtmlUrl>>> {
+	let companions = pr.parse_all_companions(
+		&config.companion_matcher,
+		companion_reference_trail,
+	);
+	if !config.companion_discovery_enabled {
+		return Ok(companions);
+	}
+
+	let owner = &pr.base.repo.owner.login;
+	let repo = &pr.base.repo.name;
+	let lockfile = match gh_client.lockfile(owner, repo, &pr.head.sha).await? {
+		Some(lockfile) => lockfile,
+		None => return Ok(companions),
+	};
+
+	let mut companions = companions.unwrap_or_default();
+	for (source_repo, branch) in
+		find_lockfile_companion_branches(config, &lockfile, owner, repo)
+	{
+		// Break cyclical references in the same way as parse_all_companions
+		if companion_reference_trail
+			.iter()
+			.any(|item| &item.owner == owner && item.repo == source_repo)
+		{
+			continue;
+		}
+		for comp_pr in gh_client
+			.open_pull_requests_for_branch(owner, &source_repo, &branch)
+			.await?
+		{
+			if companions.iter().any(|comp| {
+				&comp.owner == owner
+					&& comp.repo == source_repo
+					&& comp.number == comp_pr.number
+			}) {
+				continue;
+			}
+			log::info!(
+				"Discovered companion {} of {} through the Cargo.lock reference to branch {}",
+				comp_pr.html_url,
+				pr.html_url,
+				branch
+			);
+			companions.push(PullRequestDetailsWithHtmlUrl {
+				html_url: comp_pr.html_url,
+				owner: owner.to_owned(),
+				repo: source_repo.clone(),
+				number: comp_pr.number,
+			});
+		}
+	}
+
+	Ok(Some(companions))
+}
+
 #[async_recursion]
 pub async fn check_all_companions_are_mergeable(
 	state: &AppState,
@@ -294,7 +618,14 @@ pub async fn check_all_companions_are_mergeable(
 	requested_by: &str,
 	companion_reference_trail: &[CompanionReferenceTrailItem],
 ) -> Result<()> {
-	let companions = match pr.parse_all_companions(companion_reference_trail) {
+	let companions = match resolve_companions(
+		&state.gh_client,
+		&state.config,
+		pr,
+		companion_reference_trail,
+	)
+	.await?
+	{
 		Some(companions) => {
 			if companions.is_empty() {
 				return Ok(());
@@ -308,6 +639,44 @@ pub async fn check_all_companions_are_mergeable(
 	let AppState {
 		gh_client, config, ..
 	} = state;
+
+	// Guard against pathological graphs of companions which would otherwise have
+	// us hammering the API
+	if companion_reference_trail.len() >= config.max_dependency_depth {
+		return Err(Error::Message {
+			msg: format!(
+				"The companions of {} exceed the maximum dependency depth of {} (see MAX_DEPENDENCY_DEPTH)",
+				pr.html_url, config.max_dependency_depth
+			),
+		});
+	}
+
+	if companions.len() > config.max_companions {
+		return Err(Error::Message {
+			msg: format!(
+				"{} references {} companions, which exceeds the maximum of {} (see MAX_COMPANIONS)",
+				pr.html_url,
+				companions.len(),
+				config.max_companions
+			),
+		});
+	}
+
+	if let Some(order) = pr.body.as_deref().and_then(parse_companion_order) {
+		if let Some(item) = order.iter().find(|item| {
+			!companions.iter().any(|comp| {
+				is_companion_order_item_for(item, &comp.owner, &comp.repo)
+			})
+		}) {
+			return Err(Error::Message {
+				msg: format!(
+					"The companion-order of {} lists {}, which is not one of its companions",
+					pr.html_url, item
+				),
+			});
+		}
+	}
+
 	for PullRequestDetailsWithHtmlUrl {
 		html_url,
 		owner,
@@ -327,32 +696,24 @@ pub async fn check_all_companions_are_mergeable(
 			.map(|user| user.type_field == GithubUserType::User)
 			.unwrap_or(false);
 		if !has_user_owner {
-			return Err(Error::Message {
-				msg: format!(
-					"Companion {} is not owned by a user, therefore processbot would not be able to push the lockfile update to their branch due to a Github limitation (https://github.com/isaacs/github/issues/1681)",
-					html_url
-				),
-			});
+			return Err(Error::CompanionNotUserOwned { html_url });
 		}
 
+		// The companion's branch might be updated, thus it should still exist
+		let head_repo = companion.head_repository()?;
 		if !companion.maintainer_can_modify
 			// Even if the "Allow edits from maintainers" setting is not enabled, as long as the
 			// companion belongs to the same organization, the bot should still be able to push
 			// commits.
-			&& companion
-				.head
-				.repo
-				.owner.login != pr.base.repo.owner.login
+			&& head_repo.owner.login != pr.base.repo.owner.login
 		{
-			return Err(Error::Message {
-				msg: format!(
-					"Github API says \"Allow edits from maintainers\" is not enabled for {}. The bot would use that permission to push the lockfile update after merging this PR. Please check https://docs.github.com/en/github/collaborating-with-pull-requests/working-with-forks/allowing-changes-to-a-pull-request-branch-created-from-a-fork.",
-					html_url
-				),
-			});
+			return Err(Error::CompanionMissingMaintainerEdit { html_url });
 		}
 
-		if !config.disable_org_checks {
+		if !config.disable_org_checks_for(
+			&companion.base.repo.owner.login,
+			&companion.base.repo.name,
+		) {
 			/*
 				FIXME: Get rid of this ugly hack once the Companion Build System doesn't
 				ignore the companion's CI
@@ -361,6 +722,7 @@ pub async fn check_all_companions_are_mergeable(
 				state,
 				&companion.base.repo.owner.login,
 				&companion.base.repo.name,
+				companion.number,
 				&companion.head.sha,
 				&companion.html_url,
 				false,
@@ -383,8 +745,16 @@ pub async fn check_all_companions_are_mergeable(
 			}
 		}
 
+		// Conflicts are left for check_merge_is_allowed to report below
+		if !companion.mergeable.unwrap_or(false)
+			&& companion.mergeable_state.as_deref() != Some("dirty")
+		{
+			return Err(Error::CompanionNotMergeable {
+				html_url: companion.html_url,
+			});
+		}
+
 		// Keeping track of the trail of references is necessary to break chains like A -> B -> C -> A
-		// TODO: of course this should be tested
 		let next_companion_reference_trail = {
 			let mut next_trail =
 				Vec::with_capacity(companion_reference_trail.len() + 1);
@@ -408,6 +778,244 @@ pub async fn check_all_companions_are_mergeable(
 	Ok(())
 }
 
+/// Poll a pull request until its HEAD SHA becomes `expected_sha`, backing off exponentially
+/// between attempts. Gives up with `Error::HeadChanged` once `max_delay` (milliseconds) has
+/// elapsed.
+pub async fn wait_for_pull_request_head(
+	gh_client: &GithubClient,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	expected_sha: &str,
+	max_delay: u64,
+) -> Result<GithubPullRequest> {
+	poll_pull_request_head(gh_client, owner, repo, number, max_delay, |sha| {
+		sha == expected_sha
+	})
+	.await?
+	.map_err(|pr| Error::HeadChanged {
+		expected: expected_sha.to_string(),
+		actual: pr.head.sha,
+	})
+}
+
+/// Wait until the HEAD of a pull request has moved on from `previous_sha`, e.g.
+/// after Github updated its branch.
+pub async fn wait_for_pull_request_head_update(
+	gh_client: &GithubClient,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	previous_sha: &str,
+	max_delay: u64,
+) -> Result<GithubPullRequest> {
+	poll_pull_request_head(gh_client, owner, repo, number, max_delay, |sha| {
+		sha != previous_sha
+	})
+	.await?
+	.map_err(|pr| Error::Message {
+		msg: format!(
+			"HEAD of {} was not updated from {} in time",
+			pr.html_url, previous_sha
+		),
+	})
+}
+
+// Polls the pull request until its HEAD SHA is accepted by `is_expected_sha`.
+// If that doesn't happen until `max_delay` elapses, the last pull request which
+// was fetched is returned as the error.
+async fn poll_pull_request_head<F: Fn(&str) -> bool>(
+	gh_client: &GithubClient,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	max_delay: u64,
+	is_expected_sha: F,
+) -> Result<Result<GithubPullRequest, GithubPullRequest>> {
+	const INITIAL_POLL_DELAY: u64 = 256;
+
+	let started_at = Instant::now();
+	let max_delay = Duration::from_millis(max_delay);
+	let mut poll_delay = Duration::from_millis(INITIAL_POLL_DELAY);
+	loop {
+		let pr = gh_client.pull_request(owner, repo, number).await?;
+		if is_expected_sha(&pr.head.sha) {
+			return Ok(Ok(pr));
+		}
+
+		let elapsed = started_at.elapsed();
+		if elapsed >= max_delay {
+			return Ok(Err(pr));
+		}
+
+		log::info!(
+			"HEAD of {} is still {}; polling again in {:?}",
+			pr.html_url,
+			pr.head.sha,
+			poll_delay
+		);
+		sleep(std::cmp::min(poll_delay, max_delay - elapsed)).await;
+		poll_delay *= 2;
+	}
+}
+
+// The repositories whose references should be updated in the lockfile of a companion, i.e. the
+// ones of the pull requests it depends on
+fn infer_dependencies_to_update(comp: &MergeRequest) -> HashSet<&String> {
+	if let Some(ref dependencies) = comp.dependencies {
+		HashSet::from_iter(
+			dependencies.iter().map(|dependency| &dependency.repo),
+		)
+	} else {
+		HashSet::new()
+	}
+}
+
+/// Merge the base branch into the pull request's branch and update its lockfile in the same way
+/// as `update_companion_then_merge`, but without merging it afterwards. The dependencies are
+/// inferred from the pull request's queued merge, if any, which is then registered again for the
+/// pushed SHA. Returns the SHA which was pushed.
+pub async fn update_companion(
+	state: &AppState,
+	pr: &GithubPullRequest,
+) -> Result<String> {
+	let queued_mr = list_merge_requests(state).into_iter().find(|mr| {
+		mr.owner == pr.base.repo.owner.login
+			&& mr.repo == pr.base.repo.name
+			&& mr.number == pr.number
+	});
+	let dependencies_to_update = queued_mr
+		.as_ref()
+		.map(infer_dependencies_to_update)
+		.unwrap_or_default();
+
+	log::info!(
+		"Updating {} without merging it, including the following dependencies: {:?}",
+		pr.html_url,
+		dependencies_to_update
+	);
+
+	let head_repo = pr.head_repository()?;
+	let updated_sha = update_pr_branch(
+		state,
+		&pr.base.repo.owner.login,
+		&pr.base.repo.name,
+		&pr.base.ref_field,
+		&head_repo.owner.login,
+		&head_repo.name,
+		&pr.head.ref_field,
+		&dependencies_to_update,
+		pr.number,
+	)
+	.await?;
+
+	// Otherwise the merge would be cancelled once the push's event is received
+	// since processbot is not the merge's requester. The dependencies are kept
+	// since they might not have been merged yet.
+	if let Some(mr) = queued_mr {
+		cleanup_merge_request(
+			state,
+			&mr.sha,
+			&mr.owner,
+			&mr.repo,
+			mr.number,
+			&MergeRequestCleanupReason::AfterSHAUpdate(&updated_sha),
+		)
+		.await?;
+		queue_merge_request(
+			state,
+			&MergeRequest {
+				sha: updated_sha.clone(),
+				..mr
+			},
+			&MergeRequestQueuedMessage::None,
+		)
+		.await?;
+	}
+
+	Ok(updated_sha)
+}
+
+/// Failures of a branch update or of a merge which might not happen again on a later attempt,
+/// e.g. a failed push or an unexplained refusal of the merge. They count towards
+/// `max_merge_attempts` rather than cancelling the merge right away.
+fn is_counted_failure(err: &Error) -> bool {
+	matches!(
+		err,
+		Error::Message { .. }
+			| Error::Response { .. }
+			| Error::Http { .. }
+			| Error::CommandFailed { .. }
+			| Error::CommandTimedOut { .. }
+	)
+}
+
+/// Register `mr` again after an attempt which failed due to `err` so that it's retried later, or
+/// give up on it once `max_merge_attempts` is reached. Each failure is reported on the pull
+/// request.
+async fn register_failed_attempt(
+	state: &AppState,
+	mut mr: MergeRequest,
+	err: Error,
+) -> Result<()> {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+
+	mr.attempts += 1;
+	mr.last_attempt_at = Some(SystemTime::now());
+	log::info!(
+		"Attempt {} to merge {} failed due to {}",
+		mr.attempts,
+		mr.html_url,
+		err
+	);
+
+	if let Err(comment_err) = gh_client
+		.create_issue_comment_once(
+			&mr.owner,
+			&mr.repo,
+			mr.number,
+			&config.message_templates.render(
+				Message::MergeAttemptFailed,
+				&[
+					("attempt", &mr.attempts.to_string()),
+					("max_attempts", &config.max_merge_attempts.to_string()),
+					("error", &err.to_string()),
+				],
+			),
+		)
+		.await
+	{
+		log::error!(
+			"Failed to post comment on {} due to {}",
+			mr.html_url,
+			comment_err
+		);
+	}
+
+	if mr.attempts >= config.max_merge_attempts {
+		log::info!(
+			"Giving up on merging {} after {} attempts",
+			mr.html_url,
+			mr.attempts
+		);
+		return cleanup_merge_request(
+			state,
+			&mr.sha,
+			&mr.owner,
+			&mr.repo,
+			mr.number,
+			&MergeRequestCleanupReason::GaveUp {
+				attempts: mr.attempts,
+			},
+		)
+		.await;
+	}
+
+	queue_merge_request(state, &mr, &MergeRequestQueuedMessage::None).await
+}
+
 #[async_recursion]
 pub async fn update_companion_then_merge(
 	state: &AppState,
@@ -430,7 +1038,7 @@ pub async fn update_companion_then_merge(
 			return Ok(None);
 		}
 
-		let (updated_sha, comp_pr) = if comp.was_updated {
+		let (mut updated_sha, comp_pr) = if comp.was_updated {
 			if comp_pr.head.sha != comp.sha {
 				return Err(Error::HeadChanged {
 					expected: comp.sha.to_string(),
@@ -442,14 +1050,7 @@ pub async fn update_companion_then_merge(
 			check_merge_is_allowed(state, &comp_pr, &comp.requested_by, &[])
 				.await?;
 
-			let dependencies_to_update =
-				if let Some(ref dependencies) = comp.dependencies {
-					HashSet::from_iter(
-						dependencies.iter().map(|dependency| &dependency.repo),
-					)
-				} else {
-					HashSet::new()
-				};
+			let dependencies_to_update = infer_dependencies_to_update(comp);
 
 			if !all_dependencies_are_ready && !dependencies_to_update.is_empty()
 			{
@@ -470,41 +1071,41 @@ pub async fn update_companion_then_merge(
 				dependencies_to_update
 			);
 
-			let updated_sha = update_pr_branch(
+			let head_repo = comp_pr.head_repository()?;
+			let updated_sha = match update_pr_branch(
 				state,
 				&comp_pr.base.repo.owner.login,
 				&comp_pr.base.repo.name,
 				&comp_pr.base.ref_field,
-				&comp_pr.head.repo.owner.login,
-				&comp_pr.head.repo.name,
+				&head_repo.owner.login,
+				&head_repo.name,
 				&comp_pr.head.ref_field,
 				&dependencies_to_update,
 				comp_pr.number,
 			)
-			.await?;
-
-			// Wait a bit for the statuses to settle after we've updated the companion
-			sleep(Duration::from_millis(config.companion_status_settle_delay))
-				.await;
+			.await
+			{
+				Ok(updated_sha) => updated_sha,
+				Err(err) if is_counted_failure(&err) => {
+					// The branch was not updated, thus the update is attempted again
+					register_failed_attempt(state, comp.clone(), err).await?;
+					return Ok(None);
+				}
+				Err(err) => return Err(err),
+			};
 
 			// Fetch it again since we've pushed some commits and therefore some status or check might have
-			// failed already
-			let comp_pr = gh_client
-				.pull_request(
-					&comp_pr.base.repo.owner.login,
-					&comp_pr.base.repo.name,
-					comp_pr.number,
-				)
-				.await?;
-
-			// Sanity-check: the PR's new HEAD sha should be the updated SHA we just
-			// pushed
-			if comp_pr.head.sha != updated_sha {
-				return Err(Error::HeadChanged {
-					expected: updated_sha.to_string(),
-					actual: comp_pr.head.sha.to_string(),
-				});
-			}
+			// failed already. The API might take a while to reflect the push, so keep polling until the
+			// PR's new HEAD sha is the updated SHA we just pushed.
+			let comp_pr = wait_for_pull_request_head(
+				gh_client,
+				&comp_pr.base.repo.owner.login,
+				&comp_pr.base.repo.name,
+				comp_pr.number,
+				&updated_sha,
+				config.companion_status_settle_delay,
+			)
+			.await?;
 
 			// Cleanup the pre-update SHA in order to prevent late status deliveries from
 			// removing the updated SHA from the database
@@ -521,51 +1122,96 @@ pub async fn update_companion_then_merge(
 			(Some(updated_sha), comp_pr)
 		};
 
-		if is_ready_to_merge(state, &comp_pr).await? {
+		// Only the failures of the merge itself count towards the attempts limit;
+		// pending checks and failures which are expected to be solved later don't
+		let mut failed_attempt = None;
+		let mut last_attempt_at = comp.last_attempt_at;
+		let is_ready = match is_ready_to_merge(state, &comp_pr).await {
+			Ok(readiness) => {
+				if !readiness.is_ready() {
+					log::info!(
+						"{} is not ready: {}",
+						comp_pr.html_url,
+						readiness
+					);
+				}
+				readiness.is_ready()
+			}
+			// The companion will be checked again when it's processed later
+			Err(Error::TransientApi { msg }) => {
+				log::info!(
+					"Could not check if {} is ready due to: {}",
+					comp_pr.html_url,
+					msg
+				);
+				false
+			}
+			Err(err) => return Err(err),
+		};
+		if is_ready {
 			log::info!(
 				"Attempting to merge {} after companion update",
 				comp_pr.html_url
 			);
-			if let Err(err) =
-				merge_pull_request(state, &comp_pr, &comp.requested_by).await?
+			match merge_pull_request(state, &comp_pr, &comp.requested_by).await
 			{
-				match err {
-					Error::MergeFailureWillBeSolvedLater { .. } => {}
-					err => return Err(err),
-				};
-			} else {
-				process_dependents_after_merge(
-					state,
-					&comp_pr,
-					&comp.requested_by,
-				)
-				.await?;
-				return Ok(updated_sha);
+				Ok(Ok(())) => {
+					process_dependents_after_merge(
+						state,
+						&comp_pr,
+						&comp.requested_by,
+					)
+					.await?;
+					return Ok(updated_sha);
+				}
+				Ok(Err(Error::MergeFailureWillBeSolvedLater {
+					updated_sha: merge_updated_sha,
+					..
+				})) => {
+					last_attempt_at = Some(SystemTime::now());
+					if merge_updated_sha.is_some() {
+						updated_sha = merge_updated_sha;
+					}
+				}
+				Ok(Err(err)) => return Err(err),
+				Err(err) if is_counted_failure(&err) => {
+					failed_attempt = Some(err);
+				}
+				Err(err) => return Err(err),
 			}
 		}
 
-		log::info!(
-			"Companion updated; waiting for checks on {}",
-			comp_pr.html_url
-		);
-		queue_merge_request(
-			state,
-			&MergeRequest {
-				sha: comp_pr.head.sha,
-				owner: comp_pr.base.repo.owner.login,
-				repo: comp_pr.base.repo.name,
-				number: comp_pr.number,
-				html_url: comp_pr.html_url,
-				requested_by: (&comp.requested_by).into(),
-				// Set "was_updated: true" to avoid updating a branch more than once
-				was_updated: true,
-				// All dependencies should have been updated above, we won't update them
-				// again
-				dependencies: None,
-			},
-			msg,
-		)
-		.await?;
+		let mr = MergeRequest {
+			// The branch might have been updated for the merge attempt
+			sha: updated_sha.clone().unwrap_or(comp_pr.head.sha),
+			owner: comp_pr.base.repo.owner.login,
+			repo: comp_pr.base.repo.name,
+			number: comp_pr.number,
+			html_url: comp_pr.html_url,
+			requested_by: (&comp.requested_by).into(),
+			// Set "was_updated: true" to avoid updating a branch more than once
+			was_updated: true,
+			// All dependencies should have been updated above, we won't update them
+			// again
+			dependencies: None,
+			attempts: comp.attempts,
+			priority: comp.priority,
+			not_before: comp.not_before,
+			queued_at: comp.queued_at,
+			seq: comp.seq,
+			last_attempt_at,
+			schema_version: MERGE_REQUEST_SCHEMA_VERSION,
+		};
+		match failed_attempt {
+			Some(err) => register_failed_attempt(state, mr, err).await?,
+			None => {
+				log::info!(
+					"Companion updated; waiting for checks on {}",
+					mr.html_url
+				);
+				queue_merge_request(state, &mr, msg).await?;
+			}
+		}
 
 		Ok(updated_sha)
 	}
@@ -583,15 +1229,20 @@ pub async fn update_companion_then_merge(
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::constants::DEFAULT_COMPANION_MARKERS;
 
 	const COMPANION_MARKERS: &[&str; 2] = &["Companion", "companion"];
 
+	fn build_default_matcher() -> CompanionMatcher {
+		CompanionMatcher::new(DEFAULT_COMPANION_MARKERS)
+	}
+
 	#[test]
 	fn test_companion_parsing_url_params() {
 		for companion_marker in COMPANION_MARKERS {
 			// Extra params should not be included in the parsed URL
 			assert_eq!(
-				parse_companion_from_url(&format!(
+				build_default_matcher().parse_companion_from_url(&format!(
 					"{}: https://github.com/org/repo/pull/1234?extra_params=true",
 					companion_marker
 				)),
@@ -612,7 +1263,7 @@ mod tests {
 			// Long version should work even if the body has some other content around
 			// the companion text
 			assert_eq!(
-				parse_companion_from_url(&format!(
+				build_default_matcher().parse_companion_from_url(&format!(
 					"
 					Companion line is in the middle
 					{}: https://github.com/org/repo/pull/1234
@@ -631,13 +1282,41 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_companion_parsing_custom_hosts() {
+		for (url, owner, repo) in [
+			("https://ghe.example.com/org/repo/pull/1234", "org", "repo"),
+			(
+				"https://example.com/github/org/repo/pull/1234",
+				"org",
+				"repo",
+			),
+			(
+				"http://example.com:8080/git/hub/org/repo/pull/1234",
+				"org",
+				"repo",
+			),
+		] {
+			assert_eq!(
+				build_default_matcher()
+					.parse_companion_from_url(&format!("companion: {}", url)),
+				Some(PullRequestDetailsWithHtmlUrl {
+					html_url: url.to_owned(),
+					owner: owner.to_owned(),
+					repo: repo.to_owned(),
+					number: 1234
+				})
+			);
+		}
+	}
+
 	#[test]
 	fn test_companion_parsing_short_version_wrap() {
 		for companion_marker in COMPANION_MARKERS {
 			// Short version should work even if the body has some other content around
 			// the companion text
 			assert_eq!(
-				parse_companion_from_url(&format!(
+				build_default_matcher().parse_companion_from_url(&format!(
 					"
 					Companion line is in the middle
 					{}: org/repo#1234
@@ -662,7 +1341,7 @@ mod tests {
 			// Long version should not be detected if "companion: " and the expression
 			// are not both in the same line
 			assert_eq!(
-				parse_companion_from_url(&format!(
+				build_default_matcher().parse_companion_from_url(&format!(
 					"
 					I want to talk about {}: but NOT reference it
 					I submitted it in https://github.com/org/repo/pull/1234
@@ -680,7 +1359,7 @@ mod tests {
 			// Short version should not be detected if "companion: " and the expression are not both in
 			// the same line
 			assert_eq!(
-				parse_companion_from_url(&format!(
+				build_default_matcher().parse_companion_from_url(&format!(
 					"
 					I want to talk about {}: but NOT reference it
 					I submitted it in org/repo#1234
@@ -708,6 +1387,7 @@ mod tests {
 		for companion_marker in COMPANION_MARKERS {
 			assert_eq!(
 				parse_all_companions(
+					&build_default_matcher(),
 					&[],
 					&format!(
 						"
@@ -740,13 +1420,18 @@ mod tests {
 
 			// If the source is not referenced in the description, something is parsed
 			assert_ne!(
-				parse_all_companions(&[], &companion_description),
+				parse_all_companions(
+					&build_default_matcher(),
+					&[],
+					&companion_description
+				),
 				vec![]
 			);
 
 			// If the source is referenced in the description, it is omitted
 			assert_eq!(
 				parse_all_companions(
+					&build_default_matcher(),
 					&[CompanionReferenceTrailItem {
 						owner: owner.into(),
 						repo: repo.into()
@@ -758,6 +1443,130 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_missing_lockfile() {
+		let repo_dir = tempfile::tempdir().unwrap();
+		let err = load_lockfile(repo_dir.path().to_str().unwrap(), "repo")
+			.unwrap_err();
+		assert_eq!(
+			format!("{}", err),
+			"Could not find a Cargo.lock for repo. processbot expected a Cargo.lock in the root of the repository in order to update the dependency references before merging. If repo is not a Rust project, please disable the automatic lockfile update for it in processbot's configuration (DEPENDENCY_UPDATE_CONFIGURATION) or do not reference it as a companion."
+		);
+	}
+
+	#[test]
+	fn test_unexpected_lockfile_changes() {
+		let build_lockfile = |substrate_rev: &str, serde_version: &str| {
+			format!(
+				r#"
+version = 3
+
+[[package]]
+name = "serde"
+version = "{}"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "sp-core"
+version = "1.0.0"
+source = "git+https://github.com/org/substrate?branch=master#{}"
+"#,
+				serde_version, substrate_rev
+			)
+			.parse::<cargo_lock::Lockfile>()
+			.unwrap()
+		};
+		let sources_to_update =
+			HashSet::from_iter(vec!["https://github.com/org/substrate".into()]);
+		let original_lockfile = build_lockfile("aaa", "1.0.0");
+
+		assert!(find_unexpected_lockfile_changes(
+			&original_lockfile,
+			&build_lockfile("bbb", "1.0.0"),
+			&sources_to_update,
+		)
+		.is_empty());
+
+		let changes = find_unexpected_lockfile_changes(
+			&original_lockfile,
+			&build_lockfile("bbb", "1.0.1"),
+			&sources_to_update,
+		);
+		assert_eq!(changes.len(), 2);
+		assert!(changes[0].starts_with("-serde 1.0.0"));
+		assert!(changes[1].starts_with("+serde 1.0.1"));
+	}
+
+	#[test]
+	fn test_lockfile_changes_brought_in_by_updated_packages() {
+		let build_lockfile = |packages: &str| {
+			format!("version = 3\n{}", packages)
+				.parse::<cargo_lock::Lockfile>()
+				.unwrap()
+		};
+		let sources_to_update =
+			HashSet::from_iter(vec!["https://github.com/org/substrate".into()]);
+		let original_lockfile = build_lockfile(
+			r#"
+[[package]]
+name = "old-helper"
+version = "0.1.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "sp-core"
+version = "1.0.0"
+source = "git+https://github.com/org/substrate?branch=master#aaa"
+dependencies = ["old-helper"]
+"#,
+		);
+
+		// The updated sp-core dropped old-helper and depends on new-helper, which
+		// depends on new-transitive-helper
+		let updated_packages = r#"
+[[package]]
+name = "new-helper"
+version = "0.2.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = ["new-transitive-helper"]
+
+[[package]]
+name = "new-transitive-helper"
+version = "0.3.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "sp-core"
+version = "1.0.0"
+source = "git+https://github.com/org/substrate?branch=master#bbb"
+dependencies = ["new-helper"]
+"#;
+		assert!(find_unexpected_lockfile_changes(
+			&original_lockfile,
+			&build_lockfile(updated_packages),
+			&sources_to_update,
+		)
+		.is_empty());
+
+		// Packages which the updated ones don't depend on are still unexpected
+		let changes = find_unexpected_lockfile_changes(
+			&original_lockfile,
+			&build_lockfile(&format!(
+				"{}{}",
+				updated_packages,
+				r#"
+[[package]]
+name = "unrelated"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#
+			)),
+			&sources_to_update,
+		);
+		assert_eq!(changes.len(), 1);
+		assert!(changes[0].starts_with("+unrelated 1.0.0"));
+	}
+
 	#[test]
 	fn test_restricted_regex() {
 		let owner = "org";
@@ -767,6 +1576,7 @@ mod tests {
 		for companion_marker in COMPANION_MARKERS {
 			assert_eq!(
 				parse_all_companions(
+					&build_default_matcher(),
 					&[],
 					// the companion expression should not be matched because of the " for" part
 					&format!("{} for {}", companion_marker, &companion_url)
@@ -775,4 +1585,153 @@ mod tests {
 			);
 		}
 	}
+
+	#[test]
+	fn test_companion_order_parsing() {
+		assert_eq!(
+			parse_companion_order(
+				"
+				companion: org/repo_a#1
+				Companion-Order: org/repo_b, repo_a
+				"
+			),
+			Some(vec!["org/repo_b".to_owned(), "repo_a".to_owned()])
+		);
+		assert_eq!(parse_companion_order("companion: org/repo_a#1"), None);
+		assert_eq!(parse_companion_order("companion-order: "), None);
+	}
+
+	#[test]
+	fn test_custom_companion_markers() {
+		let matcher = CompanionMatcher::new(&["companion", "depends on"]);
+		let expected_companion = PullRequestDetailsWithHtmlUrl {
+			html_url: "https://github.com/org/repo/pull/1234".to_owned(),
+			owner: "org".to_owned(),
+			repo: "repo".to_owned(),
+			number: 1234,
+		};
+
+		for body in &[
+			"Depends on: https://github.com/org/repo/pull/1234",
+			"depends on org/repo#1234",
+			"companion: org/repo#1234",
+		] {
+			assert_eq!(
+				matcher.parse_companion_from_url(body),
+				Some(expected_companion.clone())
+			);
+		}
+
+		// The same restrictions as for the default marker apply
+		assert_eq!(
+			parse_all_companions(
+				&matcher,
+				&[],
+				"
+				This depends on: nothing in this line
+				https://github.com/org/repo/pull/1234
+				"
+			),
+			vec![]
+		);
+		assert_eq!(
+			parse_all_companions(&matcher, &[], "depends on for org/repo#1234"),
+			vec![]
+		);
+
+		// Markers which are not configured are not recognized
+		assert_eq!(
+			build_default_matcher()
+				.parse_companion_from_url("depends on: org/repo#1234"),
+			None
+		);
+	}
+
+	// Lines which are likely to contain a companion reference, possibly
+	// malformed, so that the parser is exercised beyond its happy paths
+	fn arbitrary_companion_body(
+	) -> impl proptest::strategy::Strategy<Value = String> {
+		use proptest::prelude::*;
+
+		proptest::collection::vec(
+			prop_oneof![
+				"\\PC*",
+				"(companion|Companion|depends on)[: \t#(\\[.-]{0,3}(https://[a-z./]{0,12}/)?[^\n]{0,12}/[^\n]{0,12}(/pull/|#)[0-9]{1,22}[^\n]{0,5}",
+			],
+			0..5,
+		)
+		.prop_map(|lines| lines.join("\n"))
+	}
+
+	proptest::proptest! {
+		#[test]
+		fn test_companion_parsing_never_panics(body in "\\PC*") {
+			parse_all_companions(&build_default_matcher(), &[], &body);
+		}
+
+		#[test]
+		fn test_parsed_companions_are_well_formed(body in arbitrary_companion_body()) {
+			let name = Regex::new(r"^[[:alnum:]_.-]+$").unwrap();
+			for comp in parse_all_companions(&build_default_matcher(), &[], &body) {
+				proptest::prop_assert!(name.is_match(&comp.owner), "{:?}", comp);
+				proptest::prop_assert!(name.is_match(&comp.repo), "{:?}", comp);
+				proptest::prop_assert!(is_valid_name(&comp.owner), "{:?}", comp);
+				proptest::prop_assert!(is_valid_name(&comp.repo), "{:?}", comp);
+				proptest::prop_assert!(comp.number >= 0, "{:?}", comp);
+				// The number might have leading zeros in the URL
+				proptest::prop_assert_eq!(
+					comp.html_url
+						.rsplit_once(&format!("/{}/{}/pull/", comp.owner, comp.repo))
+						.map(|(_, number)| number.parse::<i64>()),
+					Some(Ok(comp.number)),
+					"{:?}",
+					comp
+				);
+				proptest::prop_assert!(
+					!comp.html_url.contains(char::is_whitespace),
+					"{:?}",
+					comp
+				);
+			}
+		}
+
+		#[test]
+		fn test_companion_reference_trail_is_respected(body in arbitrary_companion_body()) {
+			let matcher = build_default_matcher();
+			let trail = parse_all_companions(&matcher, &[], &body)
+				.into_iter()
+				.map(|comp| CompanionReferenceTrailItem {
+					owner: comp.owner.to_uppercase(),
+					repo: comp.repo.to_lowercase(),
+				})
+				.collect::<Vec<_>>();
+			proptest::prop_assert!(
+				parse_all_companions(&matcher, &trail, &body).is_empty()
+			);
+		}
+	}
+
+	#[test]
+	fn test_companion_parsing_regressions() {
+		let matcher = build_default_matcher();
+		for body in &[
+			// The number does not fit in an i64
+			"companion: org/repo#99999999999999999999999",
+			"companion: https://github.com/org/repo/pull/99999999999999999999999",
+			// Names which would escape the repositories' directory
+			"companion: ../repo#1",
+			"companion: org/..#1",
+			"companion: https://github.com/org/../pull/1",
+			// Too many path segments for a short reference
+			"companion: org/sub/repo#1",
+			// Characters which Github does not allow in names
+			"companion: org/repo\u{200b}#1",
+			"companion: org/re po#1",
+			"companion:",
+			"companion: /#1",
+			"",
+		] {
+			assert_eq!(parse_all_companions(&matcher, &[], body), vec![]);
+		}
+	}
 }
@@ -1,4 +1,21 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+	collections::{HashMap, HashSet},
+	path::PathBuf,
+};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+	companion::CompanionMatcher,
+	constants::{
+		DEFAULT_COMPANION_MARKERS, DEFAULT_GITLAB_JOB_TARGET_URL_REGEX,
+	},
+	github::{
+		GithubCheckRunConclusion, GithubMergeMethod, GithubUser, GithubUserType,
+	},
+	logging::LogFormat,
+	messages::MessageTemplates,
+};
 
 #[derive(Debug, Clone)]
 pub struct MainConfig {
@@ -11,14 +28,95 @@ pub struct MainConfig {
 	pub webhook_proxy_url: Option<String>,
 	pub github_app_id: usize,
 	pub disable_org_checks: bool,
+	pub disable_org_checks_overrides: HashMap<String, bool>,
+	pub codeowners_authorization: bool,
+	// Teams, written as `org/team`, whose members are allowed to use the commands
+	// even if they're not members of the repository's organization
+	pub privileged_teams: Vec<String>,
+	// The logins of the bots (e.g. "release-bot[bot]") whose comments are handled
+	// as commands; comments from any other bot are ignored
+	pub allowed_command_bots: Vec<String>,
+	// Whether the requester of a merge is checked to still be allowed to use
+	// `bot merge` when the merge is attempted, not only when it's requested
+	pub recheck_requester_membership: bool,
 	pub github_api_url: String,
+	pub github_api_url_overrides: HashMap<String, String>,
+	pub github_graphql_enabled: bool,
+	// The conclusions, besides success, with which a check run does not block
+	// the merge
+	pub passing_check_conclusions: Vec<GithubCheckRunConclusion>,
+	// Per repository; whether a pull request without any check run or status is
+	// kept waiting instead of being considered green
+	pub require_at_least_one_check: HashMap<String, bool>,
+	// Per repository; the teams, written as `org/team`, of which at least one
+	// member should have approved a pull request before it's merged
+	pub required_review_teams: HashMap<String, Vec<String>>,
 	pub companion_status_settle_delay: u64,
 	pub merge_command_delay: u64,
+	pub merge_command_delay_overrides: HashMap<String, u64>,
 	pub github_source_prefix: String,
 	pub github_source_suffix: String,
 	pub gitlab_url: String,
 	pub gitlab_access_token: String,
+	pub gitlab_recovery_enabled: bool,
+	pub gitlab_circuit_breaker_threshold: u32,
+	pub gitlab_circuit_breaker_cooldown: u64,
+	pub gitlab_job_target_url_matcher: Regex,
 	pub dependency_update_configuration: HashMap<String, Vec<String>>,
+	pub max_merge_attempts: u32,
+	// In seconds; how long to wait before attempting a merge again after it
+	// failed, 0 meaning that it's attempted again right away
+	pub merge_retry_cooldown: u64,
+	// In seconds; how often the merge requests are reconciled with their pull
+	// requests in case their events were missed, 0 meaning never
+	pub reconciliation_interval: u64,
+	pub max_dependency_depth: usize,
+	// In milliseconds
+	pub dependency_fetch_interval: u64,
+	pub max_companions: usize,
+	// How many pull requests can be queued at once through merge commands; 0
+	// means that there's no limit
+	pub max_queue_size: usize,
+	pub git_commit_author_name: String,
+	pub git_commit_author_email: String,
+	pub gpg_signing_key: Option<String>,
+	pub admin_token: Option<String>,
+	pub frozen_repos: HashSet<String>,
+	pub work_queue_capacity: usize,
+	pub allowed_base_branches: HashMap<String, Vec<String>>,
+	pub default_reviewers: HashMap<String, Vec<String>>,
+	// The label which queues a merge when it's applied to a pull request, per repository
+	pub automerge_label: HashMap<String, String>,
+	// Whether commands are acknowledged through reactions to their comment rather
+	// than through a reply, errors aside
+	pub reaction_acknowledgements: bool,
+	pub companion_markers: Vec<String>,
+	pub companion_matcher: CompanionMatcher,
+	// Whether the companions referenced through git branches in a pull request's
+	// Cargo.lock are merged along with the ones from its description
+	pub companion_discovery_enabled: bool,
+	pub max_concurrent_branch_updates: usize,
+	// In seconds; 0 means that commands are never killed
+	pub command_timeout: u64,
+	// In seconds
+	pub github_request_timeout_secs: u64,
+	// How many idle connections to the Github API are kept open for reuse
+	pub github_pool_max_idle_per_host: usize,
+	// In seconds; how long every request is held off for after a secondary rate
+	// limit is hit, doubled for each one hit in a row
+	pub github_secondary_rate_limit_backoff: u64,
+	pub merge_method: GithubMergeMethod,
+	pub merge_method_overrides: HashMap<String, GithubMergeMethod>,
+	pub merge_commit_title_template: Option<String>,
+	pub merge_commit_title_template_overrides: HashMap<String, String>,
+	pub merge_commit_message_template: Option<String>,
+	pub merge_commit_message_template_overrides: HashMap<String, String>,
+	// Git trailers, e.g. "Merged-by: processbot", appended to the message of
+	// the commit created when merging a pull request
+	pub merge_commit_trailers: Vec<String>,
+	pub message_templates: MessageTemplates,
+	pub webhook_archive_dir: Option<PathBuf>,
+	pub log_format: LogFormat,
 }
 
 impl MainConfig {
@@ -78,19 +176,212 @@ impl MainConfig {
 				}
 			})
 			.unwrap_or(false);
+		let disable_org_checks_overrides =
+			parse_per_repository_var("DISABLE_ORG_CHECKS_OVERRIDES", |value| {
+				match value {
+					"true" => true,
+					"false" => false,
+					_ => panic!(
+						"$DISABLE_ORG_CHECKS_OVERRIDES values should be \"true\" or \"false\""
+					),
+				}
+			});
+
+		let codeowners_authorization = dotenv::var("CODEOWNERS_AUTHORIZATION")
+			.ok()
+			.map(|value| match value.as_str() {
+				"true" => true,
+				"false" => false,
+				_ => panic!(
+					"CODEOWNERS_AUTHORIZATION should be \"true\" or \"false\""
+				),
+			})
+			.unwrap_or(false);
+
+		let privileged_teams = dotenv::var("PRIVILEGED_TEAMS")
+			.map(|raw_configuration| {
+				raw_configuration
+					.split(',')
+					.map(|team| team.trim())
+					.filter(|team| !team.is_empty())
+					.map(|team| match team.split_once('/') {
+						Some((org, slug))
+							if !org.is_empty() && !slug.is_empty() =>
+						{
+							team.to_string()
+						}
+						_ => panic!(
+							"$PRIVILEGED_TEAMS segment \"{}\" should be of the form ORG/TEAM",
+							team
+						),
+					})
+					.collect::<Vec<_>>()
+			})
+			.unwrap_or_default();
+
+		let allowed_command_bots = dotenv::var("ALLOWED_COMMAND_BOTS")
+			.map(|raw_configuration| {
+				raw_configuration
+					.split(',')
+					.map(|login| login.trim())
+					.filter(|login| !login.is_empty())
+					.map(|login| login.to_string())
+					.collect::<Vec<_>>()
+			})
+			.unwrap_or_default();
+
+		let recheck_requester_membership =
+			dotenv::var("RECHECK_REQUESTER_MEMBERSHIP")
+				.ok()
+				.map(|value| match value.as_str() {
+					"true" => true,
+					"false" => false,
+					_ => panic!(
+						"RECHECK_REQUESTER_MEMBERSHIP should be \"true\" or \"false\""
+					),
+				})
+				.unwrap_or(false);
+
+		let github_graphql_enabled = dotenv::var("GITHUB_GRAPHQL_ENABLED")
+			.ok()
+			.map(|value| match value.as_str() {
+				"true" => true,
+				"false" => false,
+				_ => panic!(
+					"GITHUB_GRAPHQL_ENABLED should be \"true\" or \"false\""
+				),
+			})
+			.unwrap_or(false);
+
+		let passing_check_conclusions = dotenv::var("PASSING_CHECK_CONCLUSIONS")
+			.map(|raw_configuration| {
+				raw_configuration
+					.split(',')
+					.map(|conclusion| conclusion.trim())
+					.filter(|conclusion| !conclusion.is_empty())
+					.map(|conclusion| match conclusion {
+						"success" => GithubCheckRunConclusion::Success,
+						"neutral" => GithubCheckRunConclusion::Neutral,
+						"skipped" => GithubCheckRunConclusion::Skipped,
+						"cancelled" => GithubCheckRunConclusion::Cancelled,
+						"timed_out" => GithubCheckRunConclusion::TimedOut,
+						"action_required" => {
+							GithubCheckRunConclusion::ActionRequired
+						}
+						_ => panic!(
+							"$PASSING_CHECK_CONCLUSIONS segment \"{}\" is not a known check run conclusion",
+							conclusion
+						),
+					})
+					.collect::<Vec<_>>()
+			})
+			.unwrap_or_else(|_| {
+				vec![
+					GithubCheckRunConclusion::Neutral,
+					GithubCheckRunConclusion::Skipped,
+				]
+			});
+		let require_at_least_one_check =
+			parse_per_repository_var("REQUIRE_AT_LEAST_ONE_CHECK", |value| {
+				match value {
+					"true" => true,
+					"false" => false,
+					_ => panic!(
+						"$REQUIRE_AT_LEAST_ONE_CHECK values should be \"true\" or \"false\""
+					),
+				}
+			});
+		let required_review_teams =
+			parse_per_repository_var("REQUIRED_REVIEW_TEAMS", |value| {
+				value
+					.split('+')
+					.map(|team| match team.split_once('/') {
+						Some((org, slug))
+							if !org.is_empty() && !slug.is_empty() =>
+						{
+							team.to_string()
+						}
+						_ => panic!(
+							"$REQUIRED_REVIEW_TEAMS team \"{}\" should be of the form ORG/TEAM",
+							team
+						),
+					})
+					.collect::<Vec<_>>()
+			});
 
 		let github_api_url = "https://api.github.com".to_owned();
+		// The URLs might contain ":", thus the entries are separated by ","
+		let github_api_url_overrides = dotenv::var("GITHUB_API_URL_OVERRIDES")
+			.map(|raw_configuration| {
+				raw_configuration
+					.split(',')
+					.map(|token| {
+						let mut token_parts = token.splitn(2, '=');
+						match (token_parts.next(), token_parts.next()) {
+							(Some(login), Some(url)) => {
+								(login.to_string(), url.to_string())
+							}
+							_ => panic!(
+								"$GITHUB_API_URL_OVERRIDES segment \"{}\" should be of the form LOGIN=URL",
+								token
+							),
+						}
+					})
+					.collect()
+			})
+			.unwrap_or_default();
 		let github_source_prefix = dotenv::var("GITHUB_SOURCE_PREFIX")
 			.unwrap_or_else(|_| "https://github.com".to_string());
 		let github_source_suffix = dotenv::var("GITHUB_SOURCE_SUFFIX")
 			.unwrap_or_else(|_| "".to_string());
 
 		let merge_command_delay = 4096;
+		let merge_command_delay_overrides = parse_per_repository_var(
+			"MERGE_COMMAND_DELAY_OVERRIDES",
+			|value| {
+				value.parse::<u64>().expect(
+					"$MERGE_COMMAND_DELAY_OVERRIDES values should be numbers",
+				)
+			},
+		);
 
 		let companion_status_settle_delay = 4096;
 
 		let gitlab_url = dotenv::var("GITLAB_URL").unwrap();
 		let gitlab_access_token = dotenv::var("GITLAB_ACCESS_TOKEN").unwrap();
+		let gitlab_recovery_enabled = dotenv::var("GITLAB_RECOVERY_ENABLED")
+			.ok()
+			.map(|value| match value.as_str() {
+				"true" => true,
+				"false" => false,
+				_ => panic!(
+					"GITLAB_RECOVERY_ENABLED should be \"true\" or \"false\""
+				),
+			})
+			.unwrap_or(true);
+		let gitlab_circuit_breaker_threshold =
+			dotenv::var("GITLAB_CIRCUIT_BREAKER_THRESHOLD")
+				.ok()
+				.map(|value| {
+					value.parse::<u32>().expect(
+						"GITLAB_CIRCUIT_BREAKER_THRESHOLD should be a number",
+					)
+				})
+				.unwrap_or(5);
+		let gitlab_circuit_breaker_cooldown =
+			dotenv::var("GITLAB_CIRCUIT_BREAKER_COOLDOWN")
+				.ok()
+				.map(|value| {
+					value.parse::<u64>().expect(
+						"GITLAB_CIRCUIT_BREAKER_COOLDOWN should be a number",
+					)
+				})
+				.unwrap_or(5 * 60);
+		let gitlab_job_target_url_matcher = build_gitlab_job_target_url_matcher(
+			&dotenv::var("GITLAB_JOB_TARGET_URL_REGEX").unwrap_or_else(|_| {
+				DEFAULT_GITLAB_JOB_TARGET_URL_REGEX.to_string()
+			}),
+		);
 
 		let dependency_update_configuration = {
 			let mut dependency_update_configuration = HashMap::new();
@@ -122,10 +413,309 @@ impl MainConfig {
 
 			dependency_update_configuration
 		};
-		log::info!(
-			"dependency_update_configuration: {:?}",
-			dependency_update_configuration
-		);
+		let max_merge_attempts = dotenv::var("MAX_MERGE_ATTEMPTS")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<u32>()
+					.expect("MAX_MERGE_ATTEMPTS should be a number")
+			})
+			.unwrap_or(8);
+
+		let merge_retry_cooldown = dotenv::var("MERGE_RETRY_COOLDOWN")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<u64>()
+					.expect("MERGE_RETRY_COOLDOWN should be a number")
+			})
+			.unwrap_or(0);
+
+		let reconciliation_interval = dotenv::var("RECONCILIATION_INTERVAL")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<u64>()
+					.expect("RECONCILIATION_INTERVAL should be a number")
+			})
+			.unwrap_or(30 * 60);
+
+		let max_dependency_depth = dotenv::var("MAX_DEPENDENCY_DEPTH")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<usize>()
+					.expect("MAX_DEPENDENCY_DEPTH should be a number")
+			})
+			.unwrap_or(8);
+		let dependency_fetch_interval =
+			dotenv::var("DEPENDENCY_FETCH_INTERVAL")
+				.ok()
+				.map(|value| {
+					value
+						.parse::<u64>()
+						.expect("DEPENDENCY_FETCH_INTERVAL should be a number")
+				})
+				.unwrap_or(500);
+
+		let max_companions = dotenv::var("MAX_COMPANIONS")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<usize>()
+					.expect("MAX_COMPANIONS should be a number")
+			})
+			.unwrap_or(16);
+
+		let max_queue_size = dotenv::var("MAX_QUEUE_SIZE")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<usize>()
+					.expect("MAX_QUEUE_SIZE should be a number")
+			})
+			.unwrap_or(0);
+
+		let git_commit_author_name = dotenv::var("GIT_COMMIT_AUTHOR_NAME")
+			.unwrap_or_else(|_| "processbot".to_string());
+		let git_commit_author_email = dotenv::var("GIT_COMMIT_AUTHOR_EMAIL")
+			.unwrap_or_else(|_| {
+				"processbot@users.noreply.github.com".to_string()
+			});
+
+		let gpg_signing_key =
+			dotenv::var("GPG_SIGNING_KEY_PATH").ok().map(|path| {
+				std::fs::read_to_string(&path)
+					.expect("Couldn't read the GPG signing key.")
+			});
+
+		let admin_token = dotenv::var("ADMIN_TOKEN").ok();
+
+		let frozen_repos = dotenv::var("FROZEN_REPOSITORIES")
+			.map(|value| {
+				value
+					.split(',')
+					.filter(|repo| !repo.is_empty())
+					.map(|repo| repo.to_string())
+					.collect()
+			})
+			.unwrap_or_default();
+
+		let work_queue_capacity = dotenv::var("WORK_QUEUE_CAPACITY")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<usize>()
+					.expect("WORK_QUEUE_CAPACITY should be a number")
+			})
+			.unwrap_or(1024);
+
+		let allowed_base_branches =
+			parse_per_repository_var("ALLOWED_BASE_BRANCHES", |value| {
+				value
+					.split('+')
+					.map(|branch| branch.to_string())
+					.collect::<Vec<_>>()
+			});
+
+		let default_reviewers =
+			parse_per_repository_var("DEFAULT_REVIEWERS", |value| {
+				value
+					.split('+')
+					.map(|reviewer| reviewer.to_string())
+					.collect::<Vec<_>>()
+			});
+
+		let automerge_label =
+			parse_per_repository_var("AUTOMERGE_LABEL", |value| {
+				value.to_string()
+			});
+
+		let reaction_acknowledgements =
+			dotenv::var("REACTION_ACKNOWLEDGEMENTS")
+				.ok()
+				.map(|value| match value.as_str() {
+					"true" => true,
+					"false" => false,
+					_ => panic!(
+						"REACTION_ACKNOWLEDGEMENTS should be \"true\" or \"false\""
+					),
+				})
+				.unwrap_or(false);
+
+		let companion_markers = dotenv::var("COMPANION_MARKERS")
+			.ok()
+			.map(|value| {
+				value
+					.split(',')
+					.map(|marker| marker.trim())
+					.filter(|marker| !marker.is_empty())
+					.map(|marker| marker.to_string())
+					.collect::<Vec<_>>()
+			})
+			.filter(|markers| !markers.is_empty())
+			.unwrap_or_else(|| {
+				DEFAULT_COMPANION_MARKERS
+					.iter()
+					.map(|marker| marker.to_string())
+					.collect()
+			});
+		let companion_matcher = CompanionMatcher::new(&companion_markers);
+		let companion_discovery_enabled =
+			dotenv::var("COMPANION_DISCOVERY_ENABLED")
+				.ok()
+				.map(|value| match value.as_str() {
+					"true" => true,
+					"false" => false,
+					_ => panic!(
+						"COMPANION_DISCOVERY_ENABLED should be \"true\" or \"false\""
+					),
+				})
+				.unwrap_or(false);
+
+		let max_concurrent_branch_updates =
+			dotenv::var("MAX_CONCURRENT_BRANCH_UPDATES")
+				.ok()
+				.map(|value| {
+					value.parse::<usize>().expect(
+						"MAX_CONCURRENT_BRANCH_UPDATES should be a number",
+					)
+				})
+				.unwrap_or(1);
+
+		let command_timeout = dotenv::var("COMMAND_TIMEOUT")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<u64>()
+					.expect("COMMAND_TIMEOUT should be a number")
+			})
+			.unwrap_or(60 * 60);
+
+		let github_request_timeout_secs = dotenv::var("GITHUB_REQUEST_TIMEOUT")
+			.ok()
+			.map(|value| {
+				value
+					.parse::<u64>()
+					.expect("GITHUB_REQUEST_TIMEOUT should be a number")
+			})
+			.unwrap_or(10);
+
+		let github_pool_max_idle_per_host =
+			dotenv::var("GITHUB_POOL_MAX_IDLE_PER_HOST")
+				.ok()
+				.map(|value| {
+					value.parse::<usize>().expect(
+						"GITHUB_POOL_MAX_IDLE_PER_HOST should be a number",
+					)
+				})
+				.unwrap_or(16);
+
+		let github_secondary_rate_limit_backoff =
+			dotenv::var("GITHUB_SECONDARY_RATE_LIMIT_BACKOFF")
+				.ok()
+				.map(|value| {
+					value.parse::<u64>().expect(
+						"GITHUB_SECONDARY_RATE_LIMIT_BACKOFF should be a number",
+					)
+				})
+				.unwrap_or(60);
+
+		let merge_method = dotenv::var("MERGE_METHOD")
+			.map(|value| parse_merge_method("MERGE_METHOD", &value))
+			.unwrap_or(GithubMergeMethod::Squash);
+		let merge_method_overrides =
+			parse_per_repository_var("MERGE_METHOD_OVERRIDES", |value| {
+				parse_merge_method("MERGE_METHOD_OVERRIDES", value)
+			});
+
+		let merge_commit_title_template =
+			dotenv::var("MERGE_COMMIT_TITLE_TEMPLATE").ok();
+		let merge_commit_title_template_overrides =
+			dotenv::var("MERGE_COMMIT_TITLE_TEMPLATE_OVERRIDES")
+				.map(|raw_configuration| {
+					parse_per_repository_templates(
+						"MERGE_COMMIT_TITLE_TEMPLATE_OVERRIDES",
+						&raw_configuration,
+					)
+				})
+				.unwrap_or_default();
+		let merge_commit_message_template =
+			dotenv::var("MERGE_COMMIT_MESSAGE_TEMPLATE").ok();
+		let merge_commit_message_template_overrides =
+			dotenv::var("MERGE_COMMIT_MESSAGE_TEMPLATE_OVERRIDES")
+				.map(|raw_configuration| {
+					parse_per_repository_templates(
+						"MERGE_COMMIT_MESSAGE_TEMPLATE_OVERRIDES",
+						&raw_configuration,
+					)
+				})
+				.unwrap_or_default();
+		let merge_commit_trailers = dotenv::var("MERGE_COMMIT_TRAILERS")
+			.map(|raw_configuration| {
+				raw_configuration
+					.split(',')
+					.map(|trailer| trailer.trim())
+					.filter(|trailer| !trailer.is_empty())
+					.map(|trailer| match trailer.split_once(": ") {
+						Some((token, value))
+							if !token.is_empty()
+								&& token.chars().all(|c| {
+									c.is_ascii_alphanumeric() || c == '-'
+								}) && !value.trim().is_empty() =>
+						{
+							trailer.to_string()
+						}
+						_ => panic!(
+							"$MERGE_COMMIT_TRAILERS segment \"{}\" should be of the form TOKEN: VALUE",
+							trailer
+						),
+					})
+					.collect::<Vec<_>>()
+			})
+			.unwrap_or_default();
+		if !merge_commit_trailers.is_empty() {
+			// Without a template the message would only consist of the trailers
+			// rather than of the one generated by Github
+			if merge_commit_message_template.is_none() {
+				panic!(
+					"$MERGE_COMMIT_TRAILERS requires $MERGE_COMMIT_MESSAGE_TEMPLATE to be set"
+				);
+			}
+			// Rebases don't create a commit whose message could be set
+			if merge_method == GithubMergeMethod::Rebase
+				|| merge_method_overrides
+					.values()
+					.any(|method| *method == GithubMergeMethod::Rebase)
+			{
+				panic!(
+					"$MERGE_COMMIT_TRAILERS can't be used along with the rebase merge method"
+				);
+			}
+		}
+
+		let message_templates = MessageTemplates::from_env();
+
+		let webhook_archive_dir =
+			dotenv::var("WEBHOOK_ARCHIVE_DIR").ok().map(|dir| {
+				if dir.starts_with('/') {
+					PathBuf::from(dir)
+				} else {
+					root_dir.join(dir)
+				}
+			});
+
+		let log_format = dotenv::var("LOG_FORMAT")
+			.ok()
+			.map(|value| match value.as_str() {
+				"gke" => LogFormat::Gke,
+				"json" => LogFormat::Json,
+				"plain" => LogFormat::Plain,
+				_ => panic!(
+					"$LOG_FORMAT should be \"gke\", \"json\" or \"plain\""
+				),
+			})
+			.unwrap_or(LogFormat::Gke);
 
 		Self {
 			installation_login,
@@ -136,15 +726,308 @@ impl MainConfig {
 			webhook_proxy_url,
 			github_app_id,
 			disable_org_checks,
+			disable_org_checks_overrides,
+			codeowners_authorization,
+			privileged_teams,
+			allowed_command_bots,
+			recheck_requester_membership,
 			github_api_url,
+			github_api_url_overrides,
+			github_graphql_enabled,
+			passing_check_conclusions,
+			require_at_least_one_check,
+			required_review_teams,
 			merge_command_delay,
+			merge_command_delay_overrides,
 			companion_status_settle_delay,
 			repos_path,
 			github_source_prefix,
 			github_source_suffix,
 			gitlab_url,
 			gitlab_access_token,
+			gitlab_recovery_enabled,
+			gitlab_circuit_breaker_threshold,
+			gitlab_circuit_breaker_cooldown,
+			gitlab_job_target_url_matcher,
 			dependency_update_configuration,
+			max_merge_attempts,
+			merge_retry_cooldown,
+			reconciliation_interval,
+			max_dependency_depth,
+			dependency_fetch_interval,
+			max_companions,
+			max_queue_size,
+			git_commit_author_name,
+			git_commit_author_email,
+			gpg_signing_key,
+			admin_token,
+			frozen_repos,
+			work_queue_capacity,
+			allowed_base_branches,
+			default_reviewers,
+			automerge_label,
+			reaction_acknowledgements,
+			companion_markers,
+			companion_matcher,
+			companion_discovery_enabled,
+			max_concurrent_branch_updates,
+			command_timeout,
+			github_request_timeout_secs,
+			github_pool_max_idle_per_host,
+			github_secondary_rate_limit_backoff,
+			merge_method,
+			merge_method_overrides,
+			merge_commit_title_template,
+			merge_commit_title_template_overrides,
+			merge_commit_message_template,
+			merge_commit_message_template_overrides,
+			merge_commit_trailers,
+			message_templates,
+			webhook_archive_dir,
+			log_format,
+		}
+	}
+}
+
+impl MainConfig {
+	/// Whether the organization checks are disabled for `owner/repo`, which falls back to
+	/// `disable_org_checks` unless it's overridden for that repository
+	pub fn disable_org_checks_for(&self, owner: &str, repo: &str) -> bool {
+		self.disable_org_checks_overrides
+			.get(&format!("{}/{}", owner, repo))
+			.copied()
+			.unwrap_or(self.disable_org_checks)
+	}
+
+	/// How long (in milliseconds) to wait for the Github API to settle after a merge command is
+	/// received for a pull request of `owner/repo`.
+	pub fn merge_command_delay_for(&self, owner: &str, repo: &str) -> u64 {
+		self.merge_command_delay_overrides
+			.get(&format!("{}/{}", owner, repo))
+			.copied()
+			.unwrap_or(self.merge_command_delay)
+	}
+
+	/// How pull requests of `owner/repo` are merged
+	pub fn merge_method_for(
+		&self,
+		owner: &str,
+		repo: &str,
+	) -> GithubMergeMethod {
+		self.merge_method_overrides
+			.get(&format!("{}/{}", owner, repo))
+			.copied()
+			.unwrap_or(self.merge_method)
+	}
+
+	/// The templates of the squash commit's title and message for merges of pull requests of
+	/// `owner/repo`. Github's defaults are used for the templates which are not configured.
+	pub fn merge_commit_templates_for(
+		&self,
+		owner: &str,
+		repo: &str,
+	) -> (Option<&str>, Option<&str>) {
+		let repository = format!("{}/{}", owner, repo);
+		(
+			self.merge_commit_title_template_overrides
+				.get(&repository)
+				.or_else(|| self.merge_commit_title_template.as_ref())
+				.map(|template| template.as_str()),
+			self.merge_commit_message_template_overrides
+				.get(&repository)
+				.or_else(|| self.merge_commit_message_template.as_ref())
+				.map(|template| template.as_str()),
+		)
+	}
+
+	/// Whether a check run which completed with `conclusion` allows the merge. Successful check
+	/// runs always do; the others depend on `passing_check_conclusions`.
+	pub fn is_check_conclusion_passing(
+		&self,
+		conclusion: Option<GithubCheckRunConclusion>,
+	) -> bool {
+		match conclusion {
+			Some(GithubCheckRunConclusion::Success) => true,
+			Some(conclusion) => {
+				self.passing_check_conclusions.contains(&conclusion)
+			}
+			None => false,
+		}
+	}
+
+	/// Whether pull requests of `owner/repo` need at least one check run or status before they
+	/// can be merged. Not required unless it's configured for that repository.
+	pub fn requires_at_least_one_check(&self, owner: &str, repo: &str) -> bool {
+		self.require_at_least_one_check
+			.get(&format!("{}/{}", owner, repo))
+			.copied()
+			.unwrap_or(false)
+	}
+
+	/// The teams (as `org/team`) which should have approved pull requests of `owner/repo` before
+	/// they're merged. None are required for repositories which are not configured.
+	pub fn required_review_teams_for(
+		&self,
+		owner: &str,
+		repo: &str,
+	) -> &[String] {
+		self.required_review_teams
+			.get(&format!("{}/{}", owner, repo))
+			.map(|teams| teams.as_slice())
+			.unwrap_or(&[])
+	}
+
+	/// Whether `bot merge` is allowed for pull requests of `owner/repo` targeting `base_branch`.
+	/// Any base branch is allowed for repositories which are not configured.
+	pub fn is_base_branch_allowed(
+		&self,
+		owner: &str,
+		repo: &str,
+		base_branch: &str,
+	) -> bool {
+		self.allowed_base_branches
+			.get(&format!("{}/{}", owner, repo))
+			.map(|branches| branches.iter().any(|branch| branch == base_branch))
+			.unwrap_or(true)
+	}
+
+	/// The users or teams (as `org/team`) whose reviews are requested when a pull request of
+	/// `owner/repo` can't be merged for lack of approvals.
+	pub fn default_reviewers_for(&self, owner: &str, repo: &str) -> &[String] {
+		self.default_reviewers
+			.get(&format!("{}/{}", owner, repo))
+			.map(|reviewers| reviewers.as_slice())
+			.unwrap_or(&[])
+	}
+
+	/// Whether the comments of `user` are handled as commands. Comments from bots are ignored
+	/// so that processbot does not end up replying to itself (or to another bot) in a loop,
+	/// unless the bot is listed in `allowed_command_bots`. processbot's own comments, i.e. the
+	/// ones from `app_login` (see `GithubClient::app_login`), are always ignored.
+	pub fn is_allowed_command_author(
+		&self,
+		user: &GithubUser,
+		app_login: &str,
+	) -> bool {
+		if user.type_field != GithubUserType::Bot {
+			return true;
 		}
+		!user.login.eq_ignore_ascii_case(app_login)
+			&& self
+				.allowed_command_bots
+				.iter()
+				.any(|allowed| allowed.eq_ignore_ascii_case(&user.login))
 	}
+
+	/// The label which queues the merge of pull requests of `owner/repo`, if one is configured
+	/// for that repository; there's no global label to fall back to
+	pub fn automerge_label_for(&self, owner: &str, repo: &str) -> Option<&str> {
+		self.automerge_label
+			.get(&format!("{}/{}", owner, repo))
+			.map(|label| label.as_str())
+	}
+
+	/// The base URL of the Github API which hosts the installation of `installation_login`, e.g.
+	/// a Github Enterprise Server.
+	pub fn github_api_url_for(&self, installation_login: &str) -> &str {
+		self.github_api_url_overrides
+			.get(installation_login)
+			.unwrap_or(&self.github_api_url)
+	}
+	/// The configured secrets, keyed by their environment variable, which should never be
+	/// displayed in logs.
+	pub fn secrets(&self) -> Vec<(&'static str, &str)> {
+		let mut secrets = vec![
+			("WEBHOOK_SECRET", self.webhook_secret.as_str()),
+			("GITLAB_ACCESS_TOKEN", self.gitlab_access_token.as_str()),
+		];
+		if let Some(admin_token) = self.admin_token.as_ref() {
+			secrets.push(("ADMIN_TOKEN", admin_token));
+		}
+		if let Some(gpg_signing_key) = self.gpg_signing_key.as_ref() {
+			secrets.push(("GPG_SIGNING_KEY_PATH", gpg_signing_key.trim()));
+		}
+		secrets
+	}
+}
+
+/// Build the matcher used for extracting the GitLab URL, project and job ID (in this order) from
+/// the target URL of a failing status. Panics if the pattern is not usable.
+pub fn build_gitlab_job_target_url_matcher(pattern: &str) -> Regex {
+	let matcher = RegexBuilder::new(pattern)
+		.case_insensitive(true)
+		.build()
+		.unwrap_or_else(|err| {
+			panic!("GITLAB_JOB_TARGET_URL_REGEX is not a valid regex: {}", err)
+		});
+	// The first group is the whole match
+	if matcher.captures_len() != 4 {
+		panic!(
+			"GITLAB_JOB_TARGET_URL_REGEX should have exactly 3 capture groups (GitLab URL, project and job ID), but it has {}",
+			matcher.captures_len() - 1
+		);
+	}
+	matcher
+}
+
+/// Parse the templates of a variable of the form `OWNER/REPOSITORY=TEMPLATE`, one per line. The
+/// templates may contain ':' and '=', unlike the values of the other per-repository variables.
+/// Panics if a line is not of that form.
+pub fn parse_per_repository_templates(
+	var: &str,
+	raw_configuration: &str,
+) -> HashMap<String, String> {
+	raw_configuration
+		.lines()
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| match line.split_once('=') {
+			Some((repository, template)) if repository.contains('/') => {
+				(repository.trim().to_string(), template.to_string())
+			}
+			_ => panic!(
+				"${} line \"{}\" should be of the form OWNER/REPOSITORY=TEMPLATE",
+				var, line
+			),
+		})
+		.collect()
+}
+
+fn parse_merge_method(var: &str, value: &str) -> GithubMergeMethod {
+	match value.trim() {
+		"merge" => GithubMergeMethod::Merge,
+		"squash" => GithubMergeMethod::Squash,
+		"rebase" => GithubMergeMethod::Rebase,
+		_ => panic!(
+			"${} value \"{}\" should be one of merge, squash or rebase",
+			var, value
+		),
+	}
+}
+
+/// Parse a variable of the form `OWNER/REPOSITORY=VALUE:OWNER/REPOSITORY=VALUE:...`
+fn parse_per_repository_var<T>(
+	var: &str,
+	parse_value: impl Fn(&str) -> T,
+) -> HashMap<String, T> {
+	let mut values = HashMap::new();
+
+	if let Ok(raw_configuration) = dotenv::var(var) {
+		for token in raw_configuration.split(':') {
+			let token_parsing_err_msg = format!(
+				"${} segment \"{}\" should be of the form OWNER/REPOSITORY=VALUE",
+				var, token
+			);
+
+			let mut token_parts = token.split('=');
+			let repository = token_parts.next().expect(&token_parsing_err_msg);
+			let value = token_parts.next().expect(&token_parsing_err_msg);
+			if token_parts.next().is_some() {
+				panic!("{}", token_parsing_err_msg)
+			}
+
+			values.insert(repository.into(), parse_value(value));
+		}
+	}
+
+	values
 }
@@ -1,3 +1,63 @@
 // Note: the old database will be *DELETED* when changing this constant
-// Do not change this without checking the implementation first
-pub const DATABASE_VERSION: &str = "v3.0";
+// Do not change this without checking the implementation first. Changes to the
+// fields of MergeRequest do not require it; see MERGE_REQUEST_SCHEMA_VERSION.
+pub const DATABASE_VERSION: &str = "v3.5";
+
+// The versions from which the database is migrated instead of being deleted
+pub const MIGRATABLE_DATABASE_VERSIONS: &[&str] =
+	&["v3.0", "v3.1", "v3.2", "v3.3", "v3.4"];
+
+// The shape in which merge requests are serialized. Increment it when changing
+// the fields of MergeRequest and keep the previous shape around so that queued
+// merge requests are migrated instead of being lost.
+pub const MERGE_REQUEST_SCHEMA_VERSION: u32 = 4;
+
+// Captures the GitLab URL, the project and the job ID from a status' target URL
+pub const DEFAULT_GITLAB_JOB_TARGET_URL_REGEX: &str =
+	r"^(\w+://[^/]+)/(.*)/builds/([0-9]+)$";
+
+// Merge requests are processed in descending order of priority during polling
+pub const MERGE_PRIORITY_NORMAL: i32 = 0;
+pub const MERGE_PRIORITY_HIGH: i32 = 1;
+
+// The words which introduce a companion reference in a pull request's description
+pub const DEFAULT_COMPANION_MARKERS: &[&str] = &["companion"];
+
+// How long (in seconds) to wait between polls of the pending merge requests
+pub const POLL_INTERVAL: u64 = 10 * 60;
+
+// Column family of the merge requests, keyed by SHA. RocksDB's default column
+// family is used so that databases from before the split keep their records.
+pub const MERGE_REQUESTS_COLUMN_FAMILY: &str = "default";
+
+// Column family of everything else which is persisted, e.g. the frozen
+// repositories or the merge markers
+pub const METADATA_COLUMN_FAMILY: &str = "metadata";
+
+// Prefix of the metadata keys before they had their own column family
+pub const LEGACY_METADATA_KEY_PREFIX: &str = "__PROCESSBOT_METADATA__/";
+
+// How long (in seconds) the merge of a SHA is remembered for in order to avoid
+// handling it more than once
+pub const MERGE_MARKER_TTL: u64 = 60 * 60;
+
+// How long (in seconds) a recovered GitLab job is remembered for once it was
+// reported in a pull request so that it's not reported again for the same commit
+pub const RECOVERED_JOB_MARKER_TTL: u64 = 24 * 60 * 60;
+
+// How long (in seconds) an issue comment is remembered for by
+// `create_issue_comment_once` so that retries don't post it again
+pub const ISSUE_COMMENT_DEDUPLICATION_TTL: u64 = 10 * 60;
+
+// How long (in seconds) an idle connection to the GitHub API is kept open for,
+// and the interval of the TCP keep-alive probes sent over the open ones
+pub const GITHUB_POOL_IDLE_TIMEOUT: u64 = 90;
+pub const GITHUB_TCP_KEEPALIVE: u64 = 60;
+
+// The longest (in seconds) that requests to the GitHub API are held off for after
+// hitting its secondary rate limits repeatedly
+pub const SECONDARY_RATE_LIMIT_MAX_BACKOFF: u64 = 15 * 60;
+
+// Identifies processbot in the requests made to the GitHub API and in `bot ping`
+pub const USER_AGENT: &str =
+	concat!("parity-processbot/", env!("CARGO_PKG_VERSION"));
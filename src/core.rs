@@ -1,26 +1,44 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	sync::Arc,
+	time::{Duration, SystemTime},
+};
 
 use async_recursion::async_recursion;
-use regex::RegexBuilder;
+use chrono::{DateTime, Utc};
 use reqwest::Client as HttpClient;
 use rocksdb::DB;
 use snafu::ResultExt;
 
 use crate::{
-	companion::update_companion_then_merge,
+	companion::{
+		resolve_companions, update_companion, update_companion_then_merge,
+	},
 	config::MainConfig,
+	constants::{
+		MERGE_PRIORITY_HIGH, MERGE_PRIORITY_NORMAL,
+		MERGE_REQUEST_SCHEMA_VERSION, RECOVERED_JOB_MARKER_TTL, USER_AGENT,
+	},
+	db::{merge_requests_cf, metadata_cf},
 	error::{self, handle_error, Error, PullRequestDetails},
 	git_ops::{rebase, RebaseOutcome},
 	github::*,
 	gitlab::*,
 	merge_request::{
-		check_merge_is_allowed, cleanup_merge_request,
-		handle_merged_pull_request, is_ready_to_merge, merge_pull_request,
-		queue_merge_request, MergeRequest, MergeRequestCleanupReason,
+		allow_merge_once, check_merge_is_allowed, check_queue_capacity,
+		cleanup_merge_request, deserialize_merge_request,
+		handle_merged_pull_request, is_branch_frozen, is_paused,
+		is_ready_to_merge, is_repository_frozen, is_requester_still_allowed,
+		list_frozen_repositories, list_merge_requests, mark_merge_as_handled,
+		merge_pull_request, queue_merge_request, MergeReadiness, MergeRequest,
+		MergeRequestCleanupReason, MergeRequestDependency,
 		MergeRequestQueuedMessage,
 	},
+	messages::Message,
+	poll_heartbeat::PollHeartbeat,
 	types::Result,
 	vanity_service,
+	work_queue::WorkQueue,
 };
 
 #[derive(Debug)]
@@ -40,25 +58,51 @@ pub struct AppState {
 	pub db: DB,
 	pub gh_client: GithubClient,
 	pub config: MainConfig,
+	// Shared with the background workers so that they can wait for work without
+	// holding the state's lock
+	pub work_queue: Arc<WorkQueue>,
+	// Shared with the HTTP server so that the health of the poll can be
+	// reported while a poll is holding the state's lock
+	pub poll_heartbeat: Arc<PollHeartbeat>,
 }
 
 #[derive(Debug)]
 pub enum CommentCommand {
 	Merge(MergeCommentCommand),
+	// Allows the given user to merge the pull request once
+	AllowMerge(String),
 	CancelMerge,
+	Unqueue,
 	Rebase,
+	// Like `Rebase`, but merges the given branch instead of the base branch
+	RebaseOnto(String),
+	// Updates the lockfile like it's done for companions, without merging
+	Update,
+	Queue,
+	Config,
+	Ping,
 }
 
 #[derive(Debug)]
 pub enum MergeCommentCommand {
 	Normal,
+	High,
 	Force,
+	Delayed(Duration),
+	// Waits for the given pull request, possibly of another repository, to be
+	// merged first
+	After {
+		owner: String,
+		repo: String,
+		number: i64,
+	},
 }
 
 pub async fn get_commit_statuses(
 	state: &AppState,
 	owner: &str,
 	repo: &str,
+	number: i64,
 	commit_sha: &str,
 	html_url: &str,
 	should_handle_retried_jobs: bool,
@@ -66,11 +110,36 @@ pub async fn get_commit_statuses(
 	Status,
 	HashMap<String, (i64, GithubCommitStatusState, Option<String>)>,
 )> {
-	let AppState {
-		gh_client, config, ..
-	} = state;
+	let statuses = state.gh_client.statuses(owner, repo, commit_sha).await?;
+	evaluate_commit_statuses(
+		state,
+		owner,
+		repo,
+		number,
+		commit_sha,
+		html_url,
+		should_handle_retried_jobs,
+		statuses,
+	)
+	.await
+}
+
+/// Like `get_commit_statuses`, but for statuses which have already been fetched
+pub async fn evaluate_commit_statuses(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	commit_sha: &str,
+	html_url: &str,
+	should_handle_retried_jobs: bool,
+	statuses: Vec<GithubCommitStatus>,
+) -> Result<(
+	Status,
+	HashMap<String, (i64, GithubCommitStatusState, Option<String>)>,
+)> {
+	let AppState { config, .. } = state;
 
-	let statuses = gh_client.statuses(owner, repo, commit_sha).await?;
 	log::info!("{} statuses: {:?}", html_url, statuses);
 
 	// Since Github only considers the latest instance of each status, we should
@@ -115,14 +184,11 @@ pub async fn get_commit_statuses(
 		*state == GithubCommitStatusState::Error
 			|| *state == GithubCommitStatusState::Failure
 	}) {
-		if should_handle_retried_jobs {
+		if should_handle_retried_jobs && config.gitlab_recovery_enabled {
 			let mut has_failed_status_from_outside_gitlab = false;
 
 			let gitlab_job_target_url_matcher =
-				RegexBuilder::new(r"^(\w+://[^/]+)/(.*)/builds/([0-9]+)$")
-					.case_insensitive(true)
-					.build()
-					.unwrap();
+				&config.gitlab_job_target_url_matcher;
 			let failed_gitlab_jobs = latest_statuses
 				.values()
 				.filter_map(|(_, status, target_url)| match *status {
@@ -134,18 +200,15 @@ pub async fn get_commit_statuses(
 									.captures(target_url)
 									.and_then(|matches| {
 										let gitlab_url =
-											matches.get(1).unwrap().as_str();
+											matches.get(1)?.as_str();
 										if gitlab_url == config.gitlab_url {
-											let gitlab_project = matches
-												.get(2)
-												.unwrap()
-												.as_str();
+											let gitlab_project =
+												matches.get(2)?.as_str();
 											let job_id = matches
-												.get(3)
-												.unwrap()
+												.get(3)?
 												.as_str()
 												.parse::<usize>()
-												.unwrap();
+												.ok()?;
 											Some((
 												gitlab_url,
 												gitlab_project,
@@ -171,192 +234,445 @@ pub async fn get_commit_statuses(
 					failed_gitlab_jobs
 				);
 			} else if !failed_gitlab_jobs.is_empty() {
-				let mut recovered_jobs = vec![];
-
-				let http_client = HttpClient::new();
-				for (gitlab_url, gitlab_project, job_id) in failed_gitlab_jobs {
-					// https://docs.gitlab.com/ee/api/jobs.html#get-a-single-job
-					let job_api_url = format!(
-						"{}/api/v4/projects/{}/jobs/{}",
-						gitlab_url,
-						urlencoding::encode(gitlab_project),
-						job_id
+				if is_gitlab_circuit_open(config) {
+					log::info!(
+						"Skipping the recovery of the failing GitLab jobs of {} since GitLab has been failing",
+						html_url
 					);
+				} else {
+					let recovered_jobs = match find_recovered_gitlab_jobs(
+						config,
+						html_url,
+						failed_gitlab_jobs,
+					)
+					.await
+					{
+						Ok(recovered_jobs) => {
+							record_gitlab_success(config);
+							recovered_jobs
+						}
+						Err(err) => {
+							log::error!(
+								"Failed to check if the failing GitLab jobs of {} have recovered: {}",
+								html_url,
+								err
+							);
+							record_gitlab_failure(config);
+							vec![]
+						}
+					};
 
-					let job = http_client
-						.execute(
-							http_client
-								.get(&job_api_url)
-								.headers(
-									config.get_gitlab_api_request_headers()?,
-								)
-								.build()
-								.map_err(|err| Error::Message {
-									msg: format!(
-										"Failed to build request to fetch {} due to {:?}",
-										job_api_url,
-										err
-									),
-								})?,
+					if !recovered_jobs.is_empty() {
+						log::info!(
+							"{} was initially considered to be failing, but we consider it has recovered because the following jobs have recovered: {:?}",
+							html_url,
+							recovered_jobs
+						);
+						notify_recovered_gitlab_jobs(
+							state,
+							owner,
+							repo,
+							number,
+							commit_sha,
+							&recovered_jobs,
 						)
-						.await
-						.context(error::Http)?
-						.json::<GitlabJob>()
-						.await
-						.context(error::Http)?;
-
-					log::info!("Fetched job for {}: {:?}", job_api_url, job);
-
-					match job.pipeline.status {
-						GitlabPipelineStatus::Created
-						| GitlabPipelineStatus::WaitingForResource
-						| GitlabPipelineStatus::Preparing
-						| GitlabPipelineStatus::Pending
-						| GitlabPipelineStatus::Running
-						| GitlabPipelineStatus::Scheduled => {
-							log::info!("{} is failing on GitHub, but its pipeline is pending, therefore we'll check if it's running or pending (it might have been retried)", job_api_url);
-
-							let pending_or_successful_jobs = {
-								let mut pending_or_successful_jobs = vec![];
-								// https://docs.gitlab.com/ee/api/#offset-based-pagination
-								let mut page = 1;
-								loop {
-									// https://docs.gitlab.com/ee/api/jobs.html#list-pipeline-jobs
-									let pending_or_successful_jobs_api = format!(
-										"{}/api/v4/projects/{}/pipelines/{}/jobs?scope[]=pending&scope[]=running&scope[]=success&scope[]=created&per_page=100&page={}",
-										gitlab_url,
-										job.pipeline.project_id,
-										job.pipeline.id,
-										page
-									);
+						.await;
+						return Ok((Status::Pending, latest_statuses));
+					}
+				}
+			}
+		}
 
-									let page_pending_or_successful_jobs = http_client
-										.execute(
-											http_client
-												.get(&pending_or_successful_jobs_api)
-												.headers(config.get_gitlab_api_request_headers()?)
-												.build()
-												.map_err(|err| Error::Message {
-													msg: format!(
-														"Failed to build request to fetch {} due to {:?}",
-														pending_or_successful_jobs_api,
-														err
-													),
-												})?,
-										)
-										.await
-										.context(error::Http)?
-										.json::<Vec<GitlabPipelineJob>>()
-										.await
-										.context(error::Http)?;
-
-									if page_pending_or_successful_jobs
-										.is_empty()
-									{
-										break;
-									}
-
-									pending_or_successful_jobs.extend(
-										page_pending_or_successful_jobs,
-									);
+		log::info!("{} has failed status", html_url);
+		Ok((Status::Failure, latest_statuses))
+	} else {
+		log::info!("{} has pending status", html_url);
+		Ok((Status::Pending, latest_statuses))
+	}
+}
 
-									page += 1;
-								}
-								pending_or_successful_jobs
-							};
-
-							if pending_or_successful_jobs.iter().any(
-								|pending_pipeline_job| {
-									pending_pipeline_job.name == job.name
-								},
-							) {
-								recovered_jobs.push(job_api_url);
-							} else {
-								log::info!(
-									"{} 's GitLab pipeline (id: {}) for job {} (name: {}) did not list it as pending or successful, therefore the job is considered to be failing",
-									html_url,
-									job.pipeline.id,
-									job_api_url,
-									job.name
-								);
-								recovered_jobs.clear();
-								break;
-							}
-						}
-						_ => {
-							log::info!(
-								"{} 's GitLab pipeline (id: {}) for job {} (name: {}) is not pending, therefore the job itself can't be considered to be pending",
-								html_url,
-								job.pipeline.id,
-								job_api_url,
-								job.name,
-							);
-							recovered_jobs.clear();
+lazy_static::lazy_static! {
+	// The circuit breakers of the GitLab recovery, per GitLab URL
+	static ref GITLAB_CIRCUIT_BREAKERS: parking_lot::Mutex<HashMap<String, GitlabCircuitBreaker>> = {
+		parking_lot::Mutex::new(HashMap::new())
+	};
+}
+
+fn is_gitlab_circuit_open(config: &MainConfig) -> bool {
+	GITLAB_CIRCUIT_BREAKERS
+		.lock()
+		.get(&config.gitlab_url)
+		.map(|circuit_breaker| circuit_breaker.is_open())
+		.unwrap_or(false)
+}
+
+fn record_gitlab_success(config: &MainConfig) {
+	if let Some(circuit_breaker) =
+		GITLAB_CIRCUIT_BREAKERS.lock().get_mut(&config.gitlab_url)
+	{
+		circuit_breaker.record_success();
+	}
+}
+
+fn record_gitlab_failure(config: &MainConfig) {
+	if let Some(cooldown) = GITLAB_CIRCUIT_BREAKERS
+		.lock()
+		.entry(config.gitlab_url.clone())
+		.or_default()
+		.record_failure(
+			config.gitlab_circuit_breaker_threshold,
+			Duration::from_secs(config.gitlab_circuit_breaker_cooldown),
+		) {
+		log::error!(
+			"GitLab has been failing, therefore the recovery of failing GitLab jobs is disabled for {:?}",
+			cooldown
+		);
+	}
+}
+
+/// Check if the failing GitLab jobs have been retried, in which case they're
+/// listed as `(job name, job API URL)`. Nothing is listed unless all of them have
+/// been retried.
+async fn find_recovered_gitlab_jobs(
+	config: &MainConfig,
+	html_url: &str,
+	failed_gitlab_jobs: Vec<(&str, &str, usize)>,
+) -> Result<Vec<(String, String)>> {
+	let mut recovered_jobs = vec![];
+
+	let http_client = HttpClient::new();
+	for (gitlab_url, gitlab_project, job_id) in failed_gitlab_jobs {
+		// https://docs.gitlab.com/ee/api/jobs.html#get-a-single-job
+		let job_api_url = format!(
+			"{}/api/v4/projects/{}/jobs/{}",
+			gitlab_url,
+			urlencoding::encode(gitlab_project),
+			job_id
+		);
+
+		let job = http_client
+			.execute(
+				http_client
+					.get(&job_api_url)
+					.headers(config.get_gitlab_api_request_headers()?)
+					.build()
+					.map_err(|err| Error::Message {
+						msg: format!(
+							"Failed to build request to fetch {} due to {:?}",
+							job_api_url, err
+						),
+					})?,
+			)
+			.await
+			.context(error::Http)?
+			.json::<GitlabJob>()
+			.await
+			.context(error::Http)?;
+
+		log::info!("Fetched job for {}: {:?}", job_api_url, job);
+
+		match job.pipeline.status {
+			GitlabPipelineStatus::Created
+			| GitlabPipelineStatus::WaitingForResource
+			| GitlabPipelineStatus::Preparing
+			| GitlabPipelineStatus::Pending
+			| GitlabPipelineStatus::Running
+			| GitlabPipelineStatus::Scheduled => {
+				log::info!("{} is failing on GitHub, but its pipeline is pending, therefore we'll check if it's running or pending (it might have been retried)", job_api_url);
+
+				let pending_or_successful_jobs = {
+					let mut pending_or_successful_jobs = vec![];
+					// https://docs.gitlab.com/ee/api/#offset-based-pagination
+					let mut page = 1;
+					loop {
+						// https://docs.gitlab.com/ee/api/jobs.html#list-pipeline-jobs
+						let pending_or_successful_jobs_api = format!(
+							"{}/api/v4/projects/{}/pipelines/{}/jobs?scope[]=pending&scope[]=running&scope[]=success&scope[]=created&per_page=100&page={}",
+							gitlab_url,
+							job.pipeline.project_id,
+							job.pipeline.id,
+							page
+						);
+
+						let page_pending_or_successful_jobs = http_client
+							.execute(
+								http_client
+									.get(&pending_or_successful_jobs_api)
+									.headers(
+										config
+											.get_gitlab_api_request_headers()?,
+									)
+									.build()
+									.map_err(|err| Error::Message {
+										msg: format!(
+											"Failed to build request to fetch {} due to {:?}",
+											pending_or_successful_jobs_api,
+											err
+										),
+									})?,
+							)
+							.await
+							.context(error::Http)?
+							.json::<Vec<GitlabPipelineJob>>()
+							.await
+							.context(error::Http)?;
+
+						if page_pending_or_successful_jobs.is_empty() {
 							break;
 						}
+
+						pending_or_successful_jobs
+							.extend(page_pending_or_successful_jobs);
+
+						page += 1;
 					}
-				}
+					pending_or_successful_jobs
+				};
 
-				if !recovered_jobs.is_empty() {
+				if pending_or_successful_jobs.iter().any(
+					|pending_pipeline_job| {
+						pending_pipeline_job.name == job.name
+					},
+				) {
+					recovered_jobs.push((job.name, job_api_url));
+				} else {
 					log::info!(
-						"{} was initially considered to be failing, but we consider it has recovered because the following jobs have recovered: {:?}",
+						"{} 's GitLab pipeline (id: {}) for job {} (name: {}) did not list it as pending or successful, therefore the job is considered to be failing",
 						html_url,
-						recovered_jobs
+						job.pipeline.id,
+						job_api_url,
+						job.name
 					);
-					return Ok((Status::Pending, latest_statuses));
+					recovered_jobs.clear();
+					break;
 				}
 			}
+			_ => {
+				log::info!(
+					"{} 's GitLab pipeline (id: {}) for job {} (name: {}) is not pending, therefore the job itself can't be considered to be pending",
+					html_url,
+					job.pipeline.id,
+					job_api_url,
+					job.name,
+				);
+				recovered_jobs.clear();
+				break;
+			}
 		}
+	}
 
-		log::info!("{} has failed status", html_url);
-		Ok((Status::Failure, latest_statuses))
-	} else {
-		log::info!("{} has pending status", html_url);
-		Ok((Status::Pending, latest_statuses))
+	Ok(recovered_jobs)
+}
+
+// The keys of the markers recorded by `mark_recovered_jobs_as_notified`
+const RECOVERED_JOB_MARKERS_PREFIX: &str = "recovered_jobs/";
+
+fn recovered_job_marker_key(commit_sha: &str, job_api_url: &str) -> String {
+	format!(
+		"{}{}/{}",
+		RECOVERED_JOB_MARKERS_PREFIX, commit_sha, job_api_url
+	)
+}
+
+// Records that the recovered jobs were notified for `commit_sha` and returns the ones which were
+// not already notified within the last RECOVERED_JOB_MARKER_TTL seconds
+fn mark_recovered_jobs_as_notified<'a>(
+	state: &AppState,
+	commit_sha: &str,
+	recovered_jobs: &'a [(String, String)],
+) -> Result<Vec<&'a (String, String)>> {
+	let AppState { db, .. } = state;
+
+	let now = SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map(|elapsed| elapsed.as_secs())
+		.unwrap_or(0);
+	let is_expired = |value: &[u8]| {
+		bincode::deserialize::<u64>(value)
+			.map(|notified_at| notified_at + RECOVERED_JOB_MARKER_TTL < now)
+			.unwrap_or(true)
+	};
+
+	// Prune the markers which have expired
+	let db_iter = db.iterator_cf(
+		metadata_cf(db),
+		rocksdb::IteratorMode::From(
+			RECOVERED_JOB_MARKERS_PREFIX.as_bytes(),
+			rocksdb::Direction::Forward,
+		),
+	);
+	for (key, value) in db_iter {
+		if !key.starts_with(RECOVERED_JOB_MARKERS_PREFIX.as_bytes()) {
+			break;
+		}
+		if is_expired(&value) {
+			let _ = db.delete_cf(metadata_cf(db), &key);
+		}
+	}
+
+	let mut jobs_to_notify = vec![];
+	for job in recovered_jobs {
+		let (_, job_api_url) = job;
+		let key = recovered_job_marker_key(commit_sha, job_api_url);
+		if db
+			.get_cf(metadata_cf(db), key.as_bytes())
+			.context(error::Db)?
+			.is_some()
+		{
+			continue;
+		}
+		db.put_cf(
+			metadata_cf(db),
+			key.as_bytes(),
+			bincode::serialize(&now).context(error::Bincode)?,
+		)
+		.context(error::Db)?;
+		jobs_to_notify.push(job);
+	}
+
+	Ok(jobs_to_notify)
+}
+
+/// Explain in the pull request why failing statuses are not being treated as failures. The
+/// comment is posted at most once for each job of a given commit.
+async fn notify_recovered_gitlab_jobs(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	commit_sha: &str,
+	recovered_jobs: &[(String, String)],
+) {
+	let jobs_to_notify = match mark_recovered_jobs_as_notified(
+		state,
+		commit_sha,
+		recovered_jobs,
+	) {
+		Ok(jobs_to_notify) => jobs_to_notify,
+		Err(err) => {
+			log::error!(
+				"Failed to record the recovered GitLab jobs of {}/{}/pull/{} due to {:?}",
+				owner,
+				repo,
+				number,
+				err
+			);
+			return;
+		}
+	};
+	if jobs_to_notify.is_empty() {
+		return;
+	}
+
+	let jobs = jobs_to_notify
+		.iter()
+		.map(|(job_name, job_api_url)| {
+			format!("- {} ({})\n", job_name, job_api_url)
+		})
+		.collect::<String>();
+	let msg = state
+		.config
+		.message_templates
+		.render(Message::GitlabJobsRecovered, &[("jobs", &jobs)]);
+
+	if let Err(err) = state
+		.gh_client
+		.create_issue_comment(owner, repo, number, &msg)
+		.await
+	{
+		log::error!(
+			"Failed to post comment on {}/{}/pull/{} due to {}",
+			owner,
+			repo,
+			number,
+			err
+		);
 	}
 }
 
 pub async fn get_commit_checks(
-	gh_client: &GithubClient,
+	state: &AppState,
 	owner: &str,
 	repo_name: &str,
 	commit_sha: &str,
 	html_url: &str,
-) -> Result<Status> {
-	let check_runs = gh_client.check_runs(owner, repo_name, commit_sha).await?;
+) -> Result<(Status, HashMap<String, GithubCheckRun>)> {
+	let check_runs = state
+		.gh_client
+		.check_runs(owner, repo_name, commit_sha)
+		.await?;
+	Ok(evaluate_commit_checks(&state.config, check_runs, html_url))
+}
+
+/// Like `get_commit_checks`, but for check runs which have already been fetched
+pub fn evaluate_commit_checks(
+	config: &MainConfig,
+	check_runs: Vec<GithubCheckRun>,
+	html_url: &str,
+) -> (Status, HashMap<String, GithubCheckRun>) {
 	log::info!("{} check_runs: {:?}", html_url, check_runs);
 
 	// Since Github only considers the latest instance of each check, we should abide by the same
 	// rule. Each instance is uniquely identified by "name".
-	let mut latest_checks = HashMap::new();
+	let mut latest_checks: HashMap<String, GithubCheckRun> = HashMap::new();
 	for c in check_runs {
 		if latest_checks
 			.get(&c.name)
-			.map(|(prev_id, _, _)| prev_id < &c.id)
+			.map(|prev| prev.id < c.id)
 			.unwrap_or(true)
 		{
-			latest_checks.insert(c.name, (c.id, c.status, c.conclusion));
+			latest_checks.insert(c.name.clone(), c);
 		}
 	}
 	log::info!("{} latest_checks: {:?}", html_url, latest_checks);
 
-	Ok(
-		if latest_checks.values().all(|(_, _, conclusion)| {
-			*conclusion == Some(GithubCheckRunConclusion::Success)
-		}) {
-			log::info!("{} has successful checks", html_url);
-			Status::Success
-		} else if latest_checks
-			.values()
-			.all(|(_, status, _)| *status == GithubCheckRunStatus::Completed)
-		{
-			log::info!("{} has unsuccessful checks", html_url);
-			Status::Failure
-		} else {
-			log::info!("{} has pending checks", html_url);
-			Status::Pending
-		},
-	)
+	let status = if latest_checks
+		.values()
+		.all(|c| config.is_check_conclusion_passing(c.conclusion))
+	{
+		log::info!("{} has successful checks", html_url);
+		Status::Success
+	} else if latest_checks
+		.values()
+		.all(|c| c.status == GithubCheckRunStatus::Completed)
+	{
+		log::info!("{} has unsuccessful checks", html_url);
+		Status::Failure
+	} else {
+		log::info!("{} has pending checks", html_url);
+		Status::Pending
+	};
+
+	(status, latest_checks)
+}
+
+// Spaces out the fetches of a merge request's dependencies, with some jitter, so
+// that long chains don't burst the API. A longer Retry-After from the API takes
+// precedence, including for the first fetch.
+async fn wait_before_dependency_fetch(state: &AppState, index: usize) {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+
+	let spacing = if index == 0 || config.dependency_fetch_interval == 0 {
+		Duration::from_millis(0)
+	} else {
+		let jitter = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.map(|elapsed| u64::from(elapsed.subsec_millis()))
+			.unwrap_or(0)
+			% (config.dependency_fetch_interval / 4 + 1);
+		Duration::from_millis(config.dependency_fetch_interval + jitter)
+	};
+	let delay = match gh_client.retry_after() {
+		Some(retry_after) if retry_after > spacing => retry_after,
+		_ => spacing,
+	};
+
+	if !delay.is_zero() {
+		log::info!("Waiting {:?} before fetching the next dependency", delay);
+		tokio::time::sleep(delay).await;
+	}
 }
 
 #[async_recursion]
@@ -364,12 +680,20 @@ pub async fn process_commit_checks_and_statuses(
 	state: &AppState,
 	sha: &str,
 ) -> Result<()> {
-	let AppState { db, gh_client, .. } = state;
+	let AppState {
+		db,
+		gh_client,
+		config,
+		..
+	} = state;
 
 	log::info!("Checking for statuses of {}", sha);
 
-	let mr: MergeRequest = match db.get(sha.as_bytes()).context(error::Db)? {
-		Some(bytes) => bincode::deserialize(&bytes).context(error::Bincode)?,
+	let mr: MergeRequest = match db
+		.get_cf(merge_requests_cf(db), sha.as_bytes())
+		.context(error::Db)?
+	{
+		Some(bytes) => deserialize_merge_request(&bytes)?,
 		None => return Ok(()),
 	};
 	let pr = gh_client
@@ -387,6 +711,42 @@ pub async fn process_commit_checks_and_statuses(
 			return Ok(());
 		}
 
+		if mr.is_delayed() {
+			log::info!(
+				"{} is delayed until {:?}",
+				pr.html_url,
+				mr.not_before
+			);
+			return Ok(());
+		}
+
+		if is_repository_frozen(state, &mr.owner, &mr.repo)?
+			|| is_branch_frozen(
+				state,
+				&mr.owner,
+				&mr.repo,
+				&pr.base.ref_field,
+			)? {
+			log::info!("{} is frozen", pr.html_url);
+			return Ok(());
+		}
+
+		if is_paused(state)? {
+			log::info!("Not merging {} since processbot is paused", pr.html_url);
+			return Ok(());
+		}
+
+		if let Some(remaining) = mr.retry_cooldown_remaining(
+			Duration::from_secs(config.merge_retry_cooldown),
+		) {
+			log::info!(
+				"{} is cooling down for {:?} after a failed merge attempt",
+				pr.html_url,
+				remaining
+			);
+			return Ok(());
+		}
+
 		if mr.sha != pr.head.sha {
 			return Err(Error::HeadChanged {
 				expected: sha.to_string(),
@@ -394,15 +754,43 @@ pub async fn process_commit_checks_and_statuses(
 			});
 		}
 
-		if !is_ready_to_merge(state, &pr).await? {
-			log::info!("{} is not ready", pr.html_url);
+		let readiness = is_ready_to_merge(state, &pr).await?;
+		if !readiness.is_ready() {
+			log::info!("{} is not ready: {}", pr.html_url, readiness);
+			return Ok(());
+		}
+
+		// The requester might have left the organization while the merge was
+		// queued; the merge stays queued in case someone else takes it over
+		if config.recheck_requester_membership
+			&& !is_requester_still_allowed(state, &pr, &mr.requested_by)
+				.await
+				.map_err(Error::into_transient_api_error)?
+		{
+			log::info!(
+				"Holding the merge of {} since {} is no longer allowed to merge it",
+				pr.html_url,
+				mr.requested_by
+			);
+			gh_client
+				.create_issue_comment_once(
+					&mr.owner,
+					&mr.repo,
+					mr.number,
+					&config.message_templates.render(
+						Message::MergeHeld,
+						&[("requested_by", &mr.requested_by)],
+					),
+				)
+				.await?;
 			return Ok(());
 		}
 
 		check_merge_is_allowed(state, &pr, &mr.requested_by, &[]).await?;
 
 		if let Some(dependencies) = &mr.dependencies {
-			for dependency in dependencies {
+			for (index, dependency) in dependencies.iter().enumerate() {
+				wait_before_dependency_fetch(state, index).await;
 				let dependency_pr = gh_client
 					.pull_request(
 						&dependency.owner,
@@ -447,6 +835,26 @@ pub async fn process_commit_checks_and_statuses(
 			}
 		}
 
+		if mr.attempts >= config.max_merge_attempts {
+			log::info!(
+				"Giving up on merging {} after {} attempts",
+				pr.html_url,
+				mr.attempts
+			);
+			cleanup_merge_request(
+				state,
+				&mr.sha,
+				&mr.owner,
+				&mr.repo,
+				mr.number,
+				&MergeRequestCleanupReason::GaveUp {
+					attempts: mr.attempts,
+				},
+			)
+			.await?;
+			return Ok(());
+		}
+
 		log::info!("Updating companion {} before merge", pr.html_url);
 		update_companion_then_merge(
 			state,
@@ -465,6 +873,10 @@ pub async fn process_commit_checks_and_statuses(
 	.await
 	{
 		Ok(_) | Err(Error::MergeFailureWillBeSolvedLater { .. }) => Ok(()),
+		Err(Error::TransientApi { msg }) => {
+			log::info!("Will retry {} later due to: {}", pr.html_url, msg);
+			Ok(())
+		}
 		Err(err) => Err(err.with_pull_request_details(PullRequestDetails {
 			owner: pr.base.repo.owner.login,
 			repo: pr.base.repo.name,
@@ -473,10 +885,471 @@ pub async fn process_commit_checks_and_statuses(
 	}
 }
 
+/// Resume the processing of merge requests which have no pending dependencies and whose
+/// repositories are not frozen, in descending order of priority. Returns the merge requests in
+/// the order they were attempted.
+pub async fn poll_pending_merge_requests(
+	state: &AppState,
+) -> Vec<MergeRequest> {
+	/*
+		List the merge requests again after each attempt since the operations
+		performed in this loop might modify or delete multiple items from the
+		database.
+	*/
+	match is_paused(state) {
+		Ok(true) => {
+			log::info!("Skipping the poll since processbot is paused");
+			return vec![];
+		}
+		Ok(false) => (),
+		Err(err) => {
+			log::error!("Failed to check if processbot is paused: {}", err)
+		}
+	}
+
+	reconcile_merge_request_dependencies(state).await;
+
+	let mut processed_mrs: Vec<MergeRequest> = vec![];
+	loop {
+		let frozen_repos =
+			list_frozen_repositories(state).unwrap_or_else(|err| {
+				log::error!("Failed to list the frozen repositories: {}", err);
+				state.config.frozen_repos.clone()
+			});
+		let candidates = list_merge_requests(state)
+			.into_iter()
+			.filter(|mr| {
+				!processed_mrs.iter().any(|prev_mr| {
+					mr.owner == prev_mr.owner
+						&& mr.repo == prev_mr.repo
+						&& mr.number == prev_mr.number
+				})
+			})
+			.filter(|mr| !mr.is_delayed())
+			.filter(|mr| {
+				!frozen_repos.contains(&format!("{}/{}", mr.owner, mr.repo))
+			})
+			// It's only worthwhile to try merging this MR if it has no pending
+			// dependencies
+			.filter(|mr| {
+				mr.dependencies
+					.as_ref()
+					.map(|vec| vec.is_empty())
+					.unwrap_or(true)
+			})
+			.collect::<Vec<_>>();
+		// Merge requests of the same priority are processed in the order they
+		// were registered within their repository (see `MergeRequest::seq`).
+		// The sequences of different repositories are unrelated, thus the
+		// repositories themselves are taken in the database's order.
+		let mr = candidates.iter().map(|mr| mr.priority).max().and_then(
+			|priority| {
+				let first_mr =
+					candidates.iter().find(|mr| mr.priority == priority)?;
+				candidates
+					.iter()
+					.filter(|mr| {
+						mr.priority == priority
+							&& mr.owner == first_mr.owner
+							&& mr.repo == first_mr.repo
+					})
+					.min_by_key(|mr| mr.seq)
+					.cloned()
+			},
+		);
+		let mr = match mr {
+			Some(mr) => mr,
+			None => break,
+		};
+
+		log::info!(
+			"Attempting to resume merge request processing during poll: {:?}",
+			mr
+		);
+
+		if let Err(err) =
+			process_commit_checks_and_statuses(state, &mr.sha).await
+		{
+			let _ = cleanup_merge_request(
+				state,
+				&mr.sha,
+				&mr.owner,
+				&mr.repo,
+				mr.number,
+				&MergeRequestCleanupReason::Error,
+			)
+			.await;
+			handle_error(
+				PullRequestMergeCancelOutcome::WasCancelled,
+				err,
+				state,
+			)
+			.await;
+		}
+
+		processed_mrs.push(mr);
+	}
+
+	processed_mrs
+}
+
+/// Drop the dependencies which were merged from the registered merge requests and cancel the merge
+/// requests whose dependencies were closed without being merged. Normally that's handled when the
+/// events for the dependencies are received, but if those were missed the dependents would
+/// otherwise be stuck since only merge requests without pending dependencies are polled.
+async fn reconcile_merge_request_dependencies(state: &AppState) {
+	for mr in list_merge_requests(state) {
+		if mr
+			.dependencies
+			.as_ref()
+			.map(|dependencies| dependencies.is_empty())
+			.unwrap_or(true)
+		{
+			continue;
+		}
+
+		if let Err(err) = reconcile_dependencies_of(state, mr.clone()).await {
+			let _ = cleanup_merge_request(
+				state,
+				&mr.sha,
+				&mr.owner,
+				&mr.repo,
+				mr.number,
+				&MergeRequestCleanupReason::Error,
+			)
+			.await;
+			handle_error(
+				PullRequestMergeCancelOutcome::WasCancelled,
+				err.with_pull_request_details(PullRequestDetails {
+					owner: mr.owner,
+					repo: mr.repo,
+					number: mr.number,
+				}),
+				state,
+			)
+			.await;
+		}
+	}
+}
+
+async fn reconcile_dependencies_of(
+	state: &AppState,
+	mut mr: MergeRequest,
+) -> Result<()> {
+	let AppState { db, gh_client, .. } = state;
+
+	let dependencies = mr.dependencies.take().unwrap_or_default();
+	let dependencies_count = dependencies.len();
+	let mut pending_dependencies = Vec::with_capacity(dependencies_count);
+	for dependency in dependencies {
+		let dependency_pr = gh_client
+			.pull_request(
+				&dependency.owner,
+				&dependency.repo,
+				dependency.number,
+			)
+			.await?;
+		if dependency_pr.merged {
+			log::info!(
+				"Dependency {} of {} was merged, cleaning it",
+				dependency.html_url,
+				mr.html_url
+			);
+			cleanup_merge_request(
+				state,
+				&dependency_pr.head.sha,
+				&dependency.owner,
+				&dependency.repo,
+				dependency.number,
+				&MergeRequestCleanupReason::AfterMerge,
+			)
+			.await?;
+		} else if dependency_pr.state == GithubPullRequestState::Closed {
+			return Err(Error::Message {
+				msg: format!(
+					"Dependency {} was closed without being merged. Aborting.",
+					dependency.html_url
+				),
+			});
+		} else {
+			pending_dependencies.push(dependency);
+		}
+	}
+
+	if pending_dependencies.len() != dependencies_count {
+		mr.dependencies = Some(pending_dependencies);
+		db.put_cf(
+			merge_requests_cf(db),
+			mr.sha.as_bytes(),
+			bincode::serialize(&mr).context(error::Bincode)?,
+		)
+		.context(error::Db)?;
+	}
+
+	Ok(())
+}
+
+/// Bring the registered merge requests in line with their pull requests, which is normally done
+/// when their events are received. If those were missed, e.g. during an outage, the merges done
+/// outside of processbot would otherwise leave their dependents stuck. Returns how many merge
+/// requests were reconciled.
+pub async fn reconcile_merge_requests(state: &AppState) -> usize {
+	let AppState { db, .. } = state;
+
+	match is_paused(state) {
+		Ok(true) => {
+			log::info!(
+				"Skipping the reconciliation since processbot is paused"
+			);
+			return 0;
+		}
+		Ok(false) => (),
+		Err(err) => {
+			log::error!("Failed to check if processbot is paused: {}", err)
+		}
+	}
+
+	let mut reconciled_count = 0;
+	for mr in list_merge_requests(state) {
+		// Reconciling a merge request might clean up others, e.g. its dependents
+		match db.get_cf(merge_requests_cf(db), mr.sha.as_bytes()) {
+			Ok(Some(_)) => (),
+			Ok(None) => continue,
+			Err(err) => {
+				log::error!(
+					"Failed to read the merge request of {} due to {}",
+					mr.html_url,
+					err
+				);
+				continue;
+			}
+		}
+
+		// Only the outcomes confirmed by the pull request cancel a merge; other
+		// errors (e.g. an unavailable API) are retried on the next reconciliation
+		match reconcile_merge_request(state, &mr).await {
+			Ok(true) => reconciled_count += 1,
+			Ok(false) => (),
+			Err(err) => log::error!(
+				"Failed to reconcile {} due to {}; retrying on the next reconciliation",
+				mr.html_url,
+				err
+			),
+		}
+	}
+
+	reconciled_count
+}
+
+/// Returns whether `mr` had gone out of sync with its pull request
+async fn reconcile_merge_request(
+	state: &AppState,
+	mr: &MergeRequest,
+) -> Result<bool> {
+	let AppState { gh_client, .. } = state;
+
+	let pr = gh_client
+		.pull_request(&mr.owner, &mr.repo, mr.number)
+		.await?;
+
+	if pr.merged {
+		log::info!(
+			"{} was merged without its event being handled, reconciling it",
+			pr.html_url
+		);
+		handle_merged_pull_request(state, &pr, &mr.requested_by).await?;
+		return Ok(true);
+	}
+
+	if pr.state == GithubPullRequestState::Closed {
+		log::info!(
+			"Cancelling the merge of {} since it was closed",
+			pr.html_url
+		);
+		cleanup_merge_request(
+			state,
+			&mr.sha,
+			&mr.owner,
+			&mr.repo,
+			mr.number,
+			&MergeRequestCleanupReason::Cancelled,
+		)
+		.await?;
+		return Ok(true);
+	}
+
+	if pr.head.sha == mr.sha {
+		return Ok(false);
+	}
+
+	// The pusher is not known without the event, thus the merge is cancelled
+	// as it would be for a push by someone else than the requester: only the
+	// commit trusted by the requester may be merged
+	log::info!(
+		"Cancelling the merge of {} since it was updated from {} to {} without its event being handled",
+		pr.html_url,
+		mr.sha,
+		pr.head.sha
+	);
+	cleanup_merge_request(
+		state,
+		&mr.sha,
+		&mr.owner,
+		&mr.repo,
+		mr.number,
+		&MergeRequestCleanupReason::Cancelled,
+	)
+	.await?;
+	handle_error(
+		PullRequestMergeCancelOutcome::WasCancelled,
+		Error::HeadChanged {
+			expected: mr.sha.clone(),
+			actual: pr.head.sha,
+		}
+		.with_pull_request_details(PullRequestDetails {
+			owner: mr.owner.clone(),
+			repo: mr.repo.clone(),
+			number: mr.number,
+		}),
+		state,
+	)
+	.await;
+
+	Ok(true)
+}
+
+/// Register a merge request for a pull request and process it right away, e.g. for having it
+/// reconsidered after an outage without waiting for a new event. A merge request which is already
+/// registered for the pull request's HEAD is kept as it is. `requested_by` is the login recorded
+/// as the requester of the merge.
+pub async fn requeue_pull_request(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	requested_by: &str,
+) -> Result<()> {
+	let AppState { db, gh_client, .. } = state;
+
+	let pr = gh_client.pull_request(owner, repo, number).await?;
+
+	if db
+		.get_cf(merge_requests_cf(db), pr.head.sha.as_bytes())
+		.context(error::Db)?
+		.is_none()
+	{
+		let mr = MergeRequest {
+			sha: (&pr.head.sha).into(),
+			owner: (&pr.base.repo.owner.login).into(),
+			repo: (&pr.base.repo.name).into(),
+			number: pr.number,
+			html_url: (&pr.html_url).into(),
+			requested_by: requested_by.into(),
+			// The operator who requeues the pull request vouches for its current commit
+			was_updated: true,
+			dependencies: None,
+			attempts: 0,
+			priority: MERGE_PRIORITY_NORMAL,
+			not_before: None,
+			queued_at: None,
+			seq: None,
+			last_attempt_at: None,
+			schema_version: MERGE_REQUEST_SCHEMA_VERSION,
+		};
+		queue_merge_request(state, &mr, &MergeRequestQueuedMessage::None)
+			.await?;
+	}
+
+	process_commit_checks_and_statuses(state, &pr.head.sha).await
+}
+
+/// What becomes of a merge request of the database once one of its dependencies
+/// is merged (see `evaluate_dependent_liveness`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependentLiveness {
+	/// The merged pull request was its only dependency and it was directly
+	/// referenced; the merge request should be deleted
+	Dangling,
+	/// The merged pull request was directly referenced and other dependencies
+	/// remain; the merge request should be saved without it
+	Updated,
+	/// The merged pull request is an indirect dependency; the merge request
+	/// should be processed as a dependent
+	Alive,
+	/// Same as `Alive`, but a directly referenced dependency was also dropped,
+	/// thus the merge request should be saved as well
+	AliveNeedsUpdate,
+}
+
+/// Drops the merged pull request `owner/repo#number` from the dependencies of
+/// `mr` where it's directly referenced and tells what should be done with `mr`
+/// as a result. None if `mr` does not depend on it. The outcome does not depend
+/// on the order of the dependencies.
+pub fn evaluate_dependent_liveness(
+	mr: &mut MergeRequest,
+	owner: &str,
+	repo: &str,
+	number: i64,
+) -> Option<DependentLiveness> {
+	let dependencies = mr.dependencies.take()?;
+	let is_merged_pull_request = |dependency: &MergeRequestDependency| {
+		dependency.owner == owner
+			&& dependency.repo == repo
+			&& dependency.number == number
+	};
+
+	let mut was_directly_referenced = false;
+	let mut is_indirect_dependency = false;
+	let mut has_other_dependencies = false;
+	for dependency in &dependencies {
+		if !is_merged_pull_request(dependency) {
+			has_other_dependencies = true;
+		} else if dependency.is_directly_referenced {
+			was_directly_referenced = true;
+		} else {
+			is_indirect_dependency = true;
+		}
+	}
+
+	mr.dependencies = Some(
+		dependencies
+			.into_iter()
+			.filter(|dependency| {
+				!(is_merged_pull_request(dependency)
+					&& dependency.is_directly_referenced)
+			})
+			.collect(),
+	);
+
+	match (
+		was_directly_referenced,
+		is_indirect_dependency,
+		has_other_dependencies,
+	) {
+		(false, false, _) => None,
+		(false, true, _) => Some(DependentLiveness::Alive),
+		(true, true, _) => Some(DependentLiveness::AliveNeedsUpdate),
+		(true, false, true) => Some(DependentLiveness::Updated),
+		(true, false, false) => Some(DependentLiveness::Dangling),
+	}
+}
+
+/// Process the dependents of a pull request which was merged. Once it succeeds,
+/// the merge is marked as handled so that it's not handled again when the merge
+/// is noticed through another event (see `handle_merged_pull_request`).
 pub async fn process_dependents_after_merge(
 	state: &AppState,
 	pr: &GithubPullRequest,
 	requested_by: &str,
+) -> Result<()> {
+	process_dependents_of_merged_pull_request(state, pr, requested_by).await?;
+	mark_merge_as_handled(state, &pr.head.sha)
+}
+
+async fn process_dependents_of_merged_pull_request(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	requested_by: &str,
 ) -> Result<()> {
 	log::info!("Handling dependents of {}", pr.html_url);
 
@@ -533,11 +1406,10 @@ pub async fn process_dependents_after_merge(
 	*/
 	let mut processed_mrs = vec![];
 	'db_iteration_loop: loop {
-		let db_iter = db.iterator(rocksdb::IteratorMode::Start);
+		let db_iter =
+			db.iterator_cf(merge_requests_cf(db), rocksdb::IteratorMode::Start);
 		'to_next_item: for (key, value) in db_iter {
-			match bincode::deserialize::<MergeRequest>(&value)
-				.context(error::Bincode)
-			{
+			match deserialize_merge_request(&value) {
 				Ok(mut mr) => {
 					if processed_mrs.iter().any(|prev_mr: &MergeRequest| {
 						mr.owner == prev_mr.owner
@@ -561,58 +1433,20 @@ pub async fn process_dependents_after_merge(
 						}
 					}
 
-					#[derive(PartialEq)]
-					enum LivenessOutcome {
-						Updated,
-						Dangling,
-						Alive,
-						AliveNeedsUpdate,
-					}
-					let mut liveness_outcome: Option<LivenessOutcome> = None;
-
-					mr.dependencies = mr.dependencies.map(|dependencies| {
-						dependencies
-							.into_iter()
-							.filter(|dependency| {
-								if dependency.owner == pr.base.repo.owner.login
-									&& dependency.repo == pr.base.repo.name
-									&& dependency.number == pr.number
-								{
-									if dependency.is_directly_referenced {
-										if liveness_outcome.is_none() {
-											liveness_outcome =
-												Some(LivenessOutcome::Dangling);
-										}
-										false
-									} else {
-										if liveness_outcome != Some(LivenessOutcome::AliveNeedsUpdate) {
-											liveness_outcome = match liveness_outcome {
-												Some(LivenessOutcome::Updated) => Some(LivenessOutcome::AliveNeedsUpdate),
-												_ => Some(LivenessOutcome::Alive)
-											};
-										}
-										true
-									}
-								} else {
-									if let Some(LivenessOutcome::Dangling) =
-										liveness_outcome
-									{
-										liveness_outcome =
-											Some(LivenessOutcome::Updated);
-									}
-									true
-								}
-							})
-							.collect()
-					});
+					let liveness_outcome = evaluate_dependent_liveness(
+						&mut mr,
+						&pr.base.repo.owner.login,
+						&pr.base.repo.name,
+						pr.number,
+					);
 
 					if let Some(liveness_outcome) = liveness_outcome {
 						match liveness_outcome {
-							LivenessOutcome::Alive => {
+							DependentLiveness::Alive => {
 								register_alive_dependent(mr.clone());
 							}
-							LivenessOutcome::Updated
-							| LivenessOutcome::AliveNeedsUpdate => {
+							DependentLiveness::Updated
+							| DependentLiveness::AliveNeedsUpdate => {
 								if let Err(err) = db
 									.put(
 										&key,
@@ -654,13 +1488,14 @@ pub async fn process_dependents_after_merge(
 									)
 									.await;
 								} else if liveness_outcome
-									== LivenessOutcome::AliveNeedsUpdate
+									== DependentLiveness::AliveNeedsUpdate
 								{
 									register_alive_dependent(mr.clone());
 								}
 							}
-							LivenessOutcome::Dangling => {
-								let _ = db.delete(&key);
+							DependentLiveness::Dangling => {
+								let _ =
+									db.delete_cf(merge_requests_cf(db), &key);
 							}
 						};
 
@@ -674,7 +1509,7 @@ pub async fn process_dependents_after_merge(
 						String::from_utf8_lossy(&key),
 						err
 					);
-					let _ = db.delete(&key);
+					let _ = db.delete_cf(merge_requests_cf(db), &key);
 				}
 			};
 		}
@@ -685,6 +1520,13 @@ pub async fn process_dependents_after_merge(
 		if alive_dependents.is_empty() {
 			return Ok(());
 		}
+		// Process the dependents in a stable order rather than in the order they
+		// happen to be found in the database, i.e. in registration order within
+		// a repository
+		alive_dependents.sort_by(|a, b| {
+			(&a.owner, &a.repo, a.seq, a.number)
+				.cmp(&(&b.owner, &b.repo, b.seq, b.number))
+		});
 		alive_dependents
 	};
 
@@ -763,12 +1605,13 @@ pub async fn process_dependents_after_merge(
 		commits from sneaking in after the chain is built, but in this case we changed
 		the HEAD of the PR ourselves through the update, which is safe).
 	*/
-	let mut dependents_to_check = HashMap::new();
-	let db_iter = db.iterator(rocksdb::IteratorMode::Start);
+	// Keyed by (owner, repo, number, sha) so that they're checked in a stable
+	// order without the records of different head SHAs overwriting each other
+	let mut dependents_to_check = BTreeMap::new();
+	let db_iter =
+		db.iterator_cf(merge_requests_cf(db), rocksdb::IteratorMode::Start);
 	for (key, value) in db_iter {
-		match bincode::deserialize::<MergeRequest>(&value)
-			.context(error::Bincode)
-		{
+		match deserialize_merge_request(&value) {
 			Ok(mut dependent_of_dependent) => {
 				let mut should_be_included_in_check = false;
 				let mut record_needs_update = false;
@@ -853,10 +1696,26 @@ pub async fn process_dependents_after_merge(
 						)
 						.await;
 					} else {
-						dependents_to_check.insert(key, dependent_of_dependent);
+						dependents_to_check.insert(
+							(
+								dependent_of_dependent.owner.clone(),
+								dependent_of_dependent.repo.clone(),
+								dependent_of_dependent.number,
+								dependent_of_dependent.sha.clone(),
+							),
+							dependent_of_dependent,
+						);
 					}
 				} else if should_be_included_in_check {
-					dependents_to_check.insert(key, dependent_of_dependent);
+					dependents_to_check.insert(
+						(
+							dependent_of_dependent.owner.clone(),
+							dependent_of_dependent.repo.clone(),
+							dependent_of_dependent.number,
+							dependent_of_dependent.sha.clone(),
+						),
+						dependent_of_dependent,
+					);
 				}
 			}
 			Err(err) => {
@@ -865,7 +1724,7 @@ pub async fn process_dependents_after_merge(
 					String::from_utf8_lossy(&key),
 					err
 				);
-				let _ = db.delete(&key);
+				let _ = db.delete_cf(merge_requests_cf(db), &key);
 			}
 		};
 	}
@@ -903,11 +1762,80 @@ pub async fn process_dependents_after_merge(
 	Ok(())
 }
 
+// Formats a duration as e.g. "1h 2m 3s", omitting the leading units which are zero
+fn format_duration(duration: Duration) -> String {
+	let seconds = duration.as_secs();
+	let (hours, minutes, seconds) =
+		(seconds / 3600, (seconds % 3600) / 60, seconds % 60);
+	if hours > 0 {
+		format!("{}h {}m {}s", hours, minutes, seconds)
+	} else if minutes > 0 {
+		format!("{}m {}s", minutes, seconds)
+	} else {
+		format!("{}s", seconds)
+	}
+}
+
+/// Append the companions which will be merged after `pr` to a message, so that the requester can
+/// confirm that the chain detected by the bot matches their intent
+async fn append_merge_chain(
+	state: &AppState,
+	msg: &str,
+	pr: &GithubPullRequest,
+) -> String {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+
+	match resolve_companions(gh_client, config, pr, &[]).await {
+		Ok(Some(companions)) if !companions.is_empty() => format!(
+			"{}\n\nThe following companions will be merged after this pull request:\n{}",
+			msg.trim_end(),
+			companions
+				.iter()
+				.map(|comp| format!("- {}", comp.html_url))
+				.collect::<Vec<_>>()
+				.join("\n")
+		),
+		_ => msg.to_string(),
+	}
+}
+
+// The commands which push to a pull request's branch can't do so for forks of
+// bots, like for companions in check_all_companions_are_mergeable
+fn check_pull_request_branch_is_pushable(pr: &GithubPullRequest) -> Result<()> {
+	let has_user_owner = pr
+		.user
+		.as_ref()
+		.map(|user| user.type_field == GithubUserType::User)
+		.unwrap_or(false);
+	let head_repo = pr.head_repository()?;
+	if !has_user_owner && head_repo.owner.login != pr.base.repo.owner.login {
+		return Err(Error::PullRequestNotUserOwned {
+			html_url: pr.html_url.clone(),
+		});
+	}
+	Ok(())
+}
+
 pub async fn handle_command(
 	state: &AppState,
 	cmd: &CommentCommand,
 	pr: &GithubPullRequest,
 	requested_by: &str,
+) -> Result<()> {
+	run_command(state, cmd, pr, requested_by, false).await
+}
+
+/// Like `handle_command`, but the confirmation that a merge was queued is left
+/// out if `is_acknowledged_by_reactions`, i.e. if the command's comment already
+/// receives reactions for that purpose (see `reaction_acknowledgements`).
+pub async fn run_command(
+	state: &AppState,
+	cmd: &CommentCommand,
+	pr: &GithubPullRequest,
+	requested_by: &str,
+	is_acknowledged_by_reactions: bool,
 ) -> Result<()> {
 	let AppState { gh_client, .. } = state;
 
@@ -916,6 +1844,163 @@ pub async fn handle_command(
 		// command was received will act as the starting point for resolving further
 		// dependencies.
 		CommentCommand::Merge(cmd) => {
+			if is_paused(state)? {
+				log::info!(
+					"Rejecting merge command for {} since processbot is paused",
+					pr.html_url
+				);
+				if let Err(err) = gh_client
+					.create_issue_comment(
+						&pr.base.repo.owner.login,
+						&pr.base.repo.name,
+						pr.number,
+						&state
+							.config
+							.message_templates
+							.render(Message::Paused, &[]),
+					)
+					.await
+				{
+					log::error!(
+						"Failed to post comment on {} due to {}",
+						pr.html_url,
+						err
+					);
+				}
+				return Ok(());
+			}
+
+			if !state.config.is_base_branch_allowed(
+				&pr.base.repo.owner.login,
+				&pr.base.repo.name,
+				&pr.base.ref_field,
+			) {
+				log::info!(
+					"Rejecting merge command for {} since its base branch {} is not allowed",
+					pr.html_url,
+					pr.base.ref_field
+				);
+				if let Err(err) = gh_client
+					.create_issue_comment(
+						&pr.base.repo.owner.login,
+						&pr.base.repo.name,
+						pr.number,
+						&state.config.message_templates.render(
+							Message::BaseBranchNotAllowed,
+							&[("branch", &pr.base.ref_field)],
+						),
+					)
+					.await
+				{
+					log::error!(
+						"Failed to post comment on {} due to {}",
+						pr.html_url,
+						err
+					);
+				}
+				return Ok(());
+			}
+
+			if is_repository_frozen(
+				state,
+				&pr.base.repo.owner.login,
+				&pr.base.repo.name,
+			)? {
+				log::info!(
+					"Rejecting merge command for {} since its repository is frozen",
+					pr.html_url
+				);
+				if let Err(err) = gh_client
+					.create_issue_comment(
+						&pr.base.repo.owner.login,
+						&pr.base.repo.name,
+						pr.number,
+						&state
+							.config
+							.message_templates
+							.render(Message::RepositoryFrozen, &[]),
+					)
+					.await
+				{
+					log::error!(
+						"Failed to post comment on {} due to {}",
+						pr.html_url,
+						err
+					);
+				}
+				return Ok(());
+			}
+
+			if is_branch_frozen(
+				state,
+				&pr.base.repo.owner.login,
+				&pr.base.repo.name,
+				&pr.base.ref_field,
+			)? {
+				log::info!(
+					"Rejecting merge command for {} since its base branch {} is frozen",
+					pr.html_url,
+					pr.base.ref_field
+				);
+				if let Err(err) = gh_client
+					.create_issue_comment(
+						&pr.base.repo.owner.login,
+						&pr.base.repo.name,
+						pr.number,
+						&state.config.message_templates.render(
+							Message::BranchFrozen,
+							&[("branch", &pr.base.ref_field)],
+						),
+					)
+					.await
+				{
+					log::error!(
+						"Failed to post comment on {} due to {}",
+						pr.html_url,
+						err
+					);
+				}
+				return Ok(());
+			}
+
+			// The pull request named by `bot merge after` should exist and still
+			// be open for it to be waited on
+			let dependency = match cmd {
+				MergeCommentCommand::After {
+					owner,
+					repo,
+					number,
+				} => {
+					let dependency =
+						gh_client.pull_request(owner, repo, *number).await?;
+					if dependency.merged {
+						return Err(Error::Message {
+							msg: format!(
+								"{} is already merged; use `bot merge` instead",
+								dependency.html_url
+							),
+						});
+					}
+					if dependency.state == GithubPullRequestState::Closed {
+						return Err(Error::Message {
+							msg: format!(
+								"{} is closed, thus it will not be merged",
+								dependency.html_url
+							),
+						});
+					}
+					Some(MergeRequestDependency {
+						sha: dependency.head.sha,
+						owner: dependency.base.repo.owner.login,
+						repo: dependency.base.repo.name,
+						number: dependency.number,
+						html_url: dependency.html_url,
+						is_directly_referenced: false,
+					})
+				}
+				_ => None,
+			};
+
 			let mr = MergeRequest {
 				sha: (&pr.head.sha).into(),
 				owner: (&pr.base.repo.owner.login).into(),
@@ -928,16 +2013,51 @@ pub async fn handle_command(
 				// trusted the current commit, but not the ones coming after it (some
 				// malicious actor might want to sneak in changes after the command starts).
 				was_updated: true,
-				// This is the starting point of the merge chain, hence why always no
-				// dependencies are registered for it upfront
-				dependencies: None,
+				// This is the starting point of the merge chain, hence why no
+				// dependencies are registered for it upfront unless they were
+				// declared through `bot merge after`
+				dependencies: dependency
+					.clone()
+					.map(|dependency| vec![dependency]),
+				attempts: 0,
+				priority: match cmd {
+					MergeCommentCommand::High => MERGE_PRIORITY_HIGH,
+					_ => MERGE_PRIORITY_NORMAL,
+				},
+				not_before: match cmd {
+					MergeCommentCommand::Delayed(delay) => {
+						Some(SystemTime::now() + *delay)
+					}
+					_ => None,
+				},
+				queued_at: None,
+				seq: None,
+				last_attempt_at: None,
+				schema_version: MERGE_REQUEST_SCHEMA_VERSION,
 			};
 
 			check_merge_is_allowed(state, pr, requested_by, &[]).await?;
 
 			match cmd {
-				MergeCommentCommand::Normal => {
-					if is_ready_to_merge(state, pr).await? {
+				MergeCommentCommand::Normal | MergeCommentCommand::High => {
+					let readiness = match is_ready_to_merge(state, pr).await {
+						Ok(readiness) => Some(readiness),
+						// Queue the merge so that it's checked again later
+						Err(Error::TransientApi { msg }) => {
+							log::info!(
+								"Could not check if {} is ready due to: {}",
+								pr.html_url,
+								msg
+							);
+							None
+						}
+						Err(err) => return Err(err),
+					};
+					if readiness
+						.as_ref()
+						.map(MergeReadiness::is_ready)
+						.unwrap_or(false)
+					{
 						match merge_pull_request(state, pr, requested_by)
 							.await?
 						{
@@ -945,20 +2065,33 @@ pub async fn handle_command(
 							// it'll eventually resume processing when later statuses arrive
 							Err(Error::MergeFailureWillBeSolvedLater {
 								msg,
+								updated_sha,
 							}) => {
-								let msg = format!(
-									"This PR cannot be merged **at the moment** due to: {}\n\nprocessbot expects that the problem will be solved automatically later and so the auto-merge process will be started. You can simply wait for now.\n\n",
-									msg
-								);
+								let msg =
+									state.config.message_templates.render(
+										Message::QueuedAfterMergeFailure,
+										&[("reason", &msg)],
+									);
+								// The merge was already attempted, thus it's not
+								// limited by the queue's capacity
 								queue_merge_request(
 									state,
-									&mr,
-									&MergeRequestQueuedMessage::Custom(&msg),
+									&MergeRequest {
+										sha: updated_sha
+											.clone()
+											.unwrap_or_else(|| mr.sha.clone()),
+										..mr
+									},
+									&MergeRequestQueuedMessage::Custom(
+										&append_merge_chain(state, &msg, pr)
+											.await,
+									),
 								)
 								.await?;
 								return Err(
 									Error::MergeFailureWillBeSolvedLater {
 										msg,
+										updated_sha,
 									},
 								);
 							}
@@ -966,22 +2099,98 @@ pub async fn handle_command(
 							_ => (),
 						}
 					} else {
-						queue_merge_request(
-							state,
-							&mr,
-							&MergeRequestQueuedMessage::Default,
-						)
-						.await?;
+						let pending = match readiness {
+							Some(readiness) => {
+								log::info!(
+									"{} is not ready: {}",
+									pr.html_url,
+									readiness
+								);
+								readiness.to_string()
+							}
+							None => String::new(),
+						};
+						if is_acknowledged_by_reactions {
+							check_queue_capacity(state, pr)?;
+							queue_merge_request(
+								state,
+								&mr,
+								&MergeRequestQueuedMessage::None,
+							)
+							.await?;
+						} else {
+							check_queue_capacity(state, pr)?;
+							queue_merge_request(
+								state,
+								&mr,
+								&MergeRequestQueuedMessage::Custom(
+									&append_merge_chain(
+										state,
+										&state.config.message_templates.render(
+											Message::Queued,
+											&[("pending", &pending)],
+										),
+										pr,
+									)
+									.await,
+								),
+							)
+							.await?;
+						}
 						return Ok(());
 					}
 				}
+				MergeCommentCommand::Delayed(_) => {
+					let not_before = mr
+						.not_before
+						.map(|not_before| {
+							DateTime::<Utc>::from(not_before)
+								.format("%Y-%m-%d %H:%M:%S UTC")
+								.to_string()
+						})
+						.unwrap_or_default();
+					let msg = state.config.message_templates.render(
+						Message::QueuedWithDelay,
+						&[("not_before", &not_before)],
+					);
+					check_queue_capacity(state, pr)?;
+					queue_merge_request(
+						state,
+						&mr,
+						&MergeRequestQueuedMessage::Custom(
+							&append_merge_chain(state, &msg, pr).await,
+						),
+					)
+					.await?;
+					return Ok(());
+				}
+				MergeCommentCommand::After { .. } => {
+					let dependency_url = dependency
+						.map(|dependency| dependency.html_url)
+						.unwrap_or_default();
+					let msg = state.config.message_templates.render(
+						Message::QueuedAfterDependency,
+						&[("dependency", &dependency_url)],
+					);
+					check_queue_capacity(state, pr)?;
+					queue_merge_request(
+						state,
+						&mr,
+						&MergeRequestQueuedMessage::Custom(
+							&append_merge_chain(state, &msg, pr).await,
+						),
+					)
+					.await?;
+					return Ok(());
+				}
 				MergeCommentCommand::Force => {
 					match merge_pull_request(state, pr, requested_by).await? {
 						// Even if the merge failure can be solved later, it does not matter because `merge force` is
 						// supposed to be immediate. We should give up here and yield the error message.
-						Err(Error::MergeFailureWillBeSolvedLater { msg }) => {
-							return Err(Error::Message { msg })
-						}
+						Err(Error::MergeFailureWillBeSolvedLater {
+							msg,
+							..
+						}) => return Err(Error::Message { msg }),
 						Err(e) => return Err(e),
 						_ => (),
 					}
@@ -1008,7 +2217,47 @@ pub async fn handle_command(
 					&pr.base.repo.owner.login,
 					&pr.base.repo.name,
 					pr.number,
-					"Merge cancelled.",
+					&state
+						.config
+						.message_templates
+						.render(Message::MergeCancelled, &[]),
+				)
+				.await
+			{
+				log::error!(
+					"Failed to post comment on {} due to {}",
+					pr.html_url,
+					err
+				);
+			}
+
+			Ok(())
+		}
+		CommentCommand::Unqueue => {
+			log::info!(
+				"Removing merge request for {} while keeping its dependents",
+				pr.html_url
+			);
+
+			cleanup_merge_request(
+				state,
+				&pr.head.sha,
+				&pr.base.repo.owner.login,
+				&pr.base.repo.name,
+				pr.number,
+				&MergeRequestCleanupReason::Unqueued,
+			)
+			.await?;
+
+			if let Err(err) = gh_client
+				.create_issue_comment(
+					&pr.base.repo.owner.login,
+					&pr.base.repo.name,
+					pr.number,
+					&state
+						.config
+						.message_templates
+						.render(Message::Unqueued, &[]),
 				)
 				.await
 			{
@@ -1021,14 +2270,41 @@ pub async fn handle_command(
 
 			Ok(())
 		}
-		CommentCommand::Rebase => {
+		CommentCommand::Rebase | CommentCommand::RebaseOnto(_) => {
+			check_pull_request_branch_is_pushable(pr)?;
+
+			let target_branch = match cmd {
+				CommentCommand::RebaseOnto(branch) => {
+					if !gh_client
+						.branch_exists(
+							&pr.base.repo.owner.login,
+							&pr.base.repo.name,
+							branch,
+						)
+						.await?
+					{
+						return Err(Error::Message {
+							msg: format!(
+								"Branch {} does not exist in {}/{}",
+								branch,
+								pr.base.repo.owner.login,
+								pr.base.repo.name
+							),
+						});
+					}
+					branch
+				}
+				_ => &pr.base.ref_field,
+			};
+
+			let head_repo = pr.head_repository()?;
 			let outcome = rebase(
 				state,
 				&pr.base.repo.owner.login,
 				&pr.base.repo.name,
-				&pr.base.ref_field,
-				&pr.head.repo.owner.login,
-				&pr.head.repo.name,
+				target_branch,
+				&head_repo.owner.login,
+				&head_repo.name,
 				&pr.head.ref_field,
 			)
 			.await?;
@@ -1038,12 +2314,41 @@ pub async fn handle_command(
 					&pr.base.repo.owner.login,
 					&pr.base.repo.name,
 					pr.number,
-					match outcome {
-						RebaseOutcome::UpToDate => {
-							"Branch is already up-to-date"
-						}
-						RebaseOutcome::Pushed => "Rebased",
-					},
+					&state.config.message_templates.render(
+						match outcome {
+							RebaseOutcome::UpToDate => {
+								Message::BranchAlreadyUpToDate
+							}
+							RebaseOutcome::Pushed => Message::Rebased,
+						},
+						&[],
+					),
+				)
+				.await
+			{
+				log::error!(
+					"Failed to post comment on {} due to {}",
+					pr.html_url,
+					err
+				);
+			}
+
+			Ok(())
+		}
+		CommentCommand::Update => {
+			check_pull_request_branch_is_pushable(pr)?;
+
+			let updated_sha = update_companion(state, pr).await?;
+
+			if let Err(err) = gh_client
+				.create_issue_comment(
+					&pr.base.repo.owner.login,
+					&pr.base.repo.name,
+					pr.number,
+					&state
+						.config
+						.message_templates
+						.render(Message::Updated, &[("sha", &updated_sha)]),
 				)
 				.await
 			{
@@ -1054,7 +2359,206 @@ pub async fn handle_command(
 				);
 			}
 
+			Ok(())
+		}
+		CommentCommand::Queue => {
+			let owner = &pr.base.repo.owner.login;
+			let repo = &pr.base.repo.name;
+
+			let queued_mrs = list_merge_requests(state)
+				.into_iter()
+				.filter(|mr| &mr.owner == owner && &mr.repo == repo)
+				.collect::<Vec<_>>();
+
+			let msg = if queued_mrs.is_empty() {
+				format!("There are no merges queued for {}/{}.", owner, repo)
+			} else {
+				let mut msg =
+					format!("Merges queued for {}/{}:\n\n", owner, repo);
+				for mr in queued_mrs {
+					msg.push_str(&format!(
+						"- #{} ({}): requested by {}, {} pending dependencies, queued for {}\n",
+						mr.number,
+						mr.html_url,
+						mr.requested_by,
+						mr.dependencies
+							.as_ref()
+							.map(|dependencies| dependencies.len())
+							.unwrap_or(0),
+						mr.time_in_queue()
+							.map(format_duration)
+							.unwrap_or_else(|| "an unknown time".to_string())
+					));
+				}
+				msg
+			};
+
+			if let Err(err) = gh_client
+				.create_issue_comment(owner, repo, pr.number, &msg)
+				.await
+			{
+				log::error!(
+					"Failed to post comment on {} due to {}",
+					pr.html_url,
+					err
+				);
+			}
+
+			Ok(())
+		}
+		CommentCommand::AllowMerge(username) => {
+			let owner = &pr.base.repo.owner.login;
+			let repo = &pr.base.repo.name;
+
+			allow_merge_once(state, owner, repo, pr.number, username)?;
+
+			let msg = format!(
+				"@{} is allowed to use `bot merge` once on this pull request.",
+				username
+			);
+			if let Err(err) = gh_client
+				.create_issue_comment(owner, repo, pr.number, &msg)
+				.await
+			{
+				log::error!(
+					"Failed to post comment on {} due to {}",
+					pr.html_url,
+					err
+				);
+			}
+
+			Ok(())
+		}
+		CommentCommand::Config => {
+			let owner = &pr.base.repo.owner.login;
+			let repo = &pr.base.repo.name;
+
+			let msg = describe_effective_configuration(state, owner, repo)?;
+			if let Err(err) = gh_client
+				.create_issue_comment(owner, repo, pr.number, &msg)
+				.await
+			{
+				log::error!(
+					"Failed to post comment on {} due to {}",
+					pr.html_url,
+					err
+				);
+			}
+
+			Ok(())
+		}
+		CommentCommand::Ping => {
+			let owner = &pr.base.repo.owner.login;
+			let repo = &pr.base.repo.name;
+
+			let msg = format!(
+				"{} is alive, running for {}. Merge requests in the queue: {}.",
+				USER_AGENT,
+				state.config.installation_login,
+				list_merge_requests(state).len()
+			);
+			if let Err(err) = gh_client
+				.create_issue_comment(owner, repo, pr.number, &msg)
+				.await
+			{
+				log::error!(
+					"Failed to post comment on {} due to {}",
+					pr.html_url,
+					err
+				);
+			}
+
 			Ok(())
 		}
 	}
 }
+
+/// Describe the settings which apply to `owner/repo` once the per-repository overrides are
+/// resolved. Approvals and required checks are not listed since they're enforced by the branch
+/// protection rules on Github rather than by processbot.
+fn describe_effective_configuration(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+) -> Result<String> {
+	let AppState { config, .. } = state;
+
+	let (title_template, message_template) =
+		config.merge_commit_templates_for(owner, repo);
+	let allowed_base_branches = config
+		.allowed_base_branches
+		.get(&format!("{}/{}", owner, repo))
+		.map(|branches| branches.join(", "))
+		.unwrap_or_else(|| "any".to_string());
+	let dependencies_to_update = config
+		.dependency_update_configuration
+		.get(repo)
+		.map(|dependencies| dependencies.join(", "))
+		.unwrap_or_else(|| "none".to_string());
+	let frozen = if is_repository_frozen(state, owner, repo)? {
+		"yes".to_string()
+	} else {
+		let branch_prefix = format!("{}/{}:", owner, repo);
+		let mut frozen_branches = list_frozen_repositories(state)?
+			.into_iter()
+			.filter_map(|frozen| {
+				frozen
+					.strip_prefix(&branch_prefix)
+					.map(|branch| branch.to_string())
+			})
+			.collect::<Vec<_>>();
+		if frozen_branches.is_empty() {
+			"no".to_string()
+		} else {
+			frozen_branches.sort();
+			format!("only {}", frozen_branches.join(", "))
+		}
+	};
+	let merge_commit_trailers = if config.merge_commit_trailers.is_empty() {
+		"none".to_string()
+	} else {
+		config.merge_commit_trailers.join(", ")
+	};
+
+	Ok(format!(
+		"Effective configuration for {}/{}:\n\n\
+		- Merge method: {}\n\
+		- Merge commit title template: {}\n\
+		- Merge commit message template: {}\n\
+		- Merge commit trailers: {}\n\
+		- Allowed base branches: {}\n\
+		- Dependencies updated before merging: {}\n\
+		- Frozen: {}\n\
+		- Organization checks: {}\n\
+		- Merge command delay: {}ms\n\
+		- Max merge attempts: {}\n\
+		- Merge retry cooldown: {}s\n\
+		- Max dependency depth: {}\n\
+		- Max companions: {}\n\
+		- GitLab recovery: {}\n",
+		owner,
+		repo,
+		config.merge_method_for(owner, repo),
+		title_template.unwrap_or("Github's default"),
+		message_template.unwrap_or("Github's default"),
+		merge_commit_trailers,
+		allowed_base_branches,
+		dependencies_to_update,
+		frozen,
+		if config.disable_org_checks_for(owner, repo) {
+			"disabled"
+		} else {
+			"enforced"
+		},
+		config.merge_command_delay_for(owner, repo),
+		config.max_merge_attempts,
+		config.merge_retry_cooldown,
+		config.max_dependency_depth,
+		config.max_companions,
+		if config.gitlab_recovery_enabled {
+			"enabled"
+		} else {
+			"disabled"
+		},
+	))
+}
@@ -0,0 +1,275 @@
+use std::{fs, path::Path};
+
+use rocksdb::{ColumnFamily, IteratorMode, Options, DB};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use crate::{
+	constants::{
+		DATABASE_VERSION, LEGACY_METADATA_KEY_PREFIX,
+		MERGE_REQUESTS_COLUMN_FAMILY, MERGE_REQUEST_SCHEMA_VERSION,
+		METADATA_COLUMN_FAMILY, MIGRATABLE_DATABASE_VERSIONS,
+	},
+	error::{self, Error},
+	merge_request::{deserialize_merge_request, MergeRequest},
+	types::Result,
+};
+
+/// Open the database at `path`, creating it and its column families if they
+/// don't exist yet
+pub fn open_database<P: AsRef<Path>>(path: P) -> Result<DB> {
+	let mut opts = Options::default();
+	opts.create_if_missing(true);
+	opts.create_missing_column_families(true);
+	DB::open_cf(
+		&opts,
+		path,
+		vec![MERGE_REQUESTS_COLUMN_FAMILY, METADATA_COLUMN_FAMILY],
+	)
+	.context(error::Db)
+}
+
+/// The column family of the merge requests. It holds nothing else, thus it can
+/// be iterated without having to filter out unrelated records.
+pub fn merge_requests_cf(db: &DB) -> &ColumnFamily {
+	db.cf_handle(MERGE_REQUESTS_COLUMN_FAMILY)
+		.expect("merge requests column family is created in open_database")
+}
+
+pub fn metadata_cf(db: &DB) -> &ColumnFamily {
+	db.cf_handle(METADATA_COLUMN_FAMILY)
+		.expect("metadata column family is created in open_database")
+}
+
+/// Move the metadata records, which used to be stored alongside the merge
+/// requests under LEGACY_METADATA_KEY_PREFIX, to their own column family.
+/// Returns how many records were moved.
+pub fn migrate_legacy_metadata(db: &DB) -> Result<usize> {
+	let legacy_records = db
+		.iterator_cf(
+			merge_requests_cf(db),
+			IteratorMode::From(
+				LEGACY_METADATA_KEY_PREFIX.as_bytes(),
+				rocksdb::Direction::Forward,
+			),
+		)
+		.take_while(|(key, _)| {
+			key.starts_with(LEGACY_METADATA_KEY_PREFIX.as_bytes())
+		})
+		.collect::<Vec<_>>();
+
+	for (key, value) in &legacy_records {
+		db.put_cf(
+			metadata_cf(db),
+			&key[LEGACY_METADATA_KEY_PREFIX.len()..],
+			value,
+		)
+		.context(error::Db)?;
+		db.delete_cf(merge_requests_cf(db), key)
+			.context(error::Db)?;
+	}
+
+	Ok(legacy_records.len())
+}
+
+/// Rewrite the merge requests stored in older shapes with the current one.
+/// Records which can't be read in any shape are left as-is. Returns how many
+/// records were migrated.
+pub fn migrate_merge_requests(db: &DB) -> Result<usize> {
+	let mut migrated_records_count = 0;
+	for (key, value) in
+		db.iterator_cf(merge_requests_cf(db), IteratorMode::Start)
+	{
+		let is_current = bincode::deserialize::<MergeRequest>(&value)
+			.map(|mr| mr.schema_version == MERGE_REQUEST_SCHEMA_VERSION)
+			.unwrap_or(false);
+		if is_current {
+			continue;
+		}
+		let mr = match deserialize_merge_request(&value) {
+			Ok(mr) => mr,
+			Err(err) => {
+				log::error!(
+					"Failed to migrate key {} of the database due to {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				continue;
+			}
+		};
+		db.put_cf(
+			merge_requests_cf(db),
+			&key,
+			bincode::serialize(&mr).context(error::Bincode)?,
+		)
+		.context(error::Db)?;
+		migrated_records_count += 1;
+	}
+	Ok(migrated_records_count)
+}
+
+/// A portable copy of the database's records (see `export_database`). The merge
+/// requests are written as JSON so that they can be inspected and repaired by
+/// hand; the metadata records are kept as they're stored, encoded in base64.
+#[derive(Serialize, Deserialize)]
+struct DatabaseSnapshot {
+	database_version: String,
+	// Validated one by one on import, hence why they're not typed here
+	merge_requests: Vec<serde_json::Value>,
+	metadata: Vec<DatabaseSnapshotMetadataRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DatabaseSnapshotMetadataRecord {
+	key: String,
+	value: String,
+}
+
+/// What was restored by `import_database`
+#[derive(Debug, Default)]
+pub struct DatabaseImportReport {
+	pub merge_requests_count: usize,
+	pub metadata_records_count: usize,
+	// Why each of the records which were not restored was skipped
+	pub skipped_records: Vec<String>,
+}
+
+/// Write all the merge requests and metadata records of the database to `path`.
+/// `database_version` is the version of the database, which might be older than
+/// the current one if it was not migrated yet, in which case its legacy metadata
+/// records are exported as they are. Records which can't be read are left out.
+/// Returns how many merge requests were exported.
+pub fn export_database(
+	db: &DB,
+	path: &Path,
+	database_version: &str,
+) -> Result<usize> {
+	let mut merge_requests = vec![];
+	let mut metadata = vec![];
+	for (key, value) in
+		db.iterator_cf(merge_requests_cf(db), IteratorMode::Start)
+	{
+		if key.starts_with(LEGACY_METADATA_KEY_PREFIX.as_bytes()) {
+			metadata.push(DatabaseSnapshotMetadataRecord {
+				key: String::from_utf8_lossy(&key).to_string(),
+				value: base64::encode(&value),
+			});
+			continue;
+		}
+		match deserialize_merge_request(&value) {
+			Ok(mr) => merge_requests
+				.push(serde_json::to_value(&mr).context(error::Json)?),
+			Err(err) => log::error!(
+				"Leaving key {} out of the export due to {:?}",
+				String::from_utf8_lossy(&key),
+				err
+			),
+		}
+	}
+
+	metadata.extend(db.iterator_cf(metadata_cf(db), IteratorMode::Start).map(
+		|(key, value)| DatabaseSnapshotMetadataRecord {
+			key: String::from_utf8_lossy(&key).to_string(),
+			value: base64::encode(&value),
+		},
+	));
+
+	let merge_requests_count = merge_requests.len();
+	let snapshot = DatabaseSnapshot {
+		database_version: database_version.to_string(),
+		merge_requests,
+		metadata,
+	};
+	fs::write(
+		path,
+		serde_json::to_vec_pretty(&snapshot).context(error::Json)?,
+	)
+	.map_err(|err| Error::Message {
+		msg: format!("Failed to write {}: {}", path.display(), err),
+	})?;
+
+	Ok(merge_requests_count)
+}
+
+/// Restore the records exported by `export_database` from `path`. Existing
+/// records with the same keys are overwritten. Records which are not valid are
+/// skipped and listed in the report rather than failing the whole import.
+/// Snapshots of MIGRATABLE_DATABASE_VERSIONS are migrated to the current version
+/// while being restored.
+pub fn import_database(db: &DB, path: &Path) -> Result<DatabaseImportReport> {
+	let snapshot = fs::read(path).map_err(|err| Error::Message {
+		msg: format!("Failed to read {}: {}", path.display(), err),
+	})?;
+	let snapshot = serde_json::from_slice::<DatabaseSnapshot>(&snapshot)
+		.context(error::Json)?;
+	let is_migratable = MIGRATABLE_DATABASE_VERSIONS
+		.contains(&snapshot.database_version.as_str());
+	if snapshot.database_version != DATABASE_VERSION && !is_migratable {
+		return Err(Error::Message {
+			msg: format!(
+				"{} was exported from database version {}, but version {} or one of {} is expected",
+				path.display(),
+				snapshot.database_version,
+				DATABASE_VERSION,
+				MIGRATABLE_DATABASE_VERSIONS.join(", ")
+			),
+		});
+	}
+
+	let mut report = DatabaseImportReport::default();
+
+	for (index, value) in snapshot.merge_requests.into_iter().enumerate() {
+		let mr = match serde_json::from_value::<MergeRequest>(value) {
+			Ok(mr) if mr.schema_version == MERGE_REQUEST_SCHEMA_VERSION => mr,
+			Ok(mr) => {
+				report.skipped_records.push(format!(
+					"merge request {} ({}) has schema version {}",
+					index, mr.html_url, mr.schema_version
+				));
+				continue;
+			}
+			Err(err) => {
+				report.skipped_records.push(format!(
+					"merge request {} is invalid: {}",
+					index, err
+				));
+				continue;
+			}
+		};
+		db.put_cf(
+			merge_requests_cf(db),
+			mr.sha.as_bytes(),
+			bincode::serialize(&mr).context(error::Bincode)?,
+		)
+		.context(error::Db)?;
+		report.merge_requests_count += 1;
+	}
+
+	for record in snapshot.metadata {
+		let value = match base64::decode(&record.value) {
+			Ok(value) => value,
+			Err(err) => {
+				report.skipped_records.push(format!(
+					"metadata record {} is invalid: {}",
+					record.key, err
+				));
+				continue;
+			}
+		};
+		// The metadata used to be stored alongside the merge requests (see
+		// migrate_legacy_metadata)
+		let key = if is_migratable {
+			record
+				.key
+				.strip_prefix(LEGACY_METADATA_KEY_PREFIX)
+				.unwrap_or(&record.key)
+		} else {
+			&record.key
+		};
+		db.put_cf(metadata_cf(db), key.as_bytes(), value)
+			.context(error::Db)?;
+		report.metadata_records_count += 1;
+	}
+
+	Ok(report)
+}
@@ -1,6 +1,9 @@
 use snafu::Snafu;
 
-use crate::core::{AppState, PullRequestMergeCancelOutcome};
+use crate::{
+	core::{AppState, PullRequestMergeCancelOutcome},
+	messages::Message,
+};
 
 #[derive(Debug)]
 pub struct PullRequestDetails {
@@ -17,6 +20,13 @@ pub struct PullRequestDetailsWithHtmlUrl {
 	pub number: i64,
 }
 
+// A status or check which is failing, as reported to the pull request
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FailingContext {
+	pub name: String,
+	pub target_url: Option<String>,
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub")]
 pub enum Error {
@@ -24,16 +34,21 @@ pub enum Error {
 	WithPullRequestDetails {
 		source: Box<Error>,
 		details: PullRequestDetails,
+		// Replies to commands are always posted, while the errors which happen on
+		// their own are only reported once in a while (see handle_error)
+		is_command_reply: bool,
 	},
 
 	#[snafu(display("Checks failed for {}", commit_sha))]
 	ChecksFailed {
 		commit_sha: String,
+		failing_checks: Vec<FailingContext>,
 	},
 
 	#[snafu(display("Statuses failed for {}", commit_sha))]
 	StatusesFailed {
 		commit_sha: String,
+		failing_statuses: Vec<FailingContext>,
 	},
 
 	#[snafu(display("Head SHA changed from {} to {}", expected, actual))]
@@ -42,11 +57,129 @@ pub enum Error {
 		actual: String,
 	},
 
+	#[snafu(display(
+		"{} has merge conflicts; please resolve them and re-run the command",
+		html_url
+	))]
+	MergeConflict {
+		html_url: String,
+	},
+
+	#[snafu(display(
+		"Merging {} into the pull request's branch has conflicts, thus the branch was not updated; please resolve them and re-run the command",
+		branch
+	))]
+	BranchConflict {
+		branch: String,
+	},
+
+	#[snafu(display(
+		"The source branch of {} no longer exists since its fork was deleted; please open a new pull request",
+		html_url
+	))]
+	HeadRepositoryDeleted {
+		html_url: String,
+	},
+
+	#[snafu(display(
+		"{} is a draft; mark it ready for review first",
+		html_url
+	))]
+	DraftPullRequest {
+		html_url: String,
+	},
+
+	#[snafu(display(
+		"The repository of {} requires merges to go through GitHub's merge queue, which processbot is not compatible with; please add it to the merge queue through GitHub instead",
+		html_url
+	))]
+	MergeQueueRequired {
+		html_url: String,
+	},
+
+	#[snafu(display(
+		"{} needs more approving reviews before it can be merged{}",
+		html_url,
+		if requested_reviewers.is_empty() {
+			"".to_string()
+		} else {
+			format!(
+				"; reviews were requested from {}",
+				requested_reviewers.join(", ")
+			)
+		}
+	))]
+	ApprovalsRequired {
+		html_url: String,
+		requested_reviewers: Vec<String>,
+	},
+
+	#[snafu(display(
+		"The merge queue is full ({} merge requests are queued), thus {} was not queued; please try again once the queue has room",
+		max_queue_size,
+		html_url
+	))]
+	QueueFull {
+		html_url: String,
+		max_queue_size: usize,
+	},
+
+	#[snafu(display("Github API says {} is not mergeable", html_url))]
+	CompanionNotMergeable {
+		html_url: String,
+	},
+
+	#[snafu(display(
+		"Github API says \"Allow edits from maintainers\" is not enabled for {}. The bot would use that permission to push the lockfile update after merging this PR. Please check https://docs.github.com/en/github/collaborating-with-pull-requests/working-with-forks/allowing-changes-to-a-pull-request-branch-created-from-a-fork.",
+		html_url
+	))]
+	CompanionMissingMaintainerEdit {
+		html_url: String,
+	},
+
+	#[snafu(display(
+		"Companion {} is not owned by a user, therefore processbot would not be able to push the lockfile update to their branch due to a Github limitation (https://github.com/isaacs/github/issues/1681)",
+		html_url
+	))]
+	CompanionNotUserOwned {
+		html_url: String,
+	},
+
+	#[snafu(display(
+		"{} is not owned by a user, therefore processbot would not be able to push to its branch due to a Github limitation (https://github.com/isaacs/github/issues/1681)",
+		html_url
+	))]
+	PullRequestNotUserOwned {
+		html_url: String,
+	},
+
+	#[snafu(display(
+		"Updating the lockfile of {} would change packages other than the updated dependencies, thus the update was not pushed. Unexpected changes:\n```\n{}\n```",
+		pull_request,
+		changes.join("\n")
+	))]
+	UnexpectedLockfileChanges {
+		pull_request: String,
+		changes: Vec<String>,
+	},
+
 	#[snafu(display("{}", msg))]
 	Message {
 		msg: String,
 	},
 
+	// The webhook delivery is rejected with 400 Bad Request
+	#[snafu(display("Malformed webhook request: {}", msg))]
+	MalformedWebhookRequest {
+		msg: String,
+	},
+
+	// The webhook delivery is rejected with 401 Unauthorized
+	#[snafu(display("Invalid webhook signature: {}", msg))]
+	InvalidWebhookSignature {
+		msg: String,
+	},
+
 	#[snafu(display("Status code: {}\nBody:\n{:#?}", status, body,))]
 	Response {
 		status: reqwest::StatusCode,
@@ -99,12 +232,33 @@ pub enum Error {
 		err: String,
 	},
 
+	#[snafu(display(
+		"Command '{}' was killed since it did not finish within {:?}",
+		cmd,
+		timeout
+	))]
+	CommandTimedOut {
+		cmd: String,
+		timeout: std::time::Duration,
+	},
+
 	#[snafu(display(
 		"Encountered merge failure (would be solved later): {}",
 		msg
 	))]
 	MergeFailureWillBeSolvedLater {
 		msg: String,
+		// Set if the pull request's branch was updated before the merge was
+		// attempted, in which case the merge should be resumed for this SHA
+		updated_sha: Option<String>,
+	},
+
+	#[snafu(display(
+		"Encountered a transient Github API error (will be retried later): {}",
+		msg
+	))]
+	TransientApi {
+		msg: String,
 	},
 }
 
@@ -118,18 +272,51 @@ impl Error {
 			_ => Self::WithPullRequestDetails {
 				source: Box::new(self),
 				details,
+				is_command_reply: false,
 			},
 		}
 	}
+	/// Mark the error as the outcome of a command, e.g. `bot merge`, so that it's
+	/// reported even if the same error was reported shortly before
+	pub fn into_command_reply(self) -> Self {
+		match self {
+			Self::WithPullRequestDetails {
+				source, details, ..
+			} => Self::WithPullRequestDetails {
+				source,
+				details,
+				is_command_reply: true,
+			},
+			_ => self,
+		}
+	}
 	pub fn stops_merge_attempt(&self) -> bool {
 		match self {
 			Self::WithPullRequestDetails { source, .. } => {
 				source.stops_merge_attempt()
 			}
 			Self::MergeFailureWillBeSolvedLater { .. } => false,
+			Self::TransientApi { .. } => false,
 			_ => true,
 		}
 	}
+
+	/// Turn errors which are likely caused by a temporary unavailability of the Github API (e.g.
+	/// 5xx responses or timeouts) into `TransientApi` so that they don't cancel the merge.
+	pub fn into_transient_api_error(self) -> Self {
+		let is_transient = match &self {
+			Self::Response { status, .. } => status.is_server_error(),
+			Self::Http { source } => source.is_timeout() || source.is_connect(),
+			_ => false,
+		};
+		if is_transient {
+			Self::TransientApi {
+				msg: format!("{}", self),
+			}
+		} else {
+			self
+		}
+	}
 }
 
 pub async fn handle_error(
@@ -139,7 +326,8 @@ pub async fn handle_error(
 ) {
 	log::info!("handle_error: {}", err);
 	match err {
-		Error::MergeFailureWillBeSolvedLater { .. } => (),
+		Error::MergeFailureWillBeSolvedLater { .. }
+		| Error::TransientApi { .. } => (),
 		err => {
 			if let Error::WithPullRequestDetails {
 				source,
@@ -149,26 +337,40 @@ pub async fn handle_error(
 						repo,
 						number,
 					},
-				..
+				is_command_reply,
 			} = err
 			{
 				match *source {
-					Error::MergeFailureWillBeSolvedLater { .. } => (),
+					Error::MergeFailureWillBeSolvedLater { .. }
+					| Error::TransientApi { .. } => (),
 					err => {
 						let msg = {
 							let description = format_error(state, err);
 							let caption = match merge_cancel_outcome {
 								PullRequestMergeCancelOutcome::ShaNotFound  => "",
-								PullRequestMergeCancelOutcome::WasCancelled => "Merge cancelled due to error.",
-								PullRequestMergeCancelOutcome::WasNotCancelled => "Some error happened, but the merge was not cancelled (likely due to a bug).",
+								PullRequestMergeCancelOutcome::WasCancelled => state.config.message_templates.template(Message::MergeCancelledDueToError),
+								PullRequestMergeCancelOutcome::WasNotCancelled => state.config.message_templates.template(Message::ErrorNotCancelled),
 							};
 							format!("{} Error: {}", caption, description)
 						};
-						if let Err(comment_post_err) = state
-							.gh_client
-							.create_issue_comment(&owner, &repo, number, &msg)
-							.await
-						{
+						// The errors which happen on their own tend to happen
+						// again when the operation is retried
+						let result = if is_command_reply {
+							state
+								.gh_client
+								.create_issue_comment(
+									&owner, &repo, number, &msg,
+								)
+								.await
+						} else {
+							state
+								.gh_client
+								.create_issue_comment_once(
+									&owner, &repo, number, &msg,
+								)
+								.await
+						};
+						if let Err(comment_post_err) = result {
 							log::error!(
 								"Error posting comment: {}",
 								comment_post_err
@@ -191,6 +393,43 @@ fn format_error(_state: &AppState, err: Error) -> String {
 			status,
 			html_escape::encode_safe(&body.to_string())
 		),
+		Error::ChecksFailed {
+			ref commit_sha,
+			ref failing_checks,
+		} => format!(
+			"Checks failed for {}{}",
+			commit_sha,
+			format_failing_contexts("checks", failing_checks)
+		),
+		Error::StatusesFailed {
+			ref commit_sha,
+			ref failing_statuses,
+		} => format!(
+			"Statuses failed for {}{}",
+			commit_sha,
+			format_failing_contexts("statuses", failing_statuses)
+		),
 		_ => format!("{}", err),
 	}
 }
+
+// Lists the failing contexts as Markdown, linking to their details where available
+fn format_failing_contexts(kind: &str, contexts: &[FailingContext]) -> String {
+	if contexts.is_empty() {
+		return "".to_string();
+	}
+	format!(
+		". Failing {}:\n{}",
+		kind,
+		contexts
+			.iter()
+			.map(|context| match &context.target_url {
+				Some(target_url) => {
+					format!("- [{}]({})", context.name, target_url)
+				}
+				None => format!("- {}", context.name),
+			})
+			.collect::<Vec<_>>()
+			.join("\n")
+	)
+}
@@ -1,6 +1,22 @@
+use std::{
+	collections::HashMap,
+	fmt::Debug,
+	fs,
+	future::Future,
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
 use snafu::ResultExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 use crate::{
+	config::MainConfig,
 	core::AppState,
 	error::*,
 	shell::{
@@ -10,6 +26,132 @@ use crate::{
 	types::Result,
 };
 
+/// Wait until a branch of `owner/repo` is allowed to be updated. Updates of the same repository
+/// share its clone in the repositories' directory, thus at most
+/// `config.max_concurrent_branch_updates` of them are run at once. The update is allowed until the
+/// permit is dropped.
+pub async fn acquire_branch_update_permit(
+	config: &MainConfig,
+	owner: &str,
+	repo: &str,
+) -> OwnedSemaphorePermit {
+	lazy_static::lazy_static! {
+		static ref BRANCH_UPDATE_SEMAPHORES: parking_lot::Mutex<HashMap<String, Arc<Semaphore>>> = {
+			parking_lot::Mutex::new(HashMap::new())
+		};
+	}
+	let semaphore = BRANCH_UPDATE_SEMAPHORES
+		.lock()
+		.entry(format!("{}/{}", owner, repo))
+		.or_insert_with(|| {
+			Arc::new(Semaphore::new(
+				config.max_concurrent_branch_updates.max(1),
+			))
+		})
+		.clone();
+	semaphore
+		.acquire_owned()
+		.await
+		.expect("Branch update semaphores are never closed")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RepositoryCloneOutcome {
+	Cloned,
+	Reused,
+}
+
+/// Make sure that `repo_dir` holds a clone of `remote_address`. The clone is kept in between
+/// updates (and restarts) because cloning big repositories takes a long time, but it's replaced by
+/// a fresh clone if it's no longer a valid repository (e.g. after an interrupted clone).
+pub async fn ensure_repository_clone(
+	repo_dir: &Path,
+	remote_address: &str,
+	secrets_to_hide: Option<&[&str]>,
+) -> Result<RepositoryCloneOutcome> {
+	// Remembers how long the clone took so that the time saved by reusing it can be reported
+	let clone_duration_path =
+		repo_dir.join(".git").join("processbot-clone-duration");
+
+	if repo_dir.exists() {
+		let started_at = Instant::now();
+		if run_cmd(
+			"git",
+			&["rev-parse", "--verify", "HEAD^{commit}"],
+			repo_dir,
+			CommandMessage::Configured::<'_, &str>(
+				CommandMessageConfiguration {
+					secrets_to_hide: None,
+					are_errors_silenced: true,
+				},
+			),
+		)
+		.await
+		.is_ok()
+		{
+			let clone_duration = fs::read_to_string(&clone_duration_path)
+				.ok()
+				.and_then(|millis| millis.trim().parse::<u64>().ok())
+				.map(Duration::from_millis);
+			if let Some(clone_duration) = clone_duration {
+				log::info!(
+					"Reusing the clone at {:?}, which saved {:?} compared to its initial clone",
+					repo_dir,
+					clone_duration.saturating_sub(started_at.elapsed())
+				);
+			} else {
+				log::info!("Reusing the clone at {:?}", repo_dir);
+			}
+			return Ok(RepositoryCloneOutcome::Reused);
+		}
+
+		log::error!(
+			"The clone at {:?} is not a valid repository; cloning it again",
+			repo_dir
+		);
+		fs::remove_dir_all(repo_dir).map_err(|err| Error::Message {
+			msg: format!(
+				"Failed to remove the invalid clone at {:?}: {}",
+				repo_dir, err
+			),
+		})?;
+	}
+
+	let repo_dir_str = if let Some(repo_dir_str) = repo_dir.to_str() {
+		repo_dir_str
+	} else {
+		return Err(Error::Message {
+			msg: format!(
+				"Path {:?} could not be converted to string",
+				repo_dir
+			),
+		});
+	};
+	let started_at = Instant::now();
+	run_cmd_in_cwd(
+		"git",
+		&["clone", "-v", remote_address, repo_dir_str],
+		CommandMessage::Configured(CommandMessageConfiguration {
+			secrets_to_hide,
+			are_errors_silenced: false,
+		}),
+	)
+	.await?;
+	let clone_duration = started_at.elapsed();
+	log::info!("Cloning into {:?} took {:?}", repo_dir, clone_duration);
+	if let Err(err) =
+		fs::write(&clone_duration_path, clone_duration.as_millis().to_string())
+	{
+		log::error!(
+			"Failed to record the clone duration at {:?}: {}",
+			clone_duration_path,
+			err
+		);
+	}
+
+	Ok(RepositoryCloneOutcome::Cloned)
+}
+
 pub struct SetupContributorBranchData {
 	pub contributor_remote: String,
 	pub repo_dir: String,
@@ -50,25 +192,19 @@ pub async fn setup_contributor_branch(
 		});
 	};
 
-	if repo_dir.exists() {
-		log::info!("{} is already cloned; skipping", owner_repo);
-	} else {
+	{
 		let token = gh_client.auth_token().await?;
 		let secrets_to_hide = [token.as_str()];
-		let secrets_to_hide = Some(&secrets_to_hide[..]);
 		let owner_repository_domain =
 			format!("github.com/{}/{}.git", owner, owner_repo);
 		let owner_remote_address = format!(
 			"https://x-access-token:{}@{}",
 			token, owner_repository_domain
 		);
-		run_cmd_in_cwd(
-			"git",
-			&["clone", "-v", &owner_remote_address, repo_dir_str],
-			CommandMessage::Configured(CommandMessageConfiguration {
-				secrets_to_hide,
-				are_errors_silenced: false,
-			}),
+		ensure_repository_clone(
+			&repo_dir,
+			&owner_remote_address,
+			Some(&secrets_to_hide[..]),
 		)
 		.await?;
 	}
@@ -240,14 +376,11 @@ pub async fn setup_contributor_branch(
 	.await?;
 
 	// Create master merge commit before updating packages
-	run_cmd(
-		"git",
-		&["merge", &owner_remote_branch, "--no-ff", "--no-edit"],
+	merge_branch_into_head(
+		config,
 		&repo_dir,
-		CommandMessage::Configured(CommandMessageConfiguration {
-			secrets_to_hide,
-			are_errors_silenced: false,
-		}),
+		&owner_remote_branch,
+		secrets_to_hide,
 	)
 	.await?;
 
@@ -261,6 +394,327 @@ pub async fn setup_contributor_branch(
 	})
 }
 
+/// Create a merge commit of `branch` into the branch which is checked out in
+/// `repo_dir`. A merge with conflicts is aborted so that the clone is left clean
+/// for the next update.
+pub async fn merge_branch_into_head(
+	config: &MainConfig,
+	repo_dir: &Path,
+	branch: &str,
+	secrets_to_hide: Option<&[&str]>,
+) -> Result<()> {
+	let merge = |gpg_signing: Option<GpgSigningSetup>| async move {
+		let mut merge_args = build_commit_identity_args(config);
+		merge_args.extend(build_signing_config_args(gpg_signing.as_ref()));
+		merge_args.extend(
+			["merge", branch, "--no-ff", "--no-edit"]
+				.iter()
+				.map(|arg| arg.to_string()),
+		);
+		merge_args.extend(build_signing_flag(gpg_signing.as_ref()));
+		run_cmd(
+			"git",
+			&merge_args
+				.iter()
+				.map(|arg| arg.as_str())
+				.collect::<Vec<_>>(),
+			repo_dir,
+			CommandMessage::Configured(CommandMessageConfiguration {
+				secrets_to_hide,
+				are_errors_silenced: false,
+			}),
+		)
+		.await
+		.map(|_| ())
+	};
+	let err = match with_commit_signing(config, "merge commit", merge).await {
+		Ok(_) => return Ok(()),
+		Err(err) => err,
+	};
+
+	// MERGE_HEAD is only left behind by merges which stopped due to conflicts
+	let has_conflicts = run_cmd(
+		"git",
+		&["rev-parse", "-q", "--verify", "MERGE_HEAD"],
+		repo_dir,
+		CommandMessage::Configured::<'_, &str>(CommandMessageConfiguration {
+			secrets_to_hide: None,
+			are_errors_silenced: true,
+		}),
+	)
+	.await
+	.is_ok();
+	if !has_conflicts {
+		return Err(err);
+	}
+
+	run_cmd(
+		"git",
+		&["merge", "--abort"],
+		repo_dir,
+		CommandMessage::Configured::<'_, &str>(CommandMessageConfiguration {
+			secrets_to_hide: None,
+			are_errors_silenced: false,
+		}),
+	)
+	.await?;
+	Err(Error::BranchConflict {
+		branch: branch.to_string(),
+	})
+}
+
+/// Git arguments which make the following command use the identity configured
+/// for processbot's commits
+pub fn build_commit_identity_args(config: &MainConfig) -> Vec<String> {
+	vec![
+		"-c".to_string(),
+		format!("user.name={}", config.git_commit_author_name),
+		"-c".to_string(),
+		format!("user.email={}", config.git_commit_author_email),
+	]
+}
+
+/// The program and key which git should use for signing commits
+pub struct GpgSigningSetup {
+	pub program: PathBuf,
+	pub key_id: String,
+}
+
+// Makes git use the wrapper around gpg of `setup_gpg_signing`
+fn build_signing_config_args(
+	gpg_signing: Option<&GpgSigningSetup>,
+) -> Vec<String> {
+	match gpg_signing {
+		Some(GpgSigningSetup { program, .. }) => vec![
+			"-c".to_string(),
+			format!("gpg.program={}", program.display()),
+		],
+		None => vec![],
+	}
+}
+
+// Makes the commit created by a git command signed
+fn build_signing_flag(gpg_signing: Option<&GpgSigningSetup>) -> Vec<String> {
+	match gpg_signing {
+		Some(GpgSigningSetup { key_id, .. }) => vec![format!("-S{}", key_id)],
+		None => vec![],
+	}
+}
+
+pub fn build_commit_args(
+	config: &MainConfig,
+	msg: &str,
+	gpg_signing: Option<&GpgSigningSetup>,
+) -> Vec<String> {
+	let mut args = build_commit_identity_args(config);
+	args.extend(build_signing_config_args(gpg_signing));
+	args.extend(["commit", "-am", msg].iter().map(|arg| arg.to_string()));
+	args.extend(build_signing_flag(gpg_signing));
+	args
+}
+
+// Creates a keyring directory which is not shared with any other operation,
+// even from another processbot instance on the same host
+fn create_keyring_dir() -> std::io::Result<PathBuf> {
+	static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+	loop {
+		let keyring_dir = std::env::temp_dir().join(format!(
+			"processbot-gnupg-{}-{}-{}",
+			std::process::id(),
+			SEQUENCE.fetch_add(1, Ordering::SeqCst),
+			SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map(|elapsed| elapsed.as_nanos())
+				.unwrap_or(0)
+		));
+		match fs::create_dir(&keyring_dir) {
+			Ok(_) => {
+				fs::set_permissions(
+					&keyring_dir,
+					fs::Permissions::from_mode(0o700),
+				)?;
+				return Ok(keyring_dir);
+			}
+			Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+				continue
+			}
+			Err(err) => return Err(err),
+		}
+	}
+}
+
+// Imports the ASCII-armored key into a keyring at `keyring_dir`. Since git
+// does not know about that keyring, it is provided with a wrapper around gpg
+// which uses it.
+async fn setup_gpg_signing(
+	keyring_dir: &Path,
+	key: &str,
+) -> Result<GpgSigningSetup> {
+	let fs_error = |err: std::io::Error| Error::Message {
+		msg: format!(
+			"Failed to set up the GPG keyring at {:?}: {}",
+			keyring_dir, err
+		),
+	};
+
+	let key_path = keyring_dir.join("signing-key.asc");
+	fs::write(&key_path, key).map_err(fs_error)?;
+	let keyring_dir_str = &keyring_dir.display().to_string();
+	let key_path_str = &key_path.display().to_string();
+	run_cmd_in_cwd(
+		"gpg",
+		&[
+			"--homedir",
+			keyring_dir_str,
+			"--batch",
+			"--import",
+			key_path_str,
+		],
+		CommandMessage::Configured::<'_, &str>(CommandMessageConfiguration {
+			secrets_to_hide: None,
+			are_errors_silenced: false,
+		}),
+	)
+	.await?;
+	// The key is in the keyring by now
+	fs::remove_file(&key_path).map_err(fs_error)?;
+
+	let list_output = run_cmd_in_cwd(
+		"gpg",
+		&[
+			"--homedir",
+			keyring_dir_str,
+			"--batch",
+			"--with-colons",
+			"--list-secret-keys",
+		],
+		CommandMessage::Configured::<'_, &str>(CommandMessageConfiguration {
+			secrets_to_hide: None,
+			are_errors_silenced: false,
+		}),
+	)
+	.await?;
+	let key_id = String::from_utf8(list_output.stdout)
+		.context(Utf8)?
+		.lines()
+		.find(|line| line.starts_with("fpr:"))
+		.and_then(|line| line.split(':').nth(9))
+		.map(|fingerprint| fingerprint.to_string())
+		.ok_or_else(|| Error::Message {
+			msg: "No secret key was found in GPG_SIGNING_KEY_PATH".to_string(),
+		})?;
+
+	let program = keyring_dir.join("gpg");
+	fs::write(
+		&program,
+		format!(
+			"#!/bin/sh\nexec gpg --homedir '{}' \"$@\"\n",
+			keyring_dir_str
+		),
+	)
+	.map_err(fs_error)?;
+	fs::set_permissions(&program, fs::Permissions::from_mode(0o700))
+		.map_err(fs_error)?;
+
+	Ok(GpgSigningSetup { program, key_id })
+}
+
+// Stops the gpg-agent which was spawned for the keyring and deletes it
+async fn teardown_gpg_signing(keyring_dir: &Path) {
+	if let Err(err) = run_cmd_in_cwd(
+		"gpgconf",
+		&[
+			"--homedir",
+			&keyring_dir.display().to_string(),
+			"--kill",
+			"gpg-agent",
+		],
+		CommandMessage::Configured::<'_, &str>(CommandMessageConfiguration {
+			secrets_to_hide: None,
+			are_errors_silenced: true,
+		}),
+	)
+	.await
+	{
+		log::error!(
+			"Failed to stop the gpg-agent of {:?} due to {:?}",
+			keyring_dir,
+			err
+		);
+	}
+	if let Err(err) = fs::remove_dir_all(keyring_dir) {
+		log::error!(
+			"Failed to delete the GPG keyring at {:?} due to {}",
+			keyring_dir,
+			err
+		);
+	}
+}
+
+// Runs a git command which creates a commit, e.g. `git commit` or `git merge`,
+// such that the commit is signed if GPG_SIGNING_KEY_PATH is configured. The
+// keyring only lives for the duration of the command so that the key is not
+// kept around on disk. Signing is mandatory when a key is configured, thus the
+// command is never run without it if the keyring can't be set up.
+async fn with_commit_signing<F, Fut>(
+	config: &MainConfig,
+	operation: &str,
+	run: F,
+) -> Result<()>
+where
+	F: FnOnce(Option<GpgSigningSetup>) -> Fut,
+	Fut: Future<Output = Result<()>>,
+{
+	let key = match &config.gpg_signing_key {
+		Some(key) => key,
+		None => return run(None).await,
+	};
+
+	let keyring_dir = create_keyring_dir().map_err(|err| Error::Message {
+		msg: format!(
+			"Failed to create the GPG keyring for signing the {}: {}",
+			operation, err
+		),
+	})?;
+	let result = match setup_gpg_signing(&keyring_dir, key).await {
+		Ok(gpg_signing) => run(Some(gpg_signing)).await,
+		Err(err) => Err(Error::Message {
+			msg: format!(
+				"Failed to set up the signing of the {} with the key from GPG_SIGNING_KEY_PATH: {}",
+				operation, err
+			),
+		}),
+	};
+	teardown_gpg_signing(&keyring_dir).await;
+
+	result
+}
+
+pub async fn commit_all_changes<Dir: AsRef<Path> + Debug>(
+	config: &MainConfig,
+	repo_dir: Dir,
+	msg: &str,
+	secrets_to_hide: Option<&[String]>,
+) -> Result<()> {
+	let commit = |gpg_signing: Option<GpgSigningSetup>| async move {
+		let args = build_commit_args(config, msg, gpg_signing.as_ref());
+		run_cmd(
+			"git",
+			&args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>(),
+			repo_dir,
+			CommandMessage::Configured(CommandMessageConfiguration {
+				secrets_to_hide,
+				are_errors_silenced: false,
+			}),
+		)
+		.await
+		.map(|_| ())
+	};
+
+	with_commit_signing(config, "commit", commit).await
+}
+
 pub enum RebaseOutcome {
 	UpToDate,
 	Pushed,
@@ -274,6 +728,9 @@ pub async fn rebase(
 	contributor_repo: &str,
 	contributor_branch: &str,
 ) -> Result<RebaseOutcome> {
+	let _permit =
+		acquire_branch_update_permit(&state.config, owner, owner_repo).await;
+
 	let SetupContributorBranchData {
 		contributor_remote,
 		repo_dir,
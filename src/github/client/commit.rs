@@ -1,5 +1,7 @@
-use super::GithubClient;
-use crate::{github::*, types::Result};
+use reqwest::StatusCode;
+
+use super::{is_secondary_rate_limit, GithubClient};
+use crate::{error::Error, github::*, types::Result};
 
 impl GithubClient {
 	pub async fn statuses(
@@ -66,4 +68,60 @@ impl GithubClient {
 
 		Ok(check_runs)
 	}
+
+	pub async fn branch_exists(
+		&self,
+		owner: &str,
+		repo: &str,
+		branch: &str,
+	) -> Result<bool> {
+		let url = &format!(
+			"{}/repos/{}/{}/branches/{}",
+			self.github_api_url, owner, repo, branch
+		);
+		// https://docs.github.com/en/rest/branches/branches#get-a-branch
+		match self.get_status(url).await {
+			Ok(status) => Ok(status == 200),
+			Err(Error::Response { status, .. })
+				if status == StatusCode::NOT_FOUND =>
+			{
+				Ok(false)
+			}
+			Err(err) => Err(err),
+		}
+	}
+
+	/// Whether the branch protection of `branch` requires a linear history, i.e.
+	/// rejects merge commits
+	pub async fn requires_linear_history(
+		&self,
+		owner: &str,
+		repo: &str,
+		branch: &str,
+	) -> Result<bool> {
+		// https://docs.github.com/en/rest/branches/branch-protection#get-branch-protection
+		match self
+			.get::<String, GithubBranchProtection>(format!(
+				"{}/repos/{}/{}/branches/{}/protection",
+				self.github_api_url, owner, repo, branch
+			))
+			.await
+		{
+			Ok(protection) => Ok(protection
+				.required_linear_history
+				.map(|setting| setting.enabled)
+				.unwrap_or(false)),
+			// The branch is not protected (404) or the protection can't be read
+			// without admin permissions on the repository (403), in which case
+			// the merge is attempted as configured
+			Err(Error::Response { status, body })
+				if status == StatusCode::NOT_FOUND
+					|| (status == StatusCode::FORBIDDEN
+						&& !is_secondary_rate_limit(status, &body)) =>
+			{
+				Ok(false)
+			}
+			Err(err) => Err(err),
+		}
+	}
 }
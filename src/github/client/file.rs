@@ -1,5 +1,7 @@
+use reqwest::StatusCode;
+
 use super::GithubClient;
-use crate::{github::*, types::Result};
+use crate::{codeowners::CodeOwners, error::Error, github::*, types::Result};
 
 impl GithubClient {
 	pub async fn contents(
@@ -15,4 +17,67 @@ impl GithubClient {
 		);
 		self.get(url).await
 	}
+
+	/// The rules of the repository's `.github/CODEOWNERS` file at `ref_field`, if the file exists
+	pub async fn codeowners(
+		&self,
+		owner: &str,
+		repo: &str,
+		ref_field: &str,
+	) -> Result<Option<CodeOwners>> {
+		let contents = match self
+			.contents(owner, repo, ".github/CODEOWNERS", ref_field)
+			.await
+		{
+			Ok(contents) => contents,
+			Err(Error::Response { status, .. })
+				if status == StatusCode::NOT_FOUND =>
+			{
+				return Ok(None)
+			}
+			Err(err) => return Err(err),
+		};
+		let text = base64::decode(&contents.content.replace('\n', ""))
+			.map_err(|err| Error::Message {
+				msg: format!(
+					"Failed to decode the API content for the CODEOWNERS of {}/{}: {:?}",
+					owner, repo, err
+				),
+			})?;
+		Ok(Some(CodeOwners::parse(&String::from_utf8_lossy(&text))))
+	}
+	/// The repository's `Cargo.lock` at `ref_field`, if the file exists
+	pub async fn lockfile(
+		&self,
+		owner: &str,
+		repo: &str,
+		ref_field: &str,
+	) -> Result<Option<cargo_lock::Lockfile>> {
+		let contents =
+			match self.contents(owner, repo, "Cargo.lock", ref_field).await {
+				Ok(contents) => contents,
+				Err(Error::Response { status, .. })
+					if status == StatusCode::NOT_FOUND =>
+				{
+					return Ok(None)
+				}
+				Err(err) => return Err(err),
+			};
+		let text = base64::decode(&contents.content.replace('\n', ""))
+			.map_err(|err| Error::Message {
+				msg: format!(
+					"Failed to decode the API content for the lockfile of {}/{}: {:?}",
+					owner, repo, err
+				),
+			})?;
+		String::from_utf8_lossy(&text)
+			.parse::<cargo_lock::Lockfile>()
+			.map(Some)
+			.map_err(|err| Error::Message {
+				msg: format!(
+					"Failed to parse lockfile of {}/{}: {:?}",
+					owner, repo, err
+				),
+			})
+	}
 }
@@ -0,0 +1,263 @@
+use serde::Deserialize;
+
+use super::GithubClient;
+use crate::{error::Error, github::*, types::Result};
+
+// Fetches the statuses and the check runs of a commit at once. Only the first
+// 100 items of each connection are fetched; the caller should fall back to the
+// REST API if there are more.
+const COMMIT_CHECKS_AND_STATUSES_QUERY: &str = r#"
+query($owner: String!, $repo: String!, $sha: GitObjectID!) {
+	repository(owner: $owner, name: $repo) {
+		object(oid: $sha) {
+			... on Commit {
+				status {
+					contexts {
+						context
+						state
+						description
+						targetUrl
+					}
+				}
+				checkSuites(first: 100) {
+					pageInfo {
+						hasNextPage
+					}
+					nodes {
+						checkRuns(first: 100) {
+							pageInfo {
+								hasNextPage
+							}
+							nodes {
+								databaseId
+								name
+								status
+								conclusion
+								detailsUrl
+							}
+						}
+					}
+				}
+			}
+		}
+	}
+}
+"#;
+
+#[derive(Debug, Deserialize)]
+struct GraphqlResponse<T> {
+	data: Option<T>,
+	errors: Option<Vec<GraphqlError>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphqlError {
+	message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlPageInfo {
+	has_next_page: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphqlConnection<T> {
+	#[serde(rename = "pageInfo")]
+	page_info: Option<GraphqlPageInfo>,
+	nodes: Vec<T>,
+}
+
+impl<T> GraphqlConnection<T> {
+	fn has_next_page(&self) -> bool {
+		self.page_info
+			.as_ref()
+			.map(|page_info| page_info.has_next_page)
+			.unwrap_or(false)
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitChecksAndStatusesData {
+	repository: Option<CommitChecksAndStatusesRepository>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitChecksAndStatusesRepository {
+	object: Option<CommitChecksAndStatusesCommit>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommitChecksAndStatusesCommit {
+	status: Option<GraphqlCommitStatus>,
+	check_suites: Option<GraphqlConnection<GraphqlCheckSuite>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphqlCommitStatus {
+	contexts: Vec<GraphqlStatusContext>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlStatusContext {
+	context: String,
+	state: String,
+	description: Option<String>,
+	target_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlCheckSuite {
+	check_runs: Option<GraphqlConnection<GraphqlCheckRun>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlCheckRun {
+	database_id: Option<i64>,
+	name: String,
+	status: String,
+	conclusion: Option<String>,
+	details_url: Option<String>,
+}
+
+// GraphQL enums are uppercase, e.g. SUCCESS, while the REST API's are lowercase
+fn parse_graphql_enum<T: serde::de::DeserializeOwned>(
+	value: &str,
+) -> Result<T> {
+	serde_json::from_value(serde_json::Value::String(value.to_lowercase()))
+		.map_err(|err| Error::Message {
+			msg: format!("Unexpected GraphQL enum value {}: {}", value, err),
+		})
+}
+
+impl GithubClient {
+	fn graphql_url(&self) -> String {
+		// The GraphQL API of GitHub Enterprise Server lives at /api/graphql
+		// while its REST API lives at /api/v3
+		match self.github_api_url.strip_suffix("/v3") {
+			Some(base_url) => format!("{}/graphql", base_url),
+			None => format!("{}/graphql", self.github_api_url),
+		}
+	}
+
+	/// The check runs and the statuses of a commit, fetched through a single
+	/// GraphQL request. Errors if the response is incomplete, in which case the
+	/// REST API should be used instead.
+	pub async fn commit_checks_and_statuses(
+		&self,
+		owner: &str,
+		repo: &str,
+		sha: &str,
+	) -> Result<(Vec<GithubCheckRun>, Vec<GithubCommitStatus>)> {
+		let response = self
+			.post::<_, _, GraphqlResponse<CommitChecksAndStatusesData>>(
+				self.graphql_url(),
+				&serde_json::json!({
+					"query": COMMIT_CHECKS_AND_STATUSES_QUERY,
+					"variables": {
+						"owner": owner,
+						"repo": repo,
+						"sha": sha,
+					},
+				}),
+			)
+			.await?;
+
+		if let Some(errors) =
+			response.errors.filter(|errors| !errors.is_empty())
+		{
+			return Err(Error::Message {
+				msg: format!(
+					"GraphQL request for the checks and statuses of {}/{}@{} failed: {}",
+					owner,
+					repo,
+					sha,
+					errors
+						.into_iter()
+						.map(|err| err.message)
+						.collect::<Vec<_>>()
+						.join("; ")
+				),
+			});
+		}
+
+		let commit = response
+			.data
+			.and_then(|data| data.repository)
+			.and_then(|repository| repository.object)
+			.ok_or_else(|| Error::Message {
+				msg: format!(
+					"GraphQL response did not include the commit {}/{}@{}",
+					owner, repo, sha
+				),
+			})?;
+
+		let check_suites = match commit.check_suites {
+			Some(check_suites) => check_suites,
+			None => GraphqlConnection {
+				page_info: None,
+				nodes: vec![],
+			},
+		};
+		let is_incomplete = check_suites.has_next_page()
+			|| check_suites.nodes.iter().any(|check_suite| {
+				check_suite
+					.check_runs
+					.as_ref()
+					.map(|check_runs| check_runs.has_next_page())
+					.unwrap_or(false)
+			});
+		if is_incomplete {
+			return Err(Error::Message {
+				msg: format!(
+					"{}/{}@{} has too many check runs to be fetched through a single GraphQL request",
+					owner, repo, sha
+				),
+			});
+		}
+
+		let mut check_runs = vec![];
+		for check_run in check_suites
+			.nodes
+			.into_iter()
+			.filter_map(|check_suite| check_suite.check_runs)
+			.flat_map(|check_runs| check_runs.nodes)
+		{
+			check_runs.push(GithubCheckRun {
+				id: check_run.database_id.unwrap_or(0),
+				name: check_run.name,
+				status: parse_graphql_enum(&check_run.status)?,
+				conclusion: check_run
+					.conclusion
+					.as_deref()
+					.map(parse_graphql_enum)
+					.transpose()?,
+				head_sha: sha.to_string(),
+				html_url: check_run.details_url,
+			});
+		}
+
+		// GraphQL only provides the latest status of each context, which is
+		// what the REST statuses' IDs are used for, thus they're left out
+		let mut statuses = vec![];
+		for context in commit
+			.status
+			.map(|status| status.contexts)
+			.unwrap_or_default()
+		{
+			statuses.push(GithubCommitStatus {
+				id: 0,
+				context: context.context,
+				state: parse_graphql_enum(&context.state)?,
+				description: context.description,
+				target_url: context.target_url,
+			});
+		}
+
+		Ok((check_runs, statuses))
+	}
+}
@@ -1,5 +1,76 @@
+use std::{
+	collections::{hash_map::DefaultHasher, HashMap},
+	hash::{Hash, Hasher},
+	time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
 use super::GithubClient;
-use crate::types::Result;
+use crate::{
+	constants::ISSUE_COMMENT_DEDUPLICATION_TTL, github::GithubReaction,
+	types::Result,
+};
+
+#[derive(Deserialize)]
+struct CreatedIssueComment {
+	id: i64,
+}
+
+lazy_static::lazy_static! {
+	static ref RECENT_ISSUE_COMMENTS: parking_lot::Mutex<HashMap<(String, String, i64, u64), Instant>> = {
+		parking_lot::Mutex::new(HashMap::new())
+	};
+}
+
+fn recent_issue_comment_key(
+	owner: &str,
+	repo: &str,
+	number: i64,
+	comment: &str,
+) -> (String, String, i64, u64) {
+	let content_hash = {
+		let mut hasher = DefaultHasher::new();
+		comment.hash(&mut hasher);
+		hasher.finish()
+	};
+	(owner.to_string(), repo.to_string(), number, content_hash)
+}
+
+fn was_comment_posted_recently(
+	owner: &str,
+	repo: &str,
+	number: i64,
+	comment: &str,
+) -> bool {
+	let ttl = Duration::from_secs(ISSUE_COMMENT_DEDUPLICATION_TTL);
+	let mut recent_comments = RECENT_ISSUE_COMMENTS.lock();
+	recent_comments.retain(|_, posted_at| posted_at.elapsed() < ttl);
+	if recent_comments
+		.contains_key(&recent_issue_comment_key(owner, repo, number, comment))
+	{
+		log::info!(
+			"Skipping a comment on {}/{}#{} since it was already posted recently",
+			owner,
+			repo,
+			number
+		);
+		return true;
+	}
+	false
+}
+
+fn remember_posted_comment(
+	owner: &str,
+	repo: &str,
+	number: i64,
+	comment: &str,
+) {
+	RECENT_ISSUE_COMMENTS.lock().insert(
+		recent_issue_comment_key(owner, repo, number, comment),
+		Instant::now(),
+	);
+}
 
 impl GithubClient {
 	pub async fn create_issue_comment(
@@ -18,17 +89,99 @@ impl GithubClient {
 			.map(|_| ())
 	}
 
-	pub async fn acknowledge_issue_comment(
+	/// Like `create_issue_comment`, but the comment is skipped if the same
+	/// content was already posted on the issue within the last
+	/// `ISSUE_COMMENT_DEDUPLICATION_TTL` seconds, e.g. when an operation is
+	/// retried and fails in the same way again. Meant for the comments which
+	/// processbot posts on its own, not for the replies to commands.
+	pub async fn create_issue_comment_once(
+		&self,
+		owner: &str,
+		repo: &str,
+		number: i64,
+		comment: &str,
+	) -> Result<()> {
+		if was_comment_posted_recently(owner, repo, number, comment) {
+			return Ok(());
+		}
+
+		self.create_issue_comment(owner, repo, number, comment)
+			.await?;
+		remember_posted_comment(owner, repo, number, comment);
+
+		Ok(())
+	}
+
+	/// Like `create_issue_comment_once`, but returns the ID of the comment, if
+	/// it was posted, so that it can be edited later
+	pub async fn create_issue_comment_with_id_once(
+		&self,
+		owner: &str,
+		repo: &str,
+		number: i64,
+		comment: &str,
+	) -> Result<Option<i64>> {
+		if was_comment_posted_recently(owner, repo, number, comment) {
+			return Ok(None);
+		}
+
+		let comment_id = self
+			.create_issue_comment_with_id(owner, repo, number, comment)
+			.await?;
+		remember_posted_comment(owner, repo, number, comment);
+
+		Ok(Some(comment_id))
+	}
+
+	/// Like `create_issue_comment`, but returns the ID of the comment so that it can be edited
+	/// later
+	pub async fn create_issue_comment_with_id(
+		&self,
+		owner: &str,
+		repo: &str,
+		number: i64,
+		comment: &str,
+	) -> Result<i64> {
+		let url = format!(
+			"{}/repos/{}/{}/issues/{}/comments",
+			self.github_api_url, owner, repo, number
+		);
+		self.post::<_, _, CreatedIssueComment>(
+			&url,
+			&serde_json::json!({ "body": comment }),
+		)
+		.await
+		.map(|comment| comment.id)
+	}
+
+	pub async fn update_issue_comment(
+		&self,
+		owner: &str,
+		repo: &str,
+		comment_id: i64,
+		comment: &str,
+	) -> Result<()> {
+		let url = format!(
+			"{}/repos/{}/{}/issues/comments/{}",
+			self.github_api_url, owner, repo, comment_id
+		);
+		self.patch_response(&url, &serde_json::json!({ "body": comment }))
+			.await
+			.map(|_| ())
+	}
+
+	pub async fn create_reaction(
 		&self,
 		owner: &str,
 		repo: &str,
 		comment_id: i64,
+		reaction: GithubReaction,
 	) -> Result<()> {
 		let url = format!(
 			"{}/repos/{}/{}/issues/comments/{}/reactions",
 			self.github_api_url, owner, repo, comment_id
 		);
-		self.post_response(&url, &serde_json::json!({ "content": "+1" }))
+		self.post_response(&url, &serde_json::json!({ "content": reaction }))
 			.await
 			.map(|_| ())
 	}
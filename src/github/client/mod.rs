@@ -1,29 +1,93 @@
-use std::{borrow::Cow, time::SystemTime};
+use std::{
+	borrow::Cow,
+	time::{Instant, SystemTime},
+};
 
 use chrono::{DateTime, Duration, Utc};
-use reqwest::{header, IntoUrl, RequestBuilder, Response};
+use reqwest::{header, IntoUrl, RequestBuilder, Response, StatusCode};
 use serde::Serialize;
 use snafu::ResultExt;
 
 mod commit;
 mod file;
+mod graphql;
 mod issue;
 mod org;
 mod pull_request;
 
 use crate::{
 	config::MainConfig,
+	constants::{
+		GITHUB_POOL_IDLE_TIMEOUT, GITHUB_TCP_KEEPALIVE,
+		SECONDARY_RATE_LIMIT_MAX_BACKOFF, USER_AGENT,
+	},
 	error::{self, Error},
 	github,
 	types::Result,
 };
 
+lazy_static::lazy_static! {
+	static ref TOKEN_CACHE: parking_lot::Mutex<Option<(DateTime<Utc>, String)>> = {
+		parking_lot::Mutex::new(None)
+	};
+	// Until when the API asked for requests to be held off through Retry-After
+	static ref RETRY_AFTER: parking_lot::Mutex<Option<Instant>> = {
+		parking_lot::Mutex::new(None)
+	};
+	// Until when every request is held off after a secondary rate limit was hit
+	static ref THROTTLED_UNTIL: parking_lot::Mutex<Option<Instant>> = {
+		parking_lot::Mutex::new(None)
+	};
+	// How many secondary rate limits were hit in a row
+	static ref SECONDARY_RATE_LIMIT_STRIKES: parking_lot::Mutex<u32> = {
+		parking_lot::Mutex::new(0)
+	};
+}
+
+/// The connection settings of the HTTP client which is used for the Github API.
+/// HTTP/2 is negotiated with the server through ALPN; prior knowledge is not
+/// assumed since proxies in front of a Github Enterprise Server might only
+/// speak HTTP/1.1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GithubClientOptions {
+	pub pool_max_idle_per_host: usize,
+	pub pool_idle_timeout: std::time::Duration,
+	pub tcp_keepalive: std::time::Duration,
+	pub http2_adaptive_window: bool,
+}
+
+impl GithubClientOptions {
+	pub fn from_config(config: &MainConfig) -> Self {
+		Self {
+			pool_max_idle_per_host: config.github_pool_max_idle_per_host,
+			pool_idle_timeout: std::time::Duration::from_secs(
+				GITHUB_POOL_IDLE_TIMEOUT,
+			),
+			tcp_keepalive: std::time::Duration::from_secs(GITHUB_TCP_KEEPALIVE),
+			http2_adaptive_window: true,
+		}
+	}
+
+	fn build_client(&self) -> reqwest::Client {
+		reqwest::Client::builder()
+			.pool_max_idle_per_host(self.pool_max_idle_per_host)
+			.pool_idle_timeout(self.pool_idle_timeout)
+			.tcp_keepalive(self.tcp_keepalive)
+			.http2_adaptive_window(self.http2_adaptive_window)
+			.build()
+			.expect("Failed to build the HTTP client of the Github API")
+	}
+}
+
 pub struct GithubClient {
 	client: reqwest::Client,
+	options: GithubClientOptions,
 	private_key: Vec<u8>,
 	installation_login: String,
 	github_app_id: usize,
 	github_api_url: String,
+	request_timeout: std::time::Duration,
+	secondary_rate_limit_backoff: std::time::Duration,
 }
 
 macro_rules! impl_methods_with_body {
@@ -76,11 +140,86 @@ macro_rules! impl_methods_with_body {
 	}
 }
 
-async fn handle_response(response: Response) -> Result<Response> {
+// Github's abuse detection mechanism responds with a message such as "You have
+// exceeded a secondary rate limit", sometimes without a Retry-After
+fn is_secondary_rate_limit(
+	status: StatusCode,
+	body: &serde_json::Value,
+) -> bool {
+	(status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS)
+		&& body
+			.get("message")
+			.and_then(|message| message.as_str())
+			.map(|message| {
+				let message = message.to_lowercase();
+				message.contains("secondary rate limit")
+					|| message.contains("abuse detection")
+			})
+			.unwrap_or(false)
+}
+
+// Holds off every request, not only the one which hit the limit, for a duration
+// which doubles with each limit hit in a row
+fn throttle_after_secondary_rate_limit(
+	backoff: std::time::Duration,
+	retry_after: Option<std::time::Duration>,
+) {
+	let strikes = {
+		let mut strikes = SECONDARY_RATE_LIMIT_STRIKES.lock();
+		*strikes = strikes.saturating_add(1);
+		*strikes
+	};
+	let delay = backoff
+		.saturating_mul(2u32.saturating_pow(strikes - 1))
+		.min(std::time::Duration::from_secs(
+			SECONDARY_RATE_LIMIT_MAX_BACKOFF,
+		))
+		.max(retry_after.unwrap_or_default());
+	log::error!(
+		"Hit a secondary rate limit of the Github API ({} in a row); holding off every request for {:?}",
+		strikes,
+		delay
+	);
+	*THROTTLED_UNTIL.lock() = Some(Instant::now() + delay);
+}
+
+// Waits until the throttle set by `throttle_after_secondary_rate_limit` expires
+async fn wait_for_throttle() {
+	let remaining = (*THROTTLED_UNTIL.lock())
+		.and_then(|until| until.checked_duration_since(Instant::now()));
+	if let Some(remaining) = remaining {
+		log::info!(
+			"Holding off a request for {:?} due to a secondary rate limit",
+			remaining
+		);
+		tokio::time::sleep(remaining).await;
+	}
+}
+
+async fn handle_response(
+	response: Response,
+	secondary_rate_limit_backoff: std::time::Duration,
+) -> Result<Response> {
 	log::debug!("response: {:?}", &response);
 
+	// Github sends the delay in seconds, e.g. when a secondary rate limit is hit
+	let retry_after = response
+		.headers()
+		.get(header::RETRY_AFTER)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.parse::<u64>().ok())
+		.map(std::time::Duration::from_secs);
+	if let Some(retry_after) = retry_after {
+		log::info!(
+			"The API asked for requests to be held off for {:?}",
+			retry_after
+		);
+		*RETRY_AFTER.lock() = Some(Instant::now() + retry_after);
+	}
+
 	let status = response.status();
 	if status.is_success() {
+		*SECONDARY_RATE_LIMIT_STRIKES.lock() = 0;
 		Ok(response)
 	} else {
 		let text = response.text().await.context(error::Http)?;
@@ -95,21 +234,51 @@ async fn handle_response(response: Response) -> Result<Response> {
 			serde_json::json!({ "error_message": text })
 		};
 
+		if is_secondary_rate_limit(status, &body) {
+			throttle_after_secondary_rate_limit(
+				secondary_rate_limit_backoff,
+				retry_after,
+			);
+		}
+
 		error::Response { status, body }.fail()
 	}
 }
 
 impl GithubClient {
 	pub fn new(config: &MainConfig) -> Self {
+		let options = GithubClientOptions::from_config(config);
 		Self {
 			private_key: config.private_key.clone(),
 			installation_login: config.installation_login.clone(),
 			github_app_id: config.github_app_id,
-			github_api_url: config.github_api_url.clone(),
-			client: reqwest::Client::default(),
+			github_api_url: config
+				.github_api_url_for(&config.installation_login)
+				.to_string(),
+			request_timeout: std::time::Duration::from_secs(
+				config.github_request_timeout_secs,
+			),
+			secondary_rate_limit_backoff: std::time::Duration::from_secs(
+				config.github_secondary_rate_limit_backoff,
+			),
+			client: options.build_client(),
+			options,
 		}
 	}
 
+	/// The settings which the HTTP client was built with
+	pub fn options(&self) -> &GithubClientOptions {
+		&self.options
+	}
+
+	/// How much longer requests should be held off for according to the latest
+	/// Retry-After sent by the API, if at all
+	pub fn retry_after(&self) -> Option<std::time::Duration> {
+		(*RETRY_AFTER.lock())
+			.and_then(|until| until.checked_duration_since(Instant::now()))
+			.filter(|remaining| !remaining.is_zero())
+	}
+
 	impl_methods_with_body! {
 		post: post_response,
 		put: put_response,
@@ -120,12 +289,6 @@ impl GithubClient {
 	pub async fn auth_token(&self) -> Result<String> {
 		log::debug!("auth_token");
 
-		lazy_static::lazy_static! {
-			static ref TOKEN_CACHE: parking_lot::Mutex<Option<(DateTime<Utc>, String)>> = {
-				parking_lot::Mutex::new(None)
-			};
-		}
-
 		// Add some padding for avoiding token use just as it's about to expire
 		let installation_lease_with_padding =
 			Utc::now() + Duration::minutes(10);
@@ -142,29 +305,13 @@ impl GithubClient {
 			return Ok(token);
 		}
 
-		let installations: Vec<github::GithubInstallation> = self
-			.jwt_get(&format!("{}/app/installations", self.github_api_url,))
-			.await?;
-
-		let installation = if let Some(installation) = installations
-			.iter()
-			.find(|inst| inst.account.login == self.installation_login)
-		{
-			installation
-		} else {
-			return Err(Error::Message {
-				msg: format!(
-					"Installation for login {} could not be found",
-					self.installation_login
-				),
-			});
-		};
+		let installation_id = self.installation_id().await?;
 
 		let install_token: github::GithubInstallationToken = self
 			.jwt_post(
 				&format!(
 					"{}/app/installations/{}/access_tokens",
-					self.github_api_url, installation.id
+					self.github_api_url, installation_id
 				),
 				&serde_json::json!({}),
 			)
@@ -175,13 +322,115 @@ impl GithubClient {
 			.expires_at
 			.map_or(default_exp, |t| t.parse().unwrap_or(default_exp));
 		let token = install_token.token;
+		crate::shell::set_secret("installation token", &token);
 
 		*TOKEN_CACHE.lock() = Some((expiry, token.clone()));
 
 		Ok(token)
 	}
 
+	/// The ID of the Github App installation for `installation_login`
+	pub async fn installation_id(&self) -> Result<i64> {
+		lazy_static::lazy_static! {
+			static ref INSTALLATION_ID_CACHE: parking_lot::Mutex<Option<(String, i64)>> = {
+				parking_lot::Mutex::new(None)
+			};
+		}
+
+		let cached_installation_id = INSTALLATION_ID_CACHE
+			.lock()
+			.as_ref()
+			.filter(|(login, _)| login == &self.installation_login)
+			.map(|(_, id)| *id);
+		if let Some(installation_id) = cached_installation_id {
+			return Ok(installation_id);
+		}
+
+		// The App might be installed in more accounts than fit in a single page
+		let mut page = 1;
+		const PER_PAGE_MAX: usize = 100;
+
+		let installation = loop {
+			let page_installations: Vec<github::GithubInstallation> = self
+				.jwt_get(&format!(
+					"{}/app/installations?per_page={}&page={}",
+					self.github_api_url, PER_PAGE_MAX, page
+				))
+				.await?;
+
+			let should_break = page_installations.len() < PER_PAGE_MAX;
+
+			if let Some(installation) = page_installations
+				.into_iter()
+				.find(|inst| inst.account.login == self.installation_login)
+			{
+				break installation;
+			}
+
+			if should_break {
+				return Err(Error::Message {
+					msg: format!(
+						"Installation for login {} could not be found",
+						self.installation_login
+					),
+				});
+			}
+
+			page += 1;
+		};
+
+		*INSTALLATION_ID_CACHE.lock() =
+			Some((self.installation_login.clone(), installation.id));
+
+		Ok(installation.id)
+	}
+
+	/// The login which the Github App uses for commenting, e.g.
+	/// "parity-processbot[bot]", which is unrelated to `installation_login`
+	pub async fn app_login(&self) -> Result<String> {
+		lazy_static::lazy_static! {
+			static ref APP_LOGIN_CACHE: parking_lot::Mutex<Option<String>> = {
+				parking_lot::Mutex::new(None)
+			};
+		}
+
+		if let Some(app_login) = APP_LOGIN_CACHE.lock().as_ref() {
+			return Ok(app_login.clone());
+		}
+
+		let app: github::GithubApp = self
+			.jwt_get(&format!("{}/app", self.github_api_url))
+			.await?;
+		let app_login = format!("{}[bot]", app.slug);
+
+		*APP_LOGIN_CACHE.lock() = Some(app_login.clone());
+
+		Ok(app_login)
+	}
+
 	async fn execute(&self, builder: RequestBuilder) -> Result<Response> {
+		// Kept for retrying the request in case the cached token is rejected
+		let retry_builder = builder.try_clone();
+
+		let result = self.execute_once(builder).await;
+		match (&result, retry_builder) {
+			// The token might have been revoked before its expiration, in which
+			// case a fresh one is fetched. Retry only once so that requests which
+			// are legitimately unauthorized don't loop.
+			(Err(Error::Response { status, .. }), Some(retry_builder))
+				if *status == StatusCode::UNAUTHORIZED =>
+			{
+				log::info!(
+					"The installation token was rejected; retrying with a fresh one"
+				);
+				*TOKEN_CACHE.lock() = None;
+				self.execute_once(retry_builder).await
+			}
+			_ => result,
+		}
+	}
+
+	async fn execute_once(&self, builder: RequestBuilder) -> Result<Response> {
 		let request = builder
 			.bearer_auth(self.auth_token().await?)
 			.header(
@@ -200,14 +449,16 @@ impl GithubClient {
 				header::ACCEPT,
 				"application/vnd.github.machine-man-preview+json",
 			)
-			.header(header::USER_AGENT, "parity-processbot/0.0.1")
-			.timeout(std::time::Duration::from_secs(10))
+			.header(header::USER_AGENT, USER_AGENT)
+			.timeout(self.request_timeout)
 			.build()
 			.context(error::Http)?;
 
 		log::debug!("request: {:?}", &request);
+		wait_for_throttle().await;
 		handle_response(
 			self.client.execute(request).await.context(error::Http)?,
+			self.secondary_rate_limit_backoff,
 		)
 		.await
 	}
@@ -237,19 +488,20 @@ impl GithubClient {
 
 	async fn jwt_execute(&self, builder: RequestBuilder) -> Result<Response> {
 		log::debug!("jwt_execute");
+		wait_for_throttle().await;
 		let response = builder
 			.bearer_auth(&self.create_jwt()?)
 			.header(
 				header::ACCEPT,
 				"application/vnd.github.machine-man-preview+json",
 			)
-			.header(header::USER_AGENT, "parity-processbot/0.0.1")
-			.timeout(std::time::Duration::from_secs(10))
+			.header(header::USER_AGENT, USER_AGENT)
+			.timeout(self.request_timeout)
 			.send()
 			.await
 			.context(error::Http)?;
 
-		handle_response(response).await
+		handle_response(response, self.secondary_rate_limit_backoff).await
 	}
 
 	async fn jwt_get<T>(&self, url: impl IntoUrl) -> Result<T>
@@ -1,5 +1,7 @@
+use reqwest::StatusCode;
+
 use super::GithubClient;
-use crate::types::Result;
+use crate::{error::Error, types::Result};
 
 impl GithubClient {
 	pub async fn org_member(&self, org: &str, username: &str) -> Result<bool> {
@@ -11,4 +13,42 @@ impl GithubClient {
 		// https://docs.github.com/en/rest/orgs/members#check-organization-membership-for-a-user--code-samples
 		Ok(status == 204)
 	}
+
+	pub async fn team_member(
+		&self,
+		org: &str,
+		team_slug: &str,
+		username: &str,
+	) -> Result<bool> {
+		let url = &format!(
+			"{}/orgs/{}/teams/{}/memberships/{}",
+			self.github_api_url, org, team_slug, username
+		);
+		// https://docs.github.com/en/rest/teams/members#get-team-membership-for-a-user
+		match self.get_status(url).await {
+			Ok(status) => Ok(status == 200),
+			Err(Error::Response { status, .. })
+				if status == StatusCode::NOT_FOUND =>
+			{
+				Ok(false)
+			}
+			Err(err) => Err(err),
+		}
+	}
+	/// Whether `username` is a member of any of `teams`, which are written as
+	/// `org/team`
+	pub async fn member_of_any_team(
+		&self,
+		teams: &[String],
+		username: &str,
+	) -> Result<bool> {
+		for team in teams {
+			if let Some((org, team_slug)) = team.split_once('/') {
+				if self.team_member(org, team_slug, username).await? {
+					return Ok(true);
+				}
+			}
+		}
+		Ok(false)
+	}
 }
@@ -1,7 +1,11 @@
 use super::GithubClient;
 use crate::{
-	companion::CompanionReferenceTrailItem,
+	companion::{
+		is_companion_order_item_for, parse_companion_order, resolve_companions,
+		CompanionReferenceTrailItem,
+	},
 	config::MainConfig,
+	constants::{MERGE_PRIORITY_NORMAL, MERGE_REQUEST_SCHEMA_VERSION},
 	error::Error,
 	github::*,
 	merge_request::{MergeRequest, MergeRequestDependency},
@@ -22,20 +26,204 @@ impl GithubClient {
 		.await
 	}
 
+	/// The open pull requests of `owner/repo` whose head is `branch` of that same repository
+	pub async fn open_pull_requests_for_branch(
+		&self,
+		owner: &str,
+		repo: &str,
+		branch: &str,
+	) -> Result<Vec<GithubPullRequest>> {
+		// https://docs.github.com/en/rest/pulls/pulls#list-pull-requests
+		self.get(format!(
+			"{}/repos/{}/{}/pulls?state=open&head={}:{}",
+			self.github_api_url, owner, repo, owner, branch
+		))
+		.await
+	}
+
 	pub async fn merge_pull_request(
 		&self,
 		owner: &str,
 		repo: &str,
 		number: i64,
 		head_sha: &str,
+		merge_method: GithubMergeMethod,
+		commit_title: Option<&str>,
+		commit_message: Option<&str>,
 	) -> Result<()> {
 		let url = format!(
 			"{}/repos/{}/{}/pulls/{}/merge",
 			self.github_api_url, owner, repo, number
 		);
-		let params = serde_json::json!({
+		let mut params = serde_json::json!({
 			"sha": head_sha,
-			"merge_method": "squash"
+			"merge_method": merge_method
+		});
+		// Github generates the commit's title and message if they're omitted
+		if let Some(commit_title) = commit_title {
+			params["commit_title"] = commit_title.into();
+		}
+		if let Some(commit_message) = commit_message {
+			params["commit_message"] = commit_message.into();
+		}
+		self.put_response(&url, &params).await.map(|_| ())
+	}
+
+	pub async fn pull_request_files(
+		&self,
+		owner: &str,
+		repo: &str,
+		number: i64,
+	) -> Result<Vec<GithubPullRequestFile>> {
+		let mut files = vec![];
+		// https://docs.github.com/en/rest/pulls/pulls#list-pull-requests-files
+		let mut page = 1;
+		loop {
+			let page_files: Vec<GithubPullRequestFile> = self
+				.get(format!(
+					"{}/repos/{}/{}/pulls/{}/files?per_page=100&page={}",
+					self.github_api_url, owner, repo, number, page
+				))
+				.await?;
+			if page_files.is_empty() {
+				break;
+			}
+			files.extend(page_files);
+			page += 1;
+		}
+		Ok(files)
+	}
+
+	pub async fn pull_request_reviews(
+		&self,
+		owner: &str,
+		repo: &str,
+		number: i64,
+	) -> Result<Vec<GithubPullRequestReview>> {
+		let mut reviews = vec![];
+		// https://docs.github.com/en/rest/pulls/reviews#list-reviews-for-a-pull-request
+		let mut page = 1;
+		loop {
+			let page_reviews: Vec<GithubPullRequestReview> = self
+				.get(format!(
+					"{}/repos/{}/{}/pulls/{}/reviews?per_page=100&page={}",
+					self.github_api_url, owner, repo, number, page
+				))
+				.await?;
+			if page_reviews.is_empty() {
+				break;
+			}
+			reviews.extend(page_reviews);
+			page += 1;
+		}
+		Ok(reviews)
+	}
+
+	/// Whether `username` owns every file changed by the pull request according to the CODEOWNERS
+	/// of its base branch. The head branch's CODEOWNERS is not trusted since it's under the
+	/// control of the pull request's author.
+	pub async fn is_code_owner_of_pull_request(
+		&self,
+		pr: &GithubPullRequest,
+		username: &str,
+	) -> Result<bool> {
+		let owner = &pr.base.repo.owner.login;
+		let repo = &pr.base.repo.name;
+
+		let codeowners =
+			match self.codeowners(owner, repo, &pr.base.ref_field).await? {
+				Some(codeowners) => codeowners,
+				None => return Ok(false),
+			};
+
+		let files = self.pull_request_files(owner, repo, pr.number).await?;
+		if files.is_empty() {
+			return Ok(false);
+		}
+
+		let user_handle = format!("@{}", username.to_lowercase());
+		for path in files.iter().flat_map(|file| {
+			std::iter::once(&file.filename).chain(&file.previous_filename)
+		}) {
+			let owners = match codeowners.owners_of(path) {
+				Some(owners) => owners,
+				None => return Ok(false),
+			};
+
+			let mut is_owner = false;
+			for path_owner in owners {
+				let path_owner = path_owner.to_lowercase();
+				is_owner = if path_owner == user_handle {
+					true
+				} else if let Some((org, team_slug)) = path_owner
+					.strip_prefix('@')
+					.and_then(|team| team.split_once('/'))
+				{
+					self.team_member(org, team_slug, username).await?
+				} else {
+					// Owners specified by email can't be related to the user
+					false
+				};
+				if is_owner {
+					break;
+				}
+			}
+			if !is_owner {
+				log::info!(
+					"{} does not own {} in {} according to CODEOWNERS",
+					username,
+					path,
+					pr.html_url
+				);
+				return Ok(false);
+			}
+		}
+
+		Ok(true)
+	}
+
+	/// Request reviews from users or teams, which are written as `org/team`
+	pub async fn request_reviewers(
+		&self,
+		owner: &str,
+		repo: &str,
+		number: i64,
+		reviewers: &[String],
+	) -> Result<()> {
+		let (team_reviewers, reviewers): (Vec<&String>, Vec<&String>) =
+			reviewers
+				.iter()
+				.partition(|reviewer| reviewer.contains('/'));
+		let team_reviewers = team_reviewers
+			.into_iter()
+			.filter_map(|team| team.split_once('/').map(|(_, slug)| slug))
+			.collect::<Vec<_>>();
+		let url = format!(
+			"{}/repos/{}/{}/pulls/{}/requested_reviewers",
+			self.github_api_url, owner, repo, number
+		);
+		let params = serde_json::json!({
+			"reviewers": reviewers,
+			"team_reviewers": team_reviewers,
+		});
+		self.post_response(&url, &params).await.map(|_| ())
+	}
+
+	/// Merge the base branch into the pull request's branch. Github updates the
+	/// branch asynchronously, thus the new HEAD might not be visible right away.
+	pub async fn update_pull_request_branch(
+		&self,
+		owner: &str,
+		repo: &str,
+		number: i64,
+		expected_head_sha: &str,
+	) -> Result<()> {
+		let url = format!(
+			"{}/repos/{}/{}/pulls/{}/update-branch",
+			self.github_api_url, owner, repo, number
+		);
+		let params = serde_json::json!({
+			"expected_head_sha": expected_head_sha,
 		});
 		self.put_response(&url, &params).await.map(|_| ())
 	}
@@ -47,11 +235,17 @@ impl GithubClient {
 		requested_by: &str,
 		companion_reference_trail: &[CompanionReferenceTrailItem],
 	) -> Result<Option<Vec<MergeRequest>>, Error> {
-		let companions =
-			match pr.parse_all_companions(companion_reference_trail) {
-				Some(companions) => companions,
-				None => return Ok(None),
-			};
+		let companions = match resolve_companions(
+			self,
+			config,
+			pr,
+			companion_reference_trail,
+		)
+		.await?
+		{
+			Some(companions) => companions,
+			None => return Ok(None),
+		};
 
 		let parent_dependency = MergeRequestDependency {
 			sha: (&pr.head.sha).into(),
@@ -75,10 +269,17 @@ impl GithubClient {
 					number: comp_pr.number,
 					html_url: comp_pr.html_url,
 					requested_by: requested_by.into(),
-					dependencies: Some(vec![parent_dependency]),
+					dependencies: Some(vec![parent_dependency.clone()]),
+					attempts: 0,
+					priority: MERGE_PRIORITY_NORMAL,
+					not_before: None,
+					queued_at: None,
+					seq: None,
+					last_attempt_at: None,
+					schema_version: MERGE_REQUEST_SCHEMA_VERSION,
 				}]
 			} else {
-				let base_dependencies = vec![parent_dependency];
+				let base_dependencies = vec![parent_dependency.clone()];
 
 				let mut dependents = vec![];
 				for comp in &companions {
@@ -173,13 +374,82 @@ impl GithubClient {
 						html_url: comp_pr.html_url,
 						requested_by: requested_by.into(),
 						dependencies: Some(dependencies),
+						attempts: 0,
+						priority: MERGE_PRIORITY_NORMAL,
+						not_before: None,
+						queued_at: None,
+						seq: None,
+						last_attempt_at: None,
+						schema_version: MERGE_REQUEST_SCHEMA_VERSION,
 					})
 				}
 
 				dependents
 			};
 
+		// An explicit order from the author overrides the one derived from the lockfiles
+		let dependents =
+			match pr.body.as_deref().and_then(parse_companion_order) {
+				Some(order) => apply_companion_order(
+					pr,
+					&order,
+					dependents,
+					&parent_dependency,
+				)?,
+				None => dependents,
+			};
+
 		log::info!("Dependents of {}: {:?}", pr.html_url, dependents);
 		Ok(Some(dependents))
 	}
 }
+
+// Sorts the dependents in the order listed in the `companion-order` of the pull
+// request; each listed companion then only depends on the pull request and on
+// the companion listed before it. The unlisted companions keep their derived
+// dependencies and are placed after the listed ones.
+fn apply_companion_order(
+	pr: &GithubPullRequest,
+	order: &[String],
+	mut dependents: Vec<MergeRequest>,
+	parent_dependency: &MergeRequestDependency,
+) -> Result<Vec<MergeRequest>, Error> {
+	let mut ordered_dependents: Vec<MergeRequest> =
+		Vec::with_capacity(dependents.len());
+	for item in order {
+		let position = dependents
+			.iter()
+			.position(|dependent| {
+				is_companion_order_item_for(
+					item,
+					&dependent.owner,
+					&dependent.repo,
+				)
+			})
+			.ok_or_else(|| Error::Message {
+				msg: format!(
+					"The companion-order of {} lists {}, which is not one of its companions or is listed more than once",
+					pr.html_url, item
+				),
+			})?;
+		let mut dependent = dependents.remove(position);
+
+		let mut dependencies = vec![parent_dependency.clone()];
+		if let Some(previous) = ordered_dependents.last() {
+			dependencies.push(MergeRequestDependency {
+				sha: previous.sha.clone(),
+				owner: previous.owner.clone(),
+				repo: previous.repo.clone(),
+				number: previous.number,
+				html_url: previous.html_url.clone(),
+				is_directly_referenced: false,
+			});
+		}
+		dependent.dependencies = Some(dependencies);
+
+		ordered_dependents.push(dependent);
+	}
+	ordered_dependents.extend(dependents);
+
+	Ok(ordered_dependents)
+}
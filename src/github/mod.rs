@@ -1,11 +1,14 @@
+use chrono::{DateTime, Utc};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 use crate::{
 	bot::parse_bot_comment_from_text,
-	companion::{parse_all_companions, CompanionReferenceTrailItem},
+	companion::{
+		parse_all_companions, CompanionMatcher, CompanionReferenceTrailItem,
+	},
 	error::*,
-	types::PlaceholderDeserializationItem,
+	types::{PlaceholderDeserializationItem, Result},
 	OWNER_AND_REPO_SEQUENCE, PR_HTML_URL_REGEX,
 };
 
@@ -20,18 +23,34 @@ pub struct GithubPullRequest {
 	pub url: String,
 	pub html_url: String,
 	pub number: i64,
+	pub title: String,
 	pub user: Option<GithubUser>,
 	pub body: Option<String>,
 	pub head: GithubPullRequestHead,
 	pub base: GithubPullRequestBase,
 	pub mergeable: Option<bool>,
+	pub mergeable_state: Option<String>,
+	#[serde(default)]
+	pub draft: bool,
 	pub merged: bool,
 	pub maintainer_can_modify: bool,
+	pub state: GithubPullRequestState,
 }
 
 impl GithubPullRequest {
+	/// The repository of the source branch, which is needed for pushing to it
+	pub fn head_repository(&self) -> Result<&GithubPullRequestHeadRepository> {
+		self.head
+			.repo
+			.as_ref()
+			.ok_or_else(|| Error::HeadRepositoryDeleted {
+				html_url: self.html_url.clone(),
+			})
+	}
+
 	pub fn parse_all_companions(
 		&self,
+		companion_matcher: &CompanionMatcher,
 		companion_reference_trail: &[CompanionReferenceTrailItem],
 	) -> Option<Vec<PullRequestDetailsWithHtmlUrl>> {
 		let mut next_trail =
@@ -41,9 +60,9 @@ impl GithubPullRequest {
 			owner: (&self.base.repo.owner.login).into(),
 			repo: (&self.base.repo.name).into(),
 		});
-		self.body
-			.as_ref()
-			.map(|body| parse_all_companions(&next_trail, body))
+		self.body.as_ref().map(|body| {
+			parse_all_companions(companion_matcher, &next_trail, body)
+		})
 	}
 }
 
@@ -52,6 +71,34 @@ pub struct GithubFileContents {
 	pub content: String,
 }
 
+// A file changed by a pull request
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GithubPullRequestFile {
+	pub filename: String,
+	// Only provided for renamed files
+	pub previous_filename: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GithubPullRequestReviewState {
+	Approved,
+	ChangesRequested,
+	Commented,
+	Dismissed,
+	#[serde(other)]
+	Unknown,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GithubPullRequestReview {
+	// Not provided for reviews of deleted accounts
+	pub user: Option<GithubUser>,
+	pub state: GithubPullRequestReviewState,
+	// The head SHA of the pull request at the time of the review
+	pub commit_id: Option<String>,
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GithubPullRequestBase {
 	#[serde(rename = "ref")]
@@ -107,6 +154,11 @@ pub struct GithubInstallation {
 	pub account: GithubUser,
 }
 
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GithubApp {
+	pub slug: String,
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GithubInstallationToken {
 	pub token: String,
@@ -121,6 +173,75 @@ pub enum GithubIssueCommentAction {
 	Unknown,
 }
 
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GithubPullRequestAction {
+	Synchronize,
+	Closed,
+	Labeled,
+	Unlabeled,
+	#[serde(other)]
+	Unknown,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GithubLabel {
+	pub name: String,
+}
+
+/// How pull requests are merged through the API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GithubMergeMethod {
+	Merge,
+	Squash,
+	Rebase,
+}
+
+impl std::fmt::Display for GithubMergeMethod {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{}",
+			match self {
+				GithubMergeMethod::Merge => "merge",
+				GithubMergeMethod::Squash => "squash",
+				GithubMergeMethod::Rebase => "rebase",
+			}
+		)
+	}
+}
+
+/// The reactions which processbot adds to comments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GithubReaction {
+	#[serde(rename = "+1")]
+	PlusOne,
+	Eyes,
+	Rocket,
+}
+
+// The settings of a branch's protection which processbot cares about
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GithubBranchProtection {
+	pub required_linear_history: Option<GithubBranchProtectionSetting>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GithubBranchProtectionSetting {
+	pub enabled: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GithubPullRequestState {
+	Open,
+	Closed,
+	#[serde(other)]
+	Unknown,
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GithubCheckRuns {
 	pub check_runs: Vec<GithubCheckRun>,
@@ -135,7 +256,8 @@ pub struct GithubPullRequestHeadRepository {
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GithubPullRequestHead {
 	pub sha: String,
-	pub repo: GithubPullRequestHeadRepository,
+	// null if the fork was deleted
+	pub repo: Option<GithubPullRequestHeadRepository>,
 	#[serde(rename = "ref")]
 	pub ref_field: String,
 }
@@ -146,10 +268,15 @@ pub struct GithubPullRequestBaseRepository {
 	pub owner: GithubUser,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum GithubCheckRunConclusion {
 	Success,
+	Neutral,
+	Skipped,
+	Cancelled,
+	TimedOut,
+	ActionRequired,
 	#[serde(other)]
 	Unknown,
 }
@@ -169,6 +296,14 @@ pub struct GithubCheckRun {
 	pub status: GithubCheckRunStatus,
 	pub conclusion: Option<GithubCheckRunConclusion>,
 	pub head_sha: String,
+	pub html_url: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GithubCheckSuite {
+	pub head_sha: String,
+	pub status: GithubCheckRunStatus,
+	pub conclusion: Option<GithubCheckRunConclusion>,
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
@@ -182,12 +317,43 @@ impl HasPullRequestDetails for GithubIssue {
 		parse_pull_request_details_from_url(&self.html_url)
 	}
 }
+impl GithubIssue {
+	/// The details of the pull request according to `repository`, the repository of the payload
+	/// which delivered this issue. The details parsed from `html_url` are only used for validating
+	/// the payload since the URL's layout depends on the host, e.g. a Github Enterprise Server
+	/// might be served under a subpath.
+	pub fn get_pull_request_details_in(
+		&self,
+		repository: &GithubIssueRepository,
+	) -> PullRequestDetails {
+		let details = PullRequestDetails {
+			owner: repository.owner.login.to_owned(),
+			repo: repository.name.to_owned(),
+			number: self.number,
+		};
+		match self.get_pull_request_details() {
+			Some(parsed)
+				if parsed.owner.eq_ignore_ascii_case(&details.owner)
+					&& parsed.repo.eq_ignore_ascii_case(&details.repo)
+					&& parsed.number == details.number => {}
+			_ => log::warn!(
+				"{} does not match the payload's pull request {}/{}#{}",
+				self.html_url,
+				details.owner,
+				details.repo,
+				details.number
+			),
+		}
+		details
+	}
+}
 
 #[derive(PartialEq, Eq, Deserialize)]
 pub struct GithubIssueComment {
 	pub id: i64,
 	pub body: String,
 	pub user: GithubUser,
+	pub created_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -209,6 +375,17 @@ pub struct GithubIssueRepository {
 	pub name: String,
 }
 
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GithubWebhookInstallation {
+	pub id: i64,
+}
+
+// Every event delivered to a Github App specifies the installation it originated from
+#[derive(Deserialize)]
+pub struct GithubWebhookInstallationPayload {
+	pub installation: Option<GithubWebhookInstallation>,
+}
+
 #[derive(PartialEq, Eq, Deserialize)]
 #[serde(untagged)]
 pub enum GithubWebhookPayload {
@@ -219,19 +396,35 @@ pub enum GithubWebhookPayload {
 		repository: GithubIssueRepository,
 	},
 	CommitStatus {
-		// FIXME: This payload also has a field `repository` for the repository where the status
-		// originated from which should be used *together* with commit SHA for indexing pull requests.
+		// FIXME: The `repository` where the status originated from should be used *together* with
+		// commit SHA for indexing pull requests.
 		// Currently, because merge requests are indexed purely by their head SHA into the database,
 		// there's no way to disambiguate between two different PRs in two different repositories with
 		// the same head SHA.
 		sha: String,
 		state: GithubCommitStatusState,
+		repository: GithubIssueRepository,
 	},
 	CheckRun {
 		check_run: GithubCheckRun,
+		repository: GithubIssueRepository,
 	},
 	WorkflowJob {
 		workflow_job: GithubWorkflowJob,
+		repository: GithubIssueRepository,
+	},
+	CheckSuite {
+		check_suite: GithubCheckSuite,
+		repository: GithubIssueRepository,
+	},
+	PullRequest {
+		action: GithubPullRequestAction,
+		pull_request: GithubPullRequest,
+		// The previous HEAD SHA, only provided for the "synchronize" action
+		before: Option<String>,
+		// Only provided for the "labeled" and "unlabeled" actions
+		label: Option<GithubLabel>,
+		sender: GithubUser,
 	},
 }
 
@@ -267,8 +460,13 @@ pub struct DetectUserCommentPullRequest {
 	comment: Option<DetectUserCommentPullRequestComment>,
 }
 
-impl HasPullRequestDetails for DetectUserCommentPullRequest {
-	fn get_pull_request_details(&self) -> Option<PullRequestDetails> {
+impl DetectUserCommentPullRequest {
+	/// The details of the pull request if this is a command comment from a
+	/// user, as recognized by `parse_bot_comment_from_text`
+	pub fn get_pull_request_details(
+		&self,
+		bot_username: &str,
+	) -> Option<PullRequestDetails> {
 		if let DetectUserCommentPullRequest {
 			action: GithubIssueCommentAction::Created,
 			issue:
@@ -288,7 +486,7 @@ impl HasPullRequestDetails for DetectUserCommentPullRequest {
 					..
 				}) => None,
 				_ => {
-					parse_bot_comment_from_text(body)?;
+					parse_bot_comment_from_text(body, bot_username)?;
 
 					if let Some(DetectUserCommentPullRequestRepository {
 						name: Some(name),
@@ -372,4 +570,4 @@ fn parse_repository_full_name(full_name: &str) -> Option<(String, String)> {
 		.flatten()
 }
 
-pub use client::GithubClient;
+pub use client::{GithubClient, GithubClientOptions};
@@ -1,3 +1,5 @@
+use std::time::{Duration, Instant};
+
 use reqwest::header::HeaderMap;
 use serde::Deserialize;
 
@@ -49,3 +51,55 @@ pub struct GitlabJob {
 pub struct GitlabPipelineJob {
 	pub name: String,
 }
+
+/// Stops the recovery of failing GitLab jobs from calling GitLab while it's
+/// unavailable, since otherwise every status event would wait for the requests
+/// to time out. The breaker opens after `threshold` consecutive failures and
+/// stays open for a cooldown which doubles every time a request fails again
+/// right after the breaker closes.
+#[derive(Debug, Default)]
+pub struct GitlabCircuitBreaker {
+	consecutive_failures: u32,
+	consecutive_openings: u32,
+	open_until: Option<Instant>,
+}
+
+impl GitlabCircuitBreaker {
+	// Bounds the cooldown to 2^6 times the configured one
+	const MAX_COOLDOWN_DOUBLINGS: u32 = 6;
+
+	pub fn is_open(&self) -> bool {
+		self.open_until
+			.map(|open_until| Instant::now() < open_until)
+			.unwrap_or(false)
+	}
+
+	pub fn record_success(&mut self) {
+		*self = Self::default();
+	}
+
+	/// Returns the cooldown if the failure opened the breaker
+	pub fn record_failure(
+		&mut self,
+		threshold: u32,
+		cooldown: Duration,
+	) -> Option<Duration> {
+		self.consecutive_failures += 1;
+		// A failure right after the breaker was open means that GitLab has not
+		// recovered yet
+		if self.consecutive_failures < threshold
+			&& self.consecutive_openings == 0
+		{
+			return None;
+		}
+
+		let cooldown = cooldown
+			* 2u32.pow(
+				self.consecutive_openings.min(Self::MAX_COOLDOWN_DOUBLINGS),
+			);
+		self.open_until = Some(Instant::now() + cooldown);
+		self.consecutive_openings += 1;
+		self.consecutive_failures = 0;
+		Some(cooldown)
+	}
+}
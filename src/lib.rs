@@ -2,6 +2,7 @@
 #![allow(clippy::blocks_in_if_conditions)]
 #![allow(clippy::too_many_arguments)]
 
+pub mod codeowners;
 pub mod macros;
 pub mod shell;
 #[macro_use]
@@ -13,9 +14,15 @@ pub mod error;
 pub mod github;
 pub mod bot;
 pub mod core;
+pub mod db;
 pub mod git_ops;
 pub mod gitlab;
+pub mod logging;
 pub mod merge_request;
+pub mod messages;
+pub mod poll_heartbeat;
 pub mod server;
 pub mod types;
 pub mod vanity_service;
+pub mod webhook_archive;
+pub mod work_queue;
@@ -18,6 +18,8 @@ struct Log {
 	pub severity: Severity,
 	pub message: String,
 	pub timestamp: chrono::DateTime<chrono::Utc>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub delivery_id: Option<String>,
 }
 
 pub fn format(fmt: &mut Formatter, record: &Record) -> io::Result<()> {
@@ -31,6 +33,7 @@ pub fn format(fmt: &mut Formatter, record: &Record) -> io::Result<()> {
 			},
 			message: format!("{}", record.args()),
 			timestamp: chrono::Utc::now(),
+			delivery_id: super::delivery_id(),
 		})
 		.unwrap_or_else(|_| format!(
 			"ERROR: Unable to serialize {}",
@@ -0,0 +1,31 @@
+use std::io::{self, Write};
+
+use env_logger::fmt::Formatter;
+use log::Record;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Log {
+	pub level: String,
+	pub target: String,
+	pub message: String,
+	pub timestamp: chrono::DateTime<chrono::Utc>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub delivery_id: Option<String>,
+}
+
+/// Serialize a record as a single line of JSON
+pub fn format_record(record: &Record) -> String {
+	serde_json::to_string(&Log {
+		level: record.level().to_string(),
+		target: record.target().to_string(),
+		message: format!("{}", record.args()),
+		timestamp: chrono::Utc::now(),
+		delivery_id: super::delivery_id(),
+	})
+	.unwrap_or_else(|_| format!("ERROR: Unable to serialize {}", record.args()))
+}
+
+pub fn format(fmt: &mut Formatter, record: &Record) -> io::Result<()> {
+	writeln!(fmt, "{}", format_record(record))
+}
@@ -1 +1,68 @@
+use std::{
+	future::Future,
+	io::{self, Write},
+};
+
+use env_logger::fmt::Formatter;
+use log::Record;
+
 pub mod gke;
+pub mod json;
+
+tokio::task_local! {
+	static DELIVERY_ID: Option<String>;
+}
+
+/// How the logs are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+	/// JSON understood by Google Kubernetes Engine's log collection
+	Gke,
+	/// JSON with the level, target, message and timestamp of each record
+	Json,
+	/// env_logger's human-readable format
+	Plain,
+}
+
+/// Run `future` with `delivery_id` (Github's X-GitHub-Delivery header) attached
+/// to the records it logs so that a delivery can be traced in the logs
+pub async fn with_delivery_id<F: Future>(
+	delivery_id: Option<String>,
+	future: F,
+) -> F::Output {
+	DELIVERY_ID.scope(delivery_id, future).await
+}
+
+/// The delivery of the webhook being processed by the current task, if any
+pub fn delivery_id() -> Option<String> {
+	DELIVERY_ID
+		.try_with(|delivery_id| delivery_id.clone())
+		.ok()
+		.flatten()
+}
+
+/// env_logger's default format with the delivery ID, if any, in front of the
+/// message
+pub fn format_plain(fmt: &mut Formatter, record: &Record) -> io::Result<()> {
+	let timestamp = fmt.timestamp();
+	let level = fmt.default_styled_level(record.level());
+	match delivery_id() {
+		Some(delivery_id) => writeln!(
+			fmt,
+			"[{} {} {}] [delivery {}] {}",
+			timestamp,
+			level,
+			record.target(),
+			delivery_id,
+			record.args()
+		),
+		None => writeln!(
+			fmt,
+			"[{} {} {}] {}",
+			timestamp,
+			level,
+			record.target(),
+			record.args()
+		),
+	}
+}
@@ -1,43 +1,39 @@
+// Only the characters which Github allows in owner and repository names are
+// accepted so that e.g. "org/sub/repo#1" is not misparsed
 #[macro_export]
 macro_rules! OWNER_AND_REPO_SEQUENCE {
 	() => {
-		r"(?P<owner>[^ \t\n]+)/(?P<repo>[^ \t\n]+)"
+		r"(?P<owner>[[:alnum:]_.-]+)/(?P<repo>[[:alnum:]_.-]+)"
 	};
 }
 
+// The host might serve Github under a subpath, e.g. a Github Enterprise Server at
+// "https://example.com/github/org/repo/pull/1". The subpath's segments can't be
+// empty so that the match does not span across the "://" of another URL.
 #[macro_export]
 macro_rules! PR_HTML_URL_REGEX {
 	() => {
 		concat!(
-			r"(?P<html_url>https://[^ \t\n]+/",
+			r"(?P<html_url>https?://[^ \t\n/]+(?:/[^ \t\n/]+)*?/",
 			OWNER_AND_REPO_SEQUENCE!(),
 			r"/pull/(?P<number>[[:digit:]]+))"
 		)
 	};
 }
 
+// Follows the companion marker; it should not contain letters so that e.g. "companion for org/repo#1"
+// is not considered a reference
 #[macro_export]
-macro_rules! COMPANION_PREFIX_REGEX {
+macro_rules! COMPANION_MARKER_SEPARATOR_REGEX {
 	() => {
-		r"companion[^[[:alpha:]]\n]*"
+		r"[^[[:alpha:]]\n]*"
 	};
 }
 
 #[macro_export]
-macro_rules! COMPANION_LONG_REGEX {
+macro_rules! PR_SHORT_REFERENCE_REGEX {
 	() => {
-		concat!(COMPANION_PREFIX_REGEX!(), PR_HTML_URL_REGEX!())
-	};
-}
-
-#[macro_export]
-macro_rules! COMPANION_SHORT_REGEX {
-	() => {
-		concat!(
-			COMPANION_PREFIX_REGEX!(),
-			OWNER_AND_REPO_SEQUENCE!(),
-			r"#(?P<number>[[:digit:]]+)"
-		)
+		concat!(OWNER_AND_REPO_SEQUENCE!(), r"#(?P<number>[[:digit:]]+)")
 	};
 }
 
@@ -5,35 +5,58 @@ use std::{
 	sync::Arc,
 };
 
-use rocksdb::DB;
-use tokio::sync::Mutex;
-mod logging;
 use std::{thread, time::Duration};
+use tokio::sync::Mutex;
 
 use parity_processbot::{
-	bot::handle_github_payload,
+	bot::{handle_github_payload, process_next_queued_commit},
 	config::MainConfig,
 	constants::*,
-	core::{
-		process_commit_checks_and_statuses, AppState,
-		PullRequestMergeCancelOutcome,
+	core::{poll_pending_merge_requests, reconcile_merge_requests, AppState},
+	db::{
+		export_database, import_database, migrate_legacy_metadata,
+		migrate_merge_requests, open_database,
 	},
-	error::{handle_error, Bincode},
+	error::handle_error,
 	github::*,
-	merge_request::{
-		cleanup_merge_request, MergeRequest, MergeRequestCleanupReason,
-	},
-	server,
+	logging::{self, LogFormat},
+	poll_heartbeat::PollHeartbeat,
+	server, shell,
+	work_queue::WorkQueue,
 };
-use snafu::ResultExt;
 
 fn main() -> anyhow::Result<()> {
-	env_logger::from_env(env_logger::Env::default().default_filter_or("info"))
-		.format(logging::gke::format)
-		.init();
-
 	let config = MainConfig::from_env();
 
+	let mut logger = env_logger::from_env(
+		env_logger::Env::default().default_filter_or("info"),
+	);
+	match config.log_format {
+		LogFormat::Gke => {
+			logger.format(logging::gke::format);
+		}
+		LogFormat::Json => {
+			logger.format(logging::json::format);
+		}
+		LogFormat::Plain => {
+			logger.format(logging::format_plain);
+		}
+	}
+	logger.init();
+
+	log::info!(
+		"dependency_update_configuration: {:?}",
+		config.dependency_update_configuration
+	);
+
+	shell::set_command_timeout(match config.command_timeout {
+		0 => None,
+		seconds => Some(Duration::from_secs(seconds)),
+	});
+	for (name, secret) in config.secrets() {
+		shell::set_secret(name, secret);
+	}
+
 	let socket = SocketAddr::new(
 		IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
 		config.webhook_port.parse::<u16>().expect("webhook port"),
@@ -41,14 +64,80 @@ fn main() -> anyhow::Result<()> {
 
 	let db_version_path =
 		Path::new(&config.db_path).join("__PROCESSBOT_VERSION__");
-	let is_at_current_db_version = match db_version_path.exists() {
-		true => {
-			let str = fs::read_to_string(&db_version_path)?;
-			str == DATABASE_VERSION
-		}
-		false => false,
+	let db_version = match db_version_path.exists() {
+		true => Some(fs::read_to_string(&db_version_path)?),
+		false => None,
 	};
-	if !is_at_current_db_version {
+
+	let args: Vec<String> = std::env::args().collect();
+	if args.get(1).map(|arg| arg.as_str()) == Some("export-db") {
+		let path = match &args[2..] {
+			[path] => Path::new(path),
+			_ => anyhow::bail!("Usage: export-db <file>"),
+		};
+		let db = open_database(&config.db_path).map_err(|err| {
+			anyhow::anyhow!("Failed to open the database: {}", err)
+		})?;
+		// The database is exported as it is, even if it's from an older
+		// version, so that it can be migrated through the export
+		let merge_requests_count = export_database(
+			&db,
+			path,
+			db_version.as_deref().unwrap_or(DATABASE_VERSION),
+		)
+		.map_err(|err| {
+			anyhow::anyhow!("Failed to export the database: {}", err)
+		})?;
+		log::info!(
+			"Exported {} merge requests to {}",
+			merge_requests_count,
+			path.display()
+		);
+		return Ok(());
+	}
+
+	if args.get(1).map(|arg| arg.as_str()) == Some("import-db") {
+		let path = match &args[2..] {
+			[path] => Path::new(path),
+			_ => anyhow::bail!("Usage: import-db <file>"),
+		};
+		// Records of another version would be wiped along with the imported
+		// ones once the server starts
+		if let Some(db_version) = db_version
+			.as_deref()
+			.filter(|db_version| *db_version != DATABASE_VERSION)
+		{
+			anyhow::bail!(
+				"The database at {} has version {} rather than {}; import into an empty directory instead",
+				config.db_path,
+				db_version,
+				DATABASE_VERSION
+			);
+		}
+		let db = open_database(&config.db_path).map_err(|err| {
+			anyhow::anyhow!("Failed to open the database: {}", err)
+		})?;
+		let report = import_database(&db, path).map_err(|err| {
+			anyhow::anyhow!("Failed to import the database: {}", err)
+		})?;
+		fs::write(&db_version_path, DATABASE_VERSION)?;
+		for skipped_record in &report.skipped_records {
+			log::error!("Skipped {}", skipped_record);
+		}
+		log::info!(
+			"Imported {} merge requests and {} metadata records from {}; {} records were skipped",
+			report.merge_requests_count,
+			report.metadata_records_count,
+			path.display(),
+			report.skipped_records.len()
+		);
+		return Ok(());
+	}
+
+	let is_migratable = db_version.as_deref().map_or(false, |db_version| {
+		MIGRATABLE_DATABASE_VERSIONS.contains(&db_version)
+	});
+	if db_version.as_deref() != Some(DATABASE_VERSION) && !is_migratable {
 		log::info!(
 			"Clearing database to start from version {}",
 			DATABASE_VERSION
@@ -64,114 +153,119 @@ fn main() -> anyhow::Result<()> {
 				fs::remove_file(entry.path())?;
 			}
 		}
-		fs::write(db_version_path, DATABASE_VERSION)?;
+		fs::write(&db_version_path, DATABASE_VERSION)?;
 	}
 
-	let db = DB::open_default(&config.db_path)?;
+	let db = open_database(&config.db_path).map_err(|err| {
+		anyhow::anyhow!("Failed to open the database: {}", err)
+	})?;
+	if is_migratable {
+		let migrated_records_count =
+			migrate_legacy_metadata(&db).map_err(|err| {
+				anyhow::anyhow!("Failed to migrate the database: {}", err)
+			})?;
+		log::info!(
+			"Migrated {} metadata records to database version {}",
+			migrated_records_count,
+			DATABASE_VERSION
+		);
+		fs::write(&db_version_path, DATABASE_VERSION)?;
+	}
+	let migrated_merge_requests_count =
+		migrate_merge_requests(&db).map_err(|err| {
+			anyhow::anyhow!("Failed to migrate the merge requests: {}", err)
+		})?;
+	if migrated_merge_requests_count > 0 {
+		log::info!(
+			"Migrated {} merge requests to schema version {}",
+			migrated_merge_requests_count,
+			MERGE_REQUEST_SCHEMA_VERSION
+		);
+	}
 
 	let gh_client = GithubClient::new(&config);
 
+	let work_queue = Arc::new(WorkQueue::new(config.work_queue_capacity));
+
+	let poll_interval = Duration::from_secs(POLL_INTERVAL);
+	let poll_heartbeat = Arc::new(PollHeartbeat::new(poll_interval));
+
 	let webhook_proxy_url = config.webhook_proxy_url.clone();
+	let reconciliation_interval =
+		Duration::from_secs(config.reconciliation_interval);
 
 	let app_state = Arc::new(Mutex::new(AppState {
 		db,
 		gh_client,
 		config,
+		work_queue: work_queue.clone(),
+		poll_heartbeat: poll_heartbeat.clone(),
 	}));
 
-	// Poll for pending merge requests
+	// Process the commits queued by the webhook events. All processing is
+	// serialized through the state's lock, thus a single worker suffices; the
+	// lock is released after each commit so that other events are not blocked.
 	{
 		let state = app_state.clone();
 		let rt = tokio::runtime::Builder::new_multi_thread()
 			.enable_all()
 			.build()?;
+		thread::spawn(move || {
+			rt.block_on(async {
+				loop {
+					work_queue.wait_for_work().await;
+					let state = &*state.lock().await;
+					process_next_queued_commit(state).await;
+				}
+			})
+		});
+	}
+
+	// Poll for pending merge requests. The poll thread is restarted if it dies
+	// so that the pending merges are still resumed afterwards.
+	{
+		let state = app_state.clone();
 		thread::spawn(move || loop {
-			log::info!("Acquiring poll lock");
+			let state = state.clone();
+			let poll_heartbeat = poll_heartbeat.clone();
+			let poll_thread = thread::spawn(move || {
+				let rt = tokio::runtime::Builder::new_multi_thread()
+					.enable_all()
+					.build()
+					.expect("Failed to build the poll runtime");
+				loop {
+					log::info!("Acquiring poll lock");
 
-			rt.block_on(async {
-				let state = &*state.lock().await;
+					rt.block_on(async {
+						let state = &*state.lock().await;
+						poll_pending_merge_requests(state).await;
+					});
+					poll_heartbeat.record_poll_completed();
 
-				/*
-					Set up a loop for reinitializing the DB's iterator since the operations
-					performed in this loop might modify or delete multiple items from the
-					database, thus potentially making the iteration not work according to
-					expectations.
-				*/
-				let mut processed_mrs = vec![];
-				'db_iteration_loop: loop {
-					let db_iter =
-						state.db.iterator(rocksdb::IteratorMode::Start);
-					for (key, value) in db_iter {
-						match bincode::deserialize::<MergeRequest>(&value)
-							.context(Bincode)
-						{
-							Ok(mr) => {
-								if processed_mrs.iter().any(
-									|prev_mr: &MergeRequest| {
-										mr.owner == prev_mr.owner
-											&& mr.repo == prev_mr.repo && mr.number
-											== prev_mr.number
-									},
-								) {
-									continue;
-								}
-
-								// It's only worthwhile to try merging this MR if it has no pending
-								// dependencies
-								if mr
-									.dependencies
-									.as_ref()
-									.map(|vec| vec.is_empty())
-									.unwrap_or(true)
-								{
-									log::info!(
-										"Attempting to resume merge request processing during poll: {:?}",
-										mr
-									);
-
-									if let Err(err) =
-										process_commit_checks_and_statuses(
-											state, &mr.sha,
-										)
-										.await
-									{
-										let _ = cleanup_merge_request(
-											state,
-											&mr.sha,
-											&mr.owner,
-											&mr.repo,
-											mr.number,
-											&MergeRequestCleanupReason::Error,
-										)
-										.await;
-										handle_error(
-											PullRequestMergeCancelOutcome::WasCancelled,
-											err,
-											state,
-										)
-										.await;
-									}
-
-									processed_mrs.push(mr);
-									continue 'db_iteration_loop;
-								}
-							}
-							Err(err) => {
-								log::error!(
-									"Failed to deserialize key {} from the database due to {:?}",
-									String::from_utf8_lossy(&key),
-									err
-								);
-								let _ = state.db.delete(&key);
-							}
-						}
-					}
-					break;
+					log::info!("Releasing poll lock");
+					thread::sleep(poll_interval);
 				}
 			});
+			if poll_thread.join().is_err() {
+				log::error!("The poll thread panicked; restarting it");
+			}
+		});
+	}
 
-			log::info!("Releasing poll lock");
-			thread::sleep(Duration::from_secs(10 * 60));
+	// Reconcile the queued merges with their pull requests so that the events
+	// which were missed don't leave them stuck
+	if !reconciliation_interval.is_zero() {
+		let state = app_state.clone();
+		let rt = tokio::runtime::Builder::new_multi_thread()
+			.enable_all()
+			.build()?;
+		thread::spawn(move || loop {
+			thread::sleep(reconciliation_interval);
+			rt.block_on(async {
+				let state = &*state.lock().await;
+				let reconciled_count = reconcile_merge_requests(state).await;
+				log::info!("Reconciled {} merge requests", reconciled_count);
+			});
 		});
 	}
 
@@ -1,4 +1,8 @@
-use std::collections::HashMap;
+use std::{
+	collections::{BTreeMap, HashSet},
+	fmt,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use hyper::StatusCode as HttpStatusCode;
 use regex::RegexBuilder;
@@ -7,14 +11,23 @@ use snafu::ResultExt;
 
 use crate::{
 	companion::{
-		check_all_companions_are_mergeable, CompanionReferenceTrailItem,
+		check_all_companions_are_mergeable, wait_for_pull_request_head_update,
+		CompanionReferenceTrailItem,
+	},
+	constants::{
+		MERGE_MARKER_TTL, MERGE_PRIORITY_NORMAL, MERGE_REQUEST_SCHEMA_VERSION,
 	},
 	core::{
-		get_commit_checks, get_commit_statuses, process_dependents_after_merge,
-		AppState, Status,
+		evaluate_commit_checks, evaluate_commit_statuses,
+		process_dependents_after_merge, AppState, Status,
 	},
-	error::{self, Error},
-	github::GithubPullRequest,
+	db::{merge_requests_cf, metadata_cf},
+	error::{self, Error, FailingContext},
+	github::{
+		GithubCheckRunStatus, GithubCommitStatusState, GithubMergeMethod,
+		GithubPullRequest, GithubPullRequestReviewState,
+	},
+	messages::Message,
 	types::Result,
 };
 
@@ -40,6 +53,270 @@ pub struct MergeRequest {
 	pub html_url: String,
 	pub requested_by: String,
 	pub dependencies: Option<Vec<MergeRequestDependency>>,
+	/// How many times processbot has tried to merge this pull request without success.
+	/// Attempts which failed for reasons expected to be solved later (see
+	/// `Error::MergeFailureWillBeSolvedLater`) are not counted.
+	pub attempts: u32,
+	/// Merge requests with higher priority are processed first when polling (see
+	/// `MERGE_PRIORITY_NORMAL` and `MERGE_PRIORITY_HIGH`).
+	pub priority: i32,
+	/// The merge should not be attempted before this time (see `bot merge delay`).
+	pub not_before: Option<SystemTime>,
+	/// When the merge request was first registered in the database. Kept as-is when the merge
+	/// request is registered again (e.g. after a companion is updated).
+	pub queued_at: Option<SystemTime>,
+	/// The order in which the merge requests of a repository were registered.
+	/// Merge requests of the same priority are processed in this order. None
+	/// for merge requests which were registered before it existed.
+	pub seq: Option<u64>,
+	/// When the merge was last attempted without success. It's not attempted
+	/// again until `merge_retry_cooldown` has elapsed since then.
+	pub last_attempt_at: Option<SystemTime>,
+	/// The shape in which the merge request was serialized. Always
+	/// `MERGE_REQUEST_SCHEMA_VERSION` once deserialized, since records of older
+	/// shapes are migrated (see `deserialize_merge_request`).
+	pub schema_version: u32,
+}
+
+// The shape of the merge requests before the time of their last attempt was
+// tracked
+#[derive(Deserialize)]
+struct MergeRequestV3 {
+	sha: String,
+	was_updated: bool,
+	owner: String,
+	repo: String,
+	number: i64,
+	html_url: String,
+	requested_by: String,
+	dependencies: Option<Vec<MergeRequestDependency>>,
+	attempts: u32,
+	priority: i32,
+	not_before: Option<SystemTime>,
+	queued_at: Option<SystemTime>,
+	seq: Option<u64>,
+	schema_version: u32,
+}
+
+impl From<MergeRequestV3> for MergeRequest {
+	fn from(mr: MergeRequestV3) -> Self {
+		Self {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			attempts: mr.attempts,
+			priority: mr.priority,
+			not_before: mr.not_before,
+			queued_at: mr.queued_at,
+			seq: mr.seq,
+			last_attempt_at: None,
+			schema_version: MERGE_REQUEST_SCHEMA_VERSION,
+		}
+	}
+}
+
+// The shape of the merge requests before they had a sequence number
+#[derive(Deserialize)]
+struct MergeRequestV2 {
+	sha: String,
+	was_updated: bool,
+	owner: String,
+	repo: String,
+	number: i64,
+	html_url: String,
+	requested_by: String,
+	dependencies: Option<Vec<MergeRequestDependency>>,
+	attempts: u32,
+	priority: i32,
+	not_before: Option<SystemTime>,
+	queued_at: Option<SystemTime>,
+	schema_version: u32,
+}
+
+impl From<MergeRequestV2> for MergeRequest {
+	fn from(mr: MergeRequestV2) -> Self {
+		Self {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			attempts: mr.attempts,
+			priority: mr.priority,
+			not_before: mr.not_before,
+			queued_at: mr.queued_at,
+			seq: None,
+			last_attempt_at: None,
+			schema_version: MERGE_REQUEST_SCHEMA_VERSION,
+		}
+	}
+}
+
+// The shape of the merge requests of database version v3.4, before they had a
+// schema version
+#[derive(Deserialize)]
+struct MergeRequestV1 {
+	sha: String,
+	was_updated: bool,
+	owner: String,
+	repo: String,
+	number: i64,
+	html_url: String,
+	requested_by: String,
+	dependencies: Option<Vec<MergeRequestDependency>>,
+	attempts: u32,
+	priority: i32,
+	not_before: Option<SystemTime>,
+	queued_at: Option<SystemTime>,
+}
+
+impl From<MergeRequestV1> for MergeRequest {
+	fn from(mr: MergeRequestV1) -> Self {
+		Self {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			attempts: mr.attempts,
+			priority: mr.priority,
+			not_before: mr.not_before,
+			queued_at: mr.queued_at,
+			seq: None,
+			last_attempt_at: None,
+			schema_version: MERGE_REQUEST_SCHEMA_VERSION,
+		}
+	}
+}
+
+// The shape of the merge requests of database version v3.0. Versions v3.1 to v3.3
+// appended `attempts`, `priority` and `not_before` to it one after the other (see
+// `deserialize_merge_request_v0`).
+#[derive(Deserialize)]
+struct MergeRequestV0 {
+	sha: String,
+	was_updated: bool,
+	owner: String,
+	repo: String,
+	number: i64,
+	html_url: String,
+	requested_by: String,
+	dependencies: Option<Vec<MergeRequestDependency>>,
+}
+
+impl From<MergeRequestV0> for MergeRequest {
+	fn from(mr: MergeRequestV0) -> Self {
+		Self {
+			sha: mr.sha,
+			was_updated: mr.was_updated,
+			owner: mr.owner,
+			repo: mr.repo,
+			number: mr.number,
+			html_url: mr.html_url,
+			requested_by: mr.requested_by,
+			dependencies: mr.dependencies,
+			attempts: 0,
+			priority: MERGE_PRIORITY_NORMAL,
+			not_before: None,
+			queued_at: None,
+			seq: None,
+			last_attempt_at: None,
+			schema_version: MERGE_REQUEST_SCHEMA_VERSION,
+		}
+	}
+}
+
+// Reads the fields appended to MergeRequestV0 by the database versions up to v3.3
+// as far as they're present
+fn deserialize_merge_request_v0(bytes: &[u8]) -> bincode::Result<MergeRequest> {
+	let mut reader = bytes;
+	let mr: MergeRequestV0 = bincode::deserialize_from(&mut reader)?;
+	let mut mr = MergeRequest::from(mr);
+	if !reader.is_empty() {
+		mr.attempts = bincode::deserialize_from(&mut reader)?;
+	}
+	if !reader.is_empty() {
+		mr.priority = bincode::deserialize_from(&mut reader)?;
+	}
+	if !reader.is_empty() {
+		mr.not_before = bincode::deserialize_from(&mut reader)?;
+	}
+	Ok(mr)
+}
+
+/// Deserialize a merge request stored in any of the shapes it has had over
+/// time. The newest shape is tried first because bincode ignores trailing
+/// bytes, i.e. a record would also be "successfully" read as an older shape.
+pub fn deserialize_merge_request(bytes: &[u8]) -> Result<MergeRequest> {
+	let err = match bincode::deserialize::<MergeRequest>(bytes) {
+		Ok(mr) if mr.schema_version == MERGE_REQUEST_SCHEMA_VERSION => {
+			return Ok(mr)
+		}
+		Ok(mr) => {
+			return Err(Error::Message {
+				msg: format!(
+					"Merge request {}/{}/pull/{} has the unknown schema version {}",
+					mr.owner, mr.repo, mr.number, mr.schema_version
+				),
+			})
+		}
+		Err(err) => err,
+	};
+	match bincode::deserialize::<MergeRequestV3>(bytes) {
+		Ok(mr) if mr.schema_version == 3 => return Ok(mr.into()),
+		_ => (),
+	}
+	match bincode::deserialize::<MergeRequestV2>(bytes) {
+		Ok(mr) if mr.schema_version == 2 => return Ok(mr.into()),
+		_ => (),
+	}
+	if let Ok(mr) = bincode::deserialize::<MergeRequestV1>(bytes) {
+		return Ok(mr.into());
+	}
+	if let Ok(mr) = deserialize_merge_request_v0(bytes) {
+		return Ok(mr);
+	}
+	Err(err).context(error::Bincode)
+}
+
+impl MergeRequest {
+	pub fn is_delayed(&self) -> bool {
+		self.not_before
+			.map(|not_before| not_before > SystemTime::now())
+			.unwrap_or(false)
+	}
+
+	/// How long the merge should still wait for the `cooldown` after its last
+	/// unsuccessful attempt. None if it can be attempted right away.
+	pub fn retry_cooldown_remaining(
+		&self,
+		cooldown: Duration,
+	) -> Option<Duration> {
+		let elapsed = SystemTime::now()
+			.duration_since(self.last_attempt_at?)
+			.unwrap_or_default();
+		cooldown
+			.checked_sub(elapsed)
+			.filter(|remaining| !remaining.is_zero())
+	}
+
+	/// How long the merge request has been waiting since it was first registered
+	pub fn time_in_queue(&self) -> Option<Duration> {
+		self.queued_at.and_then(|queued_at| {
+			SystemTime::now().duration_since(queued_at).ok()
+		})
+	}
 }
 
 pub enum MergeRequestCleanupReason<'a> {
@@ -47,6 +324,9 @@ pub enum MergeRequestCleanupReason<'a> {
 	AfterSHAUpdate(&'a String),
 	Cancelled,
 	Error,
+	GaveUp { attempts: u32 },
+	// Only this pull request is removed; its dependents stop waiting for it
+	Unqueued,
 }
 // Removes a pull request from the database (e.g. when it has been merged) and
 // executes side-effects related to the kind of trigger for this function
@@ -60,13 +340,14 @@ pub async fn cleanup_merge_request(
 ) -> Result<()> {
 	let AppState { db, .. } = state;
 
-	let mut related_dependents = HashMap::new();
+	// Keyed by (owner, repo, number, sha) so that they're handled in a stable
+	// order without the records of different head SHAs overwriting each other
+	let mut related_dependents = BTreeMap::new();
 
-	let db_iter = db.iterator(rocksdb::IteratorMode::Start);
+	let db_iter =
+		db.iterator_cf(merge_requests_cf(db), rocksdb::IteratorMode::Start);
 	'to_next_db_item: for (key, value) in db_iter {
-		match bincode::deserialize::<MergeRequest>(&value)
-			.context(error::Bincode)
-		{
+		match deserialize_merge_request(&value) {
 			Ok(mr) => {
 				if mr.owner == owner && mr.repo == repo && mr.number == number {
 					log::info!(
@@ -78,7 +359,8 @@ pub async fn cleanup_merge_request(
 						number
 					);
 
-					if let Err(err) = db.delete(&key) {
+					if let Err(err) = db.delete_cf(merge_requests_cf(db), &key)
+					{
 						log::error!(
 							"Failed to delete {} during cleanup_merge_request due to {:?}",
 							String::from_utf8_lossy(&key),
@@ -93,7 +375,15 @@ pub async fn cleanup_merge_request(
 							&& dependency.repo == repo && dependency.number
 							== number
 						{
-							related_dependents.insert(mr.sha.clone(), mr);
+							related_dependents.insert(
+								(
+									mr.owner.clone(),
+									mr.repo.clone(),
+									mr.number,
+									mr.sha.clone(),
+								),
+								mr,
+							);
 							continue 'to_next_db_item;
 						}
 					}
@@ -178,9 +468,62 @@ pub async fn cleanup_merge_request(
 		related_dependents
 	);
 
+	// The pull request is still going to be merged after an update, thus the
+	// comment explaining why it's queued remains accurate
+	if !matches!(reason, MergeRequestCleanupReason::AfterSHAUpdate(_)) {
+		if let Err(err) = db
+			.delete_cf(metadata_cf(db), queued_comment_key(owner, repo, number))
+		{
+			log::error!(
+				"Failed to delete the queued comment of {}/{}/pull/{} due to {:?}",
+				owner,
+				repo,
+				number,
+				err
+			);
+		}
+		if let Err(err) = db.delete_cf(
+			metadata_cf(db),
+			merge_grants_key(owner, repo, number).as_bytes(),
+		) {
+			log::error!(
+				"Failed to delete the merge grants of {}/{}/pull/{} due to {:?}",
+				owner,
+				repo,
+				number,
+				err
+			);
+		}
+	}
+
 	match reason {
 		MergeRequestCleanupReason::Error
-		| MergeRequestCleanupReason::Cancelled => {
+		| MergeRequestCleanupReason::Cancelled
+		| MergeRequestCleanupReason::GaveUp { .. } => {
+			if let MergeRequestCleanupReason::GaveUp { attempts } = reason {
+				if let Err(err) = state
+					.gh_client
+					.create_issue_comment_once(
+						owner,
+						repo,
+						number,
+						&state.config.message_templates.render(
+							Message::GaveUp,
+							&[("attempts", &attempts.to_string())],
+						),
+					)
+					.await
+				{
+					log::error!(
+						"Failed to post comment on {}/{}/pull/{} due to {}",
+						owner,
+						repo,
+						number,
+						err
+					);
+				}
+			}
+
 			for dependent in related_dependents.values() {
 				// TODO: these cleanup_merge_request() might not be actually executed rn, poll them?
 				let _result = cleanup_merge_request(
@@ -221,7 +564,8 @@ pub async fn cleanup_merge_request(
 					};
 
 				if was_updated {
-					db.put(
+					db.put_cf(
+						merge_requests_cf(db),
 						dependent.sha.as_bytes(),
 						bincode::serialize(&dependent)
 							.context(error::Bincode)?,
@@ -230,7 +574,45 @@ pub async fn cleanup_merge_request(
 				}
 			}
 		}
-		MergeRequestCleanupReason::AfterMerge => {}
+		MergeRequestCleanupReason::Unqueued => {
+			for mut dependent in related_dependents.into_values() {
+				if let Some(dependencies) = dependent.dependencies.as_mut() {
+					dependencies.retain(|dependency| {
+						(
+							dependency.owner.as_str(),
+							dependency.repo.as_str(),
+							dependency.number,
+						) != (owner, repo, number)
+					});
+				}
+				log::info!(
+					"Dependency of {} on {}/{}/pull/{} was removed",
+					dependent.html_url,
+					owner,
+					repo,
+					number
+				);
+				db.put_cf(
+					merge_requests_cf(db),
+					dependent.sha.as_bytes(),
+					bincode::serialize(&dependent).context(error::Bincode)?,
+				)
+				.context(error::Db)?;
+			}
+		}
+		// The push is still needed for telling apart the merge commands which
+		// predate it as long as the pull request can be merged
+		MergeRequestCleanupReason::AfterMerge => {
+			if let Err(err) = forget_head_push(state, owner, repo, number) {
+				log::error!(
+					"Failed to delete the head push of {}/{}/pull/{} due to {:?}",
+					owner,
+					repo,
+					number,
+					err
+				);
+			}
+		}
 	}
 
 	log::info!(
@@ -253,7 +635,9 @@ pub async fn queue_merge_request(
 ) -> Result<()> {
 	register_merge_request(state, mr).await?;
 
-	let AppState { gh_client, .. } = state;
+	let AppState {
+		gh_client, config, ..
+	} = state;
 
 	let MergeRequest {
 		owner,
@@ -263,210 +647,1565 @@ pub async fn queue_merge_request(
 	} = mr;
 
 	let msg = match msg {
-		MergeRequestQueuedMessage::Custom(msg) => msg,
-		MergeRequestQueuedMessage::Default => "Waiting for commit status.",
+		MergeRequestQueuedMessage::Custom(msg) => msg.to_string(),
+		MergeRequestQueuedMessage::Default => config
+			.message_templates
+			.render(Message::Queued, &[("pending", "")]),
 		MergeRequestQueuedMessage::None => return Ok(()),
 	};
 
-	let post_comment_result = gh_client
-		.create_issue_comment(owner, repo, *number, msg)
-		.await;
-	if let Err(err) = post_comment_result {
-		log::error!("Error posting comment: {}", err);
-	}
+	post_queued_comment(state, owner, repo, *number, &msg).await;
 
 	Ok(())
 }
 
-pub async fn handle_merged_pull_request(
+fn queued_comment_key(owner: &str, repo: &str, number: i64) -> String {
+	format!("queued_comments/{}/{}/{}", owner, repo, number)
+}
+
+// A pull request might be queued multiple times (e.g. after its branch is
+// updated), thus the comment explaining why it was queued is only posted once.
+// The ID and text of that comment are kept in the database so that it can be
+// edited if the explanation changes.
+async fn post_queued_comment(
 	state: &AppState,
-	pr: &GithubPullRequest,
-	requested_by: &str,
-) -> Result<bool> {
-	if !pr.merged {
-		return Ok(false);
-	}
+	owner: &str,
+	repo: &str,
+	number: i64,
+	msg: &str,
+) {
+	let AppState { db, gh_client, .. } = state;
 
-	let was_cleaned_up = cleanup_merge_request(
-		state,
-		&pr.head.sha,
-		&pr.base.repo.owner.login,
-		&pr.base.repo.name,
-		pr.number,
-		&MergeRequestCleanupReason::AfterMerge,
-	)
-	.await
-	.map(|_| true);
+	let key = queued_comment_key(owner, repo, number);
+	let previous_comment = db
+		.get_cf(metadata_cf(db), key.as_bytes())
+		.ok()
+		.flatten()
+		.and_then(|bytes| bincode::deserialize::<(i64, String)>(&bytes).ok());
 
-	/*
-		It's not sane to try to handle the dependents if the cleanup went wrong since
-		that hints at some bug in the application
-	*/
-	if was_cleaned_up.is_ok() {
-		if let Err(err) =
-			process_dependents_after_merge(state, pr, requested_by).await
-		{
-			log::error!(
-				"Failed to process process_dependents_after_merge in cleanup_merged_pr due to {:?}",
-				err
+	let comment_id = match previous_comment {
+		Some((_, previous_msg)) if previous_msg == msg => {
+			log::info!(
+				"Skipping the queued comment of {}/{}/pull/{} since it was already posted",
+				owner,
+				repo,
+				number
 			);
+			return;
 		}
-	}
-
-	was_cleaned_up
-}
-
-pub async fn is_ready_to_merge(
-	state: &AppState,
-	pr: &GithubPullRequest,
-) -> Result<bool> {
-	let AppState { gh_client, .. } = state;
-
-	match get_commit_checks(
-		gh_client,
-		&pr.base.repo.owner.login,
-		&pr.base.repo.name,
-		&pr.head.sha,
-		&pr.html_url,
-	)
-	.await?
-	{
-		Status::Success => {
-			match get_commit_statuses(
-				state,
-				&pr.base.repo.owner.login,
-				&pr.base.repo.name,
-				&pr.head.sha,
-				&pr.html_url,
-				true,
-			)
-			.await?
-			.0
+		Some((comment_id, _)) => {
+			match gh_client
+				.update_issue_comment(owner, repo, comment_id, msg)
+				.await
 			{
-				Status::Success => Ok(true),
-				Status::Failure => Err(Error::StatusesFailed {
-					commit_sha: pr.head.sha.to_owned(),
-				}),
-				_ => Ok(false),
+				Ok(_) => Some(comment_id),
+				Err(err) => {
+					log::error!(
+						"Failed to edit comment {} of {}/{}/pull/{} due to {}",
+						comment_id,
+						owner,
+						repo,
+						number,
+						err
+					);
+					None
+				}
 			}
 		}
-		Status::Failure => Err(Error::ChecksFailed {
-			commit_sha: pr.head.sha.to_owned(),
-		}),
-		_ => Ok(false),
-	}
-}
-
-pub async fn merge_pull_request(
-	state: &AppState,
-	pr: &GithubPullRequest,
-	requested_by: &str,
-) -> Result<Result<()>> {
-	if handle_merged_pull_request(state, pr, requested_by).await? {
-		return Ok(Ok(()));
-	}
-
-	let AppState { gh_client, .. } = state;
-
-	let err = match gh_client
-		.merge_pull_request(
-			&pr.base.repo.owner.login,
-			&pr.base.repo.name,
-			pr.number,
-			&pr.head.sha,
-		)
-		.await
-	{
-		Ok(_) => {
-			log::info!("{} merged successfully.", pr.html_url);
-			// Merge succeeded! Now clean it from the database
-			if let Err(err) = cleanup_merge_request(
-				state,
-				&pr.head.sha,
-				&pr.base.repo.owner.login,
-				&pr.base.repo.name,
-				pr.number,
-				&MergeRequestCleanupReason::AfterMerge,
-			)
-			.await
-			{
-				log::error!(
-					"Failed to cleanup PR on the database after merge: {}",
-					err
-				);
-			};
-			return Ok(Ok(()));
-		}
-		Err(err) => err,
+		None => None,
 	};
 
-	let msg = match err {
-		Error::Response {
-			ref status,
-			ref body,
-		} if *status == HttpStatusCode::METHOD_NOT_ALLOWED => {
-			match body.get("message") {
-				Some(msg) => match msg.as_str() {
-					Some(msg) => msg,
-					None => {
-						log::error!("Expected \"message\" of Github API merge failure response to be a string");
-						return Err(err);
-					}
-				},
-				None => {
-					log::error!("Expected \"message\" of Github API merge failure response to be available");
-					return Err(err);
-				}
+	let comment_id = match comment_id {
+		Some(comment_id) => comment_id,
+		// The same comment might have been posted shortly before, e.g. if the
+		// merge was cancelled and requested again
+		None => match gh_client
+			.create_issue_comment_with_id_once(owner, repo, number, msg)
+			.await
+		{
+			Ok(Some(comment_id)) => comment_id,
+			Ok(None) => return,
+			Err(err) => {
+				log::error!("Error posting comment: {}", err);
+				return;
 			}
-		}
-		_ => return Err(err),
+		},
 	};
 
-	// Matches the following
-	// - "Required status check ... is {pending,expected}."
-	// - "... required status checks have not succeeded: ... {pending,expected}."
-	let missing_status_matcher =
-		RegexBuilder::new(r"required\s+status\s+.*(pending|expected)")
-			.case_insensitive(true)
-			.build()
-			.unwrap();
-
-	if missing_status_matcher.find(msg).is_some() {
-		// This problem will be solved automatically when all the required statuses are delivered, thus
-		// it can be ignored here
-		log::info!(
-			"Ignoring merge failure due to pending required status; message: {}",
-			msg
+	let result = bincode::serialize(&(comment_id, msg))
+		.context(error::Bincode)
+		.and_then(|bytes| {
+			db.put_cf(metadata_cf(db), key.as_bytes(), bytes)
+				.context(error::Db)
+		});
+	if let Err(err) = result {
+		log::error!(
+			"Failed to record the queued comment of {}/{}/pull/{} due to {}",
+			owner,
+			repo,
+			number,
+			err
 		);
-		return Ok(Err(Error::MergeFailureWillBeSolvedLater {
-			msg: msg.to_string(),
-		}));
 	}
+}
 
-	Err(Error::Message { msg: msg.into() })
+const FROZEN_REPOSITORIES_KEY: &str = "frozen_repositories";
+
+/// The repositories (as `owner/repo`) or base branches (as `owner/repo:branch`) for which
+/// merges are currently frozen. Defaults to FROZEN_REPOSITORIES until the set is modified at
+/// runtime.
+pub fn list_frozen_repositories(state: &AppState) -> Result<HashSet<String>> {
+	let AppState { db, config, .. } = state;
+
+	match db
+		.get_cf(metadata_cf(db), FROZEN_REPOSITORIES_KEY.as_bytes())
+		.context(error::Db)?
+	{
+		Some(bytes) => bincode::deserialize(&bytes).context(error::Bincode),
+		None => Ok(config.frozen_repos.clone()),
+	}
 }
 
-async fn register_merge_request(
+pub fn is_repository_frozen(
 	state: &AppState,
-	mr: &MergeRequest,
+	owner: &str,
+	repo: &str,
+) -> Result<bool> {
+	Ok(list_frozen_repositories(state)?
+		.contains(&format!("{}/{}", owner, repo)))
+}
+
+/// Whether merges into `branch` specifically are frozen, e.g. for a release branch, while the
+/// other branches of `owner/repo` stay open.
+pub fn is_branch_frozen(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	branch: &str,
+) -> Result<bool> {
+	Ok(list_frozen_repositories(state)?
+		.contains(&format!("{}/{}:{}", owner, repo, branch)))
+}
+
+/// Freeze or unfreeze the merges of `repository` (as `owner/repo`, or `owner/repo:branch` for a
+/// single base branch). The resulting set is persisted so that it survives restarts.
+pub fn set_repository_frozen(
+	state: &AppState,
+	repository: &str,
+	is_frozen: bool,
 ) -> Result<()> {
 	let AppState { db, .. } = state;
-	let MergeRequest { sha, .. } = mr;
-	log::info!("Registering merge request (sha: {}): {:?}", sha, mr);
-	db.put(
-		sha.as_bytes(),
-		bincode::serialize(mr).context(error::Bincode)?,
+
+	let mut frozen_repos = list_frozen_repositories(state)?;
+	if is_frozen {
+		frozen_repos.insert(repository.to_string());
+	} else {
+		frozen_repos.remove(repository);
+	}
+
+	db.put_cf(
+		metadata_cf(db),
+		FROZEN_REPOSITORIES_KEY.as_bytes(),
+		bincode::serialize(&frozen_repos).context(error::Bincode)?,
 	)
 	.context(error::Db)
 }
 
-pub async fn check_merge_is_allowed(
-	state: &AppState,
+const PAUSED_KEY: &str = "paused";
+
+/// Whether processbot was paused through the /pause endpoint, in which case
+/// nothing is merged until it's resumed
+pub fn is_paused(state: &AppState) -> Result<bool> {
+	let AppState { db, .. } = state;
+
+	match db
+		.get_cf(metadata_cf(db), PAUSED_KEY.as_bytes())
+		.context(error::Db)?
+	{
+		Some(bytes) => bincode::deserialize(&bytes).context(error::Bincode),
+		None => Ok(false),
+	}
+}
+
+/// Pause or resume processbot. The flag is persisted so that it survives
+/// restarts.
+pub fn set_paused(state: &AppState, is_paused: bool) -> Result<()> {
+	let AppState { db, .. } = state;
+
+	db.put_cf(
+		metadata_cf(db),
+		PAUSED_KEY.as_bytes(),
+		bincode::serialize(&is_paused).context(error::Bincode)?,
+	)
+	.context(error::Db)
+}
+
+fn head_push_key(owner: &str, repo: &str, number: i64) -> String {
+	format!("head_pushes/{}/{}/{}", owner, repo, number)
+}
+
+/// Record that `sha` was just pushed to the pull request, so that the merge
+/// commands which were issued before it can be told apart (see
+/// `was_head_pushed_after`)
+pub fn record_head_push(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	sha: &str,
+) -> Result<()> {
+	let AppState { db, .. } = state;
+
+	db.put_cf(
+		metadata_cf(db),
+		head_push_key(owner, repo, number).as_bytes(),
+		bincode::serialize(&(sha, SystemTime::now()))
+			.context(error::Bincode)?,
+	)
+	.context(error::Db)
+}
+
+/// Forget the push recorded by `record_head_push`, e.g. once the pull request is
+/// closed
+pub fn forget_head_push(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+) -> Result<()> {
+	let AppState { db, .. } = state;
+
+	db.delete_cf(
+		metadata_cf(db),
+		head_push_key(owner, repo, number).as_bytes(),
+	)
+	.context(error::Db)
+}
+
+/// Whether `sha`, the pull request's HEAD, is known to have been pushed after
+/// `time`. Pushes which happened while processbot was not receiving events are
+/// unknown.
+pub fn was_head_pushed_after(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	sha: &str,
+	time: SystemTime,
+) -> Result<bool> {
+	let AppState { db, .. } = state;
+
+	match db
+		.get_cf(
+			metadata_cf(db),
+			head_push_key(owner, repo, number).as_bytes(),
+		)
+		.context(error::Db)?
+	{
+		Some(bytes) => {
+			let (pushed_sha, pushed_at) =
+				bincode::deserialize::<(String, SystemTime)>(&bytes)
+					.context(error::Bincode)?;
+			Ok(pushed_sha == sha && pushed_at > time)
+		}
+		None => Ok(false),
+	}
+}
+
+fn merge_allowances_key(owner: &str, repo: &str, number: i64) -> String {
+	format!("merge_allowances/{}/{}/{}", owner, repo, number)
+}
+
+// The users whose allowance was consumed by a merge command which is still
+// ongoing, i.e. who are still allowed to merge the pull request once it's ready
+// (see `is_requester_still_allowed`)
+fn merge_grants_key(owner: &str, repo: &str, number: i64) -> String {
+	format!("merge_grants/{}/{}/{}", owner, repo, number)
+}
+
+fn list_merge_allowances(
+	state: &AppState,
+	key: &str,
+) -> Result<HashSet<String>> {
+	let AppState { db, .. } = state;
+
+	match db
+		.get_cf(metadata_cf(db), key.as_bytes())
+		.context(error::Db)?
+	{
+		Some(bytes) => bincode::deserialize(&bytes).context(error::Bincode),
+		None => Ok(HashSet::new()),
+	}
+}
+
+/// Allow `username` to use `bot merge` once on the pull request even if they're not a member of
+/// the organization (see `bot merge allow`)
+pub fn allow_merge_once(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	username: &str,
+) -> Result<()> {
+	let AppState { db, .. } = state;
+
+	let key = merge_allowances_key(owner, repo, number);
+	let mut allowances = list_merge_allowances(state, &key)?;
+	allowances.insert(username.to_lowercase());
+
+	db.put_cf(
+		metadata_cf(db),
+		key.as_bytes(),
+		bincode::serialize(&allowances).context(error::Bincode)?,
+	)
+	.context(error::Db)
+}
+
+/// Consume the merge which was allowed for `username` on the pull request. Returns false if there
+/// was none. The merge stays granted to them until the merge request is cleaned up, thus it's
+/// still allowed when it's attempted later.
+pub fn consume_merge_allowance(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	username: &str,
+) -> Result<bool> {
+	let AppState { db, .. } = state;
+
+	let key = merge_allowances_key(owner, repo, number);
+	let mut allowances = list_merge_allowances(state, &key)?;
+	if !allowances.remove(&username.to_lowercase()) {
+		return Ok(false);
+	}
+
+	if allowances.is_empty() {
+		db.delete_cf(metadata_cf(db), key.as_bytes())
+			.context(error::Db)?;
+	} else {
+		db.put_cf(
+			metadata_cf(db),
+			key.as_bytes(),
+			bincode::serialize(&allowances).context(error::Bincode)?,
+		)
+		.context(error::Db)?;
+	}
+
+	let grants_key = merge_grants_key(owner, repo, number);
+	let mut grants = list_merge_allowances(state, &grants_key)?;
+	grants.insert(username.to_lowercase());
+	db.put_cf(
+		metadata_cf(db),
+		grants_key.as_bytes(),
+		bincode::serialize(&grants).context(error::Bincode)?,
+	)
+	.context(error::Db)?;
+
+	Ok(true)
+}
+
+/// Whether the merge of the pull request was granted to `username` through `bot merge allow`
+/// (see `consume_merge_allowance`)
+pub fn is_merge_granted(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+	username: &str,
+) -> Result<bool> {
+	Ok(
+		list_merge_allowances(state, &merge_grants_key(owner, repo, number))?
+			.contains(&username.to_lowercase()),
+	)
+}
+
+// The keys of the markers recorded by `mark_merge_as_handled`
+const MERGE_MARKERS_PREFIX: &str = "merged/";
+
+fn merge_marker_key(sha: &str) -> String {
+	format!("{}{}", MERGE_MARKERS_PREFIX, sha)
+}
+
+// Whether the merge of `sha` was handled (see `mark_merge_as_handled`) within
+// the last MERGE_MARKER_TTL seconds
+fn is_merge_handled(state: &AppState, sha: &str) -> Result<bool> {
+	let AppState { db, .. } = state;
+
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|elapsed| elapsed.as_secs())
+		.unwrap_or(0);
+	let is_expired = |value: &[u8]| {
+		bincode::deserialize::<u64>(value)
+			.map(|handled_at| handled_at + MERGE_MARKER_TTL < now)
+			.unwrap_or(true)
+	};
+
+	// Prune the markers which have expired
+	let db_iter = db.iterator_cf(
+		metadata_cf(db),
+		rocksdb::IteratorMode::From(
+			MERGE_MARKERS_PREFIX.as_bytes(),
+			rocksdb::Direction::Forward,
+		),
+	);
+	for (key, value) in db_iter {
+		if !key.starts_with(MERGE_MARKERS_PREFIX.as_bytes()) {
+			break;
+		}
+		if is_expired(&value) {
+			let _ = db.delete_cf(metadata_cf(db), &key);
+		}
+	}
+
+	Ok(db
+		.get_cf(metadata_cf(db), merge_marker_key(sha).as_bytes())
+		.context(error::Db)?
+		.is_some())
+}
+
+/// Records that the merge of `sha` was handled, i.e. that its dependents were
+/// processed, so that it's not handled again when it's noticed once more (see
+/// `handle_merged_pull_request`). Only recorded once the handling succeeded so
+/// that a failure can be retried.
+pub fn mark_merge_as_handled(state: &AppState, sha: &str) -> Result<()> {
+	let AppState { db, .. } = state;
+
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|elapsed| elapsed.as_secs())
+		.unwrap_or(0);
+	db.put_cf(
+		metadata_cf(db),
+		merge_marker_key(sha).as_bytes(),
+		bincode::serialize(&now).context(error::Bincode)?,
+	)
+	.context(error::Db)
+}
+
+pub async fn handle_merged_pull_request(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	requested_by: &str,
+) -> Result<bool> {
+	if !pr.merged {
+		return Ok(false);
+	}
+
+	// The merge might be noticed more than once (e.g. through both a webhook and
+	// the poll), but the dependents should only be processed once; the merge is
+	// marked as handled by process_dependents_after_merge
+	if is_merge_handled(state, &pr.head.sha)? {
+		log::info!(
+			"The merge of {} (sha {}) was already handled",
+			pr.html_url,
+			pr.head.sha
+		);
+		return Ok(true);
+	}
+
+	let was_cleaned_up = cleanup_merge_request(
+		state,
+		&pr.head.sha,
+		&pr.base.repo.owner.login,
+		&pr.base.repo.name,
+		pr.number,
+		&MergeRequestCleanupReason::AfterMerge,
+	)
+	.await
+	.map(|_| true);
+
+	/*
+		It's not sane to try to handle the dependents if the cleanup went wrong since
+		that hints at some bug in the application
+	*/
+	if was_cleaned_up.is_ok() {
+		if let Err(err) =
+			process_dependents_after_merge(state, pr, requested_by).await
+		{
+			log::error!(
+				"Failed to process process_dependents_after_merge in cleanup_merged_pr due to {:?}",
+				err
+			);
+		}
+	}
+
+	was_cleaned_up
+}
+
+/// Whether a pull request's checks and statuses allow it to be merged. Failures
+/// which are not expected to be solved on their own are reported as errors
+/// instead (see `Error::ChecksFailed` and `Error::StatusesFailed`).
+#[derive(Debug, PartialEq)]
+pub enum MergeReadiness {
+	Ready,
+	// The statuses are only evaluated once all the checks have completed
+	WaitingOnChecks {
+		pending: Vec<String>,
+		failing: Vec<String>,
+	},
+	// The failing statuses are GitLab jobs which are being retried
+	WaitingOnStatuses {
+		pending: Vec<String>,
+		failing: Vec<String>,
+	},
+	// There's neither a check run nor a status yet, which is not enough for
+	// repositories configured in `require_at_least_one_check`
+	NoChecks,
+	// No member of these teams, configured in `required_review_teams`, has
+	// approved the pull request yet
+	WaitingOnReviews {
+		teams: Vec<String>,
+	},
+}
+
+impl MergeReadiness {
+	pub fn is_ready(&self) -> bool {
+		matches!(self, MergeReadiness::Ready)
+	}
+}
+
+// Describes the contexts, e.g. "waiting on 2 checks: build, test"
+fn write_waiting_contexts(
+	f: &mut fmt::Formatter<'_>,
+	(singular, plural): (&str, &str),
+	pending: &[String],
+	failing: &[String],
+) -> fmt::Result {
+	let mut parts = vec![];
+	if !pending.is_empty() {
+		parts.push(format!(
+			"waiting on {} {}: {}",
+			pending.len(),
+			if pending.len() == 1 { singular } else { plural },
+			pending.join(", ")
+		));
+	}
+	if !failing.is_empty() {
+		parts.push(format!("failing: {}", failing.join(", ")));
+	}
+	if parts.is_empty() {
+		write!(f, "waiting on the {}", plural)
+	} else {
+		write!(f, "{}", parts.join("; "))
+	}
+}
+
+impl fmt::Display for MergeReadiness {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			MergeReadiness::Ready => write!(f, "ready to merge"),
+			MergeReadiness::NoChecks => {
+				write!(f, "waiting on at least one check or status")
+			}
+			MergeReadiness::WaitingOnReviews { teams } => {
+				write!(f, "waiting on an approval from {}", teams.join(", "))
+			}
+			MergeReadiness::WaitingOnChecks { pending, failing } => {
+				write_waiting_contexts(f, ("check", "checks"), pending, failing)
+			}
+			MergeReadiness::WaitingOnStatuses { pending, failing } => {
+				write_waiting_contexts(
+					f,
+					("status", "statuses"),
+					pending,
+					failing,
+				)
+			}
+		}
+	}
+}
+
+// The teams of `required_review_teams` which none of the pull request's approvers are members
+// of. Only the latest review of each user counts, except for plain comments which don't change
+// their verdict. Reviews of previous commits are ignored so that approvals don't outlive new
+// pushes.
+async fn missing_review_teams(
+	state: &AppState,
+	pr: &GithubPullRequest,
+) -> Result<Vec<String>> {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+	let owner = &pr.base.repo.owner.login;
+	let repo = &pr.base.repo.name;
+
+	let required_teams = config.required_review_teams_for(owner, repo);
+	if required_teams.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let mut verdicts = BTreeMap::new();
+	for review in gh_client
+		.pull_request_reviews(owner, repo, pr.number)
+		.await?
+	{
+		if review.state == GithubPullRequestReviewState::Commented
+			|| review.commit_id.as_ref() != Some(&pr.head.sha)
+		{
+			continue;
+		}
+		if let Some(user) = review.user {
+			verdicts.insert(user.login, review.state);
+		}
+	}
+	let approvers = verdicts
+		.into_iter()
+		.filter(|(_, verdict)| {
+			*verdict == GithubPullRequestReviewState::Approved
+		})
+		.map(|(login, _)| login)
+		.collect::<Vec<_>>();
+
+	let mut missing_teams = vec![];
+	'teams: for team in required_teams {
+		if let Some((org, team_slug)) = team.split_once('/') {
+			for approver in &approvers {
+				if gh_client.team_member(org, team_slug, approver).await? {
+					continue 'teams;
+				}
+			}
+		}
+		missing_teams.push(team.to_owned());
+	}
+	Ok(missing_teams)
+}
+
+pub async fn is_ready_to_merge(
+	state: &AppState,
+	pr: &GithubPullRequest,
+) -> Result<MergeReadiness> {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+	let owner = &pr.base.repo.owner.login;
+	let repo = &pr.base.repo.name;
+
+	// A single GraphQL request replaces the REST requests for the check runs and
+	// the statuses
+	let prefetched = if config.github_graphql_enabled {
+		match gh_client
+			.commit_checks_and_statuses(owner, repo, &pr.head.sha)
+			.await
+		{
+			Ok(prefetched) => Some(prefetched),
+			Err(err) => {
+				log::warn!(
+					"Falling back to the REST API for the checks and statuses of {} due to {}",
+					pr.html_url,
+					err
+				);
+				None
+			}
+		}
+	} else {
+		None
+	};
+	let (check_runs, statuses) = match prefetched {
+		Some((check_runs, statuses)) => (check_runs, Some(statuses)),
+		None => (
+			gh_client
+				.check_runs(owner, repo, &pr.head.sha)
+				.await
+				.map_err(Error::into_transient_api_error)?,
+			None,
+		),
+	};
+
+	let (checks_status, latest_checks) =
+		evaluate_commit_checks(config, check_runs, &pr.html_url);
+	match checks_status {
+		Status::Success => {
+			let statuses = match statuses {
+				Some(statuses) => statuses,
+				None => gh_client
+					.statuses(owner, repo, &pr.head.sha)
+					.await
+					.map_err(Error::into_transient_api_error)?,
+			};
+			let (statuses_status, latest_statuses) = evaluate_commit_statuses(
+				state,
+				owner,
+				repo,
+				pr.number,
+				&pr.head.sha,
+				&pr.html_url,
+				true,
+				statuses,
+			)
+			.await
+			.map_err(Error::into_transient_api_error)?;
+			match statuses_status {
+				Status::Success
+					if latest_checks.is_empty()
+						&& latest_statuses.is_empty()
+						&& config.requires_at_least_one_check(owner, repo) =>
+				{
+					log::info!(
+						"{} has neither checks nor statuses",
+						pr.html_url
+					);
+					Ok(MergeReadiness::NoChecks)
+				}
+				Status::Success => {
+					let teams = missing_review_teams(state, pr)
+						.await
+						.map_err(Error::into_transient_api_error)?;
+					if teams.is_empty() {
+						Ok(MergeReadiness::Ready)
+					} else {
+						log::info!(
+							"{} lacks an approval from {}",
+							pr.html_url,
+							teams.join(", ")
+						);
+						Ok(MergeReadiness::WaitingOnReviews { teams })
+					}
+				}
+				Status::Failure => {
+					let mut failing_statuses = latest_statuses
+						.into_iter()
+						.filter(|(_, (_, status_state, _))| {
+							*status_state == GithubCommitStatusState::Error
+								|| *status_state
+									== GithubCommitStatusState::Failure
+						})
+						.map(|(context, (_, _, target_url))| FailingContext {
+							name: context,
+							target_url,
+						})
+						.collect::<Vec<_>>();
+					failing_statuses.sort_by(|a, b| a.name.cmp(&b.name));
+					Err(Error::StatusesFailed {
+						commit_sha: pr.head.sha.to_owned(),
+						failing_statuses,
+					})
+				}
+				Status::Pending => {
+					let contexts_in = |states: &[GithubCommitStatusState]| {
+						let mut contexts = latest_statuses
+							.iter()
+							.filter(|(_, (_, status_state, _))| {
+								states.contains(status_state)
+							})
+							.map(|(context, _)| context.to_owned())
+							.collect::<Vec<_>>();
+						contexts.sort();
+						contexts
+					};
+					Ok(MergeReadiness::WaitingOnStatuses {
+						pending: contexts_in(&[
+							GithubCommitStatusState::Pending,
+						]),
+						failing: contexts_in(&[
+							GithubCommitStatusState::Error,
+							GithubCommitStatusState::Failure,
+						]),
+					})
+				}
+			}
+		}
+		Status::Failure => {
+			let mut failing_checks = latest_checks
+				.into_iter()
+				.filter(|(_, check_run)| {
+					!config.is_check_conclusion_passing(check_run.conclusion)
+				})
+				.map(|(name, check_run)| FailingContext {
+					name,
+					target_url: check_run.html_url,
+				})
+				.collect::<Vec<_>>();
+			failing_checks.sort_by(|a, b| a.name.cmp(&b.name));
+			Err(Error::ChecksFailed {
+				commit_sha: pr.head.sha.to_owned(),
+				failing_checks,
+			})
+		}
+		Status::Pending => {
+			let mut pending = vec![];
+			let mut failing = vec![];
+			for (name, check_run) in latest_checks {
+				if check_run.status != GithubCheckRunStatus::Completed {
+					pending.push(name);
+				} else if !config
+					.is_check_conclusion_passing(check_run.conclusion)
+				{
+					failing.push(name);
+				}
+			}
+			pending.sort();
+			failing.sort();
+			Ok(MergeReadiness::WaitingOnChecks { pending, failing })
+		}
+	}
+}
+
+// Fills in the placeholders of a merge commit template, e.g. "{title} (#{number})"
+fn render_merge_commit_template(
+	template: &str,
+	pr: &GithubPullRequest,
+) -> String {
+	template
+		.replace("{title}", &pr.title)
+		.replace("{number}", &pr.number.to_string())
+		.replace("{html_url}", &pr.html_url)
+		.replace("{body}", pr.body.as_deref().unwrap_or(""))
+}
+
+/// Appends the configured trailers to the merge commit's `message`, separated
+/// from it by a blank line so that Git recognizes them as trailers. The
+/// placeholders {requested_by}, {number} and {html_url} are replaced in the
+/// trailers' values. Nothing is appended without a `message`, since Github
+/// only generates one if it's omitted.
+fn append_merge_commit_trailers(
+	message: Option<String>,
+	trailers: &[String],
+	pr: &GithubPullRequest,
+	requested_by: &str,
+) -> Option<String> {
+	// Github's default message is kept if no template is configured
+	let message = match message {
+		Some(message) if !trailers.is_empty() => message,
+		message => return message,
+	};
+
+	let trailers = trailers
+		.iter()
+		.map(|trailer| {
+			trailer
+				.replace("{requested_by}", requested_by)
+				.replace("{number}", &pr.number.to_string())
+				.replace("{html_url}", &pr.html_url)
+				// A line break would end the trailer
+				.replace(&['\n', '\r'][..], " ")
+		})
+		.collect::<Vec<_>>()
+		.join("\n");
+
+	Some(if message.trim().is_empty() {
+		trailers
+	} else {
+		format!("{}\n\n{}", message.trim_end(), trailers)
+	})
+}
+
+pub async fn merge_pull_request(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	requested_by: &str,
+) -> Result<Result<()>> {
+	if handle_merged_pull_request(state, pr, requested_by).await? {
+		return Ok(Ok(()));
+	}
+
+	let msg = match attempt_merge(state, pr, requested_by).await? {
+		Some(msg) => msg,
+		None => return Ok(Ok(())),
+	};
+
+	// Someone might have merged the pull request between the readiness check
+	// and the merge attempt, in which case the API refuses the merge even though
+	// it's already done
+	if is_already_merged_failure(&msg) {
+		match state
+			.gh_client
+			.pull_request(
+				&pr.base.repo.owner.login,
+				&pr.base.repo.name,
+				pr.number,
+			)
+			.await
+		{
+			Ok(fetched_pr) if fetched_pr.merged => {
+				log::info!(
+					"{} was merged by someone else before processbot could merge it; message: {}",
+					pr.html_url,
+					msg
+				);
+				handle_merged_pull_request(state, &fetched_pr, requested_by)
+					.await?;
+				return Ok(Ok(()));
+			}
+			Ok(_) => (),
+			Err(err) => log::error!(
+				"Failed to check if {} was already merged due to {}",
+				pr.html_url,
+				err
+			),
+		}
+	}
+
+	// Merging would not succeed on later attempts either, thus the merge is
+	// cancelled with an explanation rather than queued
+	if is_merge_queue_failure(&msg) {
+		log::info!(
+			"{} can't be merged through the API since its repository uses GitHub's merge queue; message: {}",
+			pr.html_url,
+			msg
+		);
+		return Err(Error::MergeQueueRequired {
+			html_url: pr.html_url.to_owned(),
+		});
+	}
+
+	// Branch protection demands more approvals, which processbot can't provide,
+	// thus the merge is cancelled after asking the configured reviewers for help
+	if is_missing_approval_failure(&msg) {
+		return Err(request_default_reviewers(state, pr, &msg).await);
+	}
+
+	if is_missing_status_failure(&msg) {
+		return Ok(Err(Error::MergeFailureWillBeSolvedLater {
+			msg,
+			updated_sha: None,
+		}));
+	}
+
+	// Branch protection might require the branch to be up-to-date with the
+	// base branch. processbot is able to update it if the branch belongs to the
+	// base repository or if the fork allows edits from maintainers.
+	let is_branch_out_of_date = is_branch_out_of_date_failure(&msg);
+	if is_branch_out_of_date && pr.head.repo.is_none() {
+		return Err(Error::HeadRepositoryDeleted {
+			html_url: pr.html_url.clone(),
+		});
+	}
+	let can_update_branch = pr.maintainer_can_modify
+		|| pr
+			.head
+			.repo
+			.as_ref()
+			.map(|head_repo| head_repo.owner.login == pr.base.repo.owner.login)
+			.unwrap_or(false);
+	if can_update_branch && is_branch_out_of_date {
+		log::info!(
+			"Updating the branch of {} since it's out-of-date; message: {}",
+			pr.html_url,
+			msg
+		);
+		let updated_pr = update_pull_request_branch(state, pr).await?;
+
+		// Retry only once so that a branch which keeps falling behind is not
+		// updated indefinitely
+		let msg = match attempt_merge(state, &updated_pr, requested_by).await? {
+			Some(msg) => msg,
+			None => return Ok(Ok(())),
+		};
+		if is_missing_status_failure(&msg) {
+			return Ok(Err(Error::MergeFailureWillBeSolvedLater {
+				msg,
+				updated_sha: Some(updated_pr.head.sha),
+			}));
+		}
+		return Err(Error::Message { msg });
+	}
+
+	Err(Error::Message { msg })
+}
+
+// Attempts the merge through the API. Returns the message of the API's response
+// if the merge was not allowed, e.g. due to branch protection rules.
+async fn attempt_merge(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	requested_by: &str,
+) -> Result<Option<String>> {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+
+	let (commit_title_template, commit_message_template) = config
+		.merge_commit_templates_for(
+			&pr.base.repo.owner.login,
+			&pr.base.repo.name,
+		);
+	let commit_title = commit_title_template
+		.map(|template| render_merge_commit_template(template, pr));
+	let commit_message = commit_message_template
+		.map(|template| render_merge_commit_template(template, pr));
+	let commit_message = append_merge_commit_trailers(
+		commit_message,
+		&config.merge_commit_trailers,
+		pr,
+		requested_by,
+	);
+
+	let merge_method = resolve_merge_method(state, pr).await?;
+
+	let err = match gh_client
+		.merge_pull_request(
+			&pr.base.repo.owner.login,
+			&pr.base.repo.name,
+			pr.number,
+			&pr.head.sha,
+			merge_method,
+			commit_title.as_deref(),
+			commit_message.as_deref(),
+		)
+		.await
+	{
+		Ok(_) => {
+			log::info!("{} merged successfully.", pr.html_url);
+			if let Some(time_in_queue) = state
+				.db
+				.get(pr.head.sha.as_bytes())
+				.ok()
+				.flatten()
+				.and_then(|bytes| deserialize_merge_request(&bytes).ok())
+				.and_then(|mr| mr.time_in_queue())
+			{
+				log::info!(
+					"{} was merged after spending {} seconds in the queue",
+					pr.html_url,
+					time_in_queue.as_secs()
+				);
+			}
+			// Merge succeeded! Now clean it from the database
+			if let Err(err) = cleanup_merge_request(
+				state,
+				&pr.head.sha,
+				&pr.base.repo.owner.login,
+				&pr.base.repo.name,
+				pr.number,
+				&MergeRequestCleanupReason::AfterMerge,
+			)
+			.await
+			{
+				log::error!(
+					"Failed to cleanup PR on the database after merge: {}",
+					err
+				);
+			};
+			post_merge_success_comment(state, pr, requested_by).await;
+			return Ok(None);
+		}
+		Err(err) => err,
+	};
+
+	match err {
+		Error::Response {
+			ref status,
+			ref body,
+		} if *status == HttpStatusCode::METHOD_NOT_ALLOWED => {
+			match body.get("message") {
+				Some(msg) => match msg.as_str() {
+					Some(msg) => Ok(Some(msg.to_string())),
+					None => {
+						log::error!("Expected \"message\" of Github API merge failure response to be a string");
+						Err(err)
+					}
+				},
+				None => {
+					log::error!("Expected \"message\" of Github API merge failure response to be available");
+					Err(err)
+				}
+			}
+		}
+		_ => Err(err),
+	}
+}
+
+// Branches which require a linear history reject merge commits, in which case
+// the pull request is rebased instead
+async fn resolve_merge_method(
+	state: &AppState,
+	pr: &GithubPullRequest,
+) -> Result<GithubMergeMethod> {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+
+	let merge_method =
+		config.merge_method_for(&pr.base.repo.owner.login, &pr.base.repo.name);
+	if merge_method == GithubMergeMethod::Merge
+		&& gh_client
+			.requires_linear_history(
+				&pr.base.repo.owner.login,
+				&pr.base.repo.name,
+				&pr.base.ref_field,
+			)
+			.await?
+	{
+		log::info!(
+			"Rebasing {} instead of creating a merge commit since {} requires a linear history",
+			pr.html_url,
+			pr.base.ref_field
+		);
+		if !config.merge_commit_trailers.is_empty() {
+			log::warn!(
+				"The merge commit trailers are left out of {} since it's rebased",
+				pr.html_url
+			);
+		}
+		return Ok(GithubMergeMethod::Rebase);
+	}
+
+	Ok(merge_method)
+}
+
+// The comment is opt-in, thus it's only posted if MESSAGE_TEMPLATE_MERGE_SUCCEEDED
+// is configured
+async fn post_merge_success_comment(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	requested_by: &str,
+) {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+
+	if config
+		.message_templates
+		.template(Message::MergeSucceeded)
+		.is_empty()
+	{
+		return;
+	}
+
+	let dependents = list_merge_requests(state)
+		.into_iter()
+		.filter(|mr| {
+			mr.dependencies
+				.as_ref()
+				.map(|dependencies| {
+					dependencies.iter().any(|dependency| {
+						dependency.owner == pr.base.repo.owner.login
+							&& dependency.repo == pr.base.repo.name
+							&& dependency.number == pr.number
+					})
+				})
+				.unwrap_or(false)
+		})
+		.map(|mr| format!("- {}", mr.html_url))
+		.collect::<Vec<_>>()
+		.join("\n");
+	let msg = config.message_templates.render(
+		Message::MergeSucceeded,
+		&[("requested_by", requested_by), ("dependents", &dependents)],
+	);
+
+	if let Err(err) = gh_client
+		.create_issue_comment(
+			&pr.base.repo.owner.login,
+			&pr.base.repo.name,
+			pr.number,
+			&msg,
+		)
+		.await
+	{
+		log::error!("Error posting comment: {}", err);
+	}
+}
+
+// Matches the following
+// - "Required status check ... is {pending,expected}."
+// - "... required status checks have not succeeded: ... {pending,expected}."
+// This problem will be solved automatically when all the required statuses are
+// delivered, thus the merge can be attempted again later.
+fn is_missing_status_failure(msg: &str) -> bool {
+	let missing_status_matcher =
+		RegexBuilder::new(r"required\s+status\s+.*(pending|expected)")
+			.case_insensitive(true)
+			.build()
+			.unwrap();
+
+	if missing_status_matcher.find(msg).is_some() {
+		log::info!(
+			"Ignoring merge failure due to pending required status; message: {}",
+			msg
+		);
+		true
+	} else {
+		false
+	}
+}
+
+// Github answers with "Pull Request is not mergeable" when the pull request was
+// already merged
+fn is_already_merged_failure(msg: &str) -> bool {
+	RegexBuilder::new(r"not\s+mergeable|already\s+merged")
+		.case_insensitive(true)
+		.build()
+		.unwrap()
+		.is_match(msg)
+}
+
+// Matches e.g. "Changes must be made through the merge queue", which is how the
+// API rejects merges for repositories where GitHub's merge queue is required
+fn is_merge_queue_failure(msg: &str) -> bool {
+	RegexBuilder::new(r"merge\s+queue")
+		.case_insensitive(true)
+		.build()
+		.unwrap()
+		.is_match(msg)
+}
+
+// Matches e.g. "At least 1 approving review is required by reviewers with write
+// access" and "Waiting on code owner review from ..."
+fn is_missing_approval_failure(msg: &str) -> bool {
+	RegexBuilder::new(
+		r"approving\s+reviews?\s+(is|are)\s+required|code\s+owner\s+review",
+	)
+	.case_insensitive(true)
+	.build()
+	.unwrap()
+	.is_match(msg)
+}
+
+async fn request_default_reviewers(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	msg: &str,
+) -> Error {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+
+	log::info!(
+		"{} can't be merged since it lacks approvals; message: {}",
+		pr.html_url,
+		msg
+	);
+
+	let reviewers = config
+		.default_reviewers_for(&pr.base.repo.owner.login, &pr.base.repo.name)
+		.iter()
+		// The author can't review their own pull request
+		.filter(|reviewer| {
+			pr.user
+				.as_ref()
+				.map(|user| user.login != **reviewer)
+				.unwrap_or(true)
+		})
+		.cloned()
+		.collect::<Vec<_>>();
+	let requested_reviewers = if reviewers.is_empty() {
+		vec![]
+	} else {
+		match gh_client
+			.request_reviewers(
+				&pr.base.repo.owner.login,
+				&pr.base.repo.name,
+				pr.number,
+				&reviewers,
+			)
+			.await
+		{
+			Ok(_) => reviewers,
+			Err(err) => {
+				log::error!(
+					"Failed to request reviews for {} due to {}",
+					pr.html_url,
+					err
+				);
+				vec![]
+			}
+		}
+	};
+
+	Error::ApprovalsRequired {
+		html_url: pr.html_url.to_owned(),
+		requested_reviewers,
+	}
+}
+
+// Matches e.g. "Head branch is out of date" and "... is not up to date with the
+// base branch"
+fn is_branch_out_of_date_failure(msg: &str) -> bool {
+	RegexBuilder::new(r"out[\s-]of[\s-]date|not\s+up[\s-]to[\s-]date")
+		.case_insensitive(true)
+		.build()
+		.unwrap()
+		.is_match(msg)
+}
+
+// Merges the base branch into the pull request's branch and waits for the new
+// HEAD to be reflected by the API. The merge request registered for the
+// previous HEAD, if any, is cleaned up so that it can be registered again for
+// the new HEAD.
+async fn update_pull_request_branch(
+	state: &AppState,
+	pr: &GithubPullRequest,
+) -> Result<GithubPullRequest> {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+
+	gh_client
+		.update_pull_request_branch(
+			&pr.base.repo.owner.login,
+			&pr.base.repo.name,
+			pr.number,
+			&pr.head.sha,
+		)
+		.await?;
+
+	let updated_pr = wait_for_pull_request_head_update(
+		gh_client,
+		&pr.base.repo.owner.login,
+		&pr.base.repo.name,
+		pr.number,
+		&pr.head.sha,
+		config.companion_status_settle_delay,
+	)
+	.await?;
+
+	cleanup_merge_request(
+		state,
+		&pr.head.sha,
+		&pr.base.repo.owner.login,
+		&pr.base.repo.name,
+		pr.number,
+		&MergeRequestCleanupReason::AfterSHAUpdate(&updated_pr.head.sha),
+	)
+	.await?;
+
+	Ok(updated_pr)
+}
+
+/// Collect all merge requests currently registered in the database. Records which fail to be
+/// deserialized are deleted.
+pub fn list_merge_requests(state: &AppState) -> Vec<MergeRequest> {
+	let AppState { db, .. } = state;
+
+	let mut mrs = vec![];
+	let db_iter =
+		db.iterator_cf(merge_requests_cf(db), rocksdb::IteratorMode::Start);
+	for (key, value) in db_iter {
+		match deserialize_merge_request(&value) {
+			Ok(mr) => mrs.push(mr),
+			Err(err) => {
+				log::error!(
+					"Failed to deserialize key {} from the database due to {:?}",
+					String::from_utf8_lossy(&key),
+					err
+				);
+				let _ = db.delete_cf(merge_requests_cf(db), &key);
+			}
+		}
+	}
+
+	mrs
+}
+
+/// Delete the merge requests registered for a pull request without any of the side-effects of
+/// `cleanup_merge_request`, e.g. for getting rid of a stuck record. Returns whether any record
+/// was deleted.
+pub fn delete_merge_request(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+	number: i64,
+) -> Result<bool> {
+	let AppState { db, .. } = state;
+
+	let mut keys_to_delete = vec![];
+	let db_iter =
+		db.iterator_cf(merge_requests_cf(db), rocksdb::IteratorMode::Start);
+	for (key, value) in db_iter {
+		if let Ok(mr) = deserialize_merge_request(&value) {
+			if mr.owner == owner && mr.repo == repo && mr.number == number {
+				keys_to_delete.push(key);
+			}
+		}
+	}
+
+	for key in &keys_to_delete {
+		log::info!(
+			"Deleting key {} of {}/{}/pull/{}",
+			String::from_utf8_lossy(key),
+			owner,
+			repo,
+			number
+		);
+		db.delete_cf(merge_requests_cf(db), key)
+			.context(error::Db)?;
+	}
+
+	Ok(!keys_to_delete.is_empty())
+}
+
+fn merge_request_seq_key(owner: &str, repo: &str) -> String {
+	format!("merge_request_seq/{}/{}", owner, repo)
+}
+
+// Allocates the next sequence number of the repository's merge requests. The
+// state is only accessed by one task at a time, thus the counter can't be
+// handed out twice.
+fn next_merge_request_seq(
+	state: &AppState,
+	owner: &str,
+	repo: &str,
+) -> Result<u64> {
+	let AppState { db, .. } = state;
+
+	let key = merge_request_seq_key(owner, repo);
+	let seq = match db
+		.get_cf(metadata_cf(db), key.as_bytes())
+		.context(error::Db)?
+	{
+		Some(bytes) => {
+			bincode::deserialize::<u64>(&bytes).context(error::Bincode)? + 1
+		}
+		None => 0,
+	};
+	db.put_cf(
+		metadata_cf(db),
+		key.as_bytes(),
+		bincode::serialize(&seq).context(error::Bincode)?,
+	)
+	.context(error::Db)?;
+
+	Ok(seq)
+}
+
+/// Rejects a new merge command for `pr` with `Error::QueueFull` if `max_queue_size` pull
+/// requests are already queued. Pull requests are counted rather than merge requests, and a pull
+/// request which is already queued can always be queued again. The merge requests registered
+/// internally, e.g. for the updated SHA of a companion, are not limited since they belong to
+/// merges which are already in progress.
+pub fn check_queue_capacity(
+	state: &AppState,
+	pr: &GithubPullRequest,
+) -> Result<()> {
+	let AppState { config, .. } = state;
+
+	if config.max_queue_size == 0 {
+		return Ok(());
+	}
+
+	let queued_prs = list_merge_requests(state)
+		.into_iter()
+		.map(|mr| (mr.owner, mr.repo, mr.number))
+		.collect::<HashSet<_>>();
+	if queued_prs.contains(&(
+		pr.base.repo.owner.login.clone(),
+		pr.base.repo.name.clone(),
+		pr.number,
+	)) {
+		return Ok(());
+	}
+
+	if queued_prs.len() >= config.max_queue_size {
+		log::info!(
+			"Rejecting merge request for {} since the queue is full ({} pull requests)",
+			pr.html_url,
+			queued_prs.len()
+		);
+		return Err(Error::QueueFull {
+			html_url: pr.html_url.clone(),
+			max_queue_size: config.max_queue_size,
+		});
+	}
+
+	Ok(())
+}
+
+async fn register_merge_request(
+	state: &AppState,
+	mr: &MergeRequest,
+) -> Result<()> {
+	let AppState { db, .. } = state;
+	let MergeRequest { sha, .. } = mr;
+
+	let mut mr = mr.clone();
+	if mr.queued_at.is_none() {
+		mr.queued_at = Some(SystemTime::now());
+	}
+	if mr.seq.is_none() {
+		// A merge request which is registered again keeps its place
+		mr.seq = match db
+			.get_cf(merge_requests_cf(db), sha.as_bytes())
+			.context(error::Db)?
+			.and_then(|bytes| deserialize_merge_request(&bytes).ok())
+			// Sequences are only comparable within a repository
+			.filter(|registered_mr| {
+				registered_mr.owner == mr.owner && registered_mr.repo == mr.repo
+			})
+			.and_then(|registered_mr| registered_mr.seq)
+		{
+			Some(seq) => Some(seq),
+			None => Some(next_merge_request_seq(state, &mr.owner, &mr.repo)?),
+		};
+	}
+
+	log::info!("Registering merge request (sha: {}): {:?}", sha, mr);
+	db.put_cf(
+		merge_requests_cf(db),
+		sha.as_bytes(),
+		bincode::serialize(&mr).context(error::Bincode)?,
+	)
+	.context(error::Db)
+}
+
+/// Whether `requested_by` would still be allowed to use `bot merge` on the pull request, i.e. they
+/// are a member of its organization or of the privileged teams, or a code owner of its changes
+/// with `codeowners_authorization`. A user whose account no longer exists is not a member of
+/// anything. A merge allowed once through `bot merge allow` stays allowed until its merge request
+/// is cleaned up (see `is_merge_granted`).
+pub async fn is_requester_still_allowed(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	requested_by: &str,
+) -> Result<bool> {
+	let AppState {
+		gh_client, config, ..
+	} = state;
+	let owner = &pr.base.repo.owner.login;
+	let repo = &pr.base.repo.name;
+
+	if config.disable_org_checks_for(owner, repo) {
+		return Ok(true);
+	}
+
+	if is_merge_granted(state, owner, repo, pr.number, requested_by)? {
+		return Ok(true);
+	}
+
+	match gh_client.org_member(owner, requested_by).await {
+		Ok(true) => return Ok(true),
+		Ok(false) | Err(Error::Response { .. }) => (),
+		Err(err) => return Err(err),
+	}
+
+	if gh_client
+		.member_of_any_team(&config.privileged_teams, requested_by)
+		.await?
+	{
+		return Ok(true);
+	}
+
+	if config.codeowners_authorization {
+		return gh_client
+			.is_code_owner_of_pull_request(pr, requested_by)
+			.await;
+	}
+
+	Ok(false)
+}
+
+pub async fn check_merge_is_allowed(
+	state: &AppState,
 	pr: &GithubPullRequest,
 	requested_by: &str,
 	companion_reference_trail: &[CompanionReferenceTrailItem],
 ) -> Result<()> {
-	if !pr.mergeable.unwrap_or(false) {
+	// Drafts are not mergeable, but the API does not say why
+	if pr.draft {
+		return Err(Error::DraftPullRequest {
+			html_url: pr.html_url.to_owned(),
+		});
+	}
+
+	// Github reports conflicts through the "dirty" mergeable state
+	if pr.mergeable == Some(false)
+		&& pr.mergeable_state.as_deref() == Some("dirty")
+	{
+		return Err(Error::MergeConflict {
+			html_url: pr.html_url.to_owned(),
+		});
+	} else if !pr.mergeable.unwrap_or(false) {
 		return Err(Error::Message {
 			msg: format!("Github API says {} is not mergeable", pr.html_url),
 		});
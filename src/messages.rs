@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+/// The comments which processbot posts in pull requests. Their templates can be
+/// overridden through the `MESSAGE_TEMPLATE_<NAME>` environment variables, e.g.
+/// `MESSAGE_TEMPLATE_QUEUED`, where `<NAME>` is given by `Message::name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Message {
+	Queued,
+	QueuedAfterMergeFailure,
+	QueuedWithDelay,
+	QueuedAfterDependency,
+	MergeCancelled,
+	MergeCancelledDueToError,
+	MergeCancelledDueToNewCommits,
+	Unqueued,
+	Rebased,
+	BranchAlreadyUpToDate,
+	Updated,
+	BaseBranchNotAllowed,
+	RepositoryFrozen,
+	BranchFrozen,
+	Paused,
+	MergeReconfirmationRequired,
+	MergeHeld,
+	MergeAttemptFailed,
+	GaveUp,
+	MergeSucceeded,
+	ErrorNotCancelled,
+	GitlabJobsRecovered,
+}
+
+impl Message {
+	pub const ALL: &'static [Message] = &[
+		Message::Queued,
+		Message::QueuedAfterMergeFailure,
+		Message::QueuedWithDelay,
+		Message::QueuedAfterDependency,
+		Message::MergeCancelled,
+		Message::MergeCancelledDueToError,
+		Message::MergeCancelledDueToNewCommits,
+		Message::Unqueued,
+		Message::Rebased,
+		Message::BranchAlreadyUpToDate,
+		Message::Updated,
+		Message::BaseBranchNotAllowed,
+		Message::RepositoryFrozen,
+		Message::BranchFrozen,
+		Message::Paused,
+		Message::MergeReconfirmationRequired,
+		Message::MergeHeld,
+		Message::MergeAttemptFailed,
+		Message::GaveUp,
+		Message::MergeSucceeded,
+		Message::ErrorNotCancelled,
+		Message::GitlabJobsRecovered,
+	];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Message::Queued => "QUEUED",
+			Message::QueuedAfterMergeFailure => "QUEUED_AFTER_MERGE_FAILURE",
+			Message::QueuedWithDelay => "QUEUED_WITH_DELAY",
+			Message::QueuedAfterDependency => "QUEUED_AFTER_DEPENDENCY",
+			Message::MergeCancelled => "MERGE_CANCELLED",
+			Message::MergeCancelledDueToError => "MERGE_CANCELLED_DUE_TO_ERROR",
+			Message::MergeCancelledDueToNewCommits => {
+				"MERGE_CANCELLED_DUE_TO_NEW_COMMITS"
+			}
+			Message::Unqueued => "UNQUEUED",
+			Message::Rebased => "REBASED",
+			Message::BranchAlreadyUpToDate => "BRANCH_ALREADY_UP_TO_DATE",
+			Message::Updated => "UPDATED",
+			Message::BaseBranchNotAllowed => "BASE_BRANCH_NOT_ALLOWED",
+			Message::RepositoryFrozen => "REPOSITORY_FROZEN",
+			Message::BranchFrozen => "BRANCH_FROZEN",
+			Message::Paused => "PAUSED",
+			Message::MergeReconfirmationRequired => {
+				"MERGE_RECONFIRMATION_REQUIRED"
+			}
+			Message::MergeHeld => "MERGE_HELD",
+			Message::MergeAttemptFailed => "MERGE_ATTEMPT_FAILED",
+			Message::GaveUp => "GAVE_UP",
+			Message::MergeSucceeded => "MERGE_SUCCEEDED",
+			Message::ErrorNotCancelled => "ERROR_NOT_CANCELLED",
+			Message::GitlabJobsRecovered => "GITLAB_JOBS_RECOVERED",
+		}
+	}
+
+	/// The placeholders of the templates are documented in .env.example
+	pub fn default_template(self) -> &'static str {
+		match self {
+			Message::Queued => "Waiting for commit status.",
+			Message::QueuedAfterMergeFailure => "This PR cannot be merged **at the moment** due to: {reason}\n\nprocessbot expects that the problem will be solved automatically later and so the auto-merge process will be started. You can simply wait for now.\n\n",
+			Message::QueuedWithDelay => "The merge will be attempted after {not_before} if the checks are passing by then.",
+			Message::QueuedAfterDependency => "The merge will be attempted after {dependency} is merged.",
+			Message::MergeCancelled => "Merge cancelled.",
+			Message::MergeCancelledDueToError => "Merge cancelled due to error.",
+			Message::MergeCancelledDueToNewCommits => "Merge cancelled since {pusher} pushed new commits which were not vetted by {requested_by}. Run `bot merge` again to merge the new commits.",
+			Message::Unqueued => "Removed from the merge queue. The merges which depended on this pull request are no longer waiting for it.",
+			Message::Rebased => "Rebased",
+			Message::BranchAlreadyUpToDate => "Branch is already up-to-date",
+			Message::Updated => "Updated the branch and its lockfile to {sha}. It will not be merged unless `bot merge` is used.",
+			Message::BaseBranchNotAllowed => "processbot is not allowed to merge pull requests into {branch} in this repository.",
+			Message::RepositoryFrozen => "Merges are frozen for this repository.",
+			Message::BranchFrozen => "Merges into {branch} are frozen for this repository.",
+			Message::Paused => "processbot is paused; nothing will be merged until it's resumed. Run the command again afterwards.",
+			Message::MergeReconfirmationRequired => "{sha} was pushed after {requested_by} requested the merge, thus it was not merged. Run `bot merge` again to merge the new commits.",
+			Message::MergeHeld => "The merge is on hold since {requested_by} is no longer allowed to merge this pull request, e.g. because they left the organization. Run `bot merge` again to merge it.",
+			Message::MergeAttemptFailed => "Attempt {attempt} of {max_attempts} to merge this pull request failed: {error}",
+			Message::GaveUp => "processbot is giving up on merging this pull request after {attempts} attempts. Please check the errors reported above and run `bot merge` again once they are solved.",
+			// Not posted unless a template is configured since the merge is
+			// already visible in the pull request
+			Message::MergeSucceeded => "",
+			Message::ErrorNotCancelled => "Some error happened, but the merge was not cancelled (likely due to a bug).",
+			Message::GitlabJobsRecovered => "The following jobs are failing on GitHub, but their GitLab pipelines are still pending (they might have been retried), therefore processbot will keep waiting for them:\n\n{jobs}",
+		}
+	}
+}
+
+/// The templates of the messages, which fall back to `Message::default_template`
+/// unless overridden
+#[derive(Debug, Clone, Default)]
+pub struct MessageTemplates {
+	overrides: HashMap<Message, String>,
+}
+
+impl MessageTemplates {
+	pub fn from_env() -> Self {
+		let mut templates = Self::default();
+		for message in Message::ALL {
+			let var = format!("MESSAGE_TEMPLATE_{}", message.name());
+			if let Ok(template) = dotenv::var(&var) {
+				templates.set(*message, template);
+			}
+		}
+		templates
+	}
+
+	pub fn set(&mut self, message: Message, template: String) {
+		self.overrides.insert(message, template);
+	}
+
+	pub fn template(&self, message: Message) -> &str {
+		self.overrides
+			.get(&message)
+			.map(|template| template.as_str())
+			.unwrap_or_else(|| message.default_template())
+	}
+
+	/// Fills in the placeholders of the message's template, e.g. `{branch}`,
+	/// with the given `(placeholder, value)` pairs. The template is scanned only
+	/// once, thus braces within the values, e.g. in error descriptions, are kept
+	/// as they are.
+	pub fn render(&self, message: Message, values: &[(&str, &str)]) -> String {
+		let mut text = String::new();
+		let mut rest = self.template(message);
+		while let Some(start) = rest.find('{') {
+			text.push_str(&rest[..start]);
+			rest = &rest[start..];
+			let placeholder = rest.find('}').and_then(|end| {
+				values
+					.iter()
+					.find(|(placeholder, _)| **placeholder == rest[1..end])
+					.map(|(_, value)| (end, value))
+			});
+			match placeholder {
+				Some((end, value)) => {
+					text.push_str(value);
+					rest = &rest[end + 1..];
+				}
+				None => {
+					text.push('{');
+					rest = &rest[1..];
+				}
+			}
+		}
+		text.push_str(rest);
+		text
+	}
+}
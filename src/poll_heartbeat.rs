@@ -0,0 +1,47 @@
+use std::{
+	sync::atomic::{AtomicU64, Ordering},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+fn now_millis() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|elapsed| elapsed.as_millis() as u64)
+		.unwrap_or(0)
+}
+
+/// Tracks when the background poll last completed. The poll runs in its own
+/// thread, so a poll which stalls or dies would otherwise go unnoticed while
+/// the server keeps handling requests.
+pub struct PollHeartbeat {
+	interval: Duration,
+	// Milliseconds since the UNIX epoch
+	last_poll_completed: AtomicU64,
+}
+
+impl PollHeartbeat {
+	/// The heartbeat starts out as if a poll had just completed so that it's
+	/// healthy until the first poll is due
+	pub fn new(interval: Duration) -> Self {
+		Self {
+			interval,
+			last_poll_completed: AtomicU64::new(now_millis()),
+		}
+	}
+
+	pub fn record_poll_completed(&self) {
+		self.last_poll_completed
+			.store(now_millis(), Ordering::SeqCst);
+	}
+
+	pub fn last_poll_completed(&self) -> Duration {
+		Duration::from_millis(self.last_poll_completed.load(Ordering::SeqCst))
+	}
+
+	/// The poll is considered stalled once it's late for over a whole interval
+	pub fn is_healthy(&self) -> bool {
+		let elapsed = Duration::from_millis(now_millis())
+			.saturating_sub(self.last_poll_completed());
+		elapsed <= self.interval * 2
+	}
+}
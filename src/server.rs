@@ -12,12 +12,15 @@ pub async fn init(
 	addr: SocketAddr,
 	state: Arc<Mutex<AppState>>,
 ) -> anyhow::Result<()> {
+	let poll_heartbeat = state.lock().await.poll_heartbeat.clone();
 	let service = make_service_fn(move |_| {
 		let state = Arc::clone(&state);
+		let poll_heartbeat = Arc::clone(&poll_heartbeat);
 		async move {
 			Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
 				let state = Arc::clone(&state);
-				handle_http_request_for_bot(req, state)
+				let poll_heartbeat = Arc::clone(&poll_heartbeat);
+				handle_http_request_for_bot(req, state, poll_heartbeat)
 			}))
 		}
 	});
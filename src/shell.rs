@@ -1,15 +1,81 @@
 use std::{
+	collections::HashMap,
 	ffi::OsStr,
 	fmt::{Debug, Display},
 	path::Path,
 	process::{Output, Stdio},
+	sync::atomic::{AtomicU64, Ordering},
+	time::Duration,
 };
 
+use parking_lot::RwLock;
 use snafu::ResultExt;
 use tokio::process::Command;
 
 use crate::{error::*, types::Result};
 
+// How long (in seconds) a command can run before it's killed; 0 disables the
+// timeout. Set from MainConfig::command_timeout when the application starts.
+static COMMAND_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_command_timeout(timeout: Option<Duration>) {
+	COMMAND_TIMEOUT.store(
+		timeout.map(|timeout| timeout.as_secs()).unwrap_or(0),
+		Ordering::SeqCst,
+	);
+}
+
+fn command_timeout() -> Option<Duration> {
+	match COMMAND_TIMEOUT.load(Ordering::SeqCst) {
+		0 => None,
+		seconds => Some(Duration::from_secs(seconds)),
+	}
+}
+
+lazy_static::lazy_static! {
+	// Secrets which are hidden from the output of every command, regardless of
+	// the secrets_to_hide of each call, keyed by their name
+	static ref SECRETS: RwLock<HashMap<String, String>> =
+		RwLock::new(HashMap::new());
+}
+
+/// Register a secret to be hidden from the output of every command. Setting a
+/// name again replaces its previous value, e.g. when a token is refreshed.
+pub fn set_secret(name: &str, secret: &str) {
+	let mut secrets = SECRETS.write();
+	if secret.is_empty() {
+		secrets.remove(name);
+	} else {
+		secrets.insert(name.to_string(), secret.to_string());
+	}
+}
+
+fn redact<Secret: AsRef<str>>(
+	text: &str,
+	logging: &CommandMessage<Secret>,
+) -> String {
+	let mut text = text.to_string();
+	match logging {
+		CommandMessage::Configured(CommandMessageConfiguration {
+			secrets_to_hide,
+			..
+		}) => {
+			if let Some(secrets) = secrets_to_hide.as_ref() {
+				for secret in secrets.iter() {
+					let secret = secret.as_ref();
+					if !secret.is_empty() {
+						text = text.replace(secret, "${SECRET}");
+					}
+				}
+			}
+		}
+	}
+	for secret in SECRETS.read().values() {
+		text = text.replace(secret, "${SECRET}");
+	}
+	text
+}
+
 #[derive(PartialEq, Eq)]
 pub struct CommandMessageConfiguration<'a, Secret: AsRef<str>> {
 	pub secrets_to_hide: Option<&'a [Secret]>,
@@ -36,7 +102,7 @@ where
 	#[allow(unused_mut)]
 	let mut init_cmd = Command::new(cmd);
 	let cmd = init_cmd.args(args).current_dir(dir).stderr(Stdio::piped());
-	let result = cmd.output().await.context(Tokio)?;
+	let result = run_with_timeout(cmd, &logging).await?;
 
 	handle_cmd_result(cmd, result, &logging)
 }
@@ -54,7 +120,7 @@ where
 	#[allow(unused_mut)]
 	let mut init_cmd = Command::new(cmd);
 	let cmd = init_cmd.args(args).stderr(Stdio::piped());
-	let result = cmd.output().await.context(Tokio)?;
+	let result = run_with_timeout(cmd, &logging).await?;
 
 	handle_cmd_result(cmd, result, &logging)
 }
@@ -78,11 +144,46 @@ where
 		.current_dir(dir)
 		.stdin(Stdio::piped())
 		.stderr(Stdio::piped());
-	let result = cmd.output().await.context(Tokio)?;
+	let result = run_with_timeout(cmd, &logging).await?;
 
 	handle_cmd_result(cmd, result, &logging)
 }
 
+// Collects the command's output like `Command::output`, but kills the command if
+// it exceeds the configured timeout. The killed process is reaped by tokio in
+// the background, thus it does not linger as a zombie.
+async fn run_with_timeout<Secret: AsRef<str>>(
+	cmd: &mut Command,
+	logging: &CommandMessage<'_, Secret>,
+) -> Result<Output> {
+	let child = cmd
+		.stdout(Stdio::piped())
+		.kill_on_drop(true)
+		.spawn()
+		.context(Tokio)?;
+	let output = child.wait_with_output();
+
+	match command_timeout() {
+		Some(timeout) => match tokio::time::timeout(timeout, output).await {
+			Ok(result) => result.context(Tokio),
+			// The child is killed as the future owning it is dropped
+			Err(_) => {
+				let cmd = display_cmd(cmd, logging);
+				log::error!("{} timed out after {:?}", cmd, timeout);
+				Err(Error::CommandTimedOut { cmd, timeout })
+			}
+		},
+		None => output.await.context(Tokio),
+	}
+}
+
+fn display_cmd<Secret: AsRef<str>>(
+	cmd: &Command,
+	logging: &CommandMessage<Secret>,
+) -> String {
+	redact(&format!("{:?}", cmd), logging)
+}
+
 fn before_cmd<Cmd, Dir, Secret: AsRef<str>>(
 	cmd: Cmd,
 	args: &[&str],
@@ -92,33 +193,18 @@ fn before_cmd<Cmd, Dir, Secret: AsRef<str>>(
 	Cmd: AsRef<OsStr> + Display,
 	Dir: AsRef<Path> + Debug,
 {
-	match logging {
-		CommandMessage::Configured(CommandMessageConfiguration {
-			secrets_to_hide,
-			..
-		}) => {
-			let mut cmd_display = format!("{}", cmd);
-			let mut args_display = format!("{:?}", args);
-			if let Some(secrets) = secrets_to_hide.as_ref() {
-				for secret in secrets.iter() {
-					cmd_display =
-						cmd_display.replace(secret.as_ref(), "${SECRET}");
-					args_display =
-						args_display.replace(secret.as_ref(), "${SECRET}");
-				}
-			}
+	let cmd_display = redact(&format!("{}", cmd), logging);
+	let args_display = redact(&format!("{:?}", args), logging);
 
-			if let Some(dir) = dir {
-				log::info!("Run {} {} in {:?}", cmd_display, args_display, dir);
-			} else {
-				log::info!(
-					"Run {} {} in the current directory",
-					cmd_display,
-					args_display,
-				);
-			}
-		}
-	};
+	if let Some(dir) = dir {
+		log::info!("Run {} {} in {:?}", cmd_display, args_display, dir);
+	} else {
+		log::info!(
+			"Run {} {} in the current directory",
+			cmd_display,
+			args_display,
+		);
+	}
 }
 
 fn handle_cmd_result<Secret: AsRef<str>>(
@@ -132,29 +218,22 @@ fn handle_cmd_result<Secret: AsRef<str>>(
 		let (cmd_display, err_msg) = match logging {
 			CommandMessage::Configured(CommandMessageConfiguration {
 				are_errors_silenced,
-				secrets_to_hide,
+				..
 			}) => {
-				let mut cmd_display = format!("{:?}", cmd);
-				if let Some(secrets) = secrets_to_hide.as_ref() {
-					for secret in secrets.iter() {
-						cmd_display =
-							cmd_display.replace(secret.as_ref(), "${SECRET}");
-					}
-				}
+				let cmd_display = display_cmd(cmd, logging);
 				let err_msg = if *are_errors_silenced {
 					None
 				} else {
-					let err_output = String::from_utf8_lossy(&result.stderr);
+					// Some commands (e.g. git merge) report failures on stdout
+					let err_output = if result.stderr.is_empty() {
+						String::from_utf8_lossy(&result.stdout)
+					} else {
+						String::from_utf8_lossy(&result.stderr)
+					};
 					if err_output.is_empty() {
 						None
 					} else {
-						let mut err_output = err_output.to_string();
-						if let Some(secrets) = secrets_to_hide.as_ref() {
-							for secret in secrets.iter() {
-								err_output = err_output
-									.replace(secret.as_ref(), "${SECRET}");
-							}
-						}
+						let err_output = redact(&err_output, logging);
 						log::error!(
 							"handle_cmd_result: {} failed with error: {}",
 							cmd_display,
@@ -0,0 +1,57 @@
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicU64, Ordering},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use snafu::ResultExt;
+
+use crate::{
+	bot::handle_github_payload,
+	core::{AppState, PullRequestMergeCancelOutcome},
+	error::{self, Error},
+	github::GithubWebhookPayload,
+	types::Result,
+};
+
+/// Write a verified webhook payload to a timestamped file of `archive_dir` so
+/// that it can be replayed later. Only the body is archived; the signature
+/// header is left out on purpose.
+pub fn archive_webhook_payload(
+	archive_dir: &Path,
+	payload: &[u8],
+) -> std::io::Result<PathBuf> {
+	// Disambiguates payloads which are received within the same millisecond
+	static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+	let received_at = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|elapsed| elapsed.as_millis())
+		.unwrap_or(0);
+	let path = archive_dir.join(format!(
+		"{}-{}.json",
+		received_at,
+		SEQUENCE.fetch_add(1, Ordering::SeqCst)
+	));
+
+	fs::create_dir_all(archive_dir)?;
+	fs::write(&path, payload)?;
+
+	Ok(path)
+}
+
+/// Process an archived payload as if it had just been delivered
+pub async fn replay_webhook_payload(
+	state: &AppState,
+	path: &Path,
+) -> Result<(PullRequestMergeCancelOutcome, Result<()>)> {
+	let payload = fs::read(path).map_err(|err| Error::Message {
+		msg: format!("Failed to read {}: {}", path.display(), err),
+	})?;
+	let payload = serde_json::from_slice::<GithubWebhookPayload>(&payload)
+		.context(error::Json)?;
+
+	log::info!("Replaying the webhook payload of {}", path.display());
+	Ok(handle_github_payload(payload, state).await)
+}
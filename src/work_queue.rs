@@ -0,0 +1,97 @@
+use std::collections::{HashMap, VecDeque};
+
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct PendingCommits {
+	len: usize,
+	shas_per_repository: HashMap<String, VecDeque<String>>,
+	// The repositories which have pending commits, in the order they'll be served
+	repositories: VecDeque<String>,
+}
+
+/// Commits whose checks and statuses should be processed in the background. The commits are
+/// grouped by repository and the repositories are served in a round-robin fashion, so that a
+/// repository which produces a flood of events can't starve the others.
+pub struct WorkQueue {
+	capacity: usize,
+	pending: parking_lot::Mutex<PendingCommits>,
+	notify: Notify,
+}
+
+impl WorkQueue {
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			capacity,
+			pending: parking_lot::Mutex::new(PendingCommits::default()),
+			notify: Notify::new(),
+		}
+	}
+
+	/// Queue a commit of `repository` (as `owner/repo`). A commit which is already pending is not
+	/// queued again since a single processing takes all of its events into account. Returns false
+	/// if the commit was dropped because the queue is full.
+	pub fn push(&self, repository: &str, sha: &str) -> bool {
+		{
+			let mut pending = self.pending.lock();
+			let pending = &mut *pending;
+
+			if let Some(shas) = pending.shas_per_repository.get(repository) {
+				if shas.iter().any(|pending_sha| pending_sha == sha) {
+					return true;
+				}
+			}
+			if pending.len >= self.capacity {
+				return false;
+			}
+
+			let shas = pending
+				.shas_per_repository
+				.entry(repository.to_string())
+				.or_default();
+			if shas.is_empty() {
+				pending.repositories.push_back(repository.to_string());
+			}
+			shas.push_back(sha.to_string());
+			pending.len += 1;
+		}
+
+		self.notify.notify_one();
+		true
+	}
+
+	/// Take the next commit to be processed as `(repository, sha)`
+	pub fn pop(&self) -> Option<(String, String)> {
+		let mut pending = self.pending.lock();
+		let pending = &mut *pending;
+
+		let repository = pending.repositories.pop_front()?;
+		let shas = pending.shas_per_repository.get_mut(&repository)?;
+		let sha = shas.pop_front()?;
+		if shas.is_empty() {
+			pending.shas_per_repository.remove(&repository);
+		} else {
+			// Move to the back of the line so that the other repositories are
+			// served before this one again
+			pending.repositories.push_back(repository.clone());
+		}
+		pending.len -= 1;
+
+		Some((repository, sha))
+	}
+
+	pub fn len(&self) -> usize {
+		self.pending.lock().len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Wait until there's at least one pending commit
+	pub async fn wait_for_work(&self) {
+		while self.is_empty() {
+			self.notify.notified().await;
+		}
+	}
+}
@@ -0,0 +1,2106 @@
+use std::{
+	sync::Arc,
+	time::{Duration, SystemTime},
+};
+
+use httptest::{all_of, cycle, matchers::*, responders::*, Expectation};
+use hyper::{Body, Method, Request, StatusCode};
+use parity_processbot::{
+	bot::{
+		handle_github_payload, handle_http_request_for_bot,
+		parse_bot_comment_from_text, wait_for_pull_request_mergeability,
+	},
+	core::{AppState, CommentCommand, MergeCommentCommand},
+	github::*,
+	merge_request::{
+		allow_merge_once, consume_merge_allowance, is_paused,
+		list_merge_requests, record_head_push, set_paused,
+		was_head_pushed_after, MergeRequest,
+	},
+	poll_heartbeat::PollHeartbeat,
+	types::PlaceholderDeserializationItem,
+	webhook_archive::replay_webhook_payload,
+};
+use ring::hmac;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+mod helpers;
+
+use helpers::{constants::*, setup::*};
+
+#[tokio::test]
+async fn merge_command_waits_for_mergeability() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "repo";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pending_pr = GithubPullRequest {
+		mergeable: None,
+		..build_pull_request(
+			&owner,
+			repo_name,
+			number,
+			"sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+
+	// Github has not computed the mergeability on the first fetch
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(2)
+		.respond_with(cycle![json_encoded(pending_pr), json_encoded(pr)]),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let pr = wait_for_pull_request_mergeability(
+		&state.gh_client,
+		&owner.login,
+		repo_name,
+		number,
+		60_000,
+	)
+	.await
+	.unwrap();
+	assert_eq!(pr.mergeable, Some(true));
+}
+
+#[tokio::test]
+async fn queue_endpoint_lists_merge_requests() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.admin_token = Some("admin token".to_string());
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: format!("{}/pull/1", URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER),
+		..build_merge_request("sha", &owner.login, "repo", 1)
+	};
+	state
+		.db
+		.put(mr.sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+	let poll_heartbeat = state.poll_heartbeat.clone();
+	let state = Arc::new(Mutex::new(state));
+
+	let build_request = |token: &str| {
+		Request::get("/queue")
+			.header("Authorization", format!("Bearer {}", token))
+			.body(Body::empty())
+			.unwrap()
+	};
+
+	let response = handle_http_request_for_bot(
+		build_request("wrong token"),
+		state.clone(),
+		poll_heartbeat.clone(),
+	)
+	.await
+	.unwrap();
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let response = handle_http_request_for_bot(
+		build_request("admin token"),
+		state.clone(),
+		poll_heartbeat,
+	)
+	.await
+	.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	let queue: Vec<MergeRequest> = serde_json::from_slice(&body).unwrap();
+	assert_eq!(queue.len(), 1);
+	assert_eq!(queue[0].sha, mr.sha);
+	assert_eq!(queue[0].html_url, mr.html_url);
+}
+
+#[tokio::test]
+async fn queue_endpoint_deletes_merge_requests() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.admin_token = Some("admin token".to_string());
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: format!("{}/pull/1", URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER),
+		..build_merge_request("stuck_sha", &owner.login, "stuck_repo", 1)
+	};
+	state
+		.db
+		.put(mr.sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+	let poll_heartbeat = state.poll_heartbeat.clone();
+	let state = Arc::new(Mutex::new(state));
+
+	let request = |method: Method, path: &str| {
+		let state = state.clone();
+		let poll_heartbeat = poll_heartbeat.clone();
+		let req = Request::builder()
+			.method(method)
+			.uri(path)
+			.header("Authorization", "Bearer admin token")
+			.body(Body::empty())
+			.unwrap();
+		async move {
+			let response =
+				handle_http_request_for_bot(req, state, poll_heartbeat)
+					.await
+					.unwrap();
+			let status = response.status();
+			let body =
+				hyper::body::to_bytes(response.into_body()).await.unwrap();
+			(
+				status,
+				serde_json::from_slice::<Vec<MergeRequest>>(&body).ok(),
+			)
+		}
+	};
+
+	assert_eq!(
+		request(Method::DELETE, "/queue/owner/stuck_repo/2").await.0,
+		StatusCode::NOT_FOUND
+	);
+	assert_eq!(
+		request(Method::DELETE, "/queue/owner/stuck_repo/1").await.0,
+		StatusCode::NO_CONTENT
+	);
+	let (status, queue) = request(Method::GET, "/queue").await;
+	assert_eq!(status, StatusCode::OK);
+	assert!(queue.unwrap().is_empty());
+	assert_eq!(
+		request(Method::DELETE, "/queue/owner/stuck_repo/1").await.0,
+		StatusCode::NOT_FOUND
+	);
+}
+
+#[test]
+fn merge_delay_is_parsed() {
+	for (text, seconds) in &[
+		("bot merge delay 2h", 2 * 60 * 60),
+		("bot merge delay 30m", 30 * 60),
+		("bot merge delay 1h30m15s", 60 * 60 + 30 * 60 + 15),
+	] {
+		match parse_bot_comment_from_text(text, "processbot") {
+			Some(CommentCommand::Merge(MergeCommentCommand::Delayed(
+				delay,
+			))) => {
+				assert_eq!(delay, Duration::from_secs(*seconds))
+			}
+			cmd => panic!("Unexpected command for {}: {:?}", text, cmd),
+		}
+	}
+
+	for text in &["bot merge delay", "bot merge delay 2", "bot merge delay 2d"]
+	{
+		assert!(parse_bot_comment_from_text(text, "processbot").is_none());
+	}
+}
+
+#[test]
+fn commands_can_mention_the_bot() {
+	for text in &["bot merge", "@processbot merge", "  @ProcessBot   merge "] {
+		assert!(
+			matches!(
+				parse_bot_comment_from_text(text, "processbot"),
+				Some(CommentCommand::Merge(MergeCommentCommand::Normal))
+			),
+			"{} should be parsed as a merge command",
+			text
+		);
+	}
+
+	for text in &[
+		"> @processbot merge",
+		"> bot merge",
+		"don't @processbot merge",
+		"@processbot-fork merge",
+		"@processbotmerge",
+		"@processbot",
+	] {
+		assert!(
+			parse_bot_comment_from_text(text, "processbot").is_none(),
+			"{} should not be parsed as a command",
+			text
+		);
+	}
+}
+
+#[tokio::test]
+async fn webhooks_from_other_installations_are_ignored() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "repo";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	// The command should not be acted upon
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(0)
+		.respond_with(status_code(200)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	let webhook_secret = config.webhook_secret.clone();
+	let state = build_state(config);
+	let poll_heartbeat = state.poll_heartbeat.clone();
+	let state = Arc::new(Mutex::new(state));
+
+	let payload = json!({
+		"action": "created",
+		"issue": {
+			"number": number,
+			"html_url": format!(
+				"https://github.com/{}/{}/pull/{}",
+				&owner.login, repo_name, number
+			),
+			"pull_request": {},
+		},
+		"comment": {
+			"id": I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+			"body": "bot merge",
+			"user": &owner,
+		},
+		"repository": {
+			"name": repo_name,
+			"owner": &owner,
+		},
+		"installation": {
+			"id": I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER + 1,
+		},
+	})
+	.to_string();
+	let signature = hmac::sign(
+		&hmac::Key::new(
+			hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+			webhook_secret.as_bytes(),
+		),
+		payload.as_bytes(),
+	);
+
+	let response = handle_http_request_for_bot(
+		Request::post("/webhook")
+			.header(
+				"x-hub-signature",
+				format!("sha1={}", base16::encode_lower(signature.as_ref())),
+			)
+			.body(Body::from(payload))
+			.unwrap(),
+		state,
+		poll_heartbeat,
+	)
+	.await
+	.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn webhook_rejections_are_reported_through_the_status() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	let webhook_secret = config.webhook_secret.clone();
+	let state = build_state(config);
+	let poll_heartbeat = state.poll_heartbeat.clone();
+	let state = Arc::new(Mutex::new(state));
+
+	let sign = |payload: &str| {
+		format!(
+			"sha1={}",
+			base16::encode_lower(
+				hmac::sign(
+					&hmac::Key::new(
+						hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+						webhook_secret.as_bytes(),
+					),
+					payload.as_bytes(),
+				)
+				.as_ref(),
+			)
+		)
+	};
+	let deliver = |payload: &str, signature: Option<String>| {
+		let state = state.clone();
+		let poll_heartbeat = poll_heartbeat.clone();
+		let mut req = Request::post("/webhook");
+		if let Some(signature) = signature {
+			req = req.header("x-hub-signature", signature);
+		}
+		let req = req.body(Body::from(payload.to_string())).unwrap();
+		async move {
+			handle_http_request_for_bot(req, state, poll_heartbeat)
+				.await
+				.unwrap()
+				.status()
+		}
+	};
+
+	// Events which processbot does not handle, e.g. the ping of a new webhook,
+	// are still acknowledged
+	let payload = r#"{"zen": "Keep it logically awesome."}"#;
+	assert_eq!(deliver(payload, Some(sign(payload))).await, StatusCode::OK);
+
+	assert_eq!(deliver(payload, None).await, StatusCode::UNAUTHORIZED);
+	assert_eq!(
+		deliver(payload, Some(sign("another payload"))).await,
+		StatusCode::UNAUTHORIZED
+	);
+	assert_eq!(
+		deliver(payload, Some("sha1=not hexadecimal".to_string())).await,
+		StatusCode::BAD_REQUEST
+	);
+
+	let payload = "not json";
+	assert_eq!(
+		deliver(payload, Some(sign(payload))).await,
+		StatusCode::BAD_REQUEST
+	);
+}
+
+#[tokio::test]
+async fn freeze_endpoint_toggles_merge_freezes() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.admin_token = Some("admin token".to_string());
+	config.frozen_repos.insert("owner/configured".to_string());
+	let state = build_state(config);
+	let poll_heartbeat = state.poll_heartbeat.clone();
+	let state = Arc::new(Mutex::new(state));
+
+	let request = |method: Method, path: &str| {
+		let state = state.clone();
+		let poll_heartbeat = poll_heartbeat.clone();
+		let req = Request::builder()
+			.method(method)
+			.uri(path)
+			.header("Authorization", "Bearer admin token")
+			.body(Body::empty())
+			.unwrap();
+		async move {
+			let response =
+				handle_http_request_for_bot(req, state, poll_heartbeat)
+					.await
+					.unwrap();
+			let status = response.status();
+			let body =
+				hyper::body::to_bytes(response.into_body()).await.unwrap();
+			(status, serde_json::from_slice::<Vec<String>>(&body).ok())
+		}
+	};
+
+	assert_eq!(
+		request(Method::GET, "/freeze").await,
+		(StatusCode::OK, Some(vec!["owner/configured".to_string()]))
+	);
+	assert_eq!(
+		request(Method::PUT, "/freeze/owner/repo").await,
+		(
+			StatusCode::OK,
+			Some(vec![
+				"owner/configured".to_string(),
+				"owner/repo".to_string()
+			])
+		)
+	);
+	assert_eq!(
+		request(Method::DELETE, "/freeze/owner/configured").await,
+		(StatusCode::OK, Some(vec!["owner/repo".to_string()]))
+	);
+	assert_eq!(
+		request(Method::PUT, "/freeze/owner/repo:release/v1").await,
+		(
+			StatusCode::OK,
+			Some(vec![
+				"owner/repo".to_string(),
+				"owner/repo:release/v1".to_string()
+			])
+		)
+	);
+	assert_eq!(
+		request(Method::PUT, "/freeze/owner").await,
+		(StatusCode::BAD_REQUEST, None)
+	);
+	assert_eq!(
+		request(Method::PUT, "/freeze/owner/repo:").await,
+		(StatusCode::BAD_REQUEST, None)
+	);
+}
+
+#[tokio::test]
+async fn pause_endpoints_toggle_the_pause() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.admin_token = Some("admin token".to_string());
+	let state = build_state(config);
+	let poll_heartbeat = state.poll_heartbeat.clone();
+	let state = Arc::new(Mutex::new(state));
+
+	let request = |method: Method, path: &str| {
+		let state = state.clone();
+		let poll_heartbeat = poll_heartbeat.clone();
+		let req = Request::builder()
+			.method(method)
+			.uri(path)
+			.header("Authorization", "Bearer admin token")
+			.body(Body::empty())
+			.unwrap();
+		async move {
+			handle_http_request_for_bot(req, state, poll_heartbeat)
+				.await
+				.unwrap()
+				.status()
+		}
+	};
+
+	assert!(!is_paused(&*state.lock().await).unwrap());
+
+	assert_eq!(request(Method::POST, "/pause").await, StatusCode::OK);
+	assert!(is_paused(&*state.lock().await).unwrap());
+
+	assert_eq!(
+		request(Method::GET, "/resume").await,
+		StatusCode::METHOD_NOT_ALLOWED
+	);
+	assert!(is_paused(&*state.lock().await).unwrap());
+
+	assert_eq!(request(Method::POST, "/resume").await, StatusCode::OK);
+	assert!(!is_paused(&*state.lock().await).unwrap());
+}
+
+#[tokio::test]
+async fn requeue_endpoint_records_the_requester() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "repo";
+	let number = 1;
+	let sha = "sha";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1..)
+		.respond_with(json_encoded(&pr)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.admin_token = Some("admin token".to_string());
+	let state = build_state(config);
+	// The merge request is only registered, not processed
+	set_paused(&state, true).unwrap();
+	let poll_heartbeat = state.poll_heartbeat.clone();
+	let state = Arc::new(Mutex::new(state));
+
+	let request = |path: &str| {
+		let state = state.clone();
+		let poll_heartbeat = poll_heartbeat.clone();
+		let req = Request::builder()
+			.method(Method::POST)
+			.uri(path)
+			.header("Authorization", "Bearer admin token")
+			.body(Body::empty())
+			.unwrap();
+		async move {
+			handle_http_request_for_bot(req, state, poll_heartbeat)
+				.await
+				.unwrap()
+				.status()
+		}
+	};
+
+	// The requester is required
+	assert_eq!(
+		request("/requeue/owner/repo/1").await,
+		StatusCode::BAD_REQUEST
+	);
+	assert_eq!(
+		request("/requeue/owner/repo?requested_by=operator").await,
+		StatusCode::BAD_REQUEST
+	);
+	assert!(list_merge_requests(&*state.lock().await).is_empty());
+
+	assert_eq!(
+		request("/requeue/owner/repo/1?requested_by=operator").await,
+		StatusCode::NO_CONTENT
+	);
+	let merge_requests = list_merge_requests(&*state.lock().await);
+	assert_eq!(merge_requests.len(), 1);
+	assert_eq!(merge_requests[0].sha, sha);
+	assert_eq!(merge_requests[0].requested_by, "operator");
+}
+
+fn register_merge_request(
+	state: &AppState,
+	pr: &GithubPullRequest,
+	sha: &str,
+	requested_by: &str,
+) {
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		requested_by: requested_by.to_string(),
+		..build_merge_request(
+			sha,
+			&pr.base.repo.owner.login,
+			&pr.base.repo.name,
+			pr.number,
+		)
+	};
+	state
+		.db
+		.put(sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+}
+
+#[tokio::test]
+async fn pushes_to_queued_pull_requests_are_tracked() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "synchronized";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	let build_pr = |sha: &str| {
+		build_pull_request(
+			&owner,
+			repo_name,
+			number,
+			sha,
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+
+	register_merge_request(&state, &build_pr("a"), "a", &owner.login);
+
+	// Commits pushed by the requester are tracked
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::PullRequest {
+			action: GithubPullRequestAction::Synchronize,
+			pull_request: build_pr("b"),
+			before: Some("a".to_string()),
+			label: None,
+			sender: owner.clone(),
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+	assert!(state.db.get("a".as_bytes()).unwrap().is_none());
+	let mr: MergeRequest =
+		bincode::deserialize(&state.db.get("b".as_bytes()).unwrap().unwrap())
+			.unwrap();
+	assert_eq!(mr.requested_by, owner.login);
+
+	// Commits pushed by someone else cancel the merge
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches("Merge cancelled")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::PullRequest {
+			action: GithubPullRequestAction::Synchronize,
+			pull_request: build_pr("c"),
+			before: Some("b".to_string()),
+			label: None,
+			sender: GithubUser {
+				login: "someone".to_string(),
+				type_field: GithubUserType::User,
+			},
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+	assert!(list_merge_requests(&state).is_empty());
+}
+
+#[tokio::test]
+async fn org_checks_can_be_disabled_per_repository() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let outsider = GithubUser {
+		login: "outsider".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/orgs/{}/members/{}", &owner.login, &outsider.login),
+		))
+		.times(1)
+		.respond_with(status_code(404)),
+	);
+
+	// The command should go through on the sandbox
+	let sandbox_pr = build_pull_request(
+		&owner,
+		"sandbox",
+		number,
+		"sandbox_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/sandbox/pulls/{}", &owner.login, number),
+		))
+		.times(1)
+		.respond_with(json_encoded(&sandbox_pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/sandbox/issues/comments/{}/reactions",
+				&owner.login, I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER
+			),
+		))
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/sandbox/issues/{}/comments",
+				&owner.login, number
+			),
+		))
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	// The command should not be acted upon where the checks are enforced
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/enforced/pulls/{}", &owner.login, number),
+		))
+		.times(0)
+		.respond_with(status_code(200)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config
+		.disable_org_checks_overrides
+		.insert(format!("{}/sandbox", &owner.login), true);
+	let state = build_state(config);
+
+	let build_comment_payload =
+		|repo_name: &str| GithubWebhookPayload::IssueComment {
+			action: GithubIssueCommentAction::Created,
+			issue: GithubIssue {
+				number,
+				html_url: format!(
+					"https://github.com/{}/{}/pull/{}",
+					&owner.login, repo_name, number
+				),
+				pull_request: Some(PlaceholderDeserializationItem {}),
+			},
+			comment: GithubIssueComment {
+				id: I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+				body: "bot merge delay 1h".to_string(),
+				user: outsider.clone(),
+				created_at: None,
+			},
+			repository: GithubIssueRepository {
+				owner: owner.clone(),
+				name: repo_name.to_string(),
+			},
+		};
+
+	let (_, result) =
+		handle_github_payload(build_comment_payload("sandbox"), &state).await;
+	result.unwrap();
+	assert!(state.db.get("sandbox_sha".as_bytes()).unwrap().is_some());
+
+	let (_, result) =
+		handle_github_payload(build_comment_payload("enforced"), &state).await;
+	assert!(result.is_err());
+}
+
+struct CodeOwnersSetup {
+	github_api: httptest::Server,
+	state: AppState,
+	payload: GithubWebhookPayload,
+	_db_dir: tempfile::TempDir,
+}
+
+// Set up a merge command from a user who is not a member of the organization on a pull request
+// which changes `changed_files`. The user owns the "sandbox" directory according to CODEOWNERS.
+fn setup_codeowners_authorization(
+	repo_name: &str,
+	changed_files: &[&str],
+) -> CodeOwnersSetup {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let outsider = GithubUser {
+		login: "outsider".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/orgs/{}/members/{}", &owner.login, &outsider.login),
+		))
+		.times(1)
+		.respond_with(status_code(404)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/orgs/{}/teams/core-devs/memberships/{}",
+				&owner.login, &outsider.login
+			),
+		))
+		.times(0..)
+		.respond_with(status_code(404)),
+	);
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"codeowners_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/contents/.github/CODEOWNERS",
+				&owner.login, repo_name
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubFileContents {
+			content: base64::encode(format!(
+				"* @{}/core-devs\n/sandbox/ @{}\n",
+				&owner.login, &outsider.login
+			)),
+		})),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/pulls/{}/files",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1..)
+		.respond_with(cycle![
+			json_encoded(
+				changed_files
+					.iter()
+					.map(|filename| GithubPullRequestFile {
+						filename: filename.to_string(),
+						previous_filename: None,
+					})
+					.collect::<Vec<_>>()
+			),
+			json_encoded(Vec::<GithubPullRequestFile>::new()),
+		]),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.codeowners_authorization = true;
+
+	CodeOwnersSetup {
+		github_api,
+		state: build_state(config),
+		payload: GithubWebhookPayload::IssueComment {
+			action: GithubIssueCommentAction::Created,
+			issue: GithubIssue {
+				number,
+				html_url: pr.html_url.clone(),
+				pull_request: Some(PlaceholderDeserializationItem {}),
+			},
+			comment: GithubIssueComment {
+				id: I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+				body: "bot merge delay 1h".to_string(),
+				user: outsider,
+				created_at: None,
+			},
+			repository: GithubIssueRepository {
+				owner,
+				name: repo_name.to_string(),
+			},
+		},
+		_db_dir: db_dir,
+	}
+}
+
+#[tokio::test]
+async fn code_owners_are_allowed_to_merge() {
+	let repo_name = "owned";
+	let CodeOwnersSetup {
+		github_api,
+		state,
+		payload,
+		_db_dir,
+	} = setup_codeowners_authorization(
+		repo_name,
+		&["sandbox/lib.rs", "sandbox/nested/main.rs"],
+	);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/owner/{}/issues/comments/{}/reactions",
+				repo_name, I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER
+			),
+		))
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!("/repos/owner/{}/issues/1/comments", repo_name),
+		))
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let (_, result) = handle_github_payload(payload, &state).await;
+	result.unwrap();
+	assert!(state.db.get("codeowners_sha".as_bytes()).unwrap().is_some());
+}
+
+#[tokio::test]
+async fn non_code_owners_are_not_allowed_to_merge() {
+	let CodeOwnersSetup {
+		github_api: _github_api,
+		state,
+		payload,
+		_db_dir,
+	} = setup_codeowners_authorization(
+		"not_owned",
+		&["sandbox/lib.rs", "runtime/lib.rs"],
+	);
+
+	let (_, result) = handle_github_payload(payload, &state).await;
+	assert!(result.is_err());
+	assert!(state.db.get("codeowners_sha".as_bytes()).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn privileged_team_members_are_allowed_to_merge() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let outsider = GithubUser {
+		login: "outsider".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "privileged";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/orgs/{}/members/{}", &owner.login, &outsider.login),
+		))
+		.times(1)
+		.respond_with(status_code(404)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/orgs/partners/teams/reviewers/memberships/{}",
+				&outsider.login
+			),
+		))
+		.times(1)
+		.respond_with(status_code(404)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/orgs/partners/teams/release-leads/memberships/{}",
+				&outsider.login
+			),
+		))
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"privileged_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/comments/{}/reactions",
+				&owner.login, repo_name, I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER
+			),
+		))
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/{}/comments",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.privileged_teams = vec![
+		"partners/reviewers".to_string(),
+		"partners/release-leads".to_string(),
+	];
+	let state = build_state(config);
+
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::IssueComment {
+			action: GithubIssueCommentAction::Created,
+			issue: GithubIssue {
+				number,
+				html_url: pr.html_url.clone(),
+				pull_request: Some(PlaceholderDeserializationItem {}),
+			},
+			comment: GithubIssueComment {
+				id: I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+				body: "bot merge delay 1h".to_string(),
+				user: outsider,
+				created_at: None,
+			},
+			repository: GithubIssueRepository {
+				owner,
+				name: repo_name.to_string(),
+			},
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+	assert!(state.db.get("privileged_sha".as_bytes()).unwrap().is_some());
+}
+
+#[tokio::test]
+async fn merge_allowances_are_consumed_once() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let outsider = GithubUser {
+		login: "outsider".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "allowed_once";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/orgs/leads/teams/merges/memberships/{}", &owner.login),
+		))
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/orgs/{}/members/{}", &owner.login, &outsider.login),
+		))
+		.times(2)
+		.respond_with(status_code(404)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/orgs/leads/teams/merges/memberships/{}", &outsider.login),
+		))
+		.times(2)
+		.respond_with(status_code(404)),
+	);
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"allowed_once_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	// Fetched for both the grant and the merge
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(2)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/comments/{}/reactions",
+				&owner.login, repo_name, I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER
+			),
+		))
+		.times(2)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/{}/comments",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(2)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.privileged_teams = vec!["leads/merges".to_string()];
+	let state = build_state(config);
+
+	let build_comment_payload =
+		|body: &str, user: &GithubUser| GithubWebhookPayload::IssueComment {
+			action: GithubIssueCommentAction::Created,
+			issue: GithubIssue {
+				number,
+				html_url: pr.html_url.clone(),
+				pull_request: Some(PlaceholderDeserializationItem {}),
+			},
+			comment: GithubIssueComment {
+				id: I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+				body: body.to_string(),
+				user: user.clone(),
+				created_at: None,
+			},
+			repository: GithubIssueRepository {
+				owner: owner.clone(),
+				name: repo_name.to_string(),
+			},
+		};
+
+	let (_, result) = handle_github_payload(
+		build_comment_payload("bot merge allow @Outsider", &owner),
+		&state,
+	)
+	.await;
+	result.unwrap();
+
+	let (_, result) = handle_github_payload(
+		build_comment_payload("bot merge delay 1h", &outsider),
+		&state,
+	)
+	.await;
+	result.unwrap();
+	assert!(state
+		.db
+		.get("allowed_once_sha".as_bytes())
+		.unwrap()
+		.is_some());
+
+	// The allowance was consumed by the previous merge
+	let (_, result) = handle_github_payload(
+		build_comment_payload("bot merge delay 1h", &outsider),
+		&state,
+	)
+	.await;
+	assert!(result.is_err());
+}
+
+#[test]
+fn merge_allowances_only_apply_to_their_pull_request() {
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		"owner",
+		"http://github.api",
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	allow_merge_once(&state, "owner", "allowances", 1, "outsider").unwrap();
+
+	assert!(!consume_merge_allowance(
+		&state,
+		"owner",
+		"allowances",
+		2,
+		"outsider"
+	)
+	.unwrap());
+	assert!(!consume_merge_allowance(
+		&state,
+		"owner",
+		"other_repo",
+		1,
+		"outsider"
+	)
+	.unwrap());
+	assert!(!consume_merge_allowance(
+		&state,
+		"owner",
+		"allowances",
+		1,
+		"someone"
+	)
+	.unwrap());
+	assert!(consume_merge_allowance(
+		&state,
+		"owner",
+		"allowances",
+		1,
+		"Outsider"
+	)
+	.unwrap());
+	assert!(!consume_merge_allowance(
+		&state,
+		"owner",
+		"allowances",
+		1,
+		"outsider"
+	)
+	.unwrap());
+}
+
+#[tokio::test]
+async fn closed_pull_requests_are_cleaned_up() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	let pr = build_pull_request(
+		&owner,
+		"closed",
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	register_merge_request(&state, &pr, "sha", &owner.login);
+	record_head_push(&state, &owner.login, "closed", 1, "sha").unwrap();
+
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::PullRequest {
+			action: GithubPullRequestAction::Closed,
+			pull_request: pr,
+			before: None,
+			label: None,
+			sender: owner.clone(),
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+	assert!(list_merge_requests(&state).is_empty());
+	assert!(!was_head_pushed_after(
+		&state,
+		&owner.login,
+		"closed",
+		1,
+		"sha",
+		SystemTime::UNIX_EPOCH
+	)
+	.unwrap());
+}
+
+#[tokio::test]
+async fn applying_the_automerge_label_queues_the_merge() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "labeled";
+	let head_sha = "labeled_sha";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.automerge_label.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		"automerge".to_string(),
+	);
+	let state = build_state(config);
+	let build_pr = || {
+		build_pull_request(
+			&owner,
+			repo_name,
+			1,
+			head_sha,
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/1", &owner.login, repo_name),
+		))
+		.times(1)
+		.respond_with(json_encoded(build_pr())),
+	);
+	// The checks are still running, thus the merge is queued
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubCheckRuns {
+			check_runs: vec![GithubCheckRun {
+				id: 1,
+				name: "test".to_string(),
+				status: GithubCheckRunStatus::Unknown,
+				conclusion: None,
+				head_sha: head_sha.to_string(),
+				html_url: None,
+			}],
+		})),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!("/repos/{}/{}/issues/1/comments", &owner.login, repo_name),
+		))
+		.times(1)
+		.respond_with(status_code(201).body(r#"{"id":1}"#)),
+	);
+
+	// Other labels are ignored
+	for label in &["unrelated", "automerge"] {
+		let (_, result) = handle_github_payload(
+			GithubWebhookPayload::PullRequest {
+				action: GithubPullRequestAction::Labeled,
+				pull_request: build_pr(),
+				before: None,
+				label: Some(GithubLabel {
+					name: label.to_string(),
+				}),
+				sender: owner.clone(),
+			},
+			&state,
+		)
+		.await;
+		result.unwrap();
+	}
+
+	let queued_mrs = list_merge_requests(&state);
+	assert_eq!(queued_mrs.len(), 1);
+	assert_eq!(queued_mrs[0].sha, head_sha);
+	assert_eq!(queued_mrs[0].requested_by, owner.login);
+}
+
+#[tokio::test]
+async fn removing_the_automerge_label_cancels_the_merge() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "unlabeled";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.automerge_label.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		"automerge".to_string(),
+	);
+	let state = build_state(config);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"unlabeled_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	register_merge_request(&state, &pr, "unlabeled_sha", &owner.login);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/1", &owner.login, repo_name),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/1/comments",
+					&owner.login, repo_name
+				),
+			),
+			request::body(matches("Merge cancelled")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::PullRequest {
+			action: GithubPullRequestAction::Unlabeled,
+			pull_request: pr,
+			before: None,
+			label: Some(GithubLabel {
+				name: "automerge".to_string(),
+			}),
+			sender: owner.clone(),
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+	assert!(list_merge_requests(&state).is_empty());
+}
+
+#[tokio::test]
+async fn poll_health_reports_a_stalled_poll() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	let state = Arc::new(Mutex::new(build_state(config)));
+	let poll_heartbeat =
+		Arc::new(PollHeartbeat::new(Duration::from_millis(100)));
+
+	let request_poll_health = || {
+		let state = state.clone();
+		let poll_heartbeat = poll_heartbeat.clone();
+		let req = Request::get("/health/poll").body(Body::empty()).unwrap();
+		async move {
+			handle_http_request_for_bot(req, state, poll_heartbeat)
+				.await
+				.unwrap()
+				.status()
+		}
+	};
+
+	assert_eq!(request_poll_health().await, StatusCode::OK);
+
+	// No poll completes for over twice the interval
+	tokio::time::sleep(Duration::from_millis(300)).await;
+	assert_eq!(request_poll_health().await, StatusCode::SERVICE_UNAVAILABLE);
+
+	poll_heartbeat.record_poll_completed();
+	assert_eq!(request_poll_health().await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn archived_webhooks_can_be_replayed() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let archive_dir = tempfile::tempdir().unwrap();
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.webhook_archive_dir = Some(archive_dir.path().to_path_buf());
+	config.admin_token = Some("admin token".to_string());
+	let webhook_secret = config.webhook_secret.clone();
+	let state = build_state(config);
+	let poll_heartbeat = state.poll_heartbeat.clone();
+	let state = Arc::new(Mutex::new(state));
+
+	let payload = json!({
+		"sha": "archived_sha",
+		"state": "success",
+		"repository": {
+			"name": "archived",
+			"owner": &owner,
+		},
+	})
+	.to_string();
+	let signature = base16::encode_lower(
+		hmac::sign(
+			&hmac::Key::new(
+				hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+				webhook_secret.as_bytes(),
+			),
+			payload.as_bytes(),
+		)
+		.as_ref(),
+	);
+	let response = handle_http_request_for_bot(
+		Request::post("/webhook")
+			.header("x-hub-signature", format!("sha1={}", signature))
+			.body(Body::from(payload.clone()))
+			.unwrap(),
+		state.clone(),
+		poll_heartbeat.clone(),
+	)
+	.await
+	.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let archived_files = std::fs::read_dir(archive_dir.path())
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.collect::<Vec<_>>();
+	assert_eq!(archived_files.len(), 1);
+	let archived_payload = std::fs::read_to_string(&archived_files[0]).unwrap();
+	assert_eq!(archived_payload, payload);
+	assert!(!archived_payload.contains(&signature));
+
+	// Replay the payload against a different state
+	let replay_db_dir = tempfile::tempdir().unwrap();
+	let replay_state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		replay_db_dir.path(),
+		replay_db_dir.path(),
+	));
+	let (_, result) = replay_webhook_payload(&replay_state, &archived_files[0])
+		.await
+		.unwrap();
+	result.unwrap();
+	assert_eq!(
+		replay_state.work_queue.pop(),
+		Some(("owner/archived".to_string(), "archived_sha".to_string()))
+	);
+
+	// Replay the payload through the running server
+	let request_replay = |path: String| {
+		let state = state.clone();
+		let poll_heartbeat = poll_heartbeat.clone();
+		let req = Request::builder()
+			.method(Method::POST)
+			.uri(path)
+			.header("Authorization", "Bearer admin token")
+			.body(Body::empty())
+			.unwrap();
+		async move {
+			handle_http_request_for_bot(req, state, poll_heartbeat)
+				.await
+				.unwrap()
+				.status()
+		}
+	};
+	let work_queue = state.lock().await.work_queue.clone();
+	assert_eq!(
+		work_queue.pop(),
+		Some(("owner/archived".to_string(), "archived_sha".to_string()))
+	);
+	assert_eq!(
+		request_replay("/replay/missing.json".to_string()).await,
+		StatusCode::NOT_FOUND
+	);
+	assert_eq!(
+		request_replay("/replay/..%2Fsecret".to_string()).await,
+		StatusCode::BAD_REQUEST
+	);
+	let archived_file = archived_files[0]
+		.file_name()
+		.unwrap()
+		.to_str()
+		.unwrap()
+		.to_string();
+	assert_eq!(
+		request_replay(format!("/replay/{}", archived_file)).await,
+		StatusCode::NO_CONTENT
+	);
+	assert_eq!(
+		work_queue.pop(),
+		Some(("owner/archived".to_string(), "archived_sha".to_string()))
+	);
+}
+
+#[tokio::test]
+async fn only_allowed_bots_can_issue_commands() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let allowed_bot = GithubUser {
+		login: "release-bot[bot]".to_string(),
+		type_field: GithubUserType::Bot,
+	};
+	let other_bot = GithubUser {
+		login: "other-bot[bot]".to_string(),
+		type_field: GithubUserType::Bot,
+	};
+	let own_bot = GithubUser {
+		login: format!("{}[bot]", APP_SLUG),
+		type_field: GithubUserType::Bot,
+	};
+	let installation_bot = GithubUser {
+		login: format!("{}[bot]", &owner.login),
+		type_field: GithubUserType::Bot,
+	};
+	let repo_name = "bot_commands";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	// Only the allowed bot's command is handled
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/{}/comments",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/comments/{}/reactions",
+				&owner.login, repo_name, I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER
+			),
+		))
+		.times(0..)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.disable_org_checks = true;
+	config.allowed_command_bots =
+		vec![allowed_bot.login.clone(), own_bot.login.clone()];
+	let state = build_state(config);
+
+	let build_comment_payload =
+		|user: &GithubUser| GithubWebhookPayload::IssueComment {
+			action: GithubIssueCommentAction::Created,
+			issue: GithubIssue {
+				number,
+				html_url: pr.html_url.clone(),
+				pull_request: Some(PlaceholderDeserializationItem {}),
+			},
+			comment: GithubIssueComment {
+				id: I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+				body: "bot ping".to_string(),
+				user: user.clone(),
+				created_at: None,
+			},
+			repository: GithubIssueRepository {
+				owner: owner.clone(),
+				name: repo_name.to_string(),
+			},
+		};
+
+	// processbot's own comments are ignored even if it's allowed by mistake. Its
+	// login comes from the App's slug, not from the installation's login.
+	for user in &[&other_bot, &own_bot, &installation_bot, &allowed_bot] {
+		let (_, result) =
+			handle_github_payload(build_comment_payload(user), &state).await;
+		result.unwrap();
+	}
+}
+
+#[tokio::test]
+async fn completed_check_suites_trigger_the_processing_of_their_commit() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		"http://github.api",
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let build_payload = |action: &str, status: &str, sha: &str| {
+		serde_json::from_value::<GithubWebhookPayload>(json!({
+			"action": action,
+			"check_suite": {
+				"head_sha": sha,
+				"status": status,
+				"conclusion": if status == "completed" {
+					Some("success")
+				} else {
+					None
+				},
+			},
+			"repository": {
+				"name": "suites",
+				"owner": &owner,
+			},
+		}))
+		.unwrap()
+	};
+
+	let (_, result) = handle_github_payload(
+		build_payload("requested", "queued", "pending_sha"),
+		&state,
+	)
+	.await;
+	result.unwrap();
+	let (_, result) = handle_github_payload(
+		build_payload("completed", "completed", "completed_sha"),
+		&state,
+	)
+	.await;
+	result.unwrap();
+
+	assert_eq!(
+		state.work_queue.pop(),
+		Some(("owner/suites".to_string(), "completed_sha".to_string()))
+	);
+	assert_eq!(state.work_queue.pop(), None);
+}
+
+#[tokio::test]
+async fn commands_can_be_acknowledged_through_reactions() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "reactions";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+	// The reply of `bot ping`
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/{}/comments",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	for reaction in &["eyes", "rocket"] {
+		github_api.expect(
+			Expectation::matching(all_of![
+				request::method_path(
+					"POST",
+					format!(
+						"/repos/{}/{}/issues/comments/{}/reactions",
+						&owner.login,
+						repo_name,
+						I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER
+					),
+				),
+				request::body(json_decoded(eq(json!({ "content": reaction })))),
+			])
+			.times(1)
+			.respond_with(status_code(201).body("{}")),
+		);
+	}
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.reaction_acknowledgements = true;
+	let state = build_state(config);
+
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::IssueComment {
+			action: GithubIssueCommentAction::Created,
+			issue: GithubIssue {
+				number,
+				html_url: pr.html_url.clone(),
+				pull_request: Some(PlaceholderDeserializationItem {}),
+			},
+			comment: GithubIssueComment {
+				id: I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+				body: "bot ping".to_string(),
+				user: owner.clone(),
+				created_at: None,
+			},
+			repository: GithubIssueRepository {
+				owner: owner.clone(),
+				name: repo_name.to_string(),
+			},
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+}
+
+#[tokio::test]
+async fn merge_commands_issued_before_a_push_require_reconfirmation() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "reconfirmation";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let build_pr = || {
+		build_pull_request(
+			&owner,
+			repo_name,
+			number,
+			"pushed_sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1..)
+		.respond_with(json_encoded(build_pr())),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches("pushed_sha was pushed after owner")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::PullRequest {
+			action: GithubPullRequestAction::Synchronize,
+			pull_request: build_pr(),
+			before: Some("reviewed_sha".to_string()),
+			label: None,
+			sender: owner.clone(),
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+
+	// The command was issued before the push was received
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::IssueComment {
+			action: GithubIssueCommentAction::Created,
+			issue: GithubIssue {
+				number,
+				html_url: build_pr().html_url,
+				pull_request: Some(PlaceholderDeserializationItem {}),
+			},
+			comment: GithubIssueComment {
+				id: I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+				body: "bot merge".to_string(),
+				user: owner.clone(),
+				created_at: Some(
+					chrono::Utc::now() - chrono::Duration::hours(1),
+				),
+			},
+			repository: GithubIssueRepository {
+				owner: owner.clone(),
+				name: repo_name.to_string(),
+			},
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+
+	assert!(list_merge_requests(&state).is_empty());
+}
@@ -0,0 +1,989 @@
+use std::{
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use httptest::{all_of, matchers::*, responders::*, Expectation};
+use parity_processbot::{
+	bot::parse_bot_comment_from_text,
+	core::{
+		handle_command, process_commit_checks_and_statuses, CommentCommand,
+		MergeCommentCommand, PullRequestMergeCancelOutcome,
+	},
+	error::{handle_error, Error, PullRequestDetails},
+	github::*,
+	merge_request::{
+		deserialize_merge_request, set_paused, MergeRequest,
+		MergeRequestDependency,
+	},
+};
+
+mod helpers;
+
+use helpers::{constants::*, setup::*};
+
+fn owner() -> GithubUser {
+	GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	}
+}
+
+#[tokio::test]
+async fn queue_command_lists_queued_merges() {
+	let owner = owner();
+	let repo_name = "repo";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	for mr in &[
+		build_merge_request("sha1", &owner.login, repo_name, 1),
+		build_merge_request("sha2", &owner.login, repo_name, 2),
+		build_merge_request("sha3", &owner.login, "other_repo", 3),
+	] {
+		state
+			.db
+			.put(mr.sha.as_bytes(), bincode::serialize(mr).unwrap())
+			.unwrap();
+	}
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		4,
+		"sha4",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("/owner/repo/pull/1")),
+			request::body(matches("/owner/repo/pull/2")),
+			request::body(not(matches("/owner/other_repo/pull/3"))),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	handle_command(&state, &CommentCommand::Queue, &pr, &owner.login)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn config_command_lists_repository_overrides() {
+	let owner = owner();
+	let repo_name = "configured";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.merge_commit_title_template = Some("Default title".to_string());
+	config.merge_commit_title_template_overrides.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		"Overridden title".to_string(),
+	);
+	let state = build_state(config);
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches(
+				"Merge commit title template: Overridden title"
+			)),
+			request::body(not(matches("Default title"))),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	handle_command(&state, &CommentCommand::Config, &pr, &owner.login)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn ping_command_replies_with_the_version() {
+	let owner = owner();
+	let repo_name = "pinged";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches(&regex::escape(&format!(
+				"parity-processbot/{}",
+				env!("CARGO_PKG_VERSION")
+			)))),
+			request::body(matches("Merge requests in the queue: 0")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	handle_command(&state, &CommentCommand::Ping, &pr, &owner.login)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn branch_commands_are_rejected_for_forks_of_bots() {
+	let owner = owner();
+	let repo_name = "bot_fork";
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let bot = GithubUser {
+		login: "some-bot".to_string(),
+		type_field: GithubUserType::Bot,
+	};
+	let pr = GithubPullRequest {
+		user: Some(bot.clone()),
+		head: GithubPullRequestHead {
+			repo: Some(GithubPullRequestHeadRepository {
+				name: repo_name.to_string(),
+				owner: bot,
+			}),
+			..build_pull_request(
+				&owner,
+				repo_name,
+				1,
+				"sha",
+				"master",
+				"bot_patches",
+				&github_api_url,
+				URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+			)
+			.head
+		},
+		..build_pull_request(
+			&owner,
+			repo_name,
+			1,
+			"sha",
+			"master",
+			"bot_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+
+	// Rejected before anything is cloned or pushed
+	for cmd in &[CommentCommand::Rebase, CommentCommand::Update] {
+		let err = handle_command(&state, cmd, &pr, &owner.login)
+			.await
+			.unwrap_err();
+		assert!(matches!(err, Error::PullRequestNotUserOwned { .. }));
+	}
+}
+
+#[tokio::test]
+async fn merge_command_reports_merge_conflicts() {
+	let owner = owner();
+	let repo_name = "repo";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mut pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	pr.mergeable = Some(false);
+	pr.mergeable_state = Some("dirty".to_string());
+
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("has merge conflicts")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let err = handle_command(
+		&state,
+		&CommentCommand::Merge(MergeCommentCommand::Normal),
+		&pr,
+		&owner.login,
+	)
+	.await
+	.unwrap_err();
+	handle_error(
+		PullRequestMergeCancelOutcome::ShaNotFound,
+		err.with_pull_request_details(PullRequestDetails {
+			owner: owner.login.clone(),
+			repo: repo_name.to_string(),
+			number: pr.number,
+		}),
+		&state,
+	)
+	.await;
+
+	// Nothing should be queued for a conflicted pull request
+	assert!(state
+		.db
+		.iterator(rocksdb::IteratorMode::Start)
+		.next()
+		.is_none());
+}
+
+#[tokio::test]
+async fn merge_command_rejects_drafts() {
+	let owner = owner();
+	let repo_name = "draft";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mut pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	pr.draft = true;
+	pr.mergeable = Some(false);
+	pr.mergeable_state = Some("draft".to_string());
+
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("is a draft; mark it ready for review")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let err = handle_command(
+		&state,
+		&CommentCommand::Merge(MergeCommentCommand::Normal),
+		&pr,
+		&owner.login,
+	)
+	.await
+	.unwrap_err();
+	handle_error(
+		PullRequestMergeCancelOutcome::ShaNotFound,
+		err.with_pull_request_details(PullRequestDetails {
+			owner: owner.login.clone(),
+			repo: repo_name.to_string(),
+			number: pr.number,
+		}),
+		&state,
+	)
+	.await;
+
+	assert!(state
+		.db
+		.iterator(rocksdb::IteratorMode::Start)
+		.next()
+		.is_none());
+}
+
+#[tokio::test]
+async fn merge_command_rejects_too_many_companions() {
+	let owner = owner();
+	let repo_name = "many_companions";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.max_companions = 2;
+	let state = build_state(config);
+
+	let mut pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	pr.body = Some(
+		(1..=3)
+			.map(|number| {
+				format!(
+					"companion: https://github.com/{}/companion/pull/{}",
+					&owner.login, number
+				)
+			})
+			.collect::<Vec<_>>()
+			.join("\n"),
+	);
+
+	// The companions should not be fetched
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("references 3 companions")),
+			request::body(matches("exceeds the maximum of 2")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let err = handle_command(
+		&state,
+		&CommentCommand::Merge(MergeCommentCommand::Normal),
+		&pr,
+		&owner.login,
+	)
+	.await
+	.unwrap_err();
+	handle_error(
+		PullRequestMergeCancelOutcome::ShaNotFound,
+		err.with_pull_request_details(PullRequestDetails {
+			owner: owner.login.clone(),
+			repo: repo_name.to_string(),
+			number: pr.number,
+		}),
+		&state,
+	)
+	.await;
+}
+
+#[tokio::test]
+async fn merge_command_is_rejected_for_frozen_repositories() {
+	let owner = owner();
+	let repo_name = "frozen";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config
+		.frozen_repos
+		.insert(format!("{}/{}", &owner.login, repo_name));
+	let state = build_state(config);
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("Merges are frozen for this repository")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	handle_command(
+		&state,
+		&CommentCommand::Merge(MergeCommentCommand::Normal),
+		&pr,
+		&owner.login,
+	)
+	.await
+	.unwrap();
+
+	assert!(state
+		.db
+		.iterator(rocksdb::IteratorMode::Start)
+		.next()
+		.is_none());
+}
+
+#[tokio::test]
+async fn merge_command_is_rejected_while_paused() {
+	let owner = owner();
+	let repo_name = "paused";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	set_paused(&state, true).unwrap();
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("processbot is paused")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	handle_command(
+		&state,
+		&CommentCommand::Merge(MergeCommentCommand::Normal),
+		&pr,
+		&owner.login,
+	)
+	.await
+	.unwrap();
+
+	assert!(state
+		.db
+		.iterator(rocksdb::IteratorMode::Start)
+		.next()
+		.is_none());
+}
+
+#[tokio::test]
+async fn replies_to_commands_are_not_deduplicated() {
+	let owner = owner();
+	let repo_name = "repeated_commands";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	set_paused(&state, true).unwrap();
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("processbot is paused")),
+		])
+		.times(2)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("Command failed")),
+		])
+		.times(2)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	for _ in 0..2 {
+		handle_command(
+			&state,
+			&CommentCommand::Merge(MergeCommentCommand::Normal),
+			&pr,
+			&owner.login,
+		)
+		.await
+		.unwrap();
+		handle_error(
+			PullRequestMergeCancelOutcome::ShaNotFound,
+			Error::Message {
+				msg: "Command failed".to_string(),
+			}
+			.with_pull_request_details(PullRequestDetails {
+				owner: owner.login.clone(),
+				repo: repo_name.to_string(),
+				number: pr.number,
+			})
+			.into_command_reply(),
+			&state,
+		)
+		.await;
+	}
+}
+
+#[tokio::test]
+async fn merge_command_is_rejected_for_disallowed_base_branches() {
+	let owner = owner();
+	let repo_name = "restricted";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.allowed_base_branches.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		vec!["master".to_string()],
+	);
+	let state = build_state(config);
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"feature",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches(
+				"not allowed to merge pull requests into feature"
+			)),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	handle_command(
+		&state,
+		&CommentCommand::Merge(MergeCommentCommand::Normal),
+		&pr,
+		&owner.login,
+	)
+	.await
+	.unwrap();
+
+	assert!(state
+		.db
+		.iterator(rocksdb::IteratorMode::Start)
+		.next()
+		.is_none());
+}
+
+#[tokio::test]
+async fn unqueue_command_keeps_the_dependents() {
+	let owner = owner();
+	let repo_name = "unqueued";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"unqueued_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let mr =
+		build_merge_request(&pr.head.sha, &owner.login, repo_name, pr.number);
+	let dependent = MergeRequest {
+		dependencies: Some(vec![MergeRequestDependency {
+			sha: mr.sha.clone(),
+			owner: mr.owner.clone(),
+			repo: mr.repo.clone(),
+			number: mr.number,
+			html_url: mr.html_url.clone(),
+			is_directly_referenced: true,
+		}]),
+		..build_merge_request(
+			"dependent_sha",
+			&owner.login,
+			"unqueued_dependent",
+			2,
+		)
+	};
+	for mr in &[&mr, &dependent] {
+		state
+			.db
+			.put(mr.sha.as_bytes(), bincode::serialize(mr).unwrap())
+			.unwrap();
+	}
+
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("Removed from the merge queue")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	handle_command(&state, &CommentCommand::Unqueue, &pr, &owner.login)
+		.await
+		.unwrap();
+
+	assert!(state.db.get(mr.sha.as_bytes()).unwrap().is_none());
+	let dependent: MergeRequest = bincode::deserialize(
+		&state.db.get(dependent.sha.as_bytes()).unwrap().unwrap(),
+	)
+	.unwrap();
+	assert_eq!(dependent.dependencies.map(|deps| deps.len()), Some(0));
+}
+
+#[tokio::test]
+async fn merge_after_waits_for_the_named_pull_request() {
+	let owner = owner();
+	let repo_name = "merge_after_dependent";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let cmd = match parse_bot_comment_from_text(
+		"bot merge after owner/merge_after_dependency#2",
+		"processbot",
+	) {
+		Some(CommentCommand::Merge(cmd)) => cmd,
+		cmd => panic!("Unexpected command: {:?}", cmd),
+	};
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"merge_after_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let build_dependency_pr = || {
+		build_pull_request(
+			&owner,
+			"merge_after_dependency",
+			2,
+			"merge_after_dependency_sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, pr.number);
+
+	let is_dependency_merged = Arc::new(AtomicBool::new(false));
+	{
+		let is_dependency_merged = is_dependency_merged.clone();
+		let dependency_pr = build_dependency_pr();
+		let merged_dependency_pr = GithubPullRequest {
+			merged: true,
+			state: GithubPullRequestState::Closed,
+			..build_dependency_pr()
+		};
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				"/repos/owner/merge_after_dependency/pulls/2",
+			))
+			.times(1..)
+			.respond_with(move || {
+				if is_dependency_merged.load(Ordering::SeqCst) {
+					json_encoded(&merged_dependency_pr)
+				} else {
+					json_encoded(&dependency_pr)
+				}
+			}),
+		);
+	}
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(
+		&github_api,
+		&owner,
+		repo_name,
+		&pr.head.sha,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("after .* is merged")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!("{}/merge", pr_api_path),
+		))
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	handle_command(&state, &CommentCommand::Merge(cmd), &pr, &owner.login)
+		.await
+		.unwrap();
+
+	let mr = deserialize_merge_request(
+		&state.db.get(pr.head.sha.as_bytes()).unwrap().unwrap(),
+	)
+	.unwrap();
+	let dependencies = mr.dependencies.unwrap();
+	assert_eq!(dependencies.len(), 1);
+	assert_eq!(dependencies[0].repo, "merge_after_dependency");
+	assert_eq!(dependencies[0].sha, "merge_after_dependency_sha");
+
+	// The dependent stays queued while the dependency is not merged
+	process_commit_checks_and_statuses(&state, &pr.head.sha)
+		.await
+		.unwrap();
+	assert!(state.db.get(pr.head.sha.as_bytes()).unwrap().is_some());
+
+	is_dependency_merged.store(true, Ordering::SeqCst);
+	process_commit_checks_and_statuses(&state, &pr.head.sha)
+		.await
+		.unwrap();
+	assert!(state.db.get(pr.head.sha.as_bytes()).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn merge_command_is_rejected_when_the_queue_is_full() {
+	let owner = owner();
+	let repo_name = "full_queue";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.max_queue_size = 1;
+	let state = build_state(config);
+
+	let queued_mr =
+		build_merge_request("queued_sha", &owner.login, "full_queue_other", 1);
+	state
+		.db
+		.put(
+			queued_mr.sha.as_bytes(),
+			bincode::serialize(&queued_mr).unwrap(),
+		)
+		.unwrap();
+
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"rejected_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, pr.number
+				),
+			),
+			request::body(matches("The merge queue is full")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let err = handle_command(
+		&state,
+		&CommentCommand::Merge(MergeCommentCommand::Delayed(
+			Duration::from_secs(60 * 60),
+		)),
+		&pr,
+		&owner.login,
+	)
+	.await
+	.unwrap_err();
+	handle_error(
+		PullRequestMergeCancelOutcome::ShaNotFound,
+		err.with_pull_request_details(PullRequestDetails {
+			owner: owner.login.clone(),
+			repo: repo_name.to_string(),
+			number: pr.number,
+		}),
+		&state,
+	)
+	.await;
+
+	assert!(state.db.get(pr.head.sha.as_bytes()).unwrap().is_none());
+	// The merge requests which were already queued are left alone
+	assert!(state.db.get(queued_mr.sha.as_bytes()).unwrap().is_some());
+}
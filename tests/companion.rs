@@ -0,0 +1,498 @@
+use httptest::{
+	all_of, cycle, matchers::*, responders::*, Expectation, Server,
+};
+use parity_processbot::{
+	companion::{resolve_companions, wait_for_pull_request_head},
+	core::{handle_command, CommentCommand, MergeCommentCommand},
+	error::{Error, PullRequestDetailsWithHtmlUrl},
+	github::*,
+	merge_request::check_merge_is_allowed,
+};
+
+mod helpers;
+
+use helpers::{constants::*, setup::*};
+
+#[tokio::test]
+async fn companion_update_waits_for_pushed_head() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "repo";
+	let number = 1;
+	let stale_sha = "stale";
+	let updated_sha = "updated";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let build_pr = |head_sha| {
+		build_pull_request(
+			&owner,
+			repo_name,
+			number,
+			head_sha,
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+
+	// The first fetch still reflects the state before the push
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(2)
+		.respond_with(cycle![
+			json_encoded(build_pr(stale_sha)),
+			json_encoded(build_pr(updated_sha)),
+		]),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let pr = wait_for_pull_request_head(
+		&state.gh_client,
+		&owner.login,
+		repo_name,
+		number,
+		updated_sha,
+		60_000,
+	)
+	.await
+	.unwrap();
+	assert_eq!(pr.head.sha, updated_sha);
+}
+
+// Registers pull requests where each one references the next one as a companion
+fn setup_companion_chain(
+	github_api: &Server,
+	github_api_url: &str,
+	owner: &GithubUser,
+	repos: &[&str],
+	references: &[(usize, usize)],
+	prepare_prs: impl FnOnce(&mut [GithubPullRequest]),
+) -> Vec<GithubPullRequest> {
+	let mut prs = repos
+		.iter()
+		.map(|repo_name| {
+			build_pull_request(
+				owner,
+				repo_name,
+				1,
+				"sha",
+				"master",
+				"contributor_patches",
+				github_api_url,
+				&format!("https://github.com/{}/{}", &owner.login, repo_name),
+			)
+		})
+		.collect::<Vec<_>>();
+	for (from, to) in references {
+		prs[*from].body = Some(format!("companion: {}", &prs[*to].html_url));
+	}
+	prepare_prs(&mut prs);
+	for pr in &prs {
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/pulls/{}",
+					&owner.login, &pr.base.repo.name, pr.number
+				),
+			))
+			.times(0..)
+			.respond_with(json_encoded(pr)),
+		);
+	}
+	prs
+}
+
+#[tokio::test]
+async fn companion_reference_cycles_are_broken() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	// A -> B -> C -> A
+	let prs = setup_companion_chain(
+		&github_api,
+		&github_api_url,
+		&owner,
+		&["a", "b", "c"],
+		&[(0, 1), (1, 2), (2, 0)],
+		|_| (),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.disable_org_checks = true;
+	let state = build_state(config);
+
+	check_merge_is_allowed(&state, &prs[0], &owner.login, &[])
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn companion_references_are_limited_in_depth() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	// A -> B -> C -> D
+	let prs = setup_companion_chain(
+		&github_api,
+		&github_api_url,
+		&owner,
+		&["a", "b", "c", "d"],
+		&[(0, 1), (1, 2), (2, 3)],
+		|_| (),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.disable_org_checks = true;
+	config.max_dependency_depth = 3;
+	let mut state = build_state(config);
+
+	// A depth of 3 is within the limit
+	check_merge_is_allowed(&state, &prs[0], &owner.login, &[])
+		.await
+		.unwrap();
+
+	state.config.max_dependency_depth = 2;
+	let err = check_merge_is_allowed(&state, &prs[0], &owner.login, &[])
+		.await
+		.unwrap_err();
+	assert!(format!("{}", err).contains(&format!(
+		"The companions of {} exceed the maximum dependency depth of 2",
+		&prs[2].html_url
+	)));
+}
+
+// Checks whether A can be merged given that it references B as a companion
+async fn check_merge_with_companion(
+	prepare_companion: impl FnOnce(&mut GithubPullRequest),
+) -> Result<(), Error> {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let prs = setup_companion_chain(
+		&github_api,
+		&github_api_url,
+		&owner,
+		&["a", "b"],
+		&[(0, 1)],
+		|prs| prepare_companion(&mut prs[1]),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.disable_org_checks = true;
+	let state = build_state(config);
+
+	check_merge_is_allowed(&state, &prs[0], &owner.login, &[]).await
+}
+
+#[tokio::test]
+async fn companion_failures_are_categorized() {
+	check_merge_with_companion(|_| ()).await.unwrap();
+
+	let err = check_merge_with_companion(|companion| {
+		companion.mergeable = None;
+	})
+	.await
+	.unwrap_err();
+	assert!(matches!(err, Error::CompanionNotMergeable { .. }));
+
+	let err = check_merge_with_companion(|companion| {
+		companion.maintainer_can_modify = false;
+		companion.head.repo.as_mut().unwrap().owner.login =
+			"contributor".to_string();
+	})
+	.await
+	.unwrap_err();
+	assert!(matches!(err, Error::CompanionMissingMaintainerEdit { .. }));
+
+	let err = check_merge_with_companion(|companion| {
+		companion.user = Some(GithubUser {
+			login: "bot".to_string(),
+			type_field: GithubUserType::Bot,
+		});
+	})
+	.await
+	.unwrap_err();
+	assert!(matches!(err, Error::CompanionNotUserOwned { .. }));
+}
+
+#[tokio::test]
+async fn queued_merge_comment_lists_the_companions() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let prs = setup_companion_chain(
+		&github_api,
+		&github_api_url,
+		&owner,
+		&["queued_a", "queued_b"],
+		&[(0, 1)],
+		|_| (),
+	);
+	let pr = &prs[0];
+
+	// Pending checks make the merge request be queued
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, &pr.base.repo.name, &pr.head.sha
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubCheckRuns {
+			check_runs: vec![GithubCheckRun {
+				id: 1,
+				name: "does not matter".to_string(),
+				status: GithubCheckRunStatus::Unknown,
+				conclusion: None,
+				head_sha: pr.head.sha.clone(),
+				html_url: None,
+			}],
+		})),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, &pr.base.repo.name, pr.number
+				),
+			),
+			request::body(matches("Waiting for commit status")),
+			request::body(matches("/owner/queued_b/pull/1")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.disable_org_checks = true;
+	let state = build_state(config);
+
+	handle_command(
+		&state,
+		&CommentCommand::Merge(MergeCommentCommand::Normal),
+		pr,
+		&owner.login,
+	)
+	.await
+	.unwrap();
+}
+
+#[tokio::test]
+async fn explicit_companion_order_is_honored() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	// Without the explicit order the companions would be independent from each
+	// other and follow the order of the description
+	let prs = setup_companion_chain(
+		&github_api,
+		&github_api_url,
+		&owner,
+		&["ordered_parent", "ordered_a", "ordered_b"],
+		&[],
+		|prs| {
+			prs[0].body = Some(format!(
+				"companion: {}\ncompanion: {}\ncompanion-order: ordered_b, owner/ordered_a",
+				&prs[1].html_url, &prs[2].html_url
+			));
+		},
+	);
+	let lockfile = base64::encode(
+		"version = 3\n\n[[package]]\nname = \"companion\"\nversion = \"0.1.0\"\n",
+	);
+	for repo_name in &["ordered_a", "ordered_b"] {
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/contents/Cargo.lock",
+					&owner.login, repo_name
+				),
+			))
+			.times(1)
+			.respond_with(json_encoded(GithubFileContents {
+				content: lockfile.clone(),
+			})),
+		);
+	}
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let dependents = state
+		.gh_client
+		.resolve_pr_dependents(&state.config, &prs[0], &owner.login, &[])
+		.await
+		.unwrap()
+		.unwrap();
+
+	assert_eq!(
+		dependents
+			.iter()
+			.map(|dependent| dependent.repo.as_str())
+			.collect::<Vec<_>>(),
+		vec!["ordered_b", "ordered_a"]
+	);
+	let dependencies_of = |index: usize| {
+		dependents[index]
+			.dependencies
+			.as_ref()
+			.unwrap()
+			.iter()
+			.map(|dependency| dependency.repo.clone())
+			.collect::<Vec<_>>()
+	};
+	assert_eq!(dependencies_of(0), vec!["ordered_parent"]);
+	assert_eq!(dependencies_of(1), vec!["ordered_parent", "ordered_b"]);
+}
+
+#[tokio::test]
+async fn lockfile_git_dependencies_are_discovered_as_companions() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let prs = setup_companion_chain(
+		&github_api,
+		&github_api_url,
+		&owner,
+		&["discovering", "discovered"],
+		&[],
+		|_| (),
+	);
+	// Only the branch of a repository from the same owner is a companion
+	let lockfile = base64::encode(
+		r#"
+version = 3
+
+[[package]]
+name = "discovered"
+version = "0.1.0"
+source = "git+https://github.com/owner/discovered?branch=feature#sha"
+
+[[package]]
+name = "external"
+version = "0.1.0"
+source = "git+https://github.com/someone/external?branch=feature#sha"
+
+[[package]]
+name = "serde"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#,
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/discovering/contents/Cargo.lock",
+					&owner.login
+				),
+			),
+			request::query(url_decoded(contains(("ref", "sha")))),
+		])
+		.times(1)
+		.respond_with(json_encoded(GithubFileContents { content: lockfile })),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"GET",
+				format!("/repos/{}/discovered/pulls", &owner.login),
+			),
+			request::query(url_decoded(contains(("state", "open")))),
+			request::query(url_decoded(contains(("head", "owner:feature")))),
+		])
+		.times(1)
+		.respond_with(json_encoded(vec![&prs[1]])),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.companion_discovery_enabled = true;
+	let state = build_state(config);
+
+	let companions =
+		resolve_companions(&state.gh_client, &state.config, &prs[0], &[])
+			.await
+			.unwrap();
+
+	assert_eq!(
+		companions,
+		Some(vec![PullRequestDetailsWithHtmlUrl {
+			html_url: prs[1].html_url.clone(),
+			owner: owner.login.clone(),
+			repo: "discovered".to_string(),
+			number: prs[1].number,
+		}])
+	);
+}
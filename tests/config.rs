@@ -0,0 +1,21 @@
+use parity_processbot::config::parse_per_repository_templates;
+
+#[test]
+fn templates_may_contain_colons_and_equal_signs() {
+	let templates = parse_per_repository_templates(
+		"MERGE_COMMIT_TITLE_TEMPLATE_OVERRIDES",
+		"owner/repo={title} (#{number}): a=b\n\nowner/other=Signed-off-by: {requested_by}\n",
+	);
+	assert_eq!(templates.len(), 2);
+	assert_eq!(templates["owner/repo"], "{title} (#{number}): a=b");
+	assert_eq!(templates["owner/other"], "Signed-off-by: {requested_by}");
+}
+
+#[test]
+#[should_panic(expected = "should be of the form OWNER/REPOSITORY=TEMPLATE")]
+fn templates_without_a_repository_are_rejected() {
+	parse_per_repository_templates(
+		"MERGE_COMMIT_TITLE_TEMPLATE_OVERRIDES",
+		"{title} (#{number})",
+	);
+}
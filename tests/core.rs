@@ -0,0 +1,1792 @@
+use std::{
+	sync::{Arc, Mutex},
+	time::{Duration, Instant, SystemTime},
+};
+
+use httptest::{
+	all_of, cycle, matchers::*, responders::*, Expectation, Server,
+};
+use parity_processbot::{
+	constants::{MERGE_PRIORITY_HIGH, MERGE_PRIORITY_NORMAL},
+	core::{
+		evaluate_dependent_liveness, get_commit_statuses,
+		poll_pending_merge_requests, process_commit_checks_and_statuses,
+		process_dependents_after_merge, reconcile_merge_requests,
+		requeue_pull_request, AppState, DependentLiveness, Status,
+	},
+	github::*,
+	merge_request::{
+		allow_merge_once, cleanup_merge_request, consume_merge_allowance,
+		deserialize_merge_request, is_merge_granted, queue_merge_request,
+		set_paused, set_repository_frozen, MergeRequest,
+		MergeRequestCleanupReason, MergeRequestDependency,
+		MergeRequestQueuedMessage,
+	},
+};
+use serde_json::json;
+use tempfile::TempDir;
+
+mod helpers;
+
+use helpers::{constants::*, setup::*};
+
+const REPO_NAME: &str = "repo";
+const NUMBER: i64 = 1;
+const SHA: &str = "sha";
+const GITLAB_JOB_NAME: &str = "test-linux-stable";
+
+struct GitlabRecoverySetup {
+	github_api: Server,
+	gitlab_api: Server,
+	owner: GithubUser,
+	state: AppState,
+	_db_dir: TempDir,
+}
+
+// Set up a failing status for a GitLab job which was retried, thus its pipeline is still
+// running
+fn setup_gitlab_recovery(gitlab_recovery_enabled: bool) -> GitlabRecoverySetup {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let gitlab_api = Server::run();
+	let gitlab_url = {
+		let url = gitlab_api.url("").to_string();
+		url[0..url.len() - 1].to_string()
+	};
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/statuses/{}", &owner.login, REPO_NAME, SHA),
+		))
+		.times(0..)
+		.respond_with(json_encoded(vec![GithubCommitStatus {
+			id: 1,
+			context: GITLAB_JOB_NAME.to_string(),
+			description: None,
+			state: GithubCommitStatusState::Failure,
+			target_url: Some(format!("{}/project/builds/123", gitlab_url)),
+		}])),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.gitlab_url = gitlab_url;
+	config.gitlab_access_token = "token".to_string();
+	config.gitlab_recovery_enabled = gitlab_recovery_enabled;
+
+	GitlabRecoverySetup {
+		github_api,
+		gitlab_api,
+		owner,
+		state: build_state(config),
+		_db_dir: db_dir,
+	}
+}
+
+fn recovery_comment_expectation(
+	owner: &GithubUser,
+	times: usize,
+) -> Expectation {
+	Expectation::matching(all_of![
+		request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/{}/comments",
+				&owner.login, REPO_NAME, NUMBER
+			),
+		),
+		request::body(matches(GITLAB_JOB_NAME)),
+	])
+	.times(times)
+	.respond_with(status_code(201).body("{}"))
+}
+
+async fn check_statuses(state: &AppState, owner: &GithubUser) -> Status {
+	get_commit_statuses(
+		state,
+		&owner.login,
+		REPO_NAME,
+		NUMBER,
+		SHA,
+		"does not matter",
+		true,
+	)
+	.await
+	.unwrap()
+	.0
+}
+
+#[tokio::test]
+async fn recovered_gitlab_jobs_are_notified_once() {
+	let GitlabRecoverySetup {
+		github_api,
+		gitlab_api,
+		owner,
+		state,
+		..
+	} = setup_gitlab_recovery(true);
+
+	gitlab_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			"/api/v4/projects/project/jobs/123",
+		))
+		.times(3)
+		.respond_with(json_encoded(json!({
+			"name": GITLAB_JOB_NAME,
+			"pipeline": {
+				"status": "running",
+				"id": 1,
+				"project_id": 2,
+			},
+		}))),
+	);
+	gitlab_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			"/api/v4/projects/2/pipelines/1/jobs",
+		))
+		.times(6)
+		.respond_with(cycle![
+			json_encoded(json!([{ "name": GITLAB_JOB_NAME }])),
+			json_encoded(json!([])),
+		]),
+	);
+	github_api.expect(recovery_comment_expectation(&owner, 1));
+
+	// The comment should only be posted once even if the statuses are checked again
+	for _ in 0..2 {
+		assert!(matches!(
+			check_statuses(&state, &owner).await,
+			Status::Pending
+		));
+	}
+
+	// Nor after a restart
+	let config = state.config.clone();
+	drop(state);
+	let state = build_state(config);
+	assert!(matches!(
+		check_statuses(&state, &owner).await,
+		Status::Pending
+	));
+}
+
+#[tokio::test]
+async fn gitlab_recovery_can_be_disabled() {
+	let GitlabRecoverySetup {
+		github_api,
+		gitlab_api,
+		owner,
+		state,
+		..
+	} = setup_gitlab_recovery(false);
+
+	gitlab_api.expect(
+		Expectation::matching(request::method(matches(".*")))
+			.times(0)
+			.respond_with(status_code(500)),
+	);
+	github_api.expect(recovery_comment_expectation(&owner, 0));
+
+	assert!(matches!(
+		check_statuses(&state, &owner).await,
+		Status::Failure
+	));
+}
+
+#[tokio::test]
+async fn gitlab_circuit_breaker_opens_after_consecutive_failures() {
+	let GitlabRecoverySetup {
+		github_api,
+		gitlab_api,
+		owner,
+		mut state,
+		..
+	} = setup_gitlab_recovery(true);
+	state.config.gitlab_circuit_breaker_threshold = 2;
+
+	// GitLab should no longer be requested once it has failed twice in a row
+	gitlab_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			"/api/v4/projects/project/jobs/123",
+		))
+		.times(2)
+		.respond_with(status_code(500)),
+	);
+	github_api.expect(recovery_comment_expectation(&owner, 0));
+
+	for _ in 0..3 {
+		assert!(matches!(
+			check_statuses(&state, &owner).await,
+			Status::Failure
+		));
+	}
+}
+
+#[tokio::test]
+async fn poll_processes_higher_priority_merge_requests_first() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	// The normal-priority merge request is registered first and its key also
+	// comes first in the database's order
+	for (number, sha, priority) in &[
+		(1, "a", MERGE_PRIORITY_NORMAL),
+		(2, "b", MERGE_PRIORITY_HIGH),
+	] {
+		let pr = build_pull_request(
+			&owner,
+			REPO_NAME,
+			*number,
+			sha,
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		);
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/pulls/{}",
+					&owner.login, REPO_NAME, number
+				),
+			))
+			.times(1)
+			.respond_with(json_encoded(&pr)),
+		);
+		// Pending checks prevent the merge from being attempted
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/commits/{}/check-runs",
+					&owner.login, REPO_NAME, sha
+				),
+			))
+			.times(1)
+			.respond_with(json_encoded(GithubCheckRuns {
+				check_runs: vec![GithubCheckRun {
+					id: 1,
+					name: "does not matter".to_string(),
+					status: GithubCheckRunStatus::Unknown,
+					conclusion: None,
+					head_sha: sha.to_string(),
+					html_url: None,
+				}],
+			})),
+		);
+
+		let mr = MergeRequest {
+			html_url: pr.html_url.clone(),
+			priority: *priority,
+			..build_merge_request(sha, &owner.login, REPO_NAME, *number)
+		};
+		state
+			.db
+			.put(sha.as_bytes(), bincode::serialize(&mr).unwrap())
+			.unwrap();
+	}
+
+	let processed_mrs = poll_pending_merge_requests(&state).await;
+	assert_eq!(
+		processed_mrs.iter().map(|mr| mr.number).collect::<Vec<_>>(),
+		vec![2, 1]
+	);
+}
+
+#[tokio::test]
+async fn poll_processes_equal_priority_merge_requests_in_registration_order() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	// The merge request which is registered first has the key which comes last
+	// in the database's order
+	for (number, sha) in &[(1, "z"), (2, "a")] {
+		let pr = build_pull_request(
+			&owner,
+			REPO_NAME,
+			*number,
+			sha,
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		);
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/pulls/{}",
+					&owner.login, REPO_NAME, number
+				),
+			))
+			.times(1)
+			.respond_with(json_encoded(&pr)),
+		);
+		// Pending checks prevent the merge from being attempted
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/commits/{}/check-runs",
+					&owner.login, REPO_NAME, sha
+				),
+			))
+			.times(1)
+			.respond_with(json_encoded(GithubCheckRuns {
+				check_runs: vec![GithubCheckRun {
+					id: 1,
+					name: "does not matter".to_string(),
+					status: GithubCheckRunStatus::Unknown,
+					conclusion: None,
+					head_sha: sha.to_string(),
+					html_url: None,
+				}],
+			})),
+		);
+
+		queue_merge_request(
+			&state,
+			&MergeRequest {
+				html_url: pr.html_url.clone(),
+				..build_merge_request(sha, &owner.login, REPO_NAME, *number)
+			},
+			&MergeRequestQueuedMessage::None,
+		)
+		.await
+		.unwrap();
+	}
+
+	let processed_mrs = poll_pending_merge_requests(&state).await;
+	assert_eq!(
+		processed_mrs
+			.iter()
+			.map(|mr| (mr.number, mr.seq))
+			.collect::<Vec<_>>(),
+		vec![(1, Some(0)), (2, Some(1))]
+	);
+}
+
+#[tokio::test]
+async fn poll_compares_registration_order_within_repositories_only() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	// In the database's order: "a" (seq 1 of first_repo), "m" (seq 0 of
+	// second_repo), "z" (seq 0 of first_repo)
+	for (repo_name, number, sha) in &[
+		("first_repo", 1, "z"),
+		("first_repo", 2, "a"),
+		("second_repo", 1, "m"),
+	] {
+		let pr = build_pull_request(
+			&owner,
+			repo_name,
+			*number,
+			sha,
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		);
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/pulls/{}",
+					&owner.login, repo_name, number
+				),
+			))
+			.times(1)
+			.respond_with(json_encoded(&pr)),
+		);
+		// Pending checks prevent the merge from being attempted
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/commits/{}/check-runs",
+					&owner.login, repo_name, sha
+				),
+			))
+			.times(1)
+			.respond_with(json_encoded(GithubCheckRuns {
+				check_runs: vec![GithubCheckRun {
+					id: 1,
+					name: "does not matter".to_string(),
+					status: GithubCheckRunStatus::Unknown,
+					conclusion: None,
+					head_sha: sha.to_string(),
+					html_url: None,
+				}],
+			})),
+		);
+
+		queue_merge_request(
+			&state,
+			&MergeRequest {
+				html_url: pr.html_url.clone(),
+				..build_merge_request(sha, &owner.login, repo_name, *number)
+			},
+			&MergeRequestQueuedMessage::None,
+		)
+		.await
+		.unwrap();
+	}
+
+	// The repository of the first merge request in the database's order goes
+	// first, in registration order, even though the merge request of the other
+	// repository has a lower sequence number than some of its merge requests
+	let processed_mrs = poll_pending_merge_requests(&state).await;
+	assert_eq!(
+		processed_mrs
+			.iter()
+			.map(|mr| (mr.repo.as_str(), mr.number, mr.seq))
+			.collect::<Vec<_>>(),
+		vec![
+			("first_repo", 1, Some(0)),
+			("first_repo", 2, Some(1)),
+			("second_repo", 1, Some(0))
+		]
+	);
+}
+
+#[tokio::test]
+async fn delayed_merge_requests_are_polled_after_their_delay() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		REPO_NAME,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, REPO_NAME, NUMBER);
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, REPO_NAME, SHA);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!("{}/merge", pr_api_path),
+		))
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let delay = Duration::from_secs(2);
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		not_before: Some(SystemTime::now() + delay),
+		..build_merge_request(SHA, &owner.login, REPO_NAME, NUMBER)
+	};
+	state
+		.db
+		.put(SHA.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	assert!(poll_pending_merge_requests(&state).await.is_empty());
+
+	tokio::time::sleep(delay).await;
+	assert_eq!(poll_pending_merge_requests(&state).await.len(), 1);
+}
+
+#[tokio::test]
+async fn requeued_pull_request_is_merged() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "requeued";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, NUMBER);
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(1..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, SHA);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!("{}/merge", pr_api_path),
+		))
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	requeue_pull_request(&state, &owner.login, repo_name, NUMBER, &owner.login)
+		.await
+		.unwrap();
+	assert!(state.db.get(SHA.as_bytes()).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn frozen_repositories_are_skipped_during_poll() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "frozen";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	// The merge request is only processed once the repository is unfrozen
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, NUMBER),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, SHA
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubCheckRuns {
+			check_runs: vec![GithubCheckRun {
+				id: 1,
+				name: "does not matter".to_string(),
+				status: GithubCheckRunStatus::Unknown,
+				conclusion: None,
+				head_sha: SHA.to_string(),
+				html_url: None,
+			}],
+		})),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(SHA, &owner.login, repo_name, NUMBER)
+	};
+	state
+		.db
+		.put(SHA.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	let repository = format!("{}/{}", &owner.login, repo_name);
+	set_repository_frozen(&state, &repository, true).unwrap();
+	assert!(poll_pending_merge_requests(&state).await.is_empty());
+
+	set_repository_frozen(&state, &repository, false).unwrap();
+	assert_eq!(poll_pending_merge_requests(&state).await.len(), 1);
+}
+
+#[tokio::test]
+async fn frozen_branches_do_not_hold_off_other_branches() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "frozen_branch";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	set_repository_frozen(
+		&state,
+		&format!("{}/{}:release", &owner.login, repo_name),
+		true,
+	)
+	.unwrap();
+
+	// Only the pull request targeting master is merged
+	for (number, sha, base, merges) in &[
+		(1, "release_sha", "release", 0usize),
+		(2, "master_sha", "master", 1),
+	] {
+		let pr = build_pull_request(
+			&owner,
+			repo_name,
+			*number,
+			sha,
+			base,
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		);
+		let pr_api_path =
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number);
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				pr_api_path.clone(),
+			))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+		);
+		setup_successful_commit_checks(&github_api, &owner, repo_name, sha);
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"PUT",
+				format!("{}/merge", pr_api_path),
+			))
+			.times(*merges)
+			.respond_with(status_code(200).body("{}")),
+		);
+
+		let mr = MergeRequest {
+			html_url: pr.html_url.clone(),
+			..build_merge_request(sha, &owner.login, repo_name, *number)
+		};
+		state
+			.db
+			.put(sha.as_bytes(), bincode::serialize(&mr).unwrap())
+			.unwrap();
+
+		process_commit_checks_and_statuses(&state, sha)
+			.await
+			.unwrap();
+	}
+
+	// The merge request of the frozen branch stays queued until it's unfrozen
+	assert!(state.db.get("release_sha".as_bytes()).unwrap().is_some());
+}
+
+#[tokio::test]
+async fn paused_bot_skips_the_poll_until_resumed() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "paused";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	// The merge request is only processed once processbot is resumed
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, NUMBER),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, SHA
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubCheckRuns {
+			check_runs: vec![GithubCheckRun {
+				id: 1,
+				name: "does not matter".to_string(),
+				status: GithubCheckRunStatus::Unknown,
+				conclusion: None,
+				head_sha: SHA.to_string(),
+				html_url: None,
+			}],
+		})),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(SHA, &owner.login, repo_name, NUMBER)
+	};
+	state
+		.db
+		.put(SHA.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	set_paused(&state, true).unwrap();
+	assert!(poll_pending_merge_requests(&state).await.is_empty());
+
+	set_paused(&state, false).unwrap();
+	assert_eq!(poll_pending_merge_requests(&state).await.len(), 1);
+}
+
+#[tokio::test]
+async fn merged_dependencies_are_reconciled_during_poll() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	// The dependency was merged without processbot having noticed it
+	let dependency_pr = GithubPullRequest {
+		merged: true,
+		state: GithubPullRequestState::Closed,
+		..build_pull_request(
+			&owner,
+			"reconciled_dependency",
+			NUMBER,
+			"dependency_sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+	let repo_name = "reconciled_dependent";
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, NUMBER);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/pulls/{}",
+				&owner.login, &dependency_pr.base.repo.name, NUMBER
+			),
+		))
+		.times(1..)
+		.respond_with(json_encoded(&dependency_pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, SHA);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!("{}/merge", pr_api_path),
+		))
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		dependencies: Some(vec![MergeRequestDependency {
+			sha: dependency_pr.head.sha.clone(),
+			owner: owner.login.clone(),
+			repo: dependency_pr.base.repo.name.clone(),
+			number: NUMBER,
+			html_url: dependency_pr.html_url.clone(),
+			is_directly_referenced: true,
+		}]),
+		..build_merge_request(SHA, &owner.login, repo_name, NUMBER)
+	};
+	state
+		.db
+		.put(SHA.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	assert_eq!(poll_pending_merge_requests(&state).await.len(), 1);
+}
+
+fn build_dependent(
+	sha: &str,
+	number: i64,
+	dependencies: &[(&str, i64, bool)],
+) -> MergeRequest {
+	MergeRequest {
+		html_url: format!("https://github.com/owner/dependent/pull/{}", number),
+		dependencies: Some(
+			dependencies
+				.iter()
+				.map(|(repo, number, is_directly_referenced)| {
+					MergeRequestDependency {
+						sha: format!("{}_{}", repo, number),
+						owner: "owner".to_string(),
+						repo: repo.to_string(),
+						number: *number,
+						html_url: format!(
+							"https://github.com/owner/{}/pull/{}",
+							repo, number
+						),
+						is_directly_referenced: *is_directly_referenced,
+					}
+				})
+				.collect(),
+		),
+		..build_merge_request(sha, "owner", "dependent", number)
+	}
+}
+
+fn dependency_numbers(mr: &MergeRequest) -> Vec<(String, i64)> {
+	mr.dependencies
+		.iter()
+		.flatten()
+		.map(|dependency| (dependency.repo.clone(), dependency.number))
+		.collect()
+}
+
+#[test]
+fn dependent_liveness_is_evaluated_after_merge() {
+	// The merged pull request is owner/merged#1
+	for (dependencies, expected_liveness, expected_dependencies) in vec![
+		(vec![("other", 2, true)], None, vec![("other", 2)]),
+		(
+			vec![("merged", 1, true)],
+			Some(DependentLiveness::Dangling),
+			vec![],
+		),
+		(
+			vec![("merged", 1, true), ("other", 2, true)],
+			Some(DependentLiveness::Updated),
+			vec![("other", 2)],
+		),
+		// The order of the dependencies does not matter
+		(
+			vec![("other", 2, true), ("merged", 1, true)],
+			Some(DependentLiveness::Updated),
+			vec![("other", 2)],
+		),
+		(
+			vec![("merged", 1, false)],
+			Some(DependentLiveness::Alive),
+			vec![("merged", 1)],
+		),
+		(
+			vec![("other", 2, true), ("merged", 1, false)],
+			Some(DependentLiveness::Alive),
+			vec![("other", 2), ("merged", 1)],
+		),
+		(
+			vec![("merged", 1, true), ("merged", 1, false)],
+			Some(DependentLiveness::AliveNeedsUpdate),
+			vec![("merged", 1)],
+		),
+		(
+			vec![
+				("merged", 1, false),
+				("other", 2, true),
+				("merged", 1, true),
+			],
+			Some(DependentLiveness::AliveNeedsUpdate),
+			vec![("merged", 1), ("other", 2)],
+		),
+	] {
+		let mut mr = build_dependent("sha", 1, &dependencies);
+		assert_eq!(
+			evaluate_dependent_liveness(&mut mr, "owner", "merged", 1),
+			expected_liveness,
+			"{:?}",
+			dependencies
+		);
+		assert_eq!(
+			dependency_numbers(&mr),
+			expected_dependencies
+				.into_iter()
+				.map(|(repo, number)| (repo.to_string(), number))
+				.collect::<Vec<_>>(),
+			"{:?}",
+			dependencies
+		);
+	}
+}
+
+#[tokio::test]
+async fn dangling_dependents_are_cleaned_up_after_merge() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	// No request is expected since the merged pull request has no companions
+	// and none of the dependents is alive
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let merged_pr = GithubPullRequest {
+		merged: true,
+		..build_pull_request(
+			&owner,
+			"merged",
+			1,
+			"merged_sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	for mr in &[
+		build_dependent("dangling", 1, &[("merged", 1, true)]),
+		build_dependent(
+			"updated",
+			2,
+			&[("other", 2, true), ("merged", 1, true)],
+		),
+		build_dependent("unrelated", 3, &[("other", 2, true)]),
+	] {
+		state
+			.db
+			.put(mr.sha.as_bytes(), bincode::serialize(mr).unwrap())
+			.unwrap();
+	}
+
+	process_dependents_after_merge(&state, &merged_pr, &owner.login)
+		.await
+		.unwrap();
+
+	let stored_dependencies = |sha: &str| {
+		state.db.get(sha.as_bytes()).unwrap().map(|bytes| {
+			dependency_numbers(&deserialize_merge_request(&bytes).unwrap())
+		})
+	};
+	assert_eq!(stored_dependencies("dangling"), None);
+	assert_eq!(
+		stored_dependencies("updated"),
+		Some(vec![("other".to_string(), 2)])
+	);
+	assert_eq!(
+		stored_dependencies("unrelated"),
+		Some(vec![("other".to_string(), 2)])
+	);
+}
+
+#[tokio::test]
+async fn merges_done_out_of_band_are_reconciled() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	// The pull request was merged without its webhook being received
+	let merged_pr = GithubPullRequest {
+		merged: true,
+		..build_pull_request(
+			&owner,
+			"merged",
+			1,
+			"merged_sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			"/repos/owner/merged/pulls/1",
+		))
+		.times(1..)
+		.respond_with(json_encoded(&merged_pr)),
+	);
+	let dependent_pr = build_pull_request(
+		&owner,
+		"dependent",
+		2,
+		"dependent_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			"/repos/owner/dependent/pulls/2",
+		))
+		.times(1..)
+		.respond_with(json_encoded(&dependent_pr)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	for mr in &[
+		MergeRequest {
+			repo: "merged".to_string(),
+			number: 1,
+			html_url: merged_pr.html_url.clone(),
+			dependencies: None,
+			..build_dependent("merged_sha", 1, &[])
+		},
+		build_dependent(
+			"dependent_sha",
+			2,
+			&[("other", 2, true), ("merged", 1, true)],
+		),
+	] {
+		state
+			.db
+			.put(mr.sha.as_bytes(), bincode::serialize(mr).unwrap())
+			.unwrap();
+	}
+
+	assert_eq!(reconcile_merge_requests(&state).await, 1);
+
+	assert!(state.db.get("merged_sha".as_bytes()).unwrap().is_none());
+	// The dependent no longer waits for the merged pull request
+	let dependent = deserialize_merge_request(
+		&state.db.get("dependent_sha".as_bytes()).unwrap().unwrap(),
+	)
+	.unwrap();
+	assert_eq!(
+		dependency_numbers(&dependent),
+		vec![("other".to_string(), 2)]
+	);
+}
+
+#[tokio::test]
+async fn reconciliation_only_cancels_confirmed_head_changes() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			"/repos/owner/unavailable/pulls/1",
+		))
+		.times(1)
+		.respond_with(status_code(500).body("{}")),
+	);
+	// Someone pushed to the pull request without its webhook being received
+	let pushed_pr = build_pull_request(
+		&owner,
+		"pushed",
+		2,
+		"new_sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			"/repos/owner/pushed/pulls/2",
+		))
+		.times(1)
+		.respond_with(json_encoded(&pushed_pr)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				"/repos/owner/pushed/issues/2/comments"
+			),
+			request::body(matches(
+				"Head SHA changed from pushed_sha to new_sha"
+			)),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	for mr in &[
+		MergeRequest {
+			repo: "unavailable".to_string(),
+			dependencies: None,
+			..build_dependent("unavailable_sha", 1, &[])
+		},
+		MergeRequest {
+			repo: "pushed".to_string(),
+			// Even if processbot still had to update the branch, only the
+			// requester's commit may be merged
+			was_updated: false,
+			dependencies: None,
+			..build_dependent("pushed_sha", 2, &[])
+		},
+	] {
+		state
+			.db
+			.put(mr.sha.as_bytes(), bincode::serialize(mr).unwrap())
+			.unwrap();
+	}
+
+	assert_eq!(reconcile_merge_requests(&state).await, 1);
+
+	// The merge request is kept so that it's reconciled again later
+	assert!(state
+		.db
+		.get("unavailable_sha".as_bytes())
+		.unwrap()
+		.is_some());
+	assert!(state.db.get("pushed_sha".as_bytes()).unwrap().is_none());
+	assert!(state.db.get("new_sha".as_bytes()).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn dependents_are_processed_in_a_stable_order() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let merged_pr = GithubPullRequest {
+		merged: true,
+		..build_pull_request(
+			&owner,
+			"ordered_dependency",
+			NUMBER,
+			"merged_sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+	let repo_name = "ordered_dependents";
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	// The database is ordered by SHA, which is the reverse of the numbers' order
+	let processed_dependents = Arc::new(Mutex::new(vec![]));
+	for (number, sha) in &[(1, "sha_c"), (2, "sha_b"), (3, "sha_a")] {
+		let pr = build_pull_request(
+			&owner,
+			repo_name,
+			*number,
+			"pushed_sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		);
+		let processed_dependents = processed_dependents.clone();
+		let number = *number;
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/pulls/{}",
+					&owner.login, repo_name, number
+				),
+			))
+			.times(1)
+			.respond_with(move || {
+				processed_dependents.lock().unwrap().push(number);
+				json_encoded(&pr)
+			}),
+		);
+		// The dependents' HEAD changed, thus they're cancelled after being fetched
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			))
+			.times(0..)
+			.respond_with(status_code(201).body("{}")),
+		);
+
+		let mr = MergeRequest {
+			dependencies: Some(vec![MergeRequestDependency {
+				sha: merged_pr.head.sha.clone(),
+				owner: owner.login.clone(),
+				repo: merged_pr.base.repo.name.clone(),
+				number: NUMBER,
+				html_url: merged_pr.html_url.clone(),
+				is_directly_referenced: false,
+			}]),
+			..build_merge_request(sha, &owner.login, repo_name, number)
+		};
+		state
+			.db
+			.put(sha.as_bytes(), bincode::serialize(&mr).unwrap())
+			.unwrap();
+	}
+
+	process_dependents_after_merge(&state, &merged_pr, &owner.login)
+		.await
+		.unwrap();
+
+	assert_eq!(*processed_dependents.lock().unwrap(), vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn dependents_of_different_head_shas_are_all_updated() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	let dependency_repo = "updated_dependency";
+	let repo_name = "dependent_with_many_shas";
+
+	for sha in &["first_sha", "second_sha"] {
+		let mr = MergeRequest {
+			was_updated: false,
+			html_url: URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER.to_string(),
+			dependencies: Some(vec![MergeRequestDependency {
+				sha: "dependency_sha".to_string(),
+				owner: owner.login.clone(),
+				repo: dependency_repo.to_string(),
+				number: NUMBER,
+				html_url: URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER.to_string(),
+				is_directly_referenced: true,
+			}]),
+			..build_merge_request(sha, &owner.login, repo_name, NUMBER)
+		};
+		state
+			.db
+			.put(sha.as_bytes(), bincode::serialize(&mr).unwrap())
+			.unwrap();
+	}
+
+	cleanup_merge_request(
+		&state,
+		"dependency_sha",
+		&owner.login,
+		dependency_repo,
+		NUMBER,
+		&MergeRequestCleanupReason::AfterSHAUpdate(
+			&"updated_dependency_sha".to_string(),
+		),
+	)
+	.await
+	.unwrap();
+
+	// Both records of the dependent follow the update rather than only the last
+	// one found for the pull request
+	for sha in &["first_sha", "second_sha"] {
+		let mr = deserialize_merge_request(
+			&state.db.get(sha.as_bytes()).unwrap().unwrap(),
+		)
+		.unwrap();
+		assert_eq!(mr.dependencies.unwrap()[0].sha, "updated_dependency_sha");
+	}
+}
+
+#[tokio::test]
+async fn dependency_fetches_are_spaced_out() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let repo_name = "spaced_dependent";
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, NUMBER),
+		))
+		.times(1..)
+		.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, SHA);
+
+	// The chain is only walked up to the last dependency, which is not merged
+	// yet, thus all of the dependencies are fetched
+	let dependency_fetches = Arc::new(Mutex::new(vec![]));
+	let mut dependencies = vec![];
+	for (index, is_merged) in [true, true, false].iter().enumerate() {
+		let dependency_pr = GithubPullRequest {
+			merged: *is_merged,
+			..build_pull_request(
+				&owner,
+				&format!("spaced_dependency_{}", index),
+				NUMBER,
+				&format!("spaced_dependency_sha_{}", index),
+				"master",
+				"contributor_patches",
+				&github_api_url,
+				URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+			)
+		};
+		dependencies.push(MergeRequestDependency {
+			sha: dependency_pr.head.sha.clone(),
+			owner: owner.login.clone(),
+			repo: dependency_pr.base.repo.name.clone(),
+			number: NUMBER,
+			html_url: dependency_pr.html_url.clone(),
+			is_directly_referenced: index == 0,
+		});
+		let dependency_fetches = dependency_fetches.clone();
+		github_api.expect(
+			Expectation::matching(request::method_path(
+				"GET",
+				format!(
+					"/repos/{}/{}/pulls/{}",
+					&owner.login, &dependency_pr.base.repo.name, NUMBER
+				),
+			))
+			.times(1)
+			.respond_with(move || {
+				dependency_fetches.lock().unwrap().push(Instant::now());
+				json_encoded(&dependency_pr)
+			}),
+		);
+	}
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.dependency_fetch_interval = 200;
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		dependencies: Some(dependencies),
+		..build_merge_request(SHA, &owner.login, repo_name, NUMBER)
+	};
+	state
+		.db
+		.put(SHA.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, SHA)
+		.await
+		.unwrap();
+
+	let dependency_fetches = dependency_fetches.lock().unwrap();
+	assert_eq!(dependency_fetches.len(), 3);
+	for fetches in dependency_fetches.windows(2) {
+		assert!(fetches[1] - fetches[0] >= Duration::from_millis(200));
+	}
+}
+
+#[tokio::test]
+async fn merges_are_not_retried_during_the_cooldown() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "cooldown";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, NUMBER),
+		))
+		.times(2)
+		.respond_with(json_encoded(&pr)),
+	);
+	// The checks are only evaluated once the cooldown has elapsed
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, SHA
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubCheckRuns {
+			check_runs: vec![GithubCheckRun {
+				id: 1,
+				name: "does not matter".to_string(),
+				status: GithubCheckRunStatus::Unknown,
+				conclusion: None,
+				head_sha: SHA.to_string(),
+				html_url: None,
+			}],
+		})),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.merge_retry_cooldown = 60;
+	let state = build_state(config);
+
+	let put_merge_request = |last_attempt_at| {
+		let mr = MergeRequest {
+			html_url: pr.html_url.clone(),
+			attempts: 1,
+			last_attempt_at: Some(last_attempt_at),
+			..build_merge_request(SHA, &owner.login, repo_name, NUMBER)
+		};
+		state
+			.db
+			.put(SHA.as_bytes(), bincode::serialize(&mr).unwrap())
+			.unwrap();
+	};
+
+	put_merge_request(SystemTime::now() - Duration::from_secs(10));
+	process_commit_checks_and_statuses(&state, SHA)
+		.await
+		.unwrap();
+
+	put_merge_request(SystemTime::now() - Duration::from_secs(120));
+	process_commit_checks_and_statuses(&state, SHA)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn merges_are_held_once_the_requester_is_no_longer_a_member() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let former_member = "former_member";
+	let repo_name = "former_member_merge";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, NUMBER),
+		))
+		.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, SHA);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/orgs/{}/members/{}", &owner.login, former_member),
+		))
+		.respond_with(status_code(404)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, NUMBER
+				),
+			),
+			request::body(matches(&format!(
+				"{} is no longer allowed to merge",
+				former_member
+			))),
+		])
+		.respond_with(
+			status_code(201)
+				.append_header("Content-Type", "application/json")
+				.body("{}"),
+		),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!(
+				"/repos/{}/{}/pulls/{}/merge",
+				&owner.login, repo_name, NUMBER
+			),
+		))
+		.times(0),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.recheck_requester_membership = true;
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		requested_by: former_member.to_string(),
+		..build_merge_request(SHA, &owner.login, repo_name, NUMBER)
+	};
+	state
+		.db
+		.put(SHA.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, SHA)
+		.await
+		.unwrap();
+
+	// The merge is held rather than cancelled
+	assert!(state.db.get(SHA.as_bytes()).unwrap().is_some());
+}
+
+#[tokio::test]
+async fn merges_allowed_once_are_not_held() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let contributor = "contributor";
+	let repo_name = "allowed_merge";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		NUMBER,
+		SHA,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, NUMBER),
+		))
+		.times(1..)
+		.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, SHA);
+	// The grant is enough, thus the membership is not checked
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/orgs/{}/members/{}", &owner.login, contributor),
+		))
+		.times(0)
+		.respond_with(status_code(404)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!(
+				"/repos/{}/{}/pulls/{}/merge",
+				&owner.login, repo_name, NUMBER
+			),
+		))
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.recheck_requester_membership = true;
+	let state = build_state(config);
+
+	// The allowance is consumed by the merge command
+	allow_merge_once(&state, &owner.login, repo_name, NUMBER, contributor)
+		.unwrap();
+	assert!(consume_merge_allowance(
+		&state,
+		&owner.login,
+		repo_name,
+		NUMBER,
+		contributor
+	)
+	.unwrap());
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		requested_by: contributor.to_string(),
+		..build_merge_request(SHA, &owner.login, repo_name, NUMBER)
+	};
+	state
+		.db
+		.put(SHA.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, SHA)
+		.await
+		.unwrap();
+
+	assert!(state.db.get(SHA.as_bytes()).unwrap().is_none());
+	// The grant ends along with the merge request
+	assert!(!is_merge_granted(
+		&state,
+		&owner.login,
+		repo_name,
+		NUMBER,
+		contributor
+	)
+	.unwrap());
+}
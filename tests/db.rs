@@ -0,0 +1,335 @@
+use parity_processbot::{
+	constants::{
+		DATABASE_VERSION, LEGACY_METADATA_KEY_PREFIX, MERGE_PRIORITY_HIGH,
+		MERGE_PRIORITY_NORMAL, MERGE_REQUEST_SCHEMA_VERSION,
+	},
+	db::{
+		export_database, import_database, merge_requests_cf, metadata_cf,
+		migrate_legacy_metadata, migrate_merge_requests, open_database,
+	},
+	merge_request::{
+		deserialize_merge_request, list_frozen_repositories,
+		set_repository_frozen, MergeRequest, MergeRequestDependency,
+	},
+};
+use rocksdb::{IteratorMode, DB};
+
+mod helpers;
+
+use helpers::setup::*;
+
+#[test]
+fn metadata_is_not_iterated_with_merge_requests() {
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		"owner",
+		"http://github.api",
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = build_merge_request("sha", "owner", "db", 1);
+	state
+		.db
+		.put_cf(
+			merge_requests_cf(&state.db),
+			mr.sha.as_bytes(),
+			bincode::serialize(&mr).unwrap(),
+		)
+		.unwrap();
+	set_repository_frozen(&state, "owner/db", true).unwrap();
+
+	let keys = state
+		.db
+		.iterator_cf(merge_requests_cf(&state.db), IteratorMode::Start)
+		.map(|(key, value)| {
+			bincode::deserialize::<MergeRequest>(&value).unwrap();
+			key
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(keys, vec![Box::<[u8]>::from(mr.sha.as_bytes())]);
+
+	assert!(state
+		.db
+		.iterator_cf(metadata_cf(&state.db), IteratorMode::Start)
+		.next()
+		.is_some());
+	assert!(list_frozen_repositories(&state)
+		.unwrap()
+		.contains("owner/db"));
+}
+
+#[test]
+fn legacy_metadata_is_migrated_to_its_column_family() {
+	let db_dir = tempfile::tempdir().unwrap();
+
+	let mr = build_merge_request("sha", "owner", "db", 1);
+	{
+		let db = DB::open_default(db_dir.path()).unwrap();
+		db.put(mr.sha.as_bytes(), bincode::serialize(&mr).unwrap())
+			.unwrap();
+		db.put(
+			format!("{}merged/sha", LEGACY_METADATA_KEY_PREFIX).as_bytes(),
+			bincode::serialize(&0u64).unwrap(),
+		)
+		.unwrap();
+	}
+
+	let db = open_database(db_dir.path()).unwrap();
+	assert_eq!(migrate_legacy_metadata(&db).unwrap(), 1);
+
+	assert!(db
+		.get_cf(merge_requests_cf(&db), mr.sha.as_bytes())
+		.unwrap()
+		.is_some());
+	assert!(db
+		.iterator_cf(merge_requests_cf(&db), IteratorMode::Start)
+		.all(
+			|(key, _)| !key.starts_with(LEGACY_METADATA_KEY_PREFIX.as_bytes())
+		));
+	assert!(db
+		.get_cf(metadata_cf(&db), "merged/sha".as_bytes())
+		.unwrap()
+		.is_some());
+}
+
+// The shape of the merge requests of database version v3.0
+#[derive(serde::Serialize)]
+struct MergeRequestV0 {
+	sha: String,
+	was_updated: bool,
+	owner: String,
+	repo: String,
+	number: i64,
+	html_url: String,
+	requested_by: String,
+	dependencies: Option<Vec<MergeRequestDependency>>,
+}
+
+#[test]
+fn old_merge_requests_are_migrated_in_place() {
+	let db_dir = tempfile::tempdir().unwrap();
+	let db = open_database(db_dir.path()).unwrap();
+
+	let old_mr = MergeRequestV0 {
+		sha: "old_sha".to_string(),
+		was_updated: true,
+		owner: "owner".to_string(),
+		repo: "db".to_string(),
+		number: 2,
+		html_url: "https://github.com/owner/db/pull/2".to_string(),
+		requested_by: "requester".to_string(),
+		dependencies: Some(vec![MergeRequestDependency {
+			sha: "dependency_sha".to_string(),
+			owner: "owner".to_string(),
+			repo: "dependency".to_string(),
+			number: 3,
+			html_url: "https://github.com/owner/dependency/pull/3".to_string(),
+			is_directly_referenced: true,
+		}]),
+	};
+	db.put_cf(
+		merge_requests_cf(&db),
+		old_mr.sha.as_bytes(),
+		bincode::serialize(&old_mr).unwrap(),
+	)
+	.unwrap();
+	// Versions v3.1 to v3.3 appended their fields to the shape of v3.0
+	let not_before = std::time::SystemTime::UNIX_EPOCH;
+	let v3_3_mr = MergeRequestV0 {
+		sha: "v3_3_sha".to_string(),
+		was_updated: false,
+		owner: "owner".to_string(),
+		repo: "db".to_string(),
+		number: 4,
+		html_url: "https://github.com/owner/db/pull/4".to_string(),
+		requested_by: "requester".to_string(),
+		dependencies: None,
+	};
+	db.put_cf(
+		merge_requests_cf(&db),
+		v3_3_mr.sha.as_bytes(),
+		bincode::serialize(&(
+			&v3_3_mr,
+			2u32,
+			MERGE_PRIORITY_HIGH,
+			Some(not_before),
+		))
+		.unwrap(),
+	)
+	.unwrap();
+	let mr = build_merge_request("sha", "owner", "db", 1);
+	db.put_cf(
+		merge_requests_cf(&db),
+		mr.sha.as_bytes(),
+		bincode::serialize(&mr).unwrap(),
+	)
+	.unwrap();
+
+	let migrated_mr = deserialize_merge_request(
+		&db.get_cf(merge_requests_cf(&db), old_mr.sha.as_bytes())
+			.unwrap()
+			.unwrap(),
+	)
+	.unwrap();
+	assert_eq!(migrated_mr.requested_by, "requester");
+	assert_eq!(migrated_mr.priority, MERGE_PRIORITY_NORMAL);
+	assert_eq!(migrated_mr.schema_version, MERGE_REQUEST_SCHEMA_VERSION);
+
+	// Only the old records are rewritten
+	assert_eq!(migrate_merge_requests(&db).unwrap(), 2);
+	assert_eq!(migrate_merge_requests(&db).unwrap(), 0);
+
+	let stored_mr = bincode::deserialize::<MergeRequest>(
+		&db.get_cf(merge_requests_cf(&db), old_mr.sha.as_bytes())
+			.unwrap()
+			.unwrap(),
+	)
+	.unwrap();
+	assert_eq!(stored_mr.schema_version, MERGE_REQUEST_SCHEMA_VERSION);
+	assert!(stored_mr.was_updated);
+	assert_eq!(stored_mr.number, 2);
+	assert_eq!(stored_mr.attempts, 0);
+	assert!(stored_mr.queued_at.is_none());
+	assert_eq!(
+		stored_mr
+			.dependencies
+			.unwrap()
+			.iter()
+			.map(|dependency| dependency.sha.as_str())
+			.collect::<Vec<_>>(),
+		vec!["dependency_sha"]
+	);
+
+	let stored_mr = bincode::deserialize::<MergeRequest>(
+		&db.get_cf(merge_requests_cf(&db), v3_3_mr.sha.as_bytes())
+			.unwrap()
+			.unwrap(),
+	)
+	.unwrap();
+	assert_eq!(stored_mr.number, 4);
+	assert_eq!(stored_mr.attempts, 2);
+	assert_eq!(stored_mr.priority, MERGE_PRIORITY_HIGH);
+	assert_eq!(stored_mr.not_before, Some(not_before));
+	assert!(stored_mr.queued_at.is_none());
+}
+
+#[test]
+fn database_is_restored_from_its_export() {
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		"owner",
+		"http://github.api",
+		db_dir.path(),
+		db_dir.path(),
+	));
+	for (sha, number) in &[("sha_a", 1), ("sha_b", 2)] {
+		let mr = MergeRequest {
+			number: *number,
+			queued_at: Some(std::time::SystemTime::now()),
+			seq: Some(*number as u64),
+			..build_merge_request(sha, "owner", "db", 1)
+		};
+		state
+			.db
+			.put_cf(
+				merge_requests_cf(&state.db),
+				mr.sha.as_bytes(),
+				bincode::serialize(&mr).unwrap(),
+			)
+			.unwrap();
+	}
+	set_repository_frozen(&state, "owner/db", true).unwrap();
+
+	let export_dir = tempfile::tempdir().unwrap();
+	let export_path = export_dir.path().join("export.json");
+	assert_eq!(
+		export_database(&state.db, &export_path, DATABASE_VERSION).unwrap(),
+		2
+	);
+
+	// Corrupt records are skipped on import
+	let mut export = serde_json::from_slice::<serde_json::Value>(
+		&std::fs::read(&export_path).unwrap(),
+	)
+	.unwrap();
+	export["merge_requests"]
+		.as_array_mut()
+		.unwrap()
+		.push(serde_json::json!({ "sha": 1 }));
+	export["metadata"]
+		.as_array_mut()
+		.unwrap()
+		.push(serde_json::json!({ "key": "corrupt", "value": "%" }));
+	std::fs::write(&export_path, serde_json::to_vec(&export).unwrap()).unwrap();
+
+	let imported_db_dir = tempfile::tempdir().unwrap();
+	let imported_state = build_state(build_config(
+		"owner",
+		"http://github.api",
+		imported_db_dir.path(),
+		imported_db_dir.path(),
+	));
+	let report = import_database(&imported_state.db, &export_path).unwrap();
+	assert_eq!(report.merge_requests_count, 2);
+	assert_eq!(report.metadata_records_count, 1);
+	assert_eq!(report.skipped_records.len(), 2);
+
+	let records = |db: &DB| {
+		vec![merge_requests_cf(db), metadata_cf(db)]
+			.into_iter()
+			.flat_map(|cf| db.iterator_cf(cf, IteratorMode::Start))
+			.collect::<Vec<_>>()
+	};
+	assert_eq!(records(&imported_state.db), records(&state.db));
+	assert!(list_frozen_repositories(&imported_state)
+		.unwrap()
+		.contains("owner/db"));
+}
+
+#[test]
+fn database_of_the_previous_version_is_migrated_through_its_export() {
+	let db_dir = tempfile::tempdir().unwrap();
+
+	let mr = build_merge_request("sha", "owner", "db", 1);
+	{
+		let db = DB::open_default(db_dir.path()).unwrap();
+		db.put(mr.sha.as_bytes(), bincode::serialize(&mr).unwrap())
+			.unwrap();
+		db.put(
+			format!("{}merged/sha", LEGACY_METADATA_KEY_PREFIX).as_bytes(),
+			bincode::serialize(&0u64).unwrap(),
+		)
+		.unwrap();
+	}
+
+	let db = open_database(db_dir.path()).unwrap();
+	let export_dir = tempfile::tempdir().unwrap();
+	let export_path = export_dir.path().join("export.json");
+	assert_eq!(export_database(&db, &export_path, "v3.4").unwrap(), 1);
+
+	let imported_db_dir = tempfile::tempdir().unwrap();
+	let imported_db = open_database(imported_db_dir.path()).unwrap();
+	let report = import_database(&imported_db, &export_path).unwrap();
+	assert_eq!(report.merge_requests_count, 1);
+	assert_eq!(report.metadata_records_count, 1);
+	assert!(report.skipped_records.is_empty());
+
+	assert!(imported_db
+		.get_cf(merge_requests_cf(&imported_db), mr.sha.as_bytes())
+		.unwrap()
+		.is_some());
+	assert!(imported_db
+		.get_cf(metadata_cf(&imported_db), "merged/sha".as_bytes())
+		.unwrap()
+		.is_some());
+
+	// Snapshots of unknown versions are still refused
+	let mut export = serde_json::from_slice::<serde_json::Value>(
+		&std::fs::read(&export_path).unwrap(),
+	)
+	.unwrap();
+	export["database_version"] = serde_json::json!("v1");
+	std::fs::write(&export_path, serde_json::to_vec(&export).unwrap()).unwrap();
+	assert!(import_database(&imported_db, &export_path).is_err());
+}
@@ -0,0 +1,287 @@
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use parity_processbot::{
+	error::Error,
+	git_ops::{
+		acquire_branch_update_permit, build_commit_args, commit_all_changes,
+		ensure_repository_clone, merge_branch_into_head, GpgSigningSetup,
+		RepositoryCloneOutcome,
+	},
+};
+
+mod helpers;
+
+use helpers::{cmd::*, initialize_repository, setup::*};
+
+#[tokio::test]
+async fn commits_use_the_configured_identity() {
+	let repo_dir = tempfile::tempdir().unwrap();
+	initialize_repository(repo_dir.path(), "master");
+	fs::write(repo_dir.path().join("README"), "updated").unwrap();
+
+	let mut config = build_config(
+		"owner",
+		"http://does-not-matter",
+		repo_dir.path(),
+		repo_dir.path(),
+	);
+	config.git_commit_author_name = "custom-bot".to_string();
+	config.git_commit_author_email = "custom-bot@example.com".to_string();
+
+	commit_all_changes(&config, repo_dir.path(), "update README", None)
+		.await
+		.unwrap();
+
+	// The configured identity should override the repository's own
+	// configuration for both the author and the committer
+	assert_eq!(
+		get_cmd_output(
+			"git",
+			&["log", "-1", "--format=%an <%ae>; %cn <%ce>"],
+			Some(repo_dir.path()),
+		),
+		"custom-bot <custom-bot@example.com>; custom-bot <custom-bot@example.com>"
+	);
+}
+
+#[test]
+fn commits_are_signed_when_a_key_is_configured() {
+	let config = build_config(
+		"owner",
+		"http://does-not-matter",
+		&PathBuf::from("does-not-matter"),
+		&PathBuf::from("does-not-matter"),
+	);
+
+	let args = build_commit_args(&config, "update lockfile", None);
+	assert!(!args.iter().any(|arg| arg.starts_with("-S")));
+
+	let args = build_commit_args(
+		&config,
+		"update lockfile",
+		Some(&GpgSigningSetup {
+			program: PathBuf::from("/keyring/gpg"),
+			key_id: "KEY".to_string(),
+		}),
+	);
+	assert!(args.contains(&"gpg.program=/keyring/gpg".to_string()));
+	assert!(args.contains(&"-SKEY".to_string()));
+}
+
+#[tokio::test]
+async fn branch_updates_of_the_same_repository_are_serialized() {
+	let db_dir = tempfile::tempdir().unwrap();
+	let config = build_config(
+		"owner",
+		"http://does-not-matter",
+		db_dir.path(),
+		db_dir.path(),
+	);
+	let wait_time = Duration::from_millis(100);
+
+	let first_update =
+		acquire_branch_update_permit(&config, "owner", "serialized").await;
+
+	// A second update of the same repository has to wait for the first one
+	let second_update =
+		acquire_branch_update_permit(&config, "owner", "serialized");
+	tokio::pin!(second_update);
+	assert!(tokio::time::timeout(wait_time, &mut second_update)
+		.await
+		.is_err());
+
+	// Updates of other repositories are not held back
+	tokio::time::timeout(
+		wait_time,
+		acquire_branch_update_permit(&config, "owner", "not_serialized"),
+	)
+	.await
+	.unwrap();
+
+	drop(first_update);
+	tokio::time::timeout(wait_time, second_update)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn repository_clones_are_reused() {
+	let source_dir = tempfile::tempdir().unwrap();
+	initialize_repository(source_dir.path(), "master");
+	let source = source_dir.path().display().to_string();
+	let repos_dir = tempfile::tempdir().unwrap();
+	let clone_dir = repos_dir.path().join("repo");
+
+	assert_eq!(
+		ensure_repository_clone(&clone_dir, &source, None)
+			.await
+			.unwrap(),
+		RepositoryCloneOutcome::Cloned
+	);
+	// The repository is not cloned again for the next update
+	assert_eq!(
+		ensure_repository_clone(&clone_dir, &source, None)
+			.await
+			.unwrap(),
+		RepositoryCloneOutcome::Reused
+	);
+
+	// A clone which is no longer a valid repository is replaced
+	fs::remove_dir_all(clone_dir.join(".git").join("objects")).unwrap();
+	assert_eq!(
+		ensure_repository_clone(&clone_dir, &source, None)
+			.await
+			.unwrap(),
+		RepositoryCloneOutcome::Cloned
+	);
+	assert_eq!(
+		get_cmd_output("git", &["log", "-1", "--format=%s"], Some(&clone_dir)),
+		"initial commit"
+	);
+}
+
+// Creates a "release" branch and a "contributor_patches" branch, both forked
+// from master, each with a commit which writes the given contents to the given
+// file. "contributor_patches" is checked out afterwards.
+fn setup_diverged_branches(
+	repo_dir: &Path,
+	release_change: (&str, &str),
+	contributor_change: (&str, &str),
+) {
+	initialize_repository(repo_dir, "master");
+	for (branch, (file, contents)) in &[
+		("release", release_change),
+		("contributor_patches", contributor_change),
+	] {
+		exec("git", &["checkout", "master"], Some(repo_dir), None);
+		exec("git", &["checkout", "-b", branch], Some(repo_dir), None);
+		fs::write(repo_dir.join(file), contents).unwrap();
+		exec("git", &["add", "."], Some(repo_dir), None);
+		exec(
+			"git",
+			&["commit", "-m", &format!("change {}", file)],
+			Some(repo_dir),
+			None,
+		);
+	}
+}
+
+#[tokio::test]
+async fn branches_are_merged_onto_other_branches() {
+	let repo_dir = tempfile::tempdir().unwrap();
+	setup_diverged_branches(
+		repo_dir.path(),
+		("RELEASE", "release notes"),
+		("README", "contributor changes"),
+	);
+	let config = build_config(
+		"owner",
+		"http://does-not-matter",
+		repo_dir.path(),
+		repo_dir.path(),
+	);
+
+	merge_branch_into_head(&config, repo_dir.path(), "release", None)
+		.await
+		.unwrap();
+
+	assert_eq!(
+		get_cmd_output(
+			"git",
+			&["rev-parse", "--abbrev-ref", "HEAD"],
+			Some(repo_dir.path()),
+		),
+		"contributor_patches"
+	);
+	// The merge commit has both branches as its parents
+	assert_eq!(
+		get_cmd_output(
+			"git",
+			&["log", "-1", "--format=%P"],
+			Some(repo_dir.path()),
+		)
+		.split_whitespace()
+		.count(),
+		2
+	);
+	assert_eq!(
+		fs::read_to_string(repo_dir.path().join("RELEASE")).unwrap(),
+		"release notes"
+	);
+}
+
+#[tokio::test]
+async fn conflicting_merges_are_aborted() {
+	let repo_dir = tempfile::tempdir().unwrap();
+	setup_diverged_branches(
+		repo_dir.path(),
+		("README", "release changes"),
+		("README", "contributor changes"),
+	);
+	let config = build_config(
+		"owner",
+		"http://does-not-matter",
+		repo_dir.path(),
+		repo_dir.path(),
+	);
+	let head_before_merge =
+		get_cmd_output("git", &["rev-parse", "HEAD"], Some(repo_dir.path()));
+
+	let err = merge_branch_into_head(&config, repo_dir.path(), "release", None)
+		.await
+		.unwrap_err();
+	assert!(matches!(
+		err,
+		Error::BranchConflict { ref branch } if branch == "release"
+	));
+
+	// The branch is left as it was before the merge
+	assert!(!get_cmd_success(
+		"git",
+		&["rev-parse", "-q", "--verify", "MERGE_HEAD"],
+		Some(repo_dir.path()),
+	));
+	assert_eq!(
+		get_cmd_output("git", &["rev-parse", "HEAD"], Some(repo_dir.path())),
+		head_before_merge
+	);
+	assert_eq!(
+		fs::read_to_string(repo_dir.path().join("README")).unwrap(),
+		"contributor changes"
+	);
+}
+
+#[tokio::test]
+async fn merges_are_not_created_unsigned_when_signing_fails() {
+	let repo_dir = tempfile::tempdir().unwrap();
+	setup_diverged_branches(
+		repo_dir.path(),
+		("RELEASE", "release notes"),
+		("README", "contributor changes"),
+	);
+	let mut config = build_config(
+		"owner",
+		"http://does-not-matter",
+		repo_dir.path(),
+		repo_dir.path(),
+	);
+	config.gpg_signing_key = Some("not a key".to_string());
+	let head_before_merge =
+		get_cmd_output("git", &["rev-parse", "HEAD"], Some(repo_dir.path()));
+
+	let err = merge_branch_into_head(&config, repo_dir.path(), "release", None)
+		.await
+		.unwrap_err();
+	assert!(matches!(
+		err,
+		Error::Message { ref msg } if msg.contains("signing of the merge commit")
+	));
+	assert_eq!(
+		get_cmd_output("git", &["rev-parse", "HEAD"], Some(repo_dir.path())),
+		head_before_merge
+	);
+}
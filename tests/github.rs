@@ -0,0 +1,422 @@
+use std::time::Duration;
+
+use httptest::{
+	all_of, cycle, matchers::*, responders::*, Expectation, Server,
+};
+use parity_processbot::{error::Error, github::*};
+
+mod helpers;
+
+use helpers::{constants::*, setup::*};
+
+#[tokio::test]
+async fn client_uses_the_api_url_of_its_installation() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "repo";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/pulls/{}",
+				&owner.login, repo_name, pr.number
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+
+	// Nothing is listening on the default URL, thus the request would fail if it
+	// was used
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		"http://127.0.0.1:1",
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config
+		.github_api_url_overrides
+		.insert(owner.login.clone(), github_api_url.clone());
+
+	let gh_client = GithubClient::new(&config);
+	let fetched_pr = gh_client
+		.pull_request(&owner.login, repo_name, pr.number)
+		.await
+		.unwrap();
+	assert_eq!(fetched_pr.html_url, pr.html_url);
+}
+
+#[tokio::test]
+async fn rejected_installation_tokens_are_refreshed() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "revoked_token";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	// The first request is rejected as if the cached token had been revoked
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/pulls/{}",
+				&owner.login, repo_name, pr.number
+			),
+		))
+		.times(2)
+		.respond_with(cycle![
+			status_code(401).body(r#"{"message":"Bad credentials"}"#),
+			json_encoded(&pr),
+		]),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+
+	let gh_client = GithubClient::new(&config);
+	let fetched_pr = gh_client
+		.pull_request(&owner.login, repo_name, pr.number)
+		.await
+		.unwrap();
+	assert_eq!(fetched_pr.html_url, pr.html_url);
+}
+
+#[tokio::test]
+async fn installations_are_found_past_the_first_page() {
+	let installation_login = "paginated_installation";
+	let github_api = Server::run();
+	let github_api_url = {
+		let url = github_api.url("").to_string();
+		url[0..url.len() - 1].to_string()
+	};
+
+	let other_installations = (0..100)
+		.map(|id| GithubInstallation {
+			id,
+			account: GithubUser {
+				login: format!("other_{}", id),
+				type_field: GithubUserType::Bot,
+			},
+		})
+		.collect::<Vec<_>>();
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("GET", "/app/installations"),
+			request::query(url_decoded(contains(("page", "1")))),
+		])
+		.times(1)
+		.respond_with(json_encoded(other_installations)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("GET", "/app/installations"),
+			request::query(url_decoded(contains(("page", "2")))),
+		])
+		.times(1)
+		.respond_with(json_encoded(vec![GithubInstallation {
+			id: 1000,
+			account: GithubUser {
+				login: installation_login.to_string(),
+				type_field: GithubUserType::Bot,
+			},
+		}])),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let config = build_config(
+		installation_login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+
+	let gh_client = GithubClient::new(&config);
+	assert_eq!(gh_client.installation_id().await.unwrap(), 1000);
+}
+
+#[tokio::test]
+async fn identical_comments_are_only_posted_once() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "deduplicated_comments";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/1/comments",
+					&owner.login, repo_name
+				),
+			),
+			request::body(matches("Merge cancelled due to error")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/1/comments",
+					&owner.login, repo_name
+				),
+			),
+			request::body(matches("Something else")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	let gh_client = GithubClient::new(&config);
+	for comment in &[
+		"Merge cancelled due to error.",
+		"Merge cancelled due to error.",
+		"Something else",
+	] {
+		gh_client
+			.create_issue_comment_once(&owner.login, repo_name, 1, comment)
+			.await
+			.unwrap();
+	}
+}
+
+#[tokio::test]
+async fn requests_use_the_configured_timeout() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let build_pr = |repo_name: &str| {
+		build_pull_request(
+			&owner,
+			repo_name,
+			1,
+			"sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+	let slow_repo = "slow_response";
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/1", &owner.login, slow_repo),
+		))
+		.times(1)
+		.respond_with(delay_and_then(
+			Duration::from_secs(2),
+			json_encoded(build_pr(slow_repo)),
+		)),
+	);
+	// Timed out requests are retried, thus this one is expected more than once
+	let unresponsive_repo = "unresponsive";
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/1", &owner.login, unresponsive_repo),
+		))
+		.times(1..)
+		.respond_with(delay_and_then(
+			Duration::from_secs(5),
+			json_encoded(build_pr(unresponsive_repo)),
+		)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.github_request_timeout_secs = 4;
+	let gh_client = GithubClient::new(&config);
+
+	gh_client
+		.pull_request(&owner.login, slow_repo, 1)
+		.await
+		.unwrap();
+
+	config.github_request_timeout_secs = 1;
+	let gh_client = GithubClient::new(&config);
+	let err = gh_client
+		.pull_request(&owner.login, unresponsive_repo, 1)
+		.await
+		.unwrap_err();
+	assert!(matches!(err, Error::Http { source } if source.is_timeout()));
+}
+
+#[tokio::test]
+async fn client_is_built_with_the_configured_connection_pool() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "pooled";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, 1),
+		))
+		.times(2)
+		.respond_with(json_encoded(&pr)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.github_pool_max_idle_per_host = 4;
+	let gh_client = GithubClient::new(&config);
+	assert_eq!(
+		gh_client.options(),
+		&GithubClientOptions::from_config(&config)
+	);
+	assert_eq!(gh_client.options().pool_max_idle_per_host, 4);
+
+	// The connection is reused across consecutive requests, e.g. the ones of a
+	// dependency chain
+	for _ in 0..2 {
+		gh_client
+			.pull_request(&owner.login, repo_name, 1)
+			.await
+			.unwrap();
+	}
+}
+
+#[test]
+fn pull_requests_of_deleted_forks_are_deserialized() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let mut payload = serde_json::to_value(build_pull_request(
+		&owner,
+		"deleted_fork",
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	))
+	.unwrap();
+	payload["head"]["repo"] = serde_json::Value::Null;
+
+	let pr = serde_json::from_value::<GithubPullRequest>(payload).unwrap();
+	assert_eq!(pr.head.repo, None);
+	assert!(matches!(
+		pr.head_repository(),
+		Err(Error::HeadRepositoryDeleted { .. })
+	));
+}
+
+#[test]
+fn pull_request_details_are_parsed_from_custom_hosts() {
+	for html_url in [
+		"https://github.com/owner/repo/pull/1",
+		"https://ghe.example.com/owner/repo/pull/1",
+		"https://example.com/github/owner/repo/pull/1",
+	] {
+		let issue = GithubIssue {
+			number: 1,
+			html_url: html_url.to_string(),
+			pull_request: None,
+		};
+		let details = issue.get_pull_request_details().unwrap();
+		assert_eq!(
+			(
+				details.owner.as_str(),
+				details.repo.as_str(),
+				details.number
+			),
+			("owner", "repo", 1),
+			"{}",
+			html_url
+		);
+	}
+}
+
+#[test]
+fn pull_request_details_prefer_the_payload_repository() {
+	let repository = GithubIssueRepository {
+		owner: GithubUser {
+			login: "owner".to_string(),
+			type_field: GithubUserType::User,
+		},
+		name: "repo".to_string(),
+	};
+	// The URL of a host which does not follow Github's layout
+	let issue = GithubIssue {
+		number: 1,
+		html_url: "https://example.com/pulls/1".to_string(),
+		pull_request: None,
+	};
+	assert!(issue.get_pull_request_details().is_none());
+	let details = issue.get_pull_request_details_in(&repository);
+	assert_eq!(
+		(
+			details.owner.as_str(),
+			details.repo.as_str(),
+			details.number
+		),
+		("owner", "repo", 1)
+	);
+}
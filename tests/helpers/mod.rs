@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 use std::{
 	fs::{self, remove_dir_all, remove_file, File},
 	io::Read,
@@ -0,0 +1,42 @@
+use parity_processbot::logging::{json::format_record, with_delivery_id};
+
+#[test]
+fn json_logs_are_valid_json_lines() {
+	let line = format_record(
+		&log::Record::builder()
+			.args(format_args!("Merging \"{}\"\nnow", "owner/repo#1"))
+			.level(log::Level::Warn)
+			.target("parity_processbot::merge_request")
+			.build(),
+	);
+
+	assert!(!line.contains('\n'));
+	let log: serde_json::Value = serde_json::from_str(&line).unwrap();
+	assert_eq!(log["level"], "WARN");
+	assert_eq!(log["target"], "parity_processbot::merge_request");
+	assert_eq!(log["message"], "Merging \"owner/repo#1\"\nnow");
+	assert!(log["timestamp"].is_string());
+}
+
+#[tokio::test]
+async fn logs_of_a_delivery_include_its_id() {
+	let build_line = || {
+		format_record(
+			&log::Record::builder()
+				.args(format_args!("Processing the payload"))
+				.level(log::Level::Info)
+				.build(),
+		)
+	};
+
+	let line = with_delivery_id(Some("delivery-id".to_string()), async {
+		tokio::task::yield_now().await;
+		build_line()
+	})
+	.await;
+	let log: serde_json::Value = serde_json::from_str(&line).unwrap();
+	assert_eq!(log["delivery_id"], "delivery-id");
+
+	let log: serde_json::Value = serde_json::from_str(&build_line()).unwrap();
+	assert!(log.get("delivery_id").is_none());
+}
@@ -1,11 +1,12 @@
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, sync::Arc, time::Duration};
 
 use insta::assert_snapshot;
 use parity_processbot::{
-	self, bot::handle_github_payload, config::MainConfig, core::AppState,
-	github::*, types::PlaceholderDeserializationItem,
+	self, bot::handle_github_payload, config::MainConfig,
+	constants::POLL_INTERVAL, core::AppState, db::open_database, github::*,
+	poll_heartbeat::PollHeartbeat, types::PlaceholderDeserializationItem,
+	work_queue::WorkQueue,
 };
-use rocksdb::DB;
 
 mod helpers;
 
@@ -68,6 +69,7 @@ async fn simple_merge_succeeds() {
 		id: I64_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
 		body: "bot merge".to_string(),
 		user: owner.clone(),
+		created_at: None,
 	};
 
 	let mut next_pr_number: i64 = 0;
@@ -99,12 +101,23 @@ async fn simple_merge_succeeds() {
 		gitlab_url: "".into(),
 		gitlab_access_token: "".into(),
 		dependency_update_configuration: HashMap::new(),
+		// The settings which were introduced afterwards keep their defaults
+		..build_config(
+			&owner.login,
+			github_api_url,
+			db_dir.path(),
+			git_daemon_dir.path(),
+		)
 	};
 	let gh_client = GithubClient::new(&config);
-	let db = DB::open_default(&config.db_path).unwrap();
+	let db = open_database(&config.db_path).unwrap();
 	let state = AppState {
 		db,
 		gh_client,
+		work_queue: Arc::new(WorkQueue::new(config.work_queue_capacity)),
+		poll_heartbeat: Arc::new(PollHeartbeat::new(Duration::from_secs(
+			POLL_INTERVAL,
+		))),
 		config,
 	};
 
@@ -0,0 +1,2119 @@
+use std::time::Duration;
+
+use httptest::{all_of, cycle, matchers::*, responders::*, Expectation};
+use parity_processbot::{
+	bot::{handle_github_payload, process_next_queued_commit},
+	core::{process_commit_checks_and_statuses, PullRequestMergeCancelOutcome},
+	error::{handle_error, Error, PullRequestDetails},
+	github::*,
+	merge_request::{
+		cleanup_merge_request, handle_merged_pull_request, is_ready_to_merge,
+		list_merge_requests, merge_pull_request, queue_merge_request,
+		MergeReadiness, MergeRequest, MergeRequestCleanupReason,
+		MergeRequestDependency, MergeRequestQueuedMessage,
+	},
+	messages::{Message, MessageTemplates},
+};
+
+mod helpers;
+
+use helpers::{constants::*, setup::*};
+
+#[tokio::test]
+async fn merge_request_is_given_up_after_max_attempts() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "repo";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number);
+
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, head_sha);
+
+	// The merge fails for reasons which are not expected to be solved on their
+	// own, thus each failure counts as an attempt
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!("{}/merge", pr_api_path),
+		))
+		.times(2)
+		.respond_with(status_code(500).body("{}")),
+	);
+	let comments_path = format!(
+		"/repos/{}/{}/issues/{}/comments",
+		&owner.login, repo_name, number
+	);
+	for (msg, times) in &[
+		("Attempt 1 of 2 .* failed", 1),
+		("Attempt 2 of 2 .* failed", 1),
+		("Gave up after 2 attempts", 1),
+	] {
+		github_api.expect(
+			Expectation::matching(all_of![
+				request::method_path("POST", comments_path.clone()),
+				request::body(matches(*msg)),
+			])
+			.times(*times)
+			.respond_with(status_code(201).body("{}")),
+		);
+	}
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.max_merge_attempts = 2;
+	config.message_templates.set(
+		Message::GaveUp,
+		"Gave up after {attempts} attempts.".to_string(),
+	);
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	// The first failure is retried later
+	process_commit_checks_and_statuses(&state, head_sha)
+		.await
+		.unwrap();
+	let attempts = list_merge_requests(&state)
+		.into_iter()
+		.map(|mr| mr.attempts)
+		.collect::<Vec<_>>();
+	assert_eq!(attempts, vec![1]);
+
+	process_commit_checks_and_statuses(&state, head_sha)
+		.await
+		.unwrap();
+	assert!(state.db.get(head_sha.as_bytes()).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn pending_checks_do_not_count_as_merge_attempts() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "pending";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number);
+
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, head_sha);
+	// Github refuses the merge until a required status is reported
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!("{}/merge", pr_api_path),
+		))
+		.times(3)
+		.respond_with(
+			status_code(405)
+				.append_header("Content-Type", "application/json")
+				.body(
+					serde_json::json!({
+						"message": "Required status check \"build\" is expected."
+					})
+					.to_string(),
+				),
+		),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.max_merge_attempts = 2;
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	for _ in 0..3 {
+		process_commit_checks_and_statuses(&state, head_sha)
+			.await
+			.unwrap();
+	}
+	let attempts = list_merge_requests(&state)
+		.into_iter()
+		.map(|mr| mr.attempts)
+		.collect::<Vec<_>>();
+	assert_eq!(attempts, vec![0]);
+}
+
+#[tokio::test]
+async fn merges_in_progress_are_registered_again_when_the_queue_is_full() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.max_queue_size = 1;
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER.to_string(),
+		..build_merge_request("other_sha", &owner.login, "other", 1)
+	};
+	state
+		.db
+		.put(mr.sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	// A companion's merge request is registered again for the SHA pushed by
+	// processbot after its previous one was cleaned up, which should not be
+	// prevented by the full queue
+	queue_merge_request(
+		&state,
+		&MergeRequest {
+			sha: "updated_sha".to_string(),
+			repo: "companion".to_string(),
+			..mr
+		},
+		&MergeRequestQueuedMessage::None,
+	)
+	.await
+	.unwrap();
+
+	assert!(state.db.get("updated_sha".as_bytes()).unwrap().is_some());
+}
+
+#[tokio::test]
+async fn merged_pull_request_is_handled_once() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let build_merged_pr = |repo_name: &str| GithubPullRequest {
+		merged: true,
+		..build_pull_request(
+			&owner,
+			repo_name,
+			1,
+			"sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			&format!("https://github.com/{}/{}", &owner.login, repo_name),
+		)
+	};
+
+	let companion = build_merged_pr("merged_companion");
+	let pr = GithubPullRequest {
+		body: Some(format!("companion: {}", companion.html_url)),
+		..build_merged_pr("merged_parent")
+	};
+
+	// The companion is fetched when resolving the dependents and then again when
+	// updating them, which should only happen for the first merged event
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/pulls/{}",
+				&owner.login, &companion.base.repo.name, companion.number
+			),
+		))
+		.times(2)
+		.respond_with(json_encoded(&companion)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	for _ in 0..2 {
+		assert!(handle_merged_pull_request(&state, &pr, &owner.login)
+			.await
+			.unwrap());
+	}
+}
+
+#[tokio::test]
+async fn merged_pull_request_is_handled_again_after_a_failure() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let build_merged_pr = |repo_name: &str| GithubPullRequest {
+		merged: true,
+		..build_pull_request(
+			&owner,
+			repo_name,
+			1,
+			"sha",
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			&format!("https://github.com/{}/{}", &owner.login, repo_name),
+		)
+	};
+
+	let companion = build_merged_pr("failed_companion");
+	let pr = GithubPullRequest {
+		body: Some(format!("companion: {}", companion.html_url)),
+		..build_merged_pr("failed_parent")
+	};
+
+	// Resolving the dependents fails for the first merged event, thus the merge
+	// is handled again for the second one, but not for the third one
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/pulls/{}",
+				&owner.login, &companion.base.repo.name, companion.number
+			),
+		))
+		.times(3)
+		.respond_with(cycle![
+			status_code(500),
+			json_encoded(&companion),
+			json_encoded(&companion),
+		]),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	for _ in 0..3 {
+		assert!(handle_merged_pull_request(&state, &pr, &owner.login)
+			.await
+			.unwrap());
+	}
+}
+
+#[tokio::test]
+async fn time_in_queue_is_tracked_from_registration() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = MergeRequest {
+		html_url: format!("{}/pull/1", URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER),
+		..build_merge_request("sha", &owner.login, "repo", 1)
+	};
+	queue_merge_request(&state, &mr, &MergeRequestQueuedMessage::None)
+		.await
+		.unwrap();
+
+	let read_time_in_queue = || {
+		list_merge_requests(&state)
+			.into_iter()
+			.find(|queued_mr| queued_mr.sha == mr.sha)
+			.and_then(|queued_mr| queued_mr.time_in_queue())
+			.unwrap()
+	};
+
+	tokio::time::sleep(Duration::from_millis(10)).await;
+	let first_time_in_queue = read_time_in_queue();
+	assert!(first_time_in_queue > Duration::from_secs(0));
+
+	tokio::time::sleep(Duration::from_millis(10)).await;
+	assert!(read_time_in_queue() > first_time_in_queue);
+
+	// Registering the merge request again does not reset its time in the queue
+	let queued_mr = list_merge_requests(&state).pop().unwrap();
+	queue_merge_request(&state, &queued_mr, &MergeRequestQueuedMessage::None)
+		.await
+		.unwrap();
+	assert!(read_time_in_queue() > first_time_in_queue);
+}
+
+#[tokio::test]
+async fn transient_api_errors_do_not_cancel_the_merge() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "unavailable";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(1)
+		.respond_with(status_code(503)),
+	);
+	// No error should be reported to the pull request
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/{}/comments",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(0)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, head_sha)
+		.await
+		.unwrap();
+
+	assert!(state.db.get(head_sha.as_bytes()).unwrap().is_some());
+}
+
+#[tokio::test]
+async fn failing_checks_are_named_in_the_comment() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "failing_checks";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let failing_check = GithubCheckRun {
+		id: 2,
+		name: "lint".to_string(),
+		status: GithubCheckRunStatus::Completed,
+		conclusion: Some(GithubCheckRunConclusion::Unknown),
+		head_sha: head_sha.to_string(),
+		html_url: Some("https://ci.example.com/lint".to_string()),
+	};
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1..)
+		.respond_with(json_encoded(&pr)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubCheckRuns {
+			check_runs: vec![
+				GithubCheckRun {
+					id: 1,
+					name: "build".to_string(),
+					status: GithubCheckRunStatus::Completed,
+					conclusion: Some(GithubCheckRunConclusion::Success),
+					head_sha: head_sha.to_string(),
+					html_url: None,
+				},
+				GithubCheckRun {
+					id: 2,
+					name: "lint".to_string(),
+					status: GithubCheckRunStatus::Completed,
+					conclusion: Some(GithubCheckRunConclusion::Unknown),
+					head_sha: head_sha.to_string(),
+					html_url: Some("https://ci.example.com/lint".to_string()),
+				},
+			],
+		})),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches(
+				r"Failing checks:\\n- \[lint\]\(https://ci\.example\.com/lint\)"
+			)),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	let (_, result) = handle_github_payload(
+		GithubWebhookPayload::CheckRun {
+			check_run: failing_check,
+			repository: GithubIssueRepository {
+				owner: owner.clone(),
+				name: repo_name.to_string(),
+			},
+		},
+		&state,
+	)
+	.await;
+	result.unwrap();
+	process_next_queued_commit(&state).await.unwrap();
+
+	assert!(state.db.get(head_sha.as_bytes()).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn merge_commit_uses_the_configured_templates() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "templated";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = GithubPullRequest {
+		title: "Fix the frobnicator".to_string(),
+		..build_pull_request(
+			&owner,
+			repo_name,
+			number,
+			head_sha,
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number);
+
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, head_sha);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("PUT", format!("{}/merge", pr_api_path)),
+			request::body(matches(
+				r#""commit_title":"Fix the frobnicator \(#1\)""#
+			)),
+			request::body(matches(
+				r#""commit_message":"Merged through processbot""#
+			)),
+		])
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	// The repository's template takes precedence over the default one
+	config.merge_commit_title_template = Some("{title}".to_string());
+	config.merge_commit_title_template_overrides.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		"{title} (#{number})".to_string(),
+	);
+	config.merge_commit_message_template =
+		Some("Merged through processbot".to_string());
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, head_sha)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn merge_commit_message_ends_with_the_configured_trailers() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let requester = "requester";
+	let repo_name = "trailers";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number);
+
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, head_sha);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("PUT", format!("{}/merge", pr_api_path)),
+			request::body(matches(&format!(
+				r#""commit_message":"Merged through processbot\\n\\nMerged-by: processbot\\nRequested-by: {}""#,
+				requester
+			))),
+		])
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.merge_commit_message_template =
+		Some("Merged through processbot".to_string());
+	config.merge_commit_trailers = vec![
+		"Merged-by: processbot".to_string(),
+		"Requested-by: {requested_by}".to_string(),
+	];
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		requested_by: requester.to_string(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, head_sha)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn default_merge_commit_message_is_kept_without_a_template() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let requester = "requester";
+	let repo_name = "default_message";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number);
+
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, head_sha);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("PUT", format!("{}/merge", pr_api_path)),
+			// Github generates the message if it's omitted
+			request::body(not(matches("commit_message"))),
+		])
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.merge_commit_trailers = vec![
+		"Merged-by: processbot".to_string(),
+		"Requested-by: {requested_by}".to_string(),
+	];
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		requested_by: requester.to_string(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, head_sha)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn merge_commits_are_replaced_by_rebases_for_linear_history() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "linear_history";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number);
+
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, head_sha);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/branches/master/protection",
+				&owner.login, repo_name
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubBranchProtection {
+			required_linear_history: Some(GithubBranchProtectionSetting {
+				enabled: true,
+			}),
+		})),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("PUT", format!("{}/merge", pr_api_path)),
+			request::body(matches(r#""merge_method":"rebase""#)),
+		])
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.merge_method_overrides.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		GithubMergeMethod::Merge,
+	);
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, head_sha)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn merge_commits_are_kept_if_the_branch_protection_is_unreadable() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "unreadable_protection";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let pr_api_path =
+		format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number);
+
+	github_api.expect(
+		Expectation::matching(request::method_path("GET", pr_api_path.clone()))
+			.times(0..)
+			.respond_with(json_encoded(&pr)),
+	);
+	setup_successful_commit_checks(&github_api, &owner, repo_name, head_sha);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/branches/master/protection",
+				&owner.login, repo_name
+			),
+		))
+		.times(1)
+		// Reading the protection requires admin permissions on the repository
+		.respond_with(status_code(403)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("PUT", format!("{}/merge", pr_api_path)),
+			request::body(matches(r#""merge_method":"merge""#)),
+		])
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.merge_method_overrides.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		GithubMergeMethod::Merge,
+	);
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	process_commit_checks_and_statuses(&state, head_sha)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn queued_message_template_can_be_overridden() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "queued_template";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches("Queued until the checks pass")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config
+		.message_templates
+		.set(Message::Queued, "Queued until the checks pass.".to_string());
+	let state = build_state(config);
+
+	let mr = MergeRequest {
+		html_url: format!(
+			"{}/pull/{}",
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER, number
+		),
+		..build_merge_request("sha", &owner.login, repo_name, number)
+	};
+	queue_merge_request(&state, &mr, &MergeRequestQueuedMessage::Default)
+		.await
+		.unwrap();
+}
+
+#[tokio::test]
+async fn queued_comment_is_posted_once() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "queued_once";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches("Waiting for commit status")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body(r#"{"id":123}"#)),
+	);
+	// A different explanation is edited into the existing comment
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"PATCH",
+				format!(
+					"/repos/{}/{}/issues/comments/123",
+					&owner.login, repo_name
+				),
+			),
+			request::body(matches("Waiting for the dependencies")),
+		])
+		.times(1)
+		.respond_with(status_code(200).body(r#"{"id":123}"#)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = MergeRequest {
+		html_url: format!(
+			"{}/pull/{}",
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER, number
+		),
+		..build_merge_request("sha", &owner.login, repo_name, number)
+	};
+	// The pull request is queued again after its branch is updated
+	for sha in &["sha", "updated_sha"] {
+		queue_merge_request(
+			&state,
+			&MergeRequest {
+				sha: sha.to_string(),
+				..mr.clone()
+			},
+			&MergeRequestQueuedMessage::Default,
+		)
+		.await
+		.unwrap();
+	}
+	queue_merge_request(
+		&state,
+		&mr,
+		&MergeRequestQueuedMessage::Custom("Waiting for the dependencies."),
+	)
+	.await
+	.unwrap();
+}
+
+#[tokio::test]
+async fn queued_comment_is_not_posted_again_shortly_after_a_cancellation() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "queued_again";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches("Waiting for commit status")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body(r#"{"id":123}"#)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let mr = MergeRequest {
+		html_url: format!(
+			"{}/pull/{}",
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER, number
+		),
+		..build_merge_request("sha", &owner.login, repo_name, number)
+	};
+	// The merge is cancelled and requested again right away
+	for _ in 0..2 {
+		queue_merge_request(&state, &mr, &MergeRequestQueuedMessage::Default)
+			.await
+			.unwrap();
+		cleanup_merge_request(
+			&state,
+			&mr.sha,
+			&mr.owner,
+			&mr.repo,
+			mr.number,
+			&MergeRequestCleanupReason::Cancelled,
+		)
+		.await
+		.unwrap();
+	}
+}
+
+#[tokio::test]
+async fn out_of_date_branches_are_updated_before_merging() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "out_of_date";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"stale_head",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let updated_pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"updated_head",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	let merge_path = format!(
+		"/repos/{}/{}/pulls/{}/merge",
+		&owner.login, repo_name, number
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("PUT", merge_path.clone()),
+			request::body(matches("stale_head")),
+		])
+		.times(1)
+		.respond_with(status_code(405).body(
+			r#"{"message":"Head branch is out of date. Review and try the merge again."}"#,
+		)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"PUT",
+				format!(
+					"/repos/{}/{}/pulls/{}/update-branch",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches("stale_head")),
+		])
+		.times(1)
+		.respond_with(status_code(202).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1..)
+		.respond_with(json_encoded(&updated_pr)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("PUT", merge_path),
+			request::body(matches("updated_head")),
+		])
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	merge_pull_request(&state, &pr, &owner.login)
+		.await
+		.unwrap()
+		.unwrap();
+}
+
+#[tokio::test]
+async fn pull_requests_merged_by_someone_else_are_cleaned_up() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "merged_elsewhere";
+	let number = 1;
+	let head_sha = "merged_elsewhere_sha";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let build_pr = || {
+		build_pull_request(
+			&owner,
+			repo_name,
+			number,
+			head_sha,
+			"master",
+			"contributor_patches",
+			&github_api_url,
+			URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+		)
+	};
+	let pr = build_pr();
+
+	// The pull request was merged manually right before processbot's attempt
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!(
+				"/repos/{}/{}/pulls/{}/merge",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1)
+		.respond_with(
+			status_code(405)
+				.body(r#"{"message":"Pull Request is already merged"}"#),
+		),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/{}/pulls/{}", &owner.login, repo_name, number),
+		))
+		.times(1..)
+		.respond_with(json_encoded(GithubPullRequest {
+			merged: true,
+			state: GithubPullRequestState::Closed,
+			..build_pr()
+		})),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		..build_merge_request(head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	merge_pull_request(&state, &pr, &owner.login)
+		.await
+		.unwrap()
+		.unwrap();
+	assert!(state.db.get(head_sha.as_bytes()).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn merge_queue_repositories_are_reported() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "merge_queue";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"head",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!(
+				"/repos/{}/{}/pulls/{}/merge",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1)
+		.respond_with(status_code(405).body(
+			r#"{"message":"Changes must be made through the merge queue"}"#,
+		)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches("GitHub's merge queue")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let err = merge_pull_request(&state, &pr, &owner.login)
+		.await
+		.unwrap_err();
+	assert!(matches!(err, Error::MergeQueueRequired { .. }));
+	handle_error(
+		PullRequestMergeCancelOutcome::ShaNotFound,
+		err.with_pull_request_details(PullRequestDetails {
+			owner: owner.login.clone(),
+			repo: repo_name.to_string(),
+			number,
+		}),
+		&state,
+	)
+	.await;
+}
+
+#[tokio::test]
+async fn default_reviewers_are_requested_when_approvals_are_missing() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "missing_approvals";
+	let number = 1;
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		"head",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!(
+				"/repos/{}/{}/pulls/{}/merge",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1)
+		.respond_with(status_code(405).body(
+			r#"{"message":"At least 1 approving review is required by reviewers with write access."}"#,
+		)),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/pulls/{}/requested_reviewers",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(json_decoded(eq(serde_json::json!({
+				"reviewers": ["reviewer"],
+				"team_reviewers": ["team"],
+			})))),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(matches("needs more approving reviews")),
+			request::body(matches("reviewer, owner/team")),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	// The author is left out since they can't review their own pull request
+	config.default_reviewers.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		vec![
+			"reviewer".to_string(),
+			owner.login.clone(),
+			"owner/team".to_string(),
+		],
+	);
+	let state = build_state(config);
+
+	let err = merge_pull_request(&state, &pr, &owner.login)
+		.await
+		.unwrap_err();
+	assert!(matches!(err, Error::ApprovalsRequired { .. }));
+	handle_error(
+		PullRequestMergeCancelOutcome::ShaNotFound,
+		err.with_pull_request_details(PullRequestDetails {
+			owner: owner.login.clone(),
+			repo: repo_name.to_string(),
+			number,
+		}),
+		&state,
+	)
+	.await;
+}
+
+#[tokio::test]
+async fn checks_and_statuses_are_fetched_through_graphql() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "graphql";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	// The REST endpoints for the checks and statuses are not expected to be
+	// requested
+	let expected_variables = serde_json::json!({
+		"owner": owner.login,
+		"repo": repo_name,
+		"sha": head_sha,
+	});
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("POST", "/graphql"),
+			request::body(json_decoded(move |body: &serde_json::Value| {
+				body["variables"] == expected_variables
+			})),
+		])
+		.times(1)
+		.respond_with(json_encoded(serde_json::json!({
+			"data": {
+				"repository": {
+					"object": {
+						"status": {
+							"contexts": [{
+								"context": "continuous-integration/gitlab-test",
+								"state": "SUCCESS",
+								"description": null,
+								"targetUrl": null,
+							}],
+						},
+						"checkSuites": {
+							"pageInfo": { "hasNextPage": false },
+							"nodes": [{
+								"checkRuns": {
+									"pageInfo": { "hasNextPage": false },
+									"nodes": [{
+										"databaseId": 1,
+										"name": "test",
+										"status": "COMPLETED",
+										"conclusion": "SUCCESS",
+										"detailsUrl": null,
+									}],
+								},
+							}],
+						},
+					},
+				},
+			},
+		}))),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.github_graphql_enabled = true;
+	let state = build_state(config);
+
+	assert!(is_ready_to_merge(&state, &pr).await.unwrap().is_ready());
+}
+
+#[tokio::test]
+async fn skipped_checks_do_not_block_the_merge() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "skipped_checks";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	let build_check_run = |id, name: &str, conclusion| GithubCheckRun {
+		id,
+		name: name.to_string(),
+		status: GithubCheckRunStatus::Completed,
+		conclusion: Some(conclusion),
+		head_sha: head_sha.to_string(),
+		html_url: None,
+	};
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(2)
+		.respond_with(json_encoded(GithubCheckRuns {
+			check_runs: vec![
+				build_check_run(1, "build", GithubCheckRunConclusion::Success),
+				build_check_run(2, "docs", GithubCheckRunConclusion::Skipped),
+				build_check_run(3, "lint", GithubCheckRunConclusion::Neutral),
+			],
+		})),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/statuses/{}",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(Vec::<GithubCommitStatus>::new())),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	assert_eq!(
+		is_ready_to_merge(&state, &pr).await.unwrap(),
+		MergeReadiness::Ready
+	);
+
+	// Only the configured conclusions are considered passing
+	let strict_db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		strict_db_dir.path(),
+		strict_db_dir.path(),
+	);
+	config.passing_check_conclusions = vec![GithubCheckRunConclusion::Neutral];
+	let state = build_state(config);
+	match is_ready_to_merge(&state, &pr).await {
+		Err(Error::ChecksFailed { failing_checks, .. }) => {
+			assert_eq!(
+				failing_checks
+					.iter()
+					.map(|check| check.name.as_str())
+					.collect::<Vec<_>>(),
+				vec!["docs"]
+			)
+		}
+		result => panic!("Unexpected result: {:?}", result),
+	}
+}
+
+#[tokio::test]
+async fn pending_checks_are_named_when_not_ready() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "pending_checks";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	let build_check_run = |id, name: &str, status, conclusion| GithubCheckRun {
+		id,
+		name: name.to_string(),
+		status,
+		conclusion,
+		head_sha: head_sha.to_string(),
+		html_url: None,
+	};
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(1)
+		.respond_with(json_encoded(GithubCheckRuns {
+			check_runs: vec![
+				build_check_run(1, "test", GithubCheckRunStatus::Unknown, None),
+				build_check_run(
+					2,
+					"lint",
+					GithubCheckRunStatus::Completed,
+					Some(GithubCheckRunConclusion::Unknown),
+				),
+				build_check_run(
+					3,
+					"build",
+					GithubCheckRunStatus::Unknown,
+					None,
+				),
+				build_check_run(
+					4,
+					"fmt",
+					GithubCheckRunStatus::Completed,
+					Some(GithubCheckRunConclusion::Success),
+				),
+			],
+		})),
+	);
+	// The statuses are not evaluated while checks are pending
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/statuses/{}",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(0)
+		.respond_with(status_code(200)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	let readiness = is_ready_to_merge(&state, &pr).await.unwrap();
+	assert_eq!(
+		readiness,
+		MergeReadiness::WaitingOnChecks {
+			pending: vec!["build".to_string(), "test".to_string()],
+			failing: vec!["lint".to_string()],
+		}
+	);
+	assert_eq!(
+		readiness.to_string(),
+		"waiting on 2 checks: build, test; failing: lint"
+	);
+}
+
+#[tokio::test]
+async fn merge_success_comment_is_posted_when_configured() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "merge_success";
+	let number = 1;
+	let head_sha = "merge_success_sha";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	let dependent_html_url = "https://github.com/owner/dependent/pull/2";
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!(
+				"/repos/{}/{}/pulls/{}/merge",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1)
+		.respond_with(status_code(200).body("{}")),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path(
+				"POST",
+				format!(
+					"/repos/{}/{}/issues/{}/comments",
+					&owner.login, repo_name, number
+				),
+			),
+			request::body(json_decoded(move |body: &serde_json::Value| {
+				body["body"]
+					== format!(
+						"Merged as requested by requester.\n- {}",
+						dependent_html_url
+					)
+			})),
+		])
+		.times(1)
+		.respond_with(status_code(201).body("{}")),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.message_templates.set(
+		Message::MergeSucceeded,
+		"Merged as requested by {requested_by}.\n{dependents}".to_string(),
+	);
+	let state = build_state(config);
+
+	let dependent = MergeRequest {
+		was_updated: false,
+		html_url: dependent_html_url.to_string(),
+		requested_by: "requester".to_string(),
+		dependencies: Some(vec![MergeRequestDependency {
+			sha: head_sha.to_string(),
+			owner: owner.login.clone(),
+			repo: repo_name.to_string(),
+			number,
+			html_url: pr.html_url.clone(),
+			is_directly_referenced: true,
+		}]),
+		..build_merge_request("dependent_sha", &owner.login, "dependent", 2)
+	};
+	state
+		.db
+		.put(
+			dependent.sha.as_bytes(),
+			bincode::serialize(&dependent).unwrap(),
+		)
+		.unwrap();
+
+	merge_pull_request(&state, &pr, "requester")
+		.await
+		.unwrap()
+		.unwrap();
+}
+
+#[tokio::test]
+async fn pull_requests_without_checks_can_be_required_to_wait() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "no_checks";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(2)
+		.respond_with(json_encoded(GithubCheckRuns { check_runs: vec![] })),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/statuses/{}",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(2)
+		.respond_with(json_encoded(Vec::<GithubCommitStatus>::new())),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+	assert_eq!(
+		is_ready_to_merge(&state, &pr).await.unwrap(),
+		MergeReadiness::Ready
+	);
+
+	let strict_db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		strict_db_dir.path(),
+		strict_db_dir.path(),
+	);
+	config
+		.require_at_least_one_check
+		.insert(format!("{}/{}", &owner.login, repo_name), true);
+	let state = build_state(config);
+	let readiness = is_ready_to_merge(&state, &pr).await.unwrap();
+	assert_eq!(readiness, MergeReadiness::NoChecks);
+	assert!(!readiness.is_ready());
+}
+
+#[tokio::test]
+async fn pull_requests_wait_for_the_approval_of_required_review_teams() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let team_member = GithubUser {
+		login: "core_dev".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let outsider = GithubUser {
+		login: "outsider".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "required_review_teams";
+	let number = 1;
+	let head_sha = "head";
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		head_sha,
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/commits/{}/check-runs",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(2)
+		.respond_with(json_encoded(GithubCheckRuns { check_runs: vec![] })),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/repos/{}/{}/statuses/{}",
+				&owner.login, repo_name, head_sha
+			),
+		))
+		.times(2)
+		.respond_with(json_encoded(Vec::<GithubCommitStatus>::new())),
+	);
+
+	let review =
+		|user: &GithubUser, state, commit_id: &str| GithubPullRequestReview {
+			user: Some(user.clone()),
+			state,
+			commit_id: Some(commit_id.to_string()),
+		};
+	let reviews_path = format!(
+		"/repos/{}/{}/pulls/{}/reviews",
+		&owner.login, repo_name, number
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("GET", reviews_path.clone()),
+			request::query(url_decoded(contains(("page", "1")))),
+		])
+		.times(2)
+		.respond_with(cycle![
+			// The team member has only approved a previous commit and commented on the current
+			// one so far
+			json_encoded(vec![
+				review(
+					&team_member,
+					GithubPullRequestReviewState::Approved,
+					"previous_head"
+				),
+				review(
+					&outsider,
+					GithubPullRequestReviewState::Approved,
+					head_sha
+				),
+				review(
+					&team_member,
+					GithubPullRequestReviewState::Commented,
+					head_sha
+				),
+			]),
+			json_encoded(vec![
+				review(
+					&team_member,
+					GithubPullRequestReviewState::Approved,
+					"previous_head"
+				),
+				review(
+					&outsider,
+					GithubPullRequestReviewState::Approved,
+					head_sha
+				),
+				review(
+					&team_member,
+					GithubPullRequestReviewState::Commented,
+					head_sha
+				),
+				review(
+					&team_member,
+					GithubPullRequestReviewState::Approved,
+					head_sha
+				),
+			]),
+		]),
+	);
+	github_api.expect(
+		Expectation::matching(all_of![
+			request::method_path("GET", reviews_path),
+			request::query(url_decoded(contains(("page", "2")))),
+		])
+		.times(2)
+		.respond_with(json_encoded(Vec::<GithubPullRequestReview>::new())),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/orgs/{}/teams/core-devs/memberships/{}",
+				&owner.login, &outsider.login
+			),
+		))
+		.times(1)
+		.respond_with(status_code(404)),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!(
+				"/orgs/{}/teams/core-devs/memberships/{}",
+				&owner.login, &team_member.login
+			),
+		))
+		.times(1)
+		.respond_with(status_code(200)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.required_review_teams.insert(
+		format!("{}/{}", &owner.login, repo_name),
+		vec![format!("{}/core-devs", &owner.login)],
+	);
+	let state = build_state(config);
+
+	let readiness = is_ready_to_merge(&state, &pr).await.unwrap();
+	assert_eq!(
+		readiness,
+		MergeReadiness::WaitingOnReviews {
+			teams: vec![format!("{}/core-devs", &owner.login)]
+		}
+	);
+	assert_eq!(
+		readiness.to_string(),
+		format!("waiting on an approval from {}/core-devs", &owner.login)
+	);
+
+	assert_eq!(
+		is_ready_to_merge(&state, &pr).await.unwrap(),
+		MergeReadiness::Ready
+	);
+}
+
+#[test]
+fn placeholders_are_not_replaced_within_values() {
+	let templates = MessageTemplates::default();
+	assert_eq!(
+		templates.render(
+			Message::MergeAttemptFailed,
+			&[
+				("attempt", "1"),
+				("error", "{max_attempts} is {unknown}"),
+				("max_attempts", "2"),
+			],
+		),
+		"Attempt 1 of 2 to merge this pull request failed: {max_attempts} is {unknown}"
+	);
+}
@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+use httptest::{matchers::*, responders::*, Expectation};
+use parity_processbot::{error::Error, github::*};
+use serde_json::json;
+
+mod helpers;
+
+use helpers::{constants::*, setup::*};
+
+// The throttle is global, thus this test lives in its own binary so that it
+// does not delay the requests of other tests
+#[tokio::test]
+async fn secondary_rate_limits_hold_off_every_request() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/abused/pulls/1", &owner.login),
+		))
+		.times(1)
+		.respond_with(
+			status_code(403)
+				.append_header("Content-Type", "application/json")
+				.body(
+					json!({
+						"message": "You have exceeded a secondary rate limit. Please wait a few minutes before you try again."
+					})
+					.to_string(),
+				),
+		),
+	);
+	let other_pr = build_pull_request(
+		&owner,
+		"other",
+		1,
+		"sha",
+		"master",
+		"contributor_patches",
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"GET",
+			format!("/repos/{}/other/pulls/1", &owner.login),
+		))
+		.times(1)
+		.respond_with(json_encoded(&other_pr)),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let mut config = build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	);
+	config.github_secondary_rate_limit_backoff = 1;
+	let gh_client = GithubClient::new(&config);
+
+	let err = gh_client
+		.pull_request(&owner.login, "abused", 1)
+		.await
+		.unwrap_err();
+	assert!(matches!(err, Error::Response { status, .. } if status == 403));
+
+	// Requests to other endpoints are held off as well
+	let started_at = Instant::now();
+	gh_client
+		.pull_request(&owner.login, "other", 1)
+		.await
+		.unwrap();
+	assert!(started_at.elapsed() >= Duration::from_millis(900));
+}
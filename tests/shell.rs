@@ -0,0 +1,95 @@
+use std::{
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use parity_processbot::{
+	error::Error,
+	shell::{
+		run_cmd, set_command_timeout, set_secret, CommandMessage,
+		CommandMessageConfiguration,
+	},
+};
+
+lazy_static::lazy_static! {
+	static ref LOGS: Mutex<Vec<String>> = Mutex::new(vec![]);
+}
+
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+	fn enabled(&self, _: &log::Metadata) -> bool {
+		true
+	}
+
+	fn log(&self, record: &log::Record) {
+		LOGS.lock().unwrap().push(format!("{}", record.args()));
+	}
+
+	fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger;
+
+// The timeout is global, thus this test lives in its own binary so that it
+// does not affect the commands of other tests
+#[tokio::test]
+async fn commands_are_killed_after_the_timeout() {
+	set_command_timeout(Some(Duration::from_secs(1)));
+
+	let dir = tempfile::tempdir().unwrap();
+	let started_at = Instant::now();
+	let err = run_cmd(
+		"sleep",
+		&["30"],
+		dir.path(),
+		CommandMessage::Configured::<'_, &str>(CommandMessageConfiguration {
+			secrets_to_hide: None,
+			are_errors_silenced: false,
+		}),
+	)
+	.await
+	.unwrap_err();
+
+	assert!(matches!(
+		err,
+		Error::CommandTimedOut { timeout, .. } if timeout == Duration::from_secs(1)
+	));
+	assert!(started_at.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn configured_secrets_are_redacted_from_command_output() {
+	log::set_logger(&LOGGER).unwrap();
+	log::set_max_level(log::LevelFilter::Trace);
+
+	let token = "super-secret-token";
+	set_secret("test token", token);
+
+	let dir = tempfile::tempdir().unwrap();
+	let err = run_cmd(
+		"sh",
+		&["-c", &format!("echo {} >&2; exit 1", token)],
+		dir.path(),
+		// The token is not listed in secrets_to_hide on purpose
+		CommandMessage::Configured::<'_, &str>(CommandMessageConfiguration {
+			secrets_to_hide: None,
+			are_errors_silenced: false,
+		}),
+	)
+	.await
+	.unwrap_err();
+
+	match err {
+		Error::CommandFailed { cmd, err, .. } => {
+			assert!(!cmd.contains(token));
+			assert!(!err.contains(token));
+			assert!(err.contains("${SECRET}"));
+		}
+		err => panic!("Unexpected error: {:?}", err),
+	}
+
+	let logs = LOGS.lock().unwrap();
+	assert!(logs.iter().any(|line| line.contains("${SECRET}")));
+	assert!(logs.iter().all(|line| !line.contains(token)));
+}
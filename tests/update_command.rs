@@ -0,0 +1,158 @@
+use std::{env, fs, path::Path};
+
+use httptest::{matchers::*, responders::*, Expectation};
+use parity_processbot::{
+	core::{handle_command, CommentCommand},
+	github::*,
+	merge_request::{deserialize_merge_request, MergeRequest},
+};
+
+mod helpers;
+
+use helpers::{cmd::*, constants::*, initialize_repository, setup::*};
+
+// Github's remotes are redirected to the local repositories of `git_dir` for
+// every Git command of this process, thus this test lives in its own binary
+fn redirect_github_remotes(git_dir: &Path) {
+	env::set_var("GIT_CONFIG_COUNT", "1");
+	env::set_var(
+		"GIT_CONFIG_KEY_0",
+		format!("url.{}/.insteadOf", git_dir.display()),
+	);
+	// The token is the one given by setup_github_api
+	env::set_var(
+		"GIT_CONFIG_VALUE_0",
+		"https://x-access-token:does not matter@github.com/",
+	);
+}
+
+fn commit_file(repo_dir: &Path, file: &str, contents: &str) {
+	fs::write(repo_dir.join(file), contents).unwrap();
+	exec("git", &["add", "."], Some(repo_dir), None);
+	exec(
+		"git",
+		&["commit", "-m", &format!("change {}", file)],
+		Some(repo_dir),
+		None,
+	);
+}
+
+#[tokio::test]
+async fn update_command_pushes_the_branch_without_merging() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let repo_name = "repo";
+	let pr_branch = "contributor_patches";
+	let number = 1;
+
+	let git_dir = tempfile::tempdir().unwrap();
+	redirect_github_remotes(git_dir.path());
+	let upstream_dir = git_dir
+		.path()
+		.join(&owner.login)
+		.join(format!("{}.git", repo_name));
+	fs::create_dir_all(&upstream_dir).unwrap();
+	initialize_repository(&upstream_dir, "master");
+	exec(
+		"git",
+		&["checkout", "-b", pr_branch],
+		Some(&upstream_dir),
+		None,
+	);
+	commit_file(&upstream_dir, "README", "contributor changes");
+	let head_sha =
+		get_cmd_output("git", &["rev-parse", "HEAD"], Some(&upstream_dir));
+	// The base branch moves on after the pull request is created
+	exec("git", &["checkout", "master"], Some(&upstream_dir), None);
+	commit_file(&upstream_dir, "CHANGELOG", "base changes");
+
+	let (github_api, github_api_url) = setup_github_api(&owner);
+	let pr = build_pull_request(
+		&owner,
+		repo_name,
+		number,
+		&head_sha,
+		"master",
+		pr_branch,
+		&github_api_url,
+		URL_PLACEHOLDER_WHICH_DOES_NOT_MATTER,
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"POST",
+			format!(
+				"/repos/{}/{}/issues/{}/comments",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(1)
+		.respond_with(
+			status_code(201)
+				.append_header("Content-Type", "application/json")
+				.body("{}"),
+		),
+	);
+	github_api.expect(
+		Expectation::matching(request::method_path(
+			"PUT",
+			format!(
+				"/repos/{}/{}/pulls/{}/merge",
+				&owner.login, repo_name, number
+			),
+		))
+		.times(0),
+	);
+
+	let db_dir = tempfile::tempdir().unwrap();
+	let repos_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		repos_dir.path(),
+	));
+	// The pull request's merge is queued, e.g. while it waits for its checks
+	let mr = MergeRequest {
+		html_url: pr.html_url.clone(),
+		requested_by: "requester".to_string(),
+		..build_merge_request(&head_sha, &owner.login, repo_name, number)
+	};
+	state
+		.db
+		.put(head_sha.as_bytes(), bincode::serialize(&mr).unwrap())
+		.unwrap();
+
+	handle_command(&state, &CommentCommand::Update, &pr, &owner.login)
+		.await
+		.unwrap();
+
+	// The base branch was merged into the pull request's branch and pushed
+	let updated_sha =
+		get_cmd_output("git", &["rev-parse", pr_branch], Some(&upstream_dir));
+	assert_ne!(updated_sha, head_sha);
+	assert_eq!(
+		get_cmd_output(
+			"git",
+			&["log", "-1", "--format=%P", pr_branch],
+			Some(&upstream_dir),
+		)
+		.split_whitespace()
+		.count(),
+		2
+	);
+	assert!(get_cmd_success(
+		"git",
+		&["merge-base", "--is-ancestor", "master", pr_branch],
+		Some(&upstream_dir),
+	));
+
+	// The queued merge follows the pushed commit
+	assert!(state.db.get(head_sha.as_bytes()).unwrap().is_none());
+	let updated_mr = deserialize_merge_request(
+		&state.db.get(updated_sha.as_bytes()).unwrap().unwrap(),
+	)
+	.unwrap();
+	assert_eq!(updated_mr.requested_by, "requester");
+}
@@ -0,0 +1,73 @@
+use parity_processbot::{
+	bot::{handle_github_payload, process_next_queued_commit},
+	github::*,
+	work_queue::WorkQueue,
+};
+
+mod helpers;
+
+use helpers::setup::*;
+
+#[tokio::test]
+async fn commits_of_different_repositories_are_interleaved() {
+	let owner = GithubUser {
+		login: "owner".to_string(),
+		type_field: GithubUserType::User,
+	};
+	let (_github_api, github_api_url) = setup_github_api(&owner);
+	let db_dir = tempfile::tempdir().unwrap();
+	let state = build_state(build_config(
+		&owner.login,
+		&github_api_url,
+		db_dir.path(),
+		db_dir.path(),
+	));
+
+	// The first repository floods the queue before the second one gets any event
+	for (repo_name, sha) in &[
+		("a", "a1"),
+		("a", "a2"),
+		("a", "a3"),
+		("b", "b1"),
+		("b", "b2"),
+	] {
+		let (_, result) = handle_github_payload(
+			GithubWebhookPayload::CommitStatus {
+				sha: sha.to_string(),
+				state: GithubCommitStatusState::Success,
+				repository: GithubIssueRepository {
+					owner: owner.clone(),
+					name: repo_name.to_string(),
+				},
+			},
+			&state,
+		)
+		.await;
+		result.unwrap();
+	}
+
+	let mut processed_shas = vec![];
+	while let Some(sha) = process_next_queued_commit(&state).await {
+		processed_shas.push(sha);
+	}
+	assert_eq!(processed_shas, vec!["a1", "b1", "a2", "b2", "a3"]);
+}
+
+#[test]
+fn work_queue_is_bounded() {
+	let work_queue = WorkQueue::new(2);
+
+	assert!(work_queue.push("owner/repo", "a"));
+	// Pending commits are not queued twice
+	assert!(work_queue.push("owner/repo", "a"));
+	assert!(work_queue.push("owner/other_repo", "b"));
+	assert_eq!(work_queue.len(), 2);
+
+	assert!(!work_queue.push("owner/repo", "c"));
+
+	assert_eq!(
+		work_queue.pop(),
+		Some(("owner/repo".to_string(), "a".to_string()))
+	);
+	assert!(work_queue.push("owner/repo", "c"));
+}